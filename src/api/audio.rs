@@ -0,0 +1,96 @@
+use crate::AppState;
+use bytes::BytesMut;
+use futures::TryStreamExt;
+use hyper::header::HeaderValue;
+use hyper::{Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use crate::api::ui;
+use crate::api::ApiResponse;
+use crate::cache;
+use crate::library::MediaId;
+use crate::media;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MediaAudioRequest {
+    pub id: MediaId,
+
+    // Audio is transcoded to the format, the file is served as is if not given
+    #[serde(default)]
+    pub format: Option<media::AudioFormat>,
+    #[serde(default)]
+    pub seek_seconds: i32,
+    #[serde(default)]
+    pub audio_opts: media::FFMpegAudioOpts,
+}
+
+/// Serves the audio of the file, as is with range support or transcoded
+pub async fn media_audio(
+    state: Arc<AppState>,
+    request: MediaAudioRequest,
+    range: Option<String>,
+) -> ApiResponse<Response<Body>> {
+    let file = ui::get_media_path(&state, &request.id)?;
+    let format = match request.format {
+        Some(format) => format,
+        None => return file_response(&file, range).await,
+    };
+    let mut audio_opts = request.audio_opts;
+    state.loudness.apply(&file, &mut audio_opts);
+    let encode = media::encode_audio(&file, format, request.seek_seconds, &audio_opts);
+    let stream = state.jobs.run("audio", &file, encode).await?;
+    let mut response = Response::new(Body::wrap_stream(stream));
+
+    // Headers
+    response.headers_mut().insert(
+        "Content-Type",
+        HeaderValue::from_static(format.content_type()),
+    );
+    response
+        .headers_mut()
+        .insert("Cache-Control", HeaderValue::from_static("no-store"));
+    Ok(response)
+}
+
+/// Serves the file as is, with range requests for seeking
+pub async fn file_response(file: &Path, range: Option<String>) -> ApiResponse<Response<Body>> {
+    let size = tokio::fs::metadata(file).await?.len();
+    let range = match range {
+        Some(range) => match cache::parse_range(&range, size) {
+            Some(range) => Some(range),
+            None => return Ok(ui::range_not_satisfiable(size)),
+        },
+        None => None,
+    };
+    let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+    let mut data = tokio::fs::File::open(file).await?;
+    data.seek(SeekFrom::Start(start)).await?;
+    let stream =
+        FramedRead::new(data.take(end + 1 - start), BytesCodec::new()).map_ok(BytesMut::freeze);
+    let mut response = Response::new(Body::wrap_stream(stream));
+    if range.is_some() {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        response.headers_mut().insert(
+            "Content-Range",
+            HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, size)).unwrap(),
+        );
+    }
+
+    // Headers
+    response.headers_mut().insert(
+        "Content-Type",
+        HeaderValue::from_static(media::content_type(file)),
+    );
+    response
+        .headers_mut()
+        .insert("Content-Length", HeaderValue::from(end + 1 - start));
+    response
+        .headers_mut()
+        .insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+    Ok(response)
+}
@@ -0,0 +1,27 @@
+use crate::AppState;
+use std::sync::Arc;
+
+use crate::api::ApiError;
+use crate::api::ApiResponse;
+use crate::cache::{CacheStatus, TranscodeCache};
+
+fn get_cache(state: &AppState) -> ApiResponse<&TranscodeCache> {
+    state.cache.as_deref().ok_or(ApiError::CacheDisabled)
+}
+
+pub async fn status(state: Arc<AppState>) -> ApiResponse<CacheStatus> {
+    Ok(get_cache(&state)?.status())
+}
+
+pub async fn purge_all(state: Arc<AppState>) -> ApiResponse<()> {
+    get_cache(&state)?.purge_all();
+    Ok(())
+}
+
+pub async fn purge(state: Arc<AppState>, key: &str) -> ApiResponse<()> {
+    if get_cache(&state)?.purge(key) {
+        Ok(())
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
@@ -4,65 +4,388 @@ use std::net::IpAddr;
 use std::sync::Arc;
 use url::Url;
 
+use crate::api::audio::MediaAudioRequest;
+use crate::api::media;
+use crate::api::photos::MediaPhotoRequest;
+use crate::api::ui;
+use crate::api::ui::MediaShowRequest;
 use crate::api::ApiError;
 use crate::api::ApiResponse;
+use crate::browser;
 use crate::chromecast;
-use crate::chromecast::BaseMediaReceiver;
+use crate::dlna;
+use crate::library;
+use crate::library::MediaId;
+use crate::media::{Chapter, Tracks};
+use crate::mpv;
 use crate::msg;
+use crate::renderer::{DeviceKind, Renderer, RendererStatus, StatusCallback};
+use crate::sessions::{Session, SessionToken};
+use crate::slideshow;
+use percent_encoding::percent_decode_str;
+use rust_cast::channels::media::{
+    IdleReason, Image, Metadata, MovieMediaMetadata, MusicTrackMediaMetadata, PhotoMediaMetadata,
+    PlayerState, TvShowMediaMetadata,
+};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{delay_for, Duration};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ChromecastRequest {
     ip: IpAddr,
     port: Option<u16>,
     dest_id: Option<String>,
+
+    // Chromecast if not given
+    #[serde(default)]
+    kind: DeviceKind,
+
+    // Description URL of the DLNA renderer, searched with SSDP by the IP if
+    // not given
+    #[serde(default)]
+    location: Option<Url>,
+
+    // IPC socket of mpv, default socket if not given
+    #[serde(default)]
+    socket: Option<PathBuf>,
+
+    // Id of the browser receiver page, the latest page of the IP if not given
+    #[serde(default)]
+    receiver_id: Option<u64>,
+}
+
+impl ChromecastRequest {
+    /// Device of the sessions and the watch history, the receiver pages of
+    /// the same browser are told apart by the id
+    fn device(&self) -> String {
+        match self.receiver_id {
+            Some(id) => format!("{}#{}", self.ip, id),
+            None => self.ip.to_string(),
+        }
+    }
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct ChromecastCastRequest {
     url: Url,
+
+    // Library item being cast, used for the title shown on the device and the
+    // watch history. Taken from the id of the JSON query of the URL if not
+    // given, e.g. media_show URL.
+    id: Option<MediaId>,
+
+    // Starts from the position saved in the watch history, seek_seconds of the
+    // media_show URL is replaced
+    #[serde(default)]
+    resume: bool,
+
+    // Seeks past the intro detected in the item when the playback enters it
+    #[serde(default)]
+    skip_intro: bool,
+
+    // Device displays HDR, HDR video of the media_show URL is passed through
+    // as 10-bit HEVC instead of tone mapping it to SDR. Detected from the
+    // device if not given, the given value overrides it
+    #[serde(default)]
+    hdr: Option<bool>,
+
+    // Items cast one after another, the URL is the one of the item being cast
+    // and the next ones get the URL of their media type with its options
+    #[serde(default)]
+    queue: Vec<MediaId>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChromecastSlideshowRequest {
+    // URL of this server as seen by the device, e.g. http://192.168.1.2:3000/
+    base_url: Url,
+
+    // Photos shown, all photos under the folder if not given
+    #[serde(default)]
+    ids: Vec<MediaId>,
+    #[serde(default)]
+    root: Option<usize>,
+    #[serde(default)]
+    path: String,
+
+    #[serde(default = "default_interval_seconds")]
+    interval_seconds: u32,
+    #[serde(default)]
+    shuffle: bool,
+
+    // Starts over after the last photo, shuffled again with shuffle
+    #[serde(default)]
+    repeat: bool,
+
+    // Photos are rendered to slowly zooming videos instead of still images
+    #[serde(default)]
+    ken_burns: bool,
+
+    // Photos are fit inside the size, 1920x1080 if not given
+    #[serde(default)]
+    width: Option<u32>,
+    #[serde(default)]
+    height: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChromecastVolumeRequest {
+    // From 0.0 to 1.0
+    level: f32,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChromecastSeekRequest {
+    // Seconds from the beginning of the file
+    position: f32,
+}
+
+#[derive(Serialize)]
+pub struct QueueEntry {
+    id: MediaId,
+    title: String,
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    id: Option<MediaId>,
+    title: Option<String>,
+
+    // Seconds from the beginning of the file
+    position: f32,
+    duration: Option<f32>,
+    queue: Vec<QueueEntry>,
+
+    // Audio and subtitle tracks of the video, empty until probed
+    tracks: Tracks,
+}
+
+fn default_interval_seconds() -> u32 {
+    10
 }
 
+/// Shortest time a photo is shown, casting takes a few seconds
+const MIN_INTERVAL_SECONDS: u32 = 3;
+
 pub struct ChromecastApi {
     pub state: Arc<AppState>,
     pub request: ChromecastRequest,
 }
 
 impl ChromecastApi {
-    fn get_receiver(&self) -> chromecast::MediaReceiver {
-        chromecast::get_default_media_receiver(
-            &self.request.ip,
-            self.request.port,
-            self.request.dest_id.clone(),
-        )
+    async fn get_receiver(&self) -> ApiResponse<Box<dyn Renderer>> {
+        Ok(match self.request.kind {
+            DeviceKind::Chromecast => Box::new(chromecast::get_default_media_receiver(
+                &self.request.ip,
+                self.request.port,
+                self.request.dest_id.clone(),
+            )),
+            DeviceKind::Dlna => {
+                // Renderer is searched on the network unless it's known
+                let ip = self.request.ip;
+                let location = self.request.location.clone();
+                let renderer =
+                    tokio::task::spawn_blocking(move || dlna::get_renderer(&ip, location))
+                        .await
+                        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?
+                        .map_err(|err| ApiError::ChromecastError(err.into()))?;
+                Box::new(renderer)
+            }
+            DeviceKind::Mpv => Box::new(mpv::get_renderer(
+                self.request.socket.clone(),
+                &self.state.opts.mpv_args,
+            )),
+            DeviceKind::Browser => Box::new(
+                browser::get_renderer(
+                    &self.state.receivers,
+                    &self.request.ip,
+                    self.request.receiver_id,
+                )
+                .map_err(|err| ApiError::ChromecastError(err.into()))?,
+            ),
+        })
     }
 
-    pub async fn pause(&self) -> ApiResponse<chromecast::ChromecastStatus> {
+    pub async fn pause(&self) -> ApiResponse<RendererStatus> {
         self.get_receiver()
+            .await?
             .pause()
             .map_err(ApiError::ChromecastError)
     }
-    pub async fn play(&self) -> ApiResponse<chromecast::ChromecastStatus> {
+    pub async fn play(&self) -> ApiResponse<RendererStatus> {
         self.get_receiver()
+            .await?
             .play()
             .map_err(ApiError::ChromecastError)
     }
-    pub async fn stop(&self) -> ApiResponse<chromecast::ChromecastStatus> {
+    pub async fn stop(&self) -> ApiResponse<RendererStatus> {
+        self.state.slideshows.stop(&self.request.device());
         self.get_receiver()
+            .await?
             .stop()
             .map_err(ApiError::ChromecastError)
     }
-    pub async fn status(&self) -> ApiResponse<chromecast::ChromecastStatus> {
+    pub async fn status(&self) -> ApiResponse<RendererStatus> {
         self.get_receiver()
+            .await?
             .get_status()
             .map_err(ApiError::ChromecastError)
     }
+    pub async fn volume(&self, volume: ChromecastVolumeRequest) -> ApiResponse<()> {
+        self.get_receiver()
+            .await?
+            .set_volume(volume.level)
+            .map_err(ApiError::ChromecastError)
+    }
+
+    /// Seeks to the position of the file, transcodes are cast again from it
+    pub async fn seek(&self, seek: ChromecastSeekRequest) -> ApiResponse<()> {
+        match self.state.sessions.get(&self.request.device()) {
+            Some(session) => self.seek_session(&session, seek.position).await,
+            None => {
+                self.get_receiver()
+                    .await?
+                    .seek(seek.position)
+                    .map_err(ApiError::ChromecastError)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Item being cast to the device and the queue of it
+    pub async fn session(&self) -> ApiResponse<SessionInfo> {
+        let session = self
+            .state
+            .sessions
+            .get(&self.request.device())
+            .ok_or(ApiError::NotFound)?;
+        let library = self.state.library.read().unwrap();
+        let item = session.id.as_ref().and_then(|id| library.get(id));
+        let queue = session
+            .queue
+            .iter()
+            .map(|id| QueueEntry {
+                id: id.clone(),
+                title: library.get(id).map_or_else(|| id.clone(), |v| v.title()),
+            })
+            .collect();
+        Ok(SessionInfo {
+            id: session.id.clone(),
+            title: item.map(|v| v.title()),
+            position: session.position,
+            duration: item.and_then(|v| v.duration),
+            queue,
+            tracks: item
+                .and_then(|v| v.video.as_ref())
+                .and_then(|v| v.tracks.clone())
+                .unwrap_or_default(),
+        })
+    }
 
     pub async fn cast(&self, cast_request: ChromecastCastRequest) -> ApiResponse<()> {
+        self.state.slideshows.stop(&self.request.device());
+        self.cast_media(cast_request).await
+    }
+
+    /// Casts the media, without stopping the slideshow of the device
+    async fn cast_media(&self, cast_request: ChromecastCastRequest) -> ApiResponse<()> {
         let state = self.state.clone();
-        let receiver = self.get_receiver();
-        let url = cast_request.url;
-        tokio::spawn(async move {
-            match receiver.cast(url) {
+        let mut receiver = self.get_receiver().await?;
+        let device = self.request.device();
+        let mut url = cast_request.url;
+        let mut show_request = media_show_request(&url);
+        let mut audio_request = media_audio_request(&url);
+        let id = cast_request.id.or_else(|| query_id(&url));
+
+        // Audio files served as is are seeked when the playback starts
+        let mut start_position = None;
+        if let (true, Some(id)) = (cast_request.resume, &id) {
+            let position = state.history.read().unwrap().resume_position(id, &device);
+            match (&mut show_request, &mut audio_request) {
+                (Some(show_request), _) => {
+                    show_request.encode_opts.seek_seconds = position as i32;
+                    url.set_query(Some(&serde_json::to_string(show_request)?));
+                }
+                (None, Some(audio_request)) if audio_request.format.is_some() => {
+                    audio_request.seek_seconds = position as i32;
+                    url.set_query(Some(&serde_json::to_string(audio_request)?));
+                }
+                (None, Some(_)) => start_position = Some(position).filter(|v| *v > 0.0),
+                (None, None) => {}
+            }
+            println!("Resuming {} from {} seconds", id, position);
+        }
+
+        if let Some(show_request) = &mut show_request {
+            let is_hdr = state
+                .library
+                .read()
+                .unwrap()
+                .get(&show_request.id)
+                .and_then(|v| v.video.as_ref())
+                .is_some_and(|v| v.hdr.is_some());
+            // Device is only queried for HDR video
+            let hdr = match cast_request.hdr {
+                Some(hdr) => hdr,
+                None if is_hdr => {
+                    let (receiver_, hdr) = tokio::task::spawn_blocking(move || {
+                        let hdr = receiver.supports_hdr();
+                        (receiver, hdr)
+                    })
+                    .await
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+                    receiver = receiver_;
+                    hdr
+                }
+                None => false,
+            };
+            if hdr {
+                show_request.encode_opts.hdr_passthrough = true;
+                url.set_query(Some(&serde_json::to_string(show_request)?));
+            }
+        }
+
+        let metadata = id
+            .as_ref()
+            .and_then(|id| media_metadata(&self.state, id, &url));
+        let content_type = match (media_photo_request(&url), &audio_request) {
+            (Some(photo_request), _) => Some(photo_request.content_type().to_string()),
+            (None, Some(audio_request)) => match audio_request.format {
+                Some(format) => Some(format.content_type().to_string()),
+                None => ui::get_media_path(&state, &audio_request.id)
+                    .ok()
+                    .map(|v| crate::media::content_type(&v).to_string()),
+            },
+            (None, None) => None,
+        };
+
+        // Position in the stream is relative to the seek
+        let offset = match (show_request, audio_request) {
+            (Some(v), _) => v.encode_opts.seek_seconds - v.encode_opts.output_offset_seconds,
+            (None, Some(v)) if v.format.is_some() => v.seek_seconds,
+            _ => 0,
+        } as f32;
+        let token = state.sessions.start(
+            &device,
+            id.clone(),
+            url.clone(),
+            offset,
+            cast_request.skip_intro,
+            cast_request.queue,
+        );
+        let on_status = track_session(
+            state.clone(),
+            self.request.clone(),
+            token,
+            id,
+            offset,
+            start_position,
+            cast_request.skip_intro,
+        );
+        // Casting blocks until the playback ends
+        tokio::task::spawn_blocking(move || {
+            match receiver.cast(url, content_type, metadata, on_status) {
                 Ok(_) => {}
                 Err(err) => {
                     (*state)
@@ -71,7 +394,591 @@ impl ChromecastApi {
                         .unwrap();
                 }
             }
+            state.sessions.end(&device, token);
         });
         Ok(())
     }
+
+    /// Starts a slideshow of the photos on the device in background
+    ///
+    /// Returns the number of photos in the slideshow.
+    pub async fn slideshow(&self, slideshow: ChromecastSlideshowRequest) -> ApiResponse<usize> {
+        let mut ids = if slideshow.ids.is_empty() {
+            folder_photos(&self.state, slideshow.root, &slideshow.path)
+        } else {
+            slideshow.ids.clone()
+        };
+        if ids.is_empty() {
+            return Err(ApiError::NotFound);
+        }
+        let device = self.request.device();
+        let token = self.state.slideshows.start(&device);
+        let count = ids.len();
+        let api = ChromecastApi {
+            state: self.state.clone(),
+            request: self.request.clone(),
+        };
+        let interval = slideshow.interval_seconds.max(MIN_INTERVAL_SECONDS);
+        tokio::spawn(async move {
+            let mut seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(1, |v| v.as_nanos() as u64);
+            loop {
+                if slideshow.shuffle {
+                    slideshow::shuffle(&mut ids, seed);
+                    seed = seed.wrapping_add(1);
+                }
+                for id in &ids {
+                    if !api.state.slideshows.is_current(&device, token) {
+                        return;
+                    }
+                    let cast_request = ChromecastCastRequest {
+                        url: photo_url(&slideshow, id, interval),
+                        id: Some(id.clone()),
+                        resume: false,
+                        skip_intro: false,
+                        hdr: None,
+                        queue: vec![],
+                    };
+                    if let Err(err) = api.cast_media(cast_request).await {
+                        println!("Unable to cast photo {} {:?}", id, err);
+                    }
+                    delay_for(Duration::from_secs(interval.into())).await;
+                }
+                if !slideshow.repeat {
+                    break;
+                }
+            }
+            api.state.slideshows.end(&device, token);
+        });
+        Ok(count)
+    }
+
+    pub async fn next_item(&self) -> ApiResponse<MediaId> {
+        self.skip_item(1).await
+    }
+
+    pub async fn previous_item(&self) -> ApiResponse<MediaId> {
+        self.skip_item(-1).await
+    }
+
+    /// Casts the item of the queue relative to the one being cast
+    async fn skip_item(&self, step: isize) -> ApiResponse<MediaId> {
+        let session = self
+            .state
+            .sessions
+            .get(&self.request.device())
+            .ok_or(ApiError::NotFound)?;
+        let (id, url) = queue_item(&self.state, &session, step).ok_or(ApiError::NotFound)?;
+        self.cast(ChromecastCastRequest {
+            url,
+            id: Some(id.clone()),
+            resume: false,
+            skip_intro: session.skip_intro,
+            hdr: None,
+            queue: session.queue,
+        })
+        .await?;
+        Ok(id)
+    }
+
+    pub async fn next_chapter(&self) -> ApiResponse<Chapter> {
+        self.seek_chapter(true).await
+    }
+
+    pub async fn previous_chapter(&self) -> ApiResponse<Chapter> {
+        self.seek_chapter(false).await
+    }
+
+    /// Seeks the item being cast to the next or previous chapter boundary
+    async fn seek_chapter(&self, forward: bool) -> ApiResponse<Chapter> {
+        let session = self
+            .state
+            .sessions
+            .get(&self.request.device())
+            .ok_or(ApiError::NotFound)?;
+        let id = session.id.clone().ok_or(ApiError::NotFound)?;
+        let file = ui::get_media_path(&self.state, &id)?;
+        let chapters = crate::media::get_info(&file).await?.chapters;
+        let chapter = if forward {
+            crate::media::next_chapter(&chapters, session.position)
+        } else {
+            crate::media::previous_chapter(&chapters, session.position)
+        }
+        .cloned()
+        .ok_or(ApiError::NotFound)?;
+        self.seek_session(&session, chapter.start).await?;
+        Ok(chapter)
+    }
+
+    /// Seeks the session to the position
+    ///
+    /// Transcoded streams can't be seeked past the encoded part, so they are
+    /// cast again with the transcode starting from the position.
+    async fn seek_session(&self, session: &Session, position: f32) -> ApiResponse<()> {
+        match media_show_request(&session.url) {
+            Some(mut show_request) => {
+                show_request.encode_opts.seek_seconds = position as i32;
+                show_request.encode_opts.output_offset_seconds = 0;
+                let mut url = session.url.clone();
+                url.set_query(Some(&serde_json::to_string(&show_request)?));
+                self.cast(ChromecastCastRequest {
+                    url,
+                    id: session.id.clone(),
+                    resume: false,
+                    skip_intro: session.skip_intro,
+                    // Keeps the passthrough of the encode options of the URL
+                    hdr: Some(show_request.encode_opts.hdr_passthrough),
+                    queue: session.queue.clone(),
+                })
+                .await
+            }
+            None => {
+                self.get_receiver()
+                    .await?
+                    .seek(position)
+                    .map_err(ApiError::ChromecastError)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Request of the media_show URL, None if the URL is something else
+fn media_show_request(url: &Url) -> Option<MediaShowRequest> {
+    if !url.path().ends_with("/media_show") {
+        return None;
+    }
+    let query = percent_decode_str(url.query()?).decode_utf8_lossy();
+    serde_json::from_str(&query).ok()
+}
+
+/// Request of the media_audio URL, None if the URL is something else
+fn media_audio_request(url: &Url) -> Option<MediaAudioRequest> {
+    if !url.path().ends_with("/media_audio") {
+        return None;
+    }
+    let query = percent_decode_str(url.query()?).decode_utf8_lossy();
+    serde_json::from_str(&query).ok()
+}
+
+/// Id in the JSON query of the URL
+fn query_id(url: &Url) -> Option<MediaId> {
+    let query = percent_decode_str(url.query()?).decode_utf8_lossy();
+    let query: serde_json::Value = serde_json::from_str(&query).ok()?;
+    query.get("id")?.as_str().map(String::from)
+}
+
+/// Item of the queue relative to the one of the session, and the URL of it
+///
+/// URL is the one for the media type of the item, with the options of the
+/// session URL kept when it's of the same type. The seek and the tracks are
+/// reset.
+fn queue_item(state: &AppState, session: &Session, step: isize) -> Option<(MediaId, Url)> {
+    let current = session
+        .queue
+        .iter()
+        .position(|v| Some(v) == session.id.as_ref())?;
+    let id = session
+        .queue
+        .get(current.checked_add_signed(step)?)?
+        .clone();
+    let (is_audio, is_image) = {
+        let library = state.library.read().unwrap();
+        let item = library.get(&id)?;
+        (item.is_audio(), item.is_image())
+    };
+    let (path, query) = if is_image {
+        let photo_request = media_photo_request(&session.url);
+        let request = MediaPhotoRequest {
+            id: id.clone(),
+            width: photo_request.as_ref().and_then(|v| v.width),
+            height: photo_request.as_ref().and_then(|v| v.height),
+            ken_burns_seconds: photo_request.and_then(|v| v.ken_burns_seconds),
+        };
+        ("media_photo", serde_json::to_string(&request).ok()?)
+    } else if is_audio {
+        let audio_request = media_audio_request(&session.url);
+        let request = MediaAudioRequest {
+            id: id.clone(),
+            format: audio_request.as_ref().and_then(|v| v.format),
+            seek_seconds: 0,
+            audio_opts: audio_request.map(|v| v.audio_opts).unwrap_or_default(),
+        };
+        ("media_audio", serde_json::to_string(&request).ok()?)
+    } else {
+        let encode_opts = media_show_request(&session.url)
+            .map(|v| v.encode_opts.next_item())
+            .unwrap_or_default();
+        let request = MediaShowRequest {
+            id: id.clone(),
+            encode_opts,
+        };
+        ("media_show", serde_json::to_string(&request).ok()?)
+    };
+    let mut url = session.url.join(path).ok()?;
+    url.set_query(Some(&query));
+    Some((id, url))
+}
+
+/// Request of the media_photo URL, None if the URL is something else
+fn media_photo_request(url: &Url) -> Option<MediaPhotoRequest> {
+    if !url.path().ends_with("/media_photo") {
+        return None;
+    }
+    let query = percent_decode_str(url.query()?).decode_utf8_lossy();
+    serde_json::from_str(&query).ok()
+}
+
+/// URL of the photo shown in the slideshow
+fn photo_url(slideshow: &ChromecastSlideshowRequest, id: &str, interval: u32) -> Url {
+    let request = MediaPhotoRequest {
+        id: id.into(),
+        width: slideshow.width,
+        height: slideshow.height,
+        ken_burns_seconds: Some(interval).filter(|_| slideshow.ken_burns),
+    };
+    let mut url = slideshow
+        .base_url
+        .join("media_photo")
+        .unwrap_or_else(|_| slideshow.base_url.clone());
+    url.set_query(Some(&serde_json::to_string(&request).unwrap_or_default()));
+    url
+}
+
+/// Photos under the folder of the library, in the order of the paths
+fn folder_photos(state: &AppState, root: Option<usize>, path: &str) -> Vec<MediaId> {
+    let folder = Path::new(path);
+    state
+        .library
+        .read()
+        .unwrap()
+        .items()
+        .into_iter()
+        .filter(|item| item.is_image())
+        .filter(|item| {
+            library::relative_path(&item.path, &state.opts.dir)
+                .is_some_and(|(i, path)| root.is_none_or(|v| v == i) && path.starts_with(folder))
+        })
+        .map(|item| item.id.clone())
+        .collect()
+}
+
+/// Records the playback positions of the cast session to the session and the
+/// watch history
+///
+/// The renderer is seeked to `start_position` when the playback starts. With
+/// `skip_intro` the intro is skipped once per session, seeking back to the
+/// intro doesn't skip it again. Next item of the queue is cast when the item
+/// finishes.
+fn track_session(
+    state: Arc<AppState>,
+    request: ChromecastRequest,
+    token: SessionToken,
+    id: Option<MediaId>,
+    offset: f32,
+    mut start_position: Option<f32>,
+    skip_intro: bool,
+) -> StatusCallback {
+    let device = request.device();
+    let mut intro_skipped = false;
+    let mut finished = false;
+    let mut last_position = 0.0;
+    Box::new(move |status: &RendererStatus| {
+        let current_time = match (&status.player_state, status.current_time) {
+            (PlayerState::Playing, Some(v)) | (PlayerState::Paused, Some(v)) => v,
+            _ => 0.0,
+        };
+        if let PlayerState::Playing = status.player_state {
+            if let Some(position) = start_position.take() {
+                skip_to(state.clone(), request.clone(), position);
+            }
+        }
+        if current_time > 0.0 {
+            last_position = offset + current_time;
+            state.sessions.set_position(&device, token, last_position);
+        }
+        let id = match &id {
+            Some(id) => id,
+            None => return,
+        };
+        if skip_intro && !intro_skipped && current_time > 0.0 {
+            let intro = state
+                .library
+                .read()
+                .unwrap()
+                .get(id)
+                .and_then(|v| v.segments.as_ref())
+                .and_then(|v| v.intro);
+            if let Some(intro) = intro.filter(|v| v.contains(offset + current_time)) {
+                intro_skipped = true;
+                skip_to(state.clone(), request.clone(), intro.end);
+            }
+        }
+        // DLNA renderers don't tell the length of the transcoded streams, the
+        // stop near the end of the library item is finishing it
+        let stopped_at_end = match status.idle_reason {
+            Some(IdleReason::Cancelled) if request.kind == DeviceKind::Dlna => state
+                .library
+                .read()
+                .unwrap()
+                .get(id)
+                .and_then(|v| v.duration)
+                .is_some_and(|duration| last_position >= duration - dlna::FINISHED_SECONDS),
+            _ => false,
+        };
+        if stopped_at_end || matches!(status.idle_reason, Some(IdleReason::Finished)) {
+            if !finished {
+                finished = true;
+                state.history.write().unwrap().set_watched(id, &device);
+                // Session ends with the cast loop, it's taken before that
+                let session = state.sessions.get(&device).filter(|v| v.token == token);
+                if let Some(session) = session {
+                    play_next(state.clone(), request.clone(), session);
+                }
+            }
+            return;
+        }
+        if status.idle_reason.is_some() {
+            // Saves the position not saved yet
+            state.history.write().unwrap().save();
+            return;
+        }
+        if current_time <= 0.0 {
+            return;
+        }
+        let duration = state
+            .library
+            .read()
+            .unwrap()
+            .get(id)
+            .and_then(|v| v.duration);
+        state.history.write().unwrap().update(
+            id,
+            &device,
+            token,
+            offset + current_time,
+            duration,
+            state.opts.watched_percent,
+        );
+    })
+}
+
+/// Seeks the session of the device in background, e.g. past the intro
+fn skip_to(state: Arc<AppState>, request: ChromecastRequest, position: f32) {
+    tokio::spawn(async move {
+        let api = ChromecastApi { state, request };
+        let session = match api.state.sessions.get(&api.request.device()) {
+            Some(session) => session,
+            None => return,
+        };
+        println!("Seeking to {} seconds", position);
+        if let Err(err) = api.seek_session(&session, position).await {
+            println!("Unable to seek {:?}", err);
+        }
+    });
+}
+
+/// Casts the next item of the queue of the finished session in background
+fn play_next(state: Arc<AppState>, request: ChromecastRequest, session: Session) {
+    let (id, url) = match queue_item(&state, &session, 1) {
+        Some(next) => next,
+        None => return,
+    };
+    tokio::spawn(async move {
+        let api = ChromecastApi { state, request };
+        println!("Casting the next item {} of the queue", id);
+        let cast_request = ChromecastCastRequest {
+            url,
+            id: Some(id),
+            resume: false,
+            skip_intro: session.skip_intro,
+            hdr: None,
+            queue: session.queue,
+        };
+        if let Err(err) = api.cast_media(cast_request).await {
+            println!("Unable to cast the next item {:?}", err);
+        }
+    });
+}
+
+/// Chromecast metadata of the library item
+///
+/// Artwork URLs are made absolute with the cast URL, which points to this
+/// server.
+fn media_metadata(state: &AppState, id: &str, url: &Url) -> Option<Metadata> {
+    let library = state.library.read().unwrap();
+    let item = library.get(id)?;
+    let parsed = item.parsed_name();
+    let images: Vec<Image> = Some(media::poster_url(item))
+        .into_iter()
+        .chain(media::fanart_url(item))
+        .filter_map(|v| url.join(&v).ok())
+        .map(|v| Image::new(v.to_string()))
+        .collect();
+    if item.is_image() {
+        return Some(Metadata::Photo(PhotoMediaMetadata {
+            title: Some(item.name()),
+            artist: None,
+            location: None,
+            latitude_longitude: None,
+            dimensions: None,
+            creation_date_time: None,
+        }));
+    }
+    if let Some(tags) = &item.audio {
+        return Some(Metadata::MusicTrack(MusicTrackMediaMetadata {
+            album_name: tags.album.clone(),
+            title: Some(item.title()),
+            album_artist: tags.album_artist.clone(),
+            artist: tags.artist.clone(),
+            composer: tags.composer.clone(),
+            track_number: tags.track,
+            disc_number: tags.disc,
+            images,
+            release_date: tags.year.map(|v| v.to_string()),
+        }));
+    }
+    Some(if parsed.is_episode() {
+        Metadata::TvShow(TvShowMediaMetadata {
+            series_title: Some(parsed.title),
+            episode_title: parsed.episode_title,
+            season: parsed.season,
+            episode: parsed.episode,
+            images,
+            original_air_date: None,
+        })
+    } else {
+        Metadata::Movie(MovieMediaMetadata {
+            title: Some(parsed.title),
+            subtitle: item.metadata.as_ref().and_then(|v| v.tagline.clone()),
+            studio: None,
+            images,
+            release_date: parsed.year.map(|v| v.to_string()),
+        })
+    })
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::tests::test_state;
+    use crate::websocket::{self, Message};
+    use hyper::{Body, Request};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Connects a receiver page, returns the socket of the page
+    async fn connect(state: &Arc<AppState>) -> TcpStream {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let page = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let state_ = state.clone();
+        tokio::spawn(async move {
+            browser::serve(&state_.receivers, stream, addr.ip(), "Test".into(), false).await;
+        });
+        while state.receivers.list().is_empty() {
+            delay_for(Duration::from_millis(10)).await;
+        }
+        page
+    }
+
+    async fn post(state: &Arc<AppState>, path: &str, body: serde_json::Value) -> serde_json::Value {
+        let mut request = body;
+        request["ip"] = "127.0.0.1".into();
+        request["kind"] = "browser".into();
+        let request = Request::post(path)
+            .body(Body::from(request.to_string()))
+            .unwrap();
+        let response = crate::api::handle_request(state.clone(), request)
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Request of the URL the page is told to load
+    async fn read_load(page: &mut TcpStream) -> MediaShowRequest {
+        let load: serde_json::Value = match websocket::read(page).await.unwrap() {
+            Some(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            v => panic!("Unexpected message {:?}", v),
+        };
+        assert_eq!(load["command"], "load");
+        media_show_request(&load["url"].as_str().unwrap().parse().unwrap()).unwrap()
+    }
+
+    async fn report(page: &mut TcpStream, current_time: f32) {
+        let status = serde_json::json!({
+            "current_time": current_time,
+            "player_state": "PLAYING",
+            "idle_reason": null,
+        });
+        websocket::write(page, &Message::Text(status.to_string()))
+            .await
+            .unwrap();
+    }
+
+    /// Waits until the position of the session is updated, fails if it isn't
+    /// within 5 seconds
+    async fn session_at(state: &Arc<AppState>, position: f32) -> serde_json::Value {
+        let polling = async {
+            loop {
+                let session = post(state, "/chromecast/session", serde_json::json!({})).await;
+                if session["position"] == position as f64 {
+                    return session;
+                }
+                delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        let session = tokio::time::timeout(Duration::from_secs(5), polling).await;
+        assert!(session.is_ok(), "Session isn't at {} seconds", position);
+        session.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_seek_and_session() {
+        let dir = std::env::temp_dir().join(format!("casterson-chromecast-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (state, id) = test_state(&dir);
+        let mut page = connect(&state).await;
+
+        let session = post(&state, "/chromecast/session", serde_json::json!({})).await;
+        assert_eq!(session["error"], "NOT_FOUND");
+
+        let url = format!("http://127.0.0.1:3000/media_show?{{\"id\":\"{}\"}}", id);
+        let cast = serde_json::json!({ "url": url, "queue": [id] });
+        assert_eq!(
+            post(&state, "/chromecast/cast", cast).await,
+            serde_json::Value::Null
+        );
+        assert_eq!(read_load(&mut page).await.encode_opts.seek_seconds, 0);
+        report(&mut page, 5.0).await;
+        let session = session_at(&state, 5.0).await;
+        assert_eq!(session["id"], id.as_str());
+        assert!(session["title"].as_str().unwrap().starts_with("Movie"));
+        assert_eq!(session["duration"], 600.0);
+        assert_eq!(session["queue"][0]["id"], id.as_str());
+        assert!(session["tracks"]["audio"].as_array().unwrap().is_empty());
+
+        // Transcode is cast again from the position, which is relative to it
+        let seek = serde_json::json!({ "position": 100.0 });
+        assert_eq!(
+            post(&state, "/chromecast/seek", seek).await,
+            serde_json::Value::Null
+        );
+        let request = read_load(&mut page).await;
+        assert_eq!(request.id, id);
+        assert_eq!(request.encode_opts.seek_seconds, 100);
+        report(&mut page, 2.0).await;
+        session_at(&state, 102.0).await;
+
+        // Closed page ends the cast
+        websocket::write(&mut page, &Message::Close).await.unwrap();
+        while !state.receivers.list().is_empty() {
+            delay_for(Duration::from_millis(10)).await;
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,53 @@
+use crate::AppState;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+use crate::api::ApiError;
+use crate::api::ApiResponse;
+use crate::dlna;
+use crate::renderer::DeviceKind;
+
+/// Time the SSDP responses are waited for
+const DISCOVERY_SECONDS: u64 = 3;
+
+#[derive(Serialize)]
+pub struct DeviceInfo {
+    kind: DeviceKind,
+    ip: IpAddr,
+    name: String,
+
+    // Description URL of the DLNA renderer
+    location: Option<Url>,
+
+    // Id of the browser receiver page
+    receiver_id: Option<u64>,
+}
+
+/// Connected receiver pages and the DLNA renderers found on the network,
+/// Chromecasts aren't searched
+pub async fn list(state: Arc<AppState>) -> ApiResponse<Vec<DeviceInfo>> {
+    let devices =
+        tokio::task::spawn_blocking(|| dlna::discover(Duration::from_secs(DISCOVERY_SECONDS)))
+            .await
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?
+            .map_err(|err| ApiError::ChromecastError(err.into()))?;
+    let receivers = state.receivers.list().into_iter().map(|v| DeviceInfo {
+        kind: DeviceKind::Browser,
+        ip: v.ip,
+        name: v.name.clone(),
+        location: None,
+        receiver_id: Some(v.id),
+    });
+    Ok(receivers
+        .chain(devices.into_iter().map(|v| DeviceInfo {
+            kind: DeviceKind::Dlna,
+            ip: v.ip,
+            name: v.name,
+            location: Some(v.location),
+            receiver_id: None,
+        }))
+        .collect())
+}
@@ -0,0 +1,37 @@
+use crate::AppState;
+use futures::stream;
+use hyper::header::HeaderValue;
+use hyper::{Body, Response};
+use std::sync::Arc;
+use tokio::sync::broadcast::RecvError;
+
+use crate::api::ApiResponse;
+
+/// Server-sent events stream of `msg::Event`s
+pub async fn events(state: Arc<AppState>) -> ApiResponse<Response<Body>> {
+    let receiver = state.events.subscribe();
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let json = serde_json::to_string(&event).unwrap();
+                    let item: Result<String, std::io::Error> = Ok(format!("data: {}\n\n", json));
+                    return Some((item, receiver));
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    let mut response = Response::new(Body::wrap_stream(stream));
+
+    // Headers
+    response.headers_mut().insert(
+        "Content-Type",
+        HeaderValue::from_static("text/event-stream"),
+    );
+    response
+        .headers_mut()
+        .insert("Cache-Control", HeaderValue::from_static("no-store"));
+    Ok(response)
+}
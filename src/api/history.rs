@@ -0,0 +1,29 @@
+use crate::AppState;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::api::ApiResponse;
+use crate::history::HistoryEntry;
+
+#[derive(Serialize)]
+pub struct HistoryInfo {
+    #[serde(flatten)]
+    entry: HistoryEntry,
+
+    // None if the item is no longer in the library
+    title: Option<String>,
+}
+
+/// Watch history, most recently watched first
+pub async fn list(state: Arc<AppState>) -> ApiResponse<Vec<HistoryInfo>> {
+    let library = state.library.read().unwrap();
+    let history = state.history.read().unwrap();
+    Ok(history
+        .entries()
+        .into_iter()
+        .map(|entry| HistoryInfo {
+            title: library.get(&entry.id).map(|v| v.title()),
+            entry: entry.clone(),
+        })
+        .collect())
+}
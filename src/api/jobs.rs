@@ -0,0 +1,14 @@
+use crate::AppState;
+use std::sync::Arc;
+
+use crate::api::ApiResponse;
+use crate::jobs::{JobId, JobStatus};
+
+pub async fn list(state: Arc<AppState>) -> ApiResponse<Vec<JobStatus>> {
+    Ok(state.jobs.list())
+}
+
+pub async fn kill(state: Arc<AppState>, id: JobId) -> ApiResponse<()> {
+    state.jobs.kill(id)?;
+    Ok(())
+}
@@ -0,0 +1,460 @@
+use crate::AppState;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::api::media;
+use crate::api::ApiResponse;
+use crate::library;
+use crate::library::{LibraryItem, MediaId};
+use crate::media::AudioTags;
+use crate::naming::ParsedName;
+use crate::nfo::LocalMetadata;
+use crate::segments::Segments;
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SortBy {
+    Name,
+    Mtime,
+    Size,
+    Duration,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct LibraryRequest {
+    // Root directory index, all roots are listed if not given
+    pub root: Option<usize>,
+
+    // Folder relative to the root
+    pub path: String,
+
+    // Searches recursively under the folder, results are ordered by relevance
+    // unless sort is given
+    pub search: String,
+
+    pub sort: Option<SortBy>,
+    pub descending: bool,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl Default for LibraryRequest {
+    fn default() -> Self {
+        LibraryRequest {
+            root: None,
+            path: "".into(),
+            search: "".into(),
+            sort: None,
+            descending: false,
+            offset: 0,
+            limit: 100,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RootInfo {
+    index: usize,
+    name: String,
+    items: usize,
+}
+
+#[derive(Serialize)]
+pub struct ItemInfo {
+    id: MediaId,
+    root: usize,
+    path: String,
+    name: String,
+    title: String,
+    size: u64,
+    mtime: u64,
+    duration: Option<f32>,
+    parsed: ParsedName,
+    metadata: Option<LocalMetadata>,
+    poster: String,
+    fanart: Option<String>,
+    thumbnail: String,
+    trickplay: Option<String>,
+    segments: Option<Segments>,
+    audio: Option<AudioTags>,
+    photo: bool,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LibraryEntry {
+    Folder {
+        name: String,
+        path: String,
+        items: usize,
+    },
+    Item(Box<ItemInfo>),
+}
+
+#[derive(Serialize)]
+pub struct LibraryResult {
+    roots: Vec<RootInfo>,
+    root: Option<usize>,
+    path: String,
+    total: usize,
+    offset: usize,
+    entries: Vec<LibraryEntry>,
+}
+
+/// Browse the library folders, or search the library
+pub async fn browse(state: Arc<AppState>, request: LibraryRequest) -> ApiResponse<LibraryResult> {
+    let library = state.library.read().unwrap();
+    let dirs = &state.opts.dir;
+    let folder = Path::new(&request.path);
+
+    // Items under the requested folder, with their roots and relative paths
+    let items: Vec<(usize, &Path, &LibraryItem)> = library
+        .items()
+        .into_iter()
+        .filter_map(|item| {
+            let (root, path) = library::relative_path(&item.path, dirs)?;
+            Some((root, path, item))
+        })
+        .filter(|(root, path, _)| {
+            request.root.is_none_or(|v| v == *root) && path.starts_with(folder)
+        })
+        .collect();
+
+    let roots = dirs
+        .iter()
+        .enumerate()
+        .map(|(index, dir)| RootInfo {
+            index,
+            name: dir
+                .file_name()
+                .map(|v| v.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            items: items.iter().filter(|v| v.0 == index).count(),
+        })
+        .collect();
+
+    // Folders first, then the files. Entries of the page are built only after
+    // sorting and paginating.
+    let mut folders: Vec<(String, usize)> = vec![];
+    let mut files: Vec<&(usize, &Path, &LibraryItem)> = vec![];
+    if !request.search.is_empty() {
+        let mut found: Vec<(i32, &(usize, &Path, &LibraryItem))> = items
+            .iter()
+            .filter_map(|v| {
+                let score = library::fuzzy_score(&request.search, &v.2.title())
+                    .max(library::fuzzy_score(&request.search, &v.2.name()))?;
+                Some((score, v))
+            })
+            .collect();
+        match request.sort {
+            Some(sort) => {
+                found.sort_by(|a, b| compare(sort, (a.1).2, (b.1).2));
+                if request.descending {
+                    found.reverse();
+                }
+            }
+            None => found.sort_by_key(|v| Reverse(v.0)),
+        }
+        files.extend(found.into_iter().map(|(_, v)| v));
+    } else if request.root.is_some() {
+        for v in &items {
+            let rest = v.1.strip_prefix(folder).unwrap();
+            let mut components = rest.components();
+            let first = components.next();
+            match (first, components.next()) {
+                (Some(name), Some(_)) => {
+                    let name = name.as_os_str().to_string_lossy().into_owned();
+                    match folders.iter_mut().find(|f| f.0 == name) {
+                        Some(f) => f.1 += 1,
+                        None => folders.push((name, 1)),
+                    }
+                }
+                _ => files.push(v),
+            }
+        }
+        folders.sort();
+        files.sort_by(|a, b| compare(request.sort.unwrap_or(SortBy::Name), a.2, b.2));
+        if request.descending {
+            files.reverse();
+        }
+    }
+
+    let total = folders.len() + files.len();
+    let files_offset = request.offset.saturating_sub(folders.len());
+    let mut entries: Vec<LibraryEntry> = folders
+        .into_iter()
+        .skip(request.offset)
+        .take(request.limit)
+        .map(|(name, count)| LibraryEntry::Folder {
+            path: folder.join(&name).to_string_lossy().into_owned(),
+            name,
+            items: count,
+        })
+        .collect();
+    let files_limit = request.limit - entries.len();
+    entries.extend(
+        files
+            .into_iter()
+            .skip(files_offset)
+            .take(files_limit)
+            .map(|v| item_info(&state, v)),
+    );
+    Ok(LibraryResult {
+        roots,
+        root: request.root,
+        path: request.path,
+        total,
+        offset: request.offset,
+        entries,
+    })
+}
+
+fn item_info(state: &AppState, v: &(usize, &Path, &LibraryItem)) -> LibraryEntry {
+    let (root, path, item) = v;
+    LibraryEntry::Item(Box::new(ItemInfo {
+        id: item.id.clone(),
+        root: *root,
+        path: path.to_string_lossy().into_owned(),
+        name: item.name(),
+        title: item.title(),
+        size: item.size,
+        mtime: item.mtime,
+        duration: item.duration,
+        parsed: item.parsed_name(),
+        metadata: item.metadata.clone(),
+        poster: media::poster_url(item),
+        fanart: media::fanart_url(item),
+        thumbnail: media::thumbnail_url(item),
+        trickplay: media::trickplay_url(state, item),
+        segments: item.segments.clone(),
+        audio: item.audio.clone(),
+        photo: item.is_image(),
+    }))
+}
+
+/// Items without duration are ordered last
+fn compare(sort: SortBy, a: &LibraryItem, b: &LibraryItem) -> Ordering {
+    match sort {
+        SortBy::Name => a.name().to_lowercase().cmp(&b.name().to_lowercase()),
+        SortBy::Mtime => a.mtime.cmp(&b.mtime),
+        SortBy::Size => a.size.cmp(&b.size),
+        SortBy::Duration => match (a.duration, b.duration) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        },
+    }
+}
+
+#[derive(Serialize)]
+pub struct EpisodeInfo {
+    id: MediaId,
+    episode: u32,
+    title: Option<String>,
+    duration: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct SeasonInfo {
+    season: u32,
+    episodes: Vec<EpisodeInfo>,
+}
+
+#[derive(Serialize)]
+pub struct ShowInfo {
+    title: String,
+    seasons: Vec<SeasonInfo>,
+}
+
+/// Episodes grouped to shows and seasons
+pub async fn shows(state: Arc<AppState>) -> ApiResponse<Vec<ShowInfo>> {
+    let library = state.library.read().unwrap();
+    let mut shows: Vec<ShowInfo> = vec![];
+    for item in library
+        .items()
+        .into_iter()
+        .filter(|v| !v.is_audio() && !v.is_image())
+    {
+        let parsed = item.parsed_name();
+        let episode = match parsed.episode {
+            Some(episode) => episode,
+            None => continue,
+        };
+        let season_number = parsed.season.unwrap_or(1);
+        let show = match shows
+            .iter()
+            .position(|v| v.title.to_lowercase() == parsed.title.to_lowercase())
+        {
+            Some(i) => &mut shows[i],
+            None => {
+                shows.push(ShowInfo {
+                    title: parsed.title.clone(),
+                    seasons: vec![],
+                });
+                shows.last_mut().unwrap()
+            }
+        };
+        let season = match show.seasons.iter().position(|v| v.season == season_number) {
+            Some(i) => &mut show.seasons[i],
+            None => {
+                show.seasons.push(SeasonInfo {
+                    season: season_number,
+                    episodes: vec![],
+                });
+                show.seasons.last_mut().unwrap()
+            }
+        };
+        season.episodes.push(EpisodeInfo {
+            id: item.id.clone(),
+            episode,
+            title: parsed.episode_title,
+            duration: item.duration,
+        });
+    }
+    shows.sort_by_key(|v| v.title.to_lowercase());
+    for show in &mut shows {
+        show.seasons.sort_by_key(|v| v.season);
+        for season in &mut show.seasons {
+            season.episodes.sort_by_key(|v| v.episode);
+        }
+    }
+    Ok(shows)
+}
+
+#[derive(Serialize)]
+pub struct TrackInfo {
+    id: MediaId,
+    disc: Option<u32>,
+    track: Option<u32>,
+    title: String,
+    artist: Option<String>,
+    duration: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct AlbumInfo {
+    title: String,
+    artist: Option<String>,
+    year: Option<u32>,
+    cover: String,
+    tracks: Vec<TrackInfo>,
+
+    #[serde(skip)]
+    folder: PathBuf,
+}
+
+/// Audio files grouped to albums
+///
+/// Tracks are grouped by the album tag and the folder, so that albums with the
+/// same name by different artists stay apart. Files without the album tag are
+/// grouped by the folder name.
+pub async fn albums(state: Arc<AppState>) -> ApiResponse<Vec<AlbumInfo>> {
+    let library = state.library.read().unwrap();
+    let mut albums: Vec<AlbumInfo> = vec![];
+    for item in library.items().into_iter().filter(|v| v.is_audio()) {
+        let tags = item.audio.clone().unwrap_or_default();
+        let folder = item.path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let title = tags.album.clone().unwrap_or_else(|| {
+            folder
+                .file_name()
+                .map(|v| v.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        });
+        let album = match albums
+            .iter()
+            .position(|v| v.folder == folder && v.title.to_lowercase() == title.to_lowercase())
+        {
+            Some(i) => &mut albums[i],
+            None => {
+                albums.push(AlbumInfo {
+                    title,
+                    artist: tags.album_artist.clone().or_else(|| tags.artist.clone()),
+                    year: tags.year,
+                    cover: media::poster_url(item),
+                    tracks: vec![],
+                    folder,
+                });
+                albums.last_mut().unwrap()
+            }
+        };
+        album.tracks.push(TrackInfo {
+            id: item.id.clone(),
+            disc: tags.disc,
+            track: tags.track,
+            title: item.title(),
+            artist: tags.artist,
+            duration: item.duration,
+        });
+    }
+    albums.sort_by_key(|v| {
+        (
+            v.artist.clone().unwrap_or_default().to_lowercase(),
+            v.year,
+            v.title.to_lowercase(),
+        )
+    });
+    for album in &mut albums {
+        album
+            .tracks
+            .sort_by_key(|v| (v.disc.unwrap_or(1), v.track, v.title.to_lowercase()));
+    }
+    Ok(albums)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::tests::test_state;
+
+    fn names(result: &LibraryResult) -> Vec<String> {
+        result
+            .entries
+            .iter()
+            .map(|v| match v {
+                LibraryEntry::Folder { name, .. } => format!("{}/", name),
+                LibraryEntry::Item(item) => item.name.clone(),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_browse_pages() {
+        let dir = std::env::temp_dir().join(format!("casterson-browse-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (state, _) = test_state(&dir);
+        let media = dir.join("media");
+        std::fs::create_dir_all(media.join("Show")).unwrap();
+        std::fs::write(media.join("Show").join("S01E01.mkv"), b"episode").unwrap();
+        std::fs::write(media.join("b.mkv"), b"other").unwrap();
+        library::rescan(&state.library, &state.opts.dir, &state.opts.media_exts);
+
+        // Descending order applies to the files, the folders stay first
+        let request = |offset| LibraryRequest {
+            root: Some(0),
+            descending: true,
+            offset,
+            limit: 2,
+            ..Default::default()
+        };
+        let first = browse(state.clone(), request(0)).await.unwrap();
+        assert_eq!(first.total, 3);
+        assert_eq!(names(&first), vec!["Show/", "Movie (2020)"]);
+        let second = browse(state.clone(), request(2)).await.unwrap();
+        assert_eq!(names(&second), vec!["b"]);
+
+        // Search results are ranked by relevance regardless of the order
+        let search = LibraryRequest {
+            search: "movie".into(),
+            descending: true,
+            ..Default::default()
+        };
+        let found = browse(state.clone(), search).await.unwrap();
+        assert_eq!(names(&found), vec!["Movie (2020)"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
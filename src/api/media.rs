@@ -0,0 +1,107 @@
+use crate::AppState;
+use hyper::header::HeaderValue;
+use hyper::{Body, Response};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::api::ApiError;
+use crate::api::ApiResponse;
+use crate::library::LibraryItem;
+use crate::trickplay;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct MediaFileRequest {
+    // Width of the thumbnail, rounded up to the configured widths
+    pub width: Option<u32>,
+}
+
+/// URL path of the poster of the item, the thumbnail if there is no poster
+pub fn poster_url(item: &LibraryItem) -> String {
+    match item.artwork.poster {
+        Some(_) => format!("/media/{}/poster.jpg", item.id),
+        None => thumbnail_url(item),
+    }
+}
+
+/// URL path of the fanart of the item, None if there is no fanart
+pub fn fanart_url(item: &LibraryItem) -> Option<String> {
+    item.artwork
+        .fanart
+        .as_ref()
+        .map(|_| format!("/media/{}/fanart.jpg", item.id))
+}
+
+/// URL path of the thumbnail of the item
+pub fn thumbnail_url(item: &LibraryItem) -> String {
+    format!("/media/{}/thumbnail.jpg", item.id)
+}
+
+/// URL path of the WebVTT thumbnails track, None if it's not generated yet
+///
+/// Sprite sheets are referred relative to the track.
+pub fn trickplay_url(state: &AppState, item: &LibraryItem) -> Option<String> {
+    if state.trickplay.is_ready(item) {
+        Some(format!(
+            "/media/{}/trickplay/{}",
+            item.id,
+            trickplay::INDEX_FILE
+        ))
+    } else {
+        None
+    }
+}
+
+/// Serves the files of the media item, path is `{id}/{file}`
+pub async fn media_file(
+    state: Arc<AppState>,
+    path: &str,
+    request: MediaFileRequest,
+) -> ApiResponse<Response<Body>> {
+    let mut parts = path.splitn(2, '/');
+    let id = parts.next().unwrap_or("");
+    let name = parts.next().unwrap_or("");
+    let item = state
+        .library
+        .read()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .ok_or(ApiError::NotFound)?;
+    let file = match name {
+        "poster.jpg" => item.artwork.poster.clone(),
+        "fanart.jpg" => item.artwork.fanart.clone(),
+        "thumbnail.jpg" => {
+            let width = state.thumbnails.width(request.width);
+            Some(state.thumbnails.get(&item, width).await?)
+        }
+        name if name.starts_with("trickplay/") => {
+            state.trickplay.file(&item, &name["trickplay/".len()..])
+        }
+        _ => None,
+    };
+    match file {
+        Some(file) => file_response(&file).await,
+        None => Err(ApiError::NotFound),
+    }
+}
+
+async fn file_response(file: &Path) -> ApiResponse<Response<Body>> {
+    let data = tokio::fs::read(file).await?;
+    let content_type = match file.extension().and_then(|v| v.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("png") => "image/png",
+        Some("vtt") => "text/vtt",
+        _ => "image/jpeg",
+    };
+    let mut response = Response::new(Body::from(data));
+
+    // Headers
+    response
+        .headers_mut()
+        .insert("Content-Type", HeaderValue::from_static(content_type));
+    response
+        .headers_mut()
+        .insert("Cache-Control", HeaderValue::from_static("max-age=3600"));
+    Ok(response)
+}
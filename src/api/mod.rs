@@ -1,6 +1,8 @@
-use crate::chromecast as chromecast_main;
+use crate::jobs::JobError;
+use crate::renderer::RendererError;
 use crate::AppState;
 use derive_more::From;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Method;
 use hyper::{Body, Request, Response, Server};
@@ -9,14 +11,31 @@ use serde::Serialize;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
+pub mod audio;
+pub mod cache;
 pub mod chromecast;
+pub mod devices;
+pub mod events;
+pub mod history;
+pub mod jobs;
+pub mod library;
+pub mod media;
+pub mod photos;
+pub mod playlists;
+pub mod receiver;
 pub mod ui;
+pub mod upnp;
 
 #[derive(Debug, From)]
 pub enum ApiError {
     NotFound,
+    CacheDisabled,
     InvalidMediaFile(String),
-    ChromecastError(chromecast_main::ChromecastError),
+    #[from(ignore)]
+    SourceNotAllowed(String),
+    // Error of any renderer, named before there were others than Chromecast
+    ChromecastError(RendererError),
+    JobError(JobError),
     JsonError(serde_json::error::Error),
     IoError(std::io::Error),
 }
@@ -44,7 +63,22 @@ impl Into<ApiJsonError> for ApiError {
                 msg: file,
             },
 
-            ApiError::NotFound => ApiJsonError {
+            ApiError::SourceNotAllowed(url) => ApiJsonError {
+                error: "SOURCE_NOT_ALLOWED".into(),
+                msg: url,
+            },
+
+            ApiError::CacheDisabled => ApiJsonError {
+                error: "CACHE_DISABLED".into(),
+                msg: "Transcode cache is not enabled".into(),
+            },
+
+            ApiError::JobError(JobError::TooManyJobs) => ApiJsonError {
+                error: "TOO_MANY_JOBS".into(),
+                msg: "Maximum number of encoding jobs is running".into(),
+            },
+
+            ApiError::NotFound | ApiError::JobError(JobError::NotFound) => ApiJsonError {
                 error: "NOT_FOUND".into(),
                 msg: "404 Not found".into(),
             },
@@ -68,6 +102,15 @@ where
     })
 }
 
+/// Empty query string is interpreted as empty JSON object
+fn or_empty(query: &str) -> &str {
+    if query.is_empty() {
+        "{}"
+    } else {
+        query
+    }
+}
+
 /// Create hyper server
 pub async fn start_server(state: Arc<AppState>) -> Result<(), hyper::error::Error> {
     println!(
@@ -77,12 +120,15 @@ pub async fn start_server(state: Arc<AppState>) -> Result<(), hyper::error::Erro
     let addr = SocketAddr::from((state.opts.ip, state.opts.port));
 
     // Creates a service creator "MakeSvc"
-    let make_svc = make_service_fn(move |_| {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
         let state_con = Arc::clone(&state);
+        let remote_addr = conn.remote_addr();
         async move {
-            // Creates a "Service" from asyncfunction
-            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+            // Creates a "Service" from asyncfunction, the remote address is
+            // passed in the extensions
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
                 let state_req = Arc::clone(&state_con);
+                req.extensions_mut().insert(remote_addr);
                 async move { handle_request(state_req, req).await }
             }))
         }
@@ -130,6 +176,14 @@ async fn handle_chromecast_request(
         "/chromecast/play" => to_response(api.play().await),
         "/chromecast/stop" => to_response(api.stop().await),
         "/chromecast/status" => to_response(api.status().await),
+        "/chromecast/volume" => to_response(api.volume(serde_json::from_slice(&body)?).await),
+        "/chromecast/seek" => to_response(api.seek(serde_json::from_slice(&body)?).await),
+        "/chromecast/session" => to_response(api.session().await),
+        "/chromecast/next_item" => to_response(api.next_item().await),
+        "/chromecast/previous_item" => to_response(api.previous_item().await),
+        "/chromecast/next_chapter" => to_response(api.next_chapter().await),
+        "/chromecast/previous_chapter" => to_response(api.previous_chapter().await),
+        "/chromecast/slideshow" => to_response(api.slideshow(serde_json::from_slice(&body)?).await),
         _ => Err(ApiError::NotFound),
     }
 }
@@ -145,22 +199,164 @@ async fn handle_other_request(
         .into_owned();
 
     match (request.method(), request.uri().path()) {
+        (&Method::GET, "/") => ui::index().await,
         (&Method::GET, "/get_media_files") => to_response(ui::get_media_files(state).await),
         (&Method::GET, "/media_show") => {
-            // Chrome is spamming with multiple requests on HTTP hosts, it causes ffmpeg to freak
-            // out. This may have something to do that first request has
-            // "Update-Insecure-Requests=1". This is mostly a testing problem but it seems to help if
-            // I wait a little bit between requests.
-            if let Some(v) = request.headers().get(hyper::header::USER_AGENT) {
-                if let Ok(vv) = v.to_str() {
-                    if vv.find("Chrome/").is_some() {
-                        tokio::time::delay_for(tokio::time::Duration::from_millis(500)).await;
-                    }
-                }
-            }
-
-            ui::media_show(state, serde_json::from_str(&query)?).await
+            // Chrome is spamming with multiple requests on HTTP hosts, the job manager shares the
+            // encoding between identical requests and kills it when the clients go away.
+            let range = request
+                .headers()
+                .get(hyper::header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from);
+            let response = ui::media_show(state, serde_json::from_str(&query)?, range).await;
+            upnp::content_features(request.headers(), response, upnp::TRANSCODE_FEATURES)
+        }
+        (&Method::GET, "/media_direct") => {
+            let range = request
+                .headers()
+                .get(hyper::header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from);
+            let response = ui::media_direct(state, serde_json::from_str(&query)?, range).await;
+            upnp::content_features(request.headers(), response, upnp::DIRECT_FEATURES)
+        }
+        (&Method::GET, "/media_stream") => {
+            ui::media_stream(state, serde_json::from_str(&query)?).await
+        }
+        (&Method::GET, "/media_audio") => {
+            let range = request
+                .headers()
+                .get(hyper::header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from);
+            let response = audio::media_audio(state, serde_json::from_str(&query)?, range).await;
+            upnp::content_features(request.headers(), response, upnp::DIRECT_FEATURES)
+        }
+        (&Method::GET, "/media_photo") => {
+            photos::media_photo(state, serde_json::from_str(&query)?).await
+        }
+        (&Method::GET, "/media_info") => {
+            to_response(ui::media_info(state, serde_json::from_str(&query)?).await)
+        }
+        (&Method::GET, "/library") => {
+            to_response(library::browse(state, serde_json::from_str(or_empty(&query))?).await)
+        }
+        (&Method::GET, "/library/shows") => to_response(library::shows(state).await),
+        (&Method::GET, "/library/albums") => to_response(library::albums(state).await),
+        (&Method::GET, "/library/playlists") => to_response(playlists::list(state).await),
+        (&Method::GET, "/playlist_export") => {
+            playlists::export(state, serde_json::from_str(or_empty(&query))?).await
+        }
+        (&Method::GET, path) if path.starts_with("/media/") => {
+            let request = serde_json::from_str(or_empty(&query))?;
+            media::media_file(state, &path["/media/".len()..], request).await
+        }
+        (&Method::POST, "/library/rescan") => to_response(ui::rescan_library(state).await),
+        (&Method::GET, "/events") => events::events(state).await,
+        (&Method::GET, "/history") => to_response(history::list(state).await),
+        (&Method::GET, "/devices") => to_response(devices::list(state).await),
+        (&Method::GET, "/receiver") => receiver::page().await,
+        (&Method::GET, "/receiver/socket") => {
+            let receiver_request = serde_json::from_str(or_empty(&query))?;
+            receiver::socket(state, receiver_request, request).await
+        }
+        (&Method::GET, "/upnp/description.xml") => upnp::description(state).await,
+        (&Method::GET, path) if path.starts_with("/upnp/scpd/") => {
+            upnp::scpd(&path["/upnp/scpd/".len()..]).await
+        }
+        (&Method::POST, path) if path.starts_with("/upnp/control/") => {
+            let service = path["/upnp/control/".len()..].to_string();
+            let client = upnp::UpnpClient::new(request.headers())?;
+            let body = hyper::body::to_bytes(request.into_body())
+                .await
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+            upnp::control(state, &service, client, &body).await
+        }
+        (method, path)
+            if path.starts_with("/upnp/event/")
+                && (method.as_str() == "SUBSCRIBE" || method.as_str() == "UNSUBSCRIBE") =>
+        {
+            upnp::subscribe(state).await
+        }
+        (&Method::GET, "/jobs") => to_response(jobs::list(state).await),
+        (&Method::DELETE, path) if path.starts_with("/jobs/") => {
+            let id = path["/jobs/".len()..]
+                .parse()
+                .map_err(|_| ApiError::NotFound)?;
+            to_response(jobs::kill(state, id).await)
+        }
+        (&Method::GET, "/cache") => to_response(cache::status(state).await),
+        (&Method::DELETE, "/cache") => to_response(cache::purge_all(state).await),
+        (&Method::DELETE, path) if path.starts_with("/cache/") => {
+            to_response(cache::purge(state, &path["/cache/".len()..]).await)
         }
         _ => Err(ApiError::NotFound),
     }
 }
+
+// Unit tests
+#[cfg(test)]
+pub mod tests {
+    use crate::library::{self, MediaId};
+    use crate::media::Tracks;
+    use crate::{browser, history, jobs, loudness, prefetch, segments, sessions, thumbnails};
+    use crate::{photos, slideshow, trickplay, upnp, AppState, CliOpts};
+    use std::path::Path;
+    use std::sync::{Arc, RwLock};
+    use structopt::StructOpt;
+
+    /// State of the server with the media directory of one video
+    pub fn test_state(dir: &Path) -> (Arc<AppState>, MediaId) {
+        let media = dir.join("media");
+        let data = dir.join("data");
+        std::fs::create_dir_all(&media).unwrap();
+        std::fs::write(media.join("Movie (2020).mkv"), b"video").unwrap();
+        let opts = CliOpts::from_iter(&[
+            "casterson".as_ref(),
+            "--no-upnp".as_ref(),
+            "--data-dir".as_ref(),
+            data.as_os_str(),
+            media.as_os_str(),
+        ]);
+        let mut library = library::Library::default();
+        library.rescan(&opts.dir, &opts.media_exts);
+        let id = library.items()[0].id.clone();
+        library.set_video_info(
+            &id,
+            crate::media::VideoInfo {
+                duration: 600.0,
+                tracks: Some(Tracks {
+                    audio: vec![],
+                    subtitles: vec![],
+                }),
+                ..Default::default()
+            },
+        );
+        let loudness = Arc::new(loudness::Loudness::load(data.join("loudness.json")));
+        let jobs = Arc::new(jobs::JobManager::new(opts.max_jobs, None));
+        let state = AppState {
+            library: RwLock::new(library),
+            thumbnails: thumbnails::Thumbnails::new(
+                data.join("thumbnails"),
+                &opts.thumbnail_widths,
+            ),
+            photos: photos::Photos::new(data.join("photos")),
+            sessions: sessions::Sessions::default(),
+            slideshows: slideshow::Slideshows::default(),
+            upnp: upnp::MediaServer::load(data.join("upnp.json"), &opts.upnp_name),
+            receivers: browser::Receivers::default(),
+            history: RwLock::new(history::History::load(data.join("history.json"))),
+            trickplay: trickplay::Trickplay::new(data.join("trickplay"), opts.trickplay_interval),
+            segments: segments::SegmentDetector::new(opts.intro_search_minutes),
+            jobs: jobs.clone(),
+            prefetcher: prefetch::Prefetcher::new(None, jobs, loudness.clone(), 0),
+            loudness,
+            cache: None,
+            opts,
+            notifier: crossbeam::unbounded().0,
+            events: tokio::sync::broadcast::channel(16).0,
+        };
+        (Arc::new(state), id)
+    }
+}
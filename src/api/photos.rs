@@ -0,0 +1,93 @@
+use crate::AppState;
+use hyper::header::HeaderValue;
+use hyper::{Body, Response};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::api::ui;
+use crate::api::ApiError;
+use crate::api::ApiResponse;
+use crate::library::MediaId;
+use crate::media;
+use crate::photos;
+
+/// Longest Ken Burns video rendered
+const MAX_KEN_BURNS_SECONDS: u32 = 60;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaPhotoRequest {
+    pub id: MediaId,
+
+    // Photo is fit inside the size, 1920x1080 if not given
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+
+    // Renders the photo to a slowly zooming video of the length instead
+    #[serde(default)]
+    pub ken_burns_seconds: Option<u32>,
+}
+
+impl MediaPhotoRequest {
+    pub fn content_type(&self) -> &'static str {
+        match self.ken_burns_seconds {
+            Some(_) => "video/mp4",
+            None => "image/jpeg",
+        }
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (
+            self.width.unwrap_or(photos::DEFAULT_SIZE.0),
+            self.height.unwrap_or(photos::DEFAULT_SIZE.1),
+        )
+    }
+}
+
+/// Serves the photo scaled and turned upright, or as a Ken Burns video
+pub async fn media_photo(
+    state: Arc<AppState>,
+    request: MediaPhotoRequest,
+) -> ApiResponse<Response<Body>> {
+    let file = ui::get_media_path(&state, &request.id)?;
+    let item = state
+        .library
+        .read()
+        .unwrap()
+        .get(&request.id)
+        .cloned()
+        .filter(|v| v.is_image())
+        .ok_or_else(|| ApiError::InvalidMediaFile(request.id.clone()))?;
+    let mut response = match request.ken_burns_seconds {
+        Some(seconds) => {
+            // Even sizes for yuv420p
+            let (width, height) = request.size();
+            let width = width.clamp(2, photos::MAX_SIZE.0) & !1;
+            let height = height.clamp(2, photos::MAX_SIZE.1) & !1;
+            let seconds = seconds.clamp(1, MAX_KEN_BURNS_SECONDS);
+            let encode = media::encode_ken_burns(&file, seconds, width, height);
+            let stream = state.jobs.run("ken_burns", &file, encode).await?;
+            let mut response = Response::new(Body::wrap_stream(stream));
+            response
+                .headers_mut()
+                .insert("Cache-Control", HeaderValue::from_static("no-store"));
+            response
+        }
+        None => {
+            let photo = state.photos.get(&item, request.size()).await?;
+            let mut response = Response::new(Body::from(tokio::fs::read(photo).await?));
+            response
+                .headers_mut()
+                .insert("Cache-Control", HeaderValue::from_static("max-age=3600"));
+            response
+        }
+    };
+
+    // Headers
+    response.headers_mut().insert(
+        "Content-Type",
+        HeaderValue::from_static(request.content_type()),
+    );
+    Ok(response)
+}
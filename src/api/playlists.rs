@@ -0,0 +1,143 @@
+use crate::AppState;
+use hyper::header::HeaderValue;
+use hyper::{Body, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::api::ApiError;
+use crate::api::ApiResponse;
+use crate::library;
+use crate::library::MediaId;
+use crate::playlist;
+use crate::playlist::PlaylistEntry;
+
+#[derive(Serialize)]
+pub struct PlaylistItemInfo {
+    id: MediaId,
+    title: String,
+    duration: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct PlaylistInfo {
+    root: usize,
+    path: String,
+    name: String,
+    items: Vec<PlaylistItemInfo>,
+
+    // Entries which are not in the library
+    missing: usize,
+}
+
+/// Playlists in the media directories, with the entries found in the library
+pub async fn list(state: Arc<AppState>) -> ApiResponse<Vec<PlaylistInfo>> {
+    let state_ = state.clone();
+    let files = state.library.read().unwrap().playlists();
+    let playlists = tokio::task::spawn_blocking(move || {
+        let dirs = &state_.opts.dir;
+        files
+            .into_iter()
+            .filter_map(
+                |file| match playlist::read(&file, dirs, &state_.opts.media_exts) {
+                    Ok(entries) => Some((file, entries)),
+                    Err(err) => {
+                        println!("Unable to read playlist {}: {}", file.display(), err);
+                        None
+                    }
+                },
+            )
+            .collect::<Vec<(PathBuf, Vec<PlaylistEntry>)>>()
+    })
+    .await
+    .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+
+    let library = state.library.read().unwrap();
+    let by_path: HashMap<&Path, &library::LibraryItem> = library
+        .items()
+        .into_iter()
+        .map(|item| (item.path.as_path(), item))
+        .collect();
+    Ok(playlists
+        .into_iter()
+        .filter_map(|(file, entries)| {
+            let (root, path) = library::relative_path(&file, &state.opts.dir)?;
+            let items: Vec<PlaylistItemInfo> = entries
+                .iter()
+                .filter_map(|entry| {
+                    let item = by_path.get(entry.path.as_path())?;
+                    Some(PlaylistItemInfo {
+                        id: item.id.clone(),
+                        title: entry.title.clone().unwrap_or_else(|| item.title()),
+                        duration: item.duration.or(entry.duration),
+                    })
+                })
+                .collect();
+            Some(PlaylistInfo {
+                root,
+                path: path.to_string_lossy().into_owned(),
+                name: file
+                    .file_stem()
+                    .map(|v| v.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                missing: entries.len() - items.len(),
+                items,
+            })
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct PlaylistExportRequest {
+    // Items of the playlist, the queue being cast to the device if not given
+    pub ids: Vec<MediaId>,
+    pub device: Option<String>,
+
+    // Folder the playlist is saved to, paths are written relative to it.
+    // Items in other roots are left out.
+    pub root: usize,
+    pub path: String,
+}
+
+/// Exports the items or the queue of the device as an extended M3U playlist
+pub async fn export(
+    state: Arc<AppState>,
+    request: PlaylistExportRequest,
+) -> ApiResponse<Response<Body>> {
+    let root = request.root;
+    let ids = match &request.device {
+        Some(device) if request.ids.is_empty() => {
+            state.sessions.get(device).ok_or(ApiError::NotFound)?.queue
+        }
+        _ => request.ids,
+    };
+    let dir = state.opts.dir.get(root).ok_or(ApiError::NotFound)?;
+    let folder = dir.join(&request.path);
+    let library = state.library.read().unwrap();
+    let entries: Vec<PlaylistEntry> = ids
+        .iter()
+        .filter_map(|id| library.get(id))
+        .filter(|item| {
+            library::relative_path(&item.path, &state.opts.dir).map(|v| v.0) == Some(root)
+        })
+        .map(|item| PlaylistEntry {
+            path: playlist::relative_to(&folder, &item.path),
+            title: Some(item.title()),
+            duration: item.duration.filter(|v| *v > 0.0),
+        })
+        .collect();
+    let mut response = Response::new(Body::from(playlist::write_m3u(&entries)));
+
+    // Headers
+    response.headers_mut().insert(
+        "Content-Type",
+        HeaderValue::from_static("audio/x-mpegurl; charset=utf-8"),
+    );
+    response.headers_mut().insert(
+        "Content-Disposition",
+        HeaderValue::from_static("attachment; filename=\"playlist.m3u8\""),
+    );
+    Ok(response)
+}
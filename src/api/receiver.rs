@@ -0,0 +1,80 @@
+use crate::AppState;
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::api::ApiError;
+use crate::api::ApiResponse;
+use crate::browser;
+use crate::websocket;
+
+/// Receiver page, the query of the page is passed to the socket
+const RECEIVER_HTML: &str = include_str!("receiver.html");
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ReceiverRequest {
+    // Name shown in the device list, "Browser" if not given
+    #[serde(default)]
+    name: Option<String>,
+
+    // Page can play HDR video, passed through to it
+    #[serde(default)]
+    hdr: bool,
+}
+
+pub async fn page() -> ApiResponse<Response<Body>> {
+    let mut response = Response::new(Body::from(RECEIVER_HTML));
+    response.headers_mut().insert(
+        "Content-Type",
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    Ok(response)
+}
+
+/// Upgrades the request to WebSocket and registers the page as a receiver
+pub async fn socket(
+    state: Arc<AppState>,
+    receiver_request: ReceiverRequest,
+    request: Request<Body>,
+) -> ApiResponse<Response<Body>> {
+    let is_upgrade = request
+        .headers()
+        .get("Upgrade")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let key = request
+        .headers()
+        .get("Sec-WebSocket-Key")
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| is_upgrade)
+        .ok_or(ApiError::NotFound)?;
+    let ip = request
+        .extensions()
+        .get::<SocketAddr>()
+        .ok_or(ApiError::NotFound)?
+        .ip();
+    let hdr = receiver_request.hdr;
+    let name = receiver_request
+        .name
+        .unwrap_or_else(|| "Browser".to_string());
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert("Upgrade", HeaderValue::from_static("websocket"));
+    headers.insert("Connection", HeaderValue::from_static("Upgrade"));
+    headers.insert(
+        "Sec-WebSocket-Accept",
+        HeaderValue::from_str(&websocket::accept_key(key)).unwrap(),
+    );
+
+    tokio::spawn(async move {
+        match request.into_body().on_upgrade().await {
+            Ok(upgraded) => browser::serve(&state.receivers, upgraded, ip, name, hdr).await,
+            Err(err) => println!("Unable to upgrade the receiver socket {:?}", err),
+        }
+    });
+    Ok(response)
+}
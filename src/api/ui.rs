@@ -1,34 +1,142 @@
 use crate::AppState;
+use futures::future;
+use futures::{StreamExt, TryStreamExt};
 use hyper::Body;
 use hyper::Response;
+use hyper::StatusCode;
 use std::sync::Arc;
 
+use crate::api;
 use crate::api::ApiResponse;
 use hyper::header::HeaderValue;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use url::Url;
 
 use crate::api::ApiError;
+use crate::cache;
+use crate::cache::{CacheEntry, TranscodeCache};
+use crate::library;
+use crate::library::MediaId;
 use crate::media;
 use crate::msg;
 
+/// Remote control page using the JSON API
+const INDEX_HTML: &str = include_str!("ui.html");
+
+#[derive(Serialize)]
+pub struct MediaFile {
+    id: MediaId,
+    root: usize,
+    path: String,
+    thumbnail: String,
+}
+
 #[derive(Serialize)]
 pub struct MediaFilesResult {
-    files: Vec<String>,
+    files: Vec<MediaFile>,
+}
+
+pub async fn index() -> ApiResponse<Response<Body>> {
+    let mut response = Response::new(Body::from(INDEX_HTML));
+    response.headers_mut().insert(
+        "Content-Type",
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    Ok(response)
 }
 
 pub async fn get_media_files(state: Arc<AppState>) -> ApiResponse<MediaFilesResult> {
-    let files: Vec<String> = media::scan_media_files(&state.opts.dir, &state.opts.media_exts)
+    let library = state.library.read().unwrap();
+    let files: Vec<MediaFile> = library
+        .items()
         .iter()
-        .filter_map(|v: &PathBuf| v.to_str())
-        .map(|v| v.to_string())
+        .filter_map(|item| {
+            let (root, path) = library::relative_path(&item.path, &state.opts.dir)?;
+            Some(MediaFile {
+                id: item.id.clone(),
+                root,
+                path: path.to_string_lossy().into_owned(),
+                thumbnail: api::media::thumbnail_url(item),
+            })
+        })
         .collect();
     Ok(MediaFilesResult { files })
 }
 
+/// Full rescan of the media directories
+pub async fn rescan_library(state: Arc<AppState>) -> ApiResponse<()> {
+    let state_ = state.clone();
+    tokio::task::spawn_blocking(move || {
+        library::rescan(&state_.library, &state_.opts.dir, &state_.opts.media_exts);
+    })
+    .await
+    .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+    state
+        .notifier
+        .send(msg::NotifyMessage::LibraryChanged)
+        .unwrap();
+    Ok(())
+}
+
+/// Stream information of the media file probed for the library
+fn video_info(state: &AppState, id: &str) -> Option<media::VideoInfo> {
+    state
+        .library
+        .read()
+        .unwrap()
+        .get(id)
+        .and_then(|v| v.video.clone())
+}
+
+/// Path of the media file, validated to be safe to serve
+pub fn get_media_path(state: &AppState, id: &str) -> ApiResponse<PathBuf> {
+    let path = state
+        .library
+        .read()
+        .unwrap()
+        .get(id)
+        .map(|item| item.path.clone());
+    match path {
+        Some(path) if media::is_safe_file(&path, &state.opts.dir, &state.opts.media_exts) => {
+            Ok(path)
+        }
+        _ => Err(ApiError::InvalidMediaFile(id.into())),
+    }
+}
+
 #[derive(Deserialize)]
+pub struct MediaInfoRequest {
+    pub id: MediaId,
+}
+
+/// Probed information of the media file, including the chapters
+pub async fn media_info(
+    state: Arc<AppState>,
+    request: MediaInfoRequest,
+) -> ApiResponse<media::VideoInfo> {
+    let file = get_media_path(&state, &request.id)?;
+    Ok(media::get_info(&file).await?)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaDirectRequest {
+    pub id: MediaId,
+}
+
+/// Serves the media file as is, with range requests for seeking
+pub async fn media_direct(
+    state: Arc<AppState>,
+    request: MediaDirectRequest,
+    range: Option<String>,
+) -> ApiResponse<Response<Body>> {
+    let file = get_media_path(&state, &request.id)?;
+    api::audio::file_response(&file, range).await
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MediaShowRequest {
-    pub file: String,
+    pub id: MediaId,
 
     #[serde(default)]
     pub encode_opts: media::EncodeOpts,
@@ -37,23 +145,64 @@ pub struct MediaShowRequest {
 pub async fn media_show(
     state: Arc<AppState>,
     request: MediaShowRequest,
+    range: Option<String>,
 ) -> ApiResponse<Response<Body>> {
-    let file = request.file;
-    // println!(
-    //     "Validate file {} {:?} {:?} {:?}",
-    //     file,
-    //     &state.opts.dir,
-    //     &state.opts.media_exts,
-    //     std::fs::canonicalize(&file).map(|v| v.to_string_lossy().into_owned())
-    // );
-    if !media::is_safe_file(&file, &state.opts.dir, &state.opts.media_exts) {
-        return Err(ApiError::InvalidMediaFile(file));
+    let file = get_media_path(&state, &request.id)?;
+    let mut encode_opts = request.encode_opts;
+    state.loudness.apply(&file, &mut encode_opts.audio_opts);
+    let video = video_info(&state, &request.id);
+    let id = &request.id;
+    let next = state.sessions.next_in_queue(id).or_else(|| {
+        state
+            .library
+            .read()
+            .unwrap()
+            .next_in_directory(id)
+            .map(|v| v.id.clone())
+    });
+    if let Some(next) = next {
+        let next_video = state
+            .library
+            .read()
+            .unwrap()
+            .get(&next)
+            .filter(|v| !v.is_audio() && !v.is_image())
+            .map(|v| v.video.clone());
+        if let (Some(next_video), Ok(next)) = (next_video, get_media_path(&state, &next)) {
+            state
+                .prefetcher
+                .schedule(next, next_video, encode_opts.clone());
+        }
+    }
+    let from_start = match &range {
+        Some(range) => cache::is_range_from_start(range),
+        None => true,
+    };
+    if let Some(cache) = &state.cache {
+        if let Some(entry) = cache.get(&file, &encode_opts) {
+            if entry.is_full() {
+                return cached_response(cache, &entry, range).await;
+            }
+
+            // Partial entries can't answer ranges, the cached beginning is
+            // continued by a new encode instead
+            if entry.complete && entry.prefix_seconds > 0 && from_start {
+                return prefixed_response(&state, cache, &entry, &file, &encode_opts, video).await;
+            }
+        }
+    }
+
+    // Encoding output can only be served from the start
+    if !from_start {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+        return Ok(response);
     }
     state
         .notifier
         .send(msg::NotifyMessage::EncodingStarted)
         .unwrap();
-    let stream = media::encode(file, request.encode_opts).await?;
+    let stream = state.jobs.stream(&file, &encode_opts, video).await?;
     let mut response = Response::new(Body::wrap_stream(stream));
 
     // Headers
@@ -69,3 +218,155 @@ pub async fn media_show(
     // );
     Ok(response)
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaStreamRequest {
+    // Network stream, the source must be in the allowed stream sources
+    pub url: Url,
+
+    #[serde(default)]
+    pub encode_opts: media::EncodeOpts,
+}
+
+/// Transcodes a network stream the device can't play itself, e.g. an RTSP
+/// camera feed
+pub async fn media_stream(
+    state: Arc<AppState>,
+    request: MediaStreamRequest,
+) -> ApiResponse<Response<Body>> {
+    let url = media::resolve_source(&request.url, &state.opts.stream_sources)
+        .await?
+        .ok_or_else(|| ApiError::SourceNotAllowed(request.url.to_string()))?;
+    state
+        .notifier
+        .send(msg::NotifyMessage::EncodingStarted)
+        .unwrap();
+    let stream = state
+        .jobs
+        .stream(url, &request.encode_opts, None)
+        .await?;
+    let mut response = Response::new(Body::wrap_stream(stream));
+
+    // Headers
+    response
+        .headers_mut()
+        .insert("Content-Type", HeaderValue::from_static("video/mp4"));
+    response
+        .headers_mut()
+        .insert("Cache-Control", HeaderValue::from_static("no-store"));
+    Ok(response)
+}
+
+/// Serves the whole transcode from the cache as a normal file with range
+/// support
+async fn cached_response(
+    cache: &TranscodeCache,
+    entry: &CacheEntry,
+    range_header: Option<String>,
+) -> ApiResponse<Response<Body>> {
+    let range = range_header
+        .as_ref()
+        .and_then(|v| cache::parse_range(v, entry.size));
+    let (start, end) = match range {
+        Some(range) => range,
+        None if range_header.is_some() || entry.size == 0 => {
+            return Ok(range_not_satisfiable(entry.size))
+        }
+        None => (0, entry.size - 1),
+    };
+    println!("Serving from cache {} bytes {}-{}", entry.key, start, end);
+    let stream = cache.read(entry, start, end).await?;
+    let mut response = Response::new(Body::wrap_stream(stream));
+    if range.is_some() {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        response.headers_mut().insert(
+            "Content-Range",
+            HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, entry.size)).unwrap(),
+        );
+    }
+
+    // Headers
+    response
+        .headers_mut()
+        .insert("Content-Type", HeaderValue::from_static("video/mp4"));
+    response
+        .headers_mut()
+        .insert("Content-Length", HeaderValue::from(end + 1 - start));
+    response
+        .headers_mut()
+        .insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+    Ok(response)
+}
+
+/// Response to a range outside of the file
+pub fn range_not_satisfiable(size: u64) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+    response.headers_mut().insert(
+        "Content-Range",
+        HeaderValue::from_str(&format!("bytes */{}", size)).unwrap(),
+    );
+    response
+}
+
+/// Serves the pre-transcoded beginning from the cache and continues with a new
+/// encoding job from where the cached part ends
+async fn prefixed_response(
+    state: &AppState,
+    cache: &TranscodeCache,
+    entry: &CacheEntry,
+    file: &Path,
+    encode_opts: &media::EncodeOpts,
+    video: Option<media::VideoInfo>,
+) -> ApiResponse<Response<Body>> {
+    println!(
+        "Serving first {} seconds from cache {}",
+        entry.prefix_seconds, entry.key
+    );
+    let prefix = cache.read(entry, 0, entry.size.saturating_sub(1)).await?;
+    let mut opts = encode_opts.clone();
+    opts.seek_seconds += entry.prefix_seconds as i32;
+    opts.output_offset_seconds += entry.prefix_seconds as i32;
+    let mut skipper = media::InitSegmentSkipper::default();
+    let rest = state
+        .jobs
+        .stream(file, &opts, video)
+        .await?
+        .try_filter_map(move |chunk| future::ready(Ok(skipper.feed(chunk))));
+    let mut response = Response::new(Body::wrap_stream(prefix.chain(rest)));
+
+    // Headers
+    response
+        .headers_mut()
+        .insert("Content-Type", HeaderValue::from_static("video/mp4"));
+    response
+        .headers_mut()
+        .insert("Cache-Control", HeaderValue::from_static("no-store"));
+    Ok(response)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::tests::test_state;
+
+    #[tokio::test]
+    async fn test_media_stream_not_allowed() {
+        let dir = std::env::temp_dir().join(format!("casterson-stream-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (state, _) = test_state(&dir);
+
+        // No sources are allowed by default, and local files never are
+        for url in &["rtsp://camera.local/stream1", "file:///etc/passwd"] {
+            let request = MediaStreamRequest {
+                url: Url::parse(url).unwrap(),
+                encode_opts: Default::default(),
+            };
+            let result = media_stream(state.clone(), request).await;
+            assert!(matches!(result, Err(ApiError::SourceNotAllowed(v)) if v == *url));
+        }
+        assert!(state.jobs.list().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,961 @@
+use crate::AppState;
+use hyper::header::HeaderValue;
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use url::Url;
+
+use crate::api::media;
+use crate::api::photos::MediaPhotoRequest;
+use crate::api::ui::{MediaDirectRequest, MediaShowRequest};
+use crate::api::ApiError;
+use crate::api::ApiResponse;
+use crate::dlna::{self, xml_escape};
+use crate::library;
+use crate::library::LibraryItem;
+use crate::upnp;
+use crate::upnp::ClientProfile;
+
+/// DLNA flags of the files served as is, byte seeking is supported
+pub const DIRECT_FEATURES: &str =
+    "DLNA.ORG_OP=01;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=01700000000000000000000000000000";
+
+/// DLNA flags of the transcodes, which can't be seeked
+pub const TRANSCODE_FEATURES: &str =
+    "DLNA.ORG_OP=00;DLNA.ORG_CI=1;DLNA.ORG_FLAGS=01700000000000000000000000000000";
+
+/// Properties searchable with the Search action
+const SEARCH_CAPABILITIES: &str = "dc:title,dc:creator,upnp:artist,upnp:album,upnp:class";
+
+/// Argument of the action: name, direction and the related state variable
+type Argument = (&'static str, &'static str, &'static str);
+
+const CONTENT_DIRECTORY_ACTIONS: &[(&str, &[Argument])] = &[
+    (
+        "Browse",
+        &[
+            ("ObjectID", "in", "A_ARG_TYPE_ObjectID"),
+            ("BrowseFlag", "in", "A_ARG_TYPE_BrowseFlag"),
+            ("Filter", "in", "A_ARG_TYPE_Filter"),
+            ("StartingIndex", "in", "A_ARG_TYPE_Index"),
+            ("RequestedCount", "in", "A_ARG_TYPE_Count"),
+            ("SortCriteria", "in", "A_ARG_TYPE_SortCriteria"),
+            ("Result", "out", "A_ARG_TYPE_Result"),
+            ("NumberReturned", "out", "A_ARG_TYPE_Count"),
+            ("TotalMatches", "out", "A_ARG_TYPE_Count"),
+            ("UpdateID", "out", "A_ARG_TYPE_UpdateID"),
+        ],
+    ),
+    (
+        "Search",
+        &[
+            ("ContainerID", "in", "A_ARG_TYPE_ObjectID"),
+            ("SearchCriteria", "in", "A_ARG_TYPE_SearchCriteria"),
+            ("Filter", "in", "A_ARG_TYPE_Filter"),
+            ("StartingIndex", "in", "A_ARG_TYPE_Index"),
+            ("RequestedCount", "in", "A_ARG_TYPE_Count"),
+            ("SortCriteria", "in", "A_ARG_TYPE_SortCriteria"),
+            ("Result", "out", "A_ARG_TYPE_Result"),
+            ("NumberReturned", "out", "A_ARG_TYPE_Count"),
+            ("TotalMatches", "out", "A_ARG_TYPE_Count"),
+            ("UpdateID", "out", "A_ARG_TYPE_UpdateID"),
+        ],
+    ),
+    (
+        "GetSearchCapabilities",
+        &[("SearchCaps", "out", "SearchCapabilities")],
+    ),
+    (
+        "GetSortCapabilities",
+        &[("SortCaps", "out", "SortCapabilities")],
+    ),
+    ("GetSystemUpdateID", &[("Id", "out", "SystemUpdateID")]),
+];
+
+const CONTENT_DIRECTORY_VARIABLES: &[(&str, &str)] = &[
+    ("A_ARG_TYPE_ObjectID", "string"),
+    ("A_ARG_TYPE_BrowseFlag", "string"),
+    ("A_ARG_TYPE_Filter", "string"),
+    ("A_ARG_TYPE_Index", "ui4"),
+    ("A_ARG_TYPE_Count", "ui4"),
+    ("A_ARG_TYPE_SortCriteria", "string"),
+    ("A_ARG_TYPE_SearchCriteria", "string"),
+    ("A_ARG_TYPE_Result", "string"),
+    ("A_ARG_TYPE_UpdateID", "ui4"),
+    ("SearchCapabilities", "string"),
+    ("SortCapabilities", "string"),
+    ("SystemUpdateID", "ui4"),
+];
+
+const CONNECTION_MANAGER_ACTIONS: &[(&str, &[Argument])] = &[
+    (
+        "GetProtocolInfo",
+        &[
+            ("Source", "out", "SourceProtocolInfo"),
+            ("Sink", "out", "SinkProtocolInfo"),
+        ],
+    ),
+    (
+        "GetCurrentConnectionIDs",
+        &[("ConnectionIDs", "out", "CurrentConnectionIDs")],
+    ),
+    (
+        "GetCurrentConnectionInfo",
+        &[
+            ("ConnectionID", "in", "A_ARG_TYPE_ConnectionID"),
+            ("RcsID", "out", "A_ARG_TYPE_RcsID"),
+            ("AVTransportID", "out", "A_ARG_TYPE_AVTransportID"),
+            ("ProtocolInfo", "out", "A_ARG_TYPE_ProtocolInfo"),
+            (
+                "PeerConnectionManager",
+                "out",
+                "A_ARG_TYPE_ConnectionManager",
+            ),
+            ("PeerConnectionID", "out", "A_ARG_TYPE_ConnectionID"),
+            ("Direction", "out", "A_ARG_TYPE_Direction"),
+            ("Status", "out", "A_ARG_TYPE_ConnectionStatus"),
+        ],
+    ),
+];
+
+const CONNECTION_MANAGER_VARIABLES: &[(&str, &str)] = &[
+    ("SourceProtocolInfo", "string"),
+    ("SinkProtocolInfo", "string"),
+    ("CurrentConnectionIDs", "string"),
+    ("A_ARG_TYPE_ConnectionID", "i4"),
+    ("A_ARG_TYPE_RcsID", "i4"),
+    ("A_ARG_TYPE_AVTransportID", "i4"),
+    ("A_ARG_TYPE_ProtocolInfo", "string"),
+    ("A_ARG_TYPE_ConnectionManager", "string"),
+    ("A_ARG_TYPE_Direction", "string"),
+    ("A_ARG_TYPE_ConnectionStatus", "string"),
+];
+
+/// Protocols of the resources served
+const SOURCE_PROTOCOL_INFO: &[&str] = &[
+    "video/mp4",
+    "video/x-matroska",
+    "video/x-msvideo",
+    "video/quicktime",
+    "video/mp2t",
+    "video/mpeg",
+    "audio/mpeg",
+    "audio/flac",
+    "audio/mp4",
+    "audio/ogg",
+    "image/jpeg",
+];
+
+/// Object of the ContentDirectory
+///
+/// Ids are `0` for the root, `d{root}/{path}` for the folders and `f{id}` for
+/// the library items.
+#[derive(Clone, PartialEq, Debug)]
+enum ObjectId {
+    Root,
+    Folder(usize, PathBuf),
+    Item(String),
+}
+
+impl ObjectId {
+    fn parse(id: &str) -> Option<ObjectId> {
+        if id == "0" {
+            return Some(ObjectId::Root);
+        }
+        if let Some(id) = id.strip_prefix('f') {
+            return Some(ObjectId::Item(id.into()));
+        }
+        let folder = id.strip_prefix('d')?;
+        let (root, path) = match folder.find('/') {
+            Some(i) => (&folder[..i], &folder[i + 1..]),
+            None => (folder, ""),
+        };
+        Some(ObjectId::Folder(root.parse().ok()?, path.into()))
+    }
+
+    fn folder(root: usize, path: &Path) -> ObjectId {
+        ObjectId::Folder(root, path.to_path_buf())
+    }
+
+    fn parent(&self) -> String {
+        match self {
+            ObjectId::Root => "-1".into(),
+            ObjectId::Folder(root, path) => match path.parent() {
+                Some(parent) => ObjectId::folder(*root, parent).to_string(),
+                None => "0".into(),
+            },
+            ObjectId::Item(_) => "0".into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectId::Root => write!(f, "0"),
+            ObjectId::Folder(root, path) if path.as_os_str().is_empty() => write!(f, "d{}", root),
+            ObjectId::Folder(root, path) => {
+                write!(f, "d{}/{}", root, path.to_string_lossy().replace('\\', "/"))
+            }
+            ObjectId::Item(id) => write!(f, "f{}", id),
+        }
+    }
+}
+
+/// Words searched and the class of the items, from the SearchCriteria
+///
+/// Only `contains` and `=` of the text properties and `derivedfrom` of the
+/// class are understood. Items matching any of the words are found.
+#[derive(Default, PartialEq, Debug)]
+struct SearchQuery {
+    words: Vec<String>,
+    class: Option<String>,
+}
+
+fn parse_search(criteria: &str) -> SearchQuery {
+    // Words and the quoted strings, with the escaped quotes
+    let mut tokens: Vec<String> = vec![];
+    let mut chars = criteria.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let mut token = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => token.extend(chars.next()),
+                        '"' => break,
+                        c => token.push(c),
+                    }
+                }
+                tokens.push(token);
+            }
+            '(' | ')' => (),
+            c if c.is_whitespace() => (),
+            c => {
+                let mut token = c.to_string();
+                while let Some(c) = chars.peek().filter(|c| !c.is_whitespace() && **c != ')') {
+                    token.push(*c);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    let mut query = SearchQuery::default();
+    for v in tokens.windows(3) {
+        match (v[0].as_str(), v[1].to_lowercase().as_str()) {
+            ("upnp:class", "derivedfrom") | ("upnp:class", "=") => {
+                query.class = Some(v[2].clone());
+            }
+            (property, "contains") | (property, "=")
+                if property.contains(':') && !v[2].is_empty() =>
+            {
+                query.words.push(v[2].to_lowercase());
+            }
+            _ => (),
+        }
+    }
+    query
+}
+
+/// Client of the request, the resource URLs point to the host it connected
+pub struct UpnpClient {
+    base_url: Url,
+    profile: &'static ClientProfile,
+}
+
+impl UpnpClient {
+    pub fn new(headers: &HeaderMap) -> ApiResponse<UpnpClient> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("")
+        };
+        let base_url = format!("http://{}/", header("host"))
+            .parse()
+            .map_err(|_| ApiError::NotFound)?;
+        let user_agent = format!("{} {}", header("user-agent"), header("x-av-client-info"));
+        let profile = upnp::profile(&user_agent);
+        Ok(UpnpClient { base_url, profile })
+    }
+
+    fn url<T: Serialize>(&self, path: &str, request: &T) -> Url {
+        let mut url = self.base_url.join(path).unwrap();
+        url.set_query(Some(&serde_json::to_string(request).unwrap_or_default()));
+        url
+    }
+}
+
+/// Device description of the media server
+pub async fn description(state: Arc<AppState>) -> ApiResponse<Response<Body>> {
+    let service = |service_type: &str, name: &str| {
+        format!(
+            "<service><serviceType>{0}</serviceType>\
+             <serviceId>urn:upnp-org:serviceId:{1}</serviceId>\
+             <SCPDURL>/upnp/scpd/{2}.xml</SCPDURL>\
+             <controlURL>/upnp/control/{2}</controlURL>\
+             <eventSubURL>/upnp/event/{2}</eventSubURL></service>",
+            service_type,
+            name,
+            snake_case(name)
+        )
+    };
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <root xmlns=\"urn:schemas-upnp-org:device-1-0\" \
+         xmlns:dlna=\"urn:schemas-dlna-org:device-1-0\">\
+         <specVersion><major>1</major><minor>0</minor></specVersion>\
+         <device><deviceType>{}</deviceType>\
+         <friendlyName>{}</friendlyName>\
+         <manufacturer>casterson</manufacturer>\
+         <modelName>casterson</modelName>\
+         <dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC>\
+         <UDN>uuid:{}</UDN>\
+         <serviceList>{}{}</serviceList></device></root>",
+        upnp::MEDIA_SERVER,
+        xml_escape(&state.upnp.name),
+        state.upnp.uuid,
+        service(upnp::CONTENT_DIRECTORY, "ContentDirectory"),
+        service(upnp::CONNECTION_MANAGER, "ConnectionManager"),
+    );
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+/// Service description, name is `content_directory.xml` or
+/// `connection_manager.xml`
+pub async fn scpd(name: &str) -> ApiResponse<Response<Body>> {
+    let (actions, variables) = match name {
+        "content_directory.xml" => (CONTENT_DIRECTORY_ACTIONS, CONTENT_DIRECTORY_VARIABLES),
+        "connection_manager.xml" => (CONNECTION_MANAGER_ACTIONS, CONNECTION_MANAGER_VARIABLES),
+        _ => return Err(ApiError::NotFound),
+    };
+    let actions: String = actions
+        .iter()
+        .map(|(name, arguments)| {
+            let arguments: String = arguments
+                .iter()
+                .map(|(name, direction, variable)| {
+                    format!(
+                        "<argument><name>{}</name><direction>{}</direction>\
+                         <relatedStateVariable>{}</relatedStateVariable></argument>",
+                        name, direction, variable
+                    )
+                })
+                .collect();
+            format!(
+                "<action><name>{}</name><argumentList>{}</argumentList></action>",
+                name, arguments
+            )
+        })
+        .collect();
+    let variables: String = variables
+        .iter()
+        .map(|(name, data_type)| {
+            format!(
+                "<stateVariable sendEvents=\"no\"><name>{}</name>\
+                 <dataType>{}</dataType></stateVariable>",
+                name, data_type
+            )
+        })
+        .collect();
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <scpd xmlns=\"urn:schemas-upnp-org:service-1-0\">\
+         <specVersion><major>1</major><minor>0</minor></specVersion>\
+         <actionList>{}</actionList>\
+         <serviceStateTable>{}</serviceStateTable></scpd>",
+        actions, variables
+    );
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+/// Accepts the event subscriptions, no events are sent and the clients poll
+/// the SystemUpdateID instead
+pub async fn subscribe(state: Arc<AppState>) -> ApiResponse<Response<Body>> {
+    let mut response = Response::new(Body::empty());
+    response.headers_mut().insert(
+        "SID",
+        HeaderValue::from_str(&format!("uuid:{}", state.upnp.uuid)).unwrap(),
+    );
+    response
+        .headers_mut()
+        .insert("TIMEOUT", HeaderValue::from_static("Second-1800"));
+    Ok(response)
+}
+
+/// Calls the SOAP action of the service, `content_directory` or
+/// `connection_manager`
+pub async fn control(
+    state: Arc<AppState>,
+    service: &str,
+    client: UpnpClient,
+    body: &[u8],
+) -> ApiResponse<Response<Body>> {
+    let body = String::from_utf8_lossy(body);
+    let doc = match roxmltree::Document::parse(&body) {
+        Ok(doc) => doc,
+        Err(_) => return Ok(fault(401, "Invalid Action")),
+    };
+    let action = match doc
+        .descendants()
+        .find(|v| v.tag_name().name() == "Body")
+        .and_then(|v| v.first_element_child())
+    {
+        Some(action) => action,
+        None => return Ok(fault(401, "Invalid Action")),
+    };
+    let arg = |name: &str| -> String {
+        action
+            .children()
+            .find(|v| v.tag_name().name() == name)
+            .and_then(|v| v.text())
+            .unwrap_or("")
+            .trim()
+            .to_string()
+    };
+    let name = action.tag_name().name();
+    let update_id = state.upnp.update_id().to_string();
+    let (service_type, values): (&str, Vec<(&str, String)>) = match (service, name) {
+        ("content_directory", "Browse") | ("content_directory", "Search") => {
+            let start: usize = arg("StartingIndex").parse().unwrap_or(0);
+            let count: usize = arg("RequestedCount").parse().unwrap_or(0);
+            let library = state.library.read().unwrap();
+            let objects = if name == "Browse" {
+                let id = match ObjectId::parse(&arg("ObjectID")) {
+                    Some(id) => id,
+                    None => return Ok(fault(701, "No such object")),
+                };
+                let objects = if arg("BrowseFlag") == "BrowseMetadata" {
+                    metadata(&state, &library, &id)
+                } else {
+                    children(&state, &library, &id)
+                };
+                match objects {
+                    Some(objects) => objects,
+                    None => return Ok(fault(701, "No such object")),
+                }
+            } else {
+                let container = match ObjectId::parse(&arg("ContainerID")) {
+                    Some(container) => container,
+                    None => return Ok(fault(710, "No such container")),
+                };
+                search(
+                    &state,
+                    &library,
+                    &container,
+                    &parse_search(&arg("SearchCriteria")),
+                )
+            };
+
+            // Only the objects of the page are rendered
+            let total = objects.len();
+            let objects: Vec<String> = objects
+                .iter()
+                .skip(start)
+                .take(if count == 0 { usize::MAX } else { count })
+                .map(|v| v.render(&client))
+                .collect();
+            (
+                upnp::CONTENT_DIRECTORY,
+                vec![
+                    ("NumberReturned", objects.len().to_string()),
+                    ("Result", didl(&objects)),
+                    ("TotalMatches", total.to_string()),
+                    ("UpdateID", update_id),
+                ],
+            )
+        }
+        ("content_directory", "GetSearchCapabilities") => (
+            upnp::CONTENT_DIRECTORY,
+            vec![("SearchCaps", SEARCH_CAPABILITIES.into())],
+        ),
+        ("content_directory", "GetSortCapabilities") => {
+            (upnp::CONTENT_DIRECTORY, vec![("SortCaps", "".into())])
+        }
+        ("content_directory", "GetSystemUpdateID") => {
+            (upnp::CONTENT_DIRECTORY, vec![("Id", update_id)])
+        }
+        ("connection_manager", "GetProtocolInfo") => {
+            let source: Vec<String> = SOURCE_PROTOCOL_INFO
+                .iter()
+                .map(|v| format!("http-get:*:{}:*", v))
+                .collect();
+            (
+                upnp::CONNECTION_MANAGER,
+                vec![("Source", source.join(",")), ("Sink", "".into())],
+            )
+        }
+        ("connection_manager", "GetCurrentConnectionIDs") => (
+            upnp::CONNECTION_MANAGER,
+            vec![("ConnectionIDs", "0".into())],
+        ),
+        ("connection_manager", "GetCurrentConnectionInfo") => (
+            upnp::CONNECTION_MANAGER,
+            vec![
+                ("RcsID", "-1".into()),
+                ("AVTransportID", "-1".into()),
+                ("ProtocolInfo", "".into()),
+                ("PeerConnectionManager", "".into()),
+                ("PeerConnectionID", "-1".into()),
+                ("Direction", "Output".into()),
+                ("Status", "OK".into()),
+            ],
+        ),
+        _ => return Ok(fault(401, "Invalid Action")),
+    };
+    let values: Vec<(&str, &str)> = values.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    let xml = dlna::soap_envelope(service_type, &format!("{}Response", name), &values);
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+/// Adds the DLNA headers of the streaming to the media response, when the
+/// client asks for them
+pub fn content_features(
+    headers: &HeaderMap,
+    response: ApiResponse<Response<Body>>,
+    features: &'static str,
+) -> ApiResponse<Response<Body>> {
+    let mut response = response?;
+    if headers.contains_key("getcontentFeatures.dlna.org") {
+        response.headers_mut().insert(
+            "contentFeatures.dlna.org",
+            HeaderValue::from_static(features),
+        );
+        response.headers_mut().insert(
+            "transferMode.dlna.org",
+            HeaderValue::from_static("Streaming"),
+        );
+    }
+    Ok(response)
+}
+
+/// DIDL-Lite object, rendered only when it's on the page returned
+#[derive(Debug)]
+enum Object<'a> {
+    // Count is the number of the direct children
+    Container {
+        id: ObjectId,
+        title: String,
+        count: usize,
+    },
+    Item {
+        item: &'a LibraryItem,
+        parent: ObjectId,
+    },
+}
+
+impl Object<'_> {
+    fn render(&self, client: &UpnpClient) -> String {
+        match self {
+            Object::Container { id, title, count } => container(id, title, *count),
+            Object::Item { item, parent } => item_object(client, item, parent),
+        }
+    }
+}
+
+/// Metadata of the object itself
+fn metadata<'a>(
+    state: &AppState,
+    library: &'a library::Library,
+    id: &ObjectId,
+) -> Option<Vec<Object<'a>>> {
+    let items = library_items(state, library);
+    match id {
+        ObjectId::Root => Some(vec![Object::Container {
+            id: id.clone(),
+            title: state.upnp.name.clone(),
+            count: state.opts.dir.len(),
+        }]),
+        ObjectId::Folder(root, path) => {
+            let count = child_count(&items, *root, path);
+            if count == 0 && !path.as_os_str().is_empty() {
+                return None;
+            }
+            Some(vec![Object::Container {
+                id: id.clone(),
+                title: folder_name(state, *root, path),
+                count,
+            }])
+        }
+        ObjectId::Item(item_id) => {
+            let (root, path, item) = items.into_iter().find(|v| &v.2.id == item_id)?;
+            let parent = ObjectId::folder(root, path.parent().unwrap_or(Path::new("")));
+            Some(vec![Object::Item { item, parent }])
+        }
+    }
+}
+
+/// Folders and items in the container
+fn children<'a>(
+    state: &AppState,
+    library: &'a library::Library,
+    id: &ObjectId,
+) -> Option<Vec<Object<'a>>> {
+    let items = library_items(state, library);
+    let (root, folder) = match id {
+        ObjectId::Root => {
+            return Some(
+                (0..state.opts.dir.len())
+                    .map(|root| Object::Container {
+                        id: ObjectId::folder(root, Path::new("")),
+                        title: folder_name(state, root, Path::new("")),
+                        count: child_count(&items, root, Path::new("")),
+                    })
+                    .collect(),
+            )
+        }
+        ObjectId::Folder(root, folder) => (*root, folder),
+        ObjectId::Item(_) => return None,
+    };
+
+    // Subfolders with the names of their direct children
+    let mut folders: BTreeMap<String, HashSet<&std::ffi::OsStr>> = BTreeMap::new();
+    let mut files: Vec<&LibraryItem> = vec![];
+    for (_, path, item) in items
+        .iter()
+        .filter(|v| v.0 == root && v.1.starts_with(folder))
+    {
+        let mut components = path.strip_prefix(folder).unwrap().components();
+        match (components.next(), components.next()) {
+            (Some(name), Some(child)) => {
+                let name = name.as_os_str().to_string_lossy().into_owned();
+                folders.entry(name).or_default().insert(child.as_os_str());
+            }
+            _ => files.push(item),
+        }
+    }
+    files.sort_by_key(|v| v.title().to_lowercase());
+    Some(
+        folders
+            .into_iter()
+            .map(|(name, children)| Object::Container {
+                id: ObjectId::folder(root, &folder.join(&name)),
+                title: name,
+                count: children.len(),
+            })
+            .chain(files.into_iter().map(|item| Object::Item {
+                item,
+                parent: id.clone(),
+            }))
+            .collect(),
+    )
+}
+
+/// Folders and files directly in the folder
+fn child_count(items: &[(usize, &Path, &LibraryItem)], root: usize, folder: &Path) -> usize {
+    items
+        .iter()
+        .filter(|v| v.0 == root)
+        .filter_map(|v| v.1.strip_prefix(folder).ok()?.components().next())
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Items under the container matching the query
+fn search<'a>(
+    state: &AppState,
+    library: &'a library::Library,
+    container: &ObjectId,
+    query: &SearchQuery,
+) -> Vec<Object<'a>> {
+    let mut found: Vec<(usize, &Path, &LibraryItem)> = library_items(state, library)
+        .into_iter()
+        .filter(|(root, path, _)| match container {
+            ObjectId::Root => true,
+            ObjectId::Folder(folder_root, folder) => {
+                root == folder_root && path.starts_with(folder)
+            }
+            ObjectId::Item(_) => false,
+        })
+        .filter(|(_, _, item)| {
+            query
+                .class
+                .as_ref()
+                .is_none_or(|class| item_class(item).starts_with(class.as_str()))
+        })
+        .filter(|(_, _, item)| {
+            let tags = item.audio.clone().unwrap_or_default();
+            let text = [
+                Some(item.title()),
+                Some(item.name()),
+                tags.artist,
+                tags.album,
+            ]
+            .iter()
+            .flatten()
+            .map(|v| v.to_lowercase())
+            .collect::<Vec<String>>()
+            .join("\n");
+            query.words.is_empty() || query.words.iter().any(|v| text.contains(v.as_str()))
+        })
+        .collect();
+    found.sort_by_key(|v| v.2.title().to_lowercase());
+    found
+        .into_iter()
+        .map(|(root, path, item)| Object::Item {
+            item,
+            parent: ObjectId::folder(root, path.parent().unwrap_or(Path::new(""))),
+        })
+        .collect()
+}
+
+/// Library items with their roots and relative paths
+fn library_items<'a>(
+    state: &AppState,
+    library: &'a library::Library,
+) -> Vec<(usize, &'a Path, &'a LibraryItem)> {
+    library
+        .items()
+        .into_iter()
+        .filter_map(|item| {
+            let (root, path) = library::relative_path(&item.path, &state.opts.dir)?;
+            Some((root, path, item))
+        })
+        .collect()
+}
+
+fn folder_name(state: &AppState, root: usize, path: &Path) -> String {
+    path.file_name()
+        .or_else(|| state.opts.dir.get(root).and_then(|v| v.file_name()))
+        .map(|v| v.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn container(id: &ObjectId, title: &str, count: usize) -> String {
+    format!(
+        "<container id=\"{}\" parentID=\"{}\" restricted=\"1\" searchable=\"1\" \
+         childCount=\"{}\"><dc:title>{}</dc:title>\
+         <upnp:class>object.container.storageFolder</upnp:class></container>",
+        xml_escape(&id.to_string()),
+        xml_escape(&id.parent()),
+        count,
+        xml_escape(title)
+    )
+}
+
+fn item_class(item: &LibraryItem) -> &'static str {
+    if item.is_image() {
+        "object.item.imageItem.photo"
+    } else if item.is_audio() {
+        "object.item.audioItem.musicTrack"
+    } else {
+        "object.item.videoItem"
+    }
+}
+
+/// DIDL-Lite item of the library item, with the resource chosen for the client
+fn item_object(client: &UpnpClient, item: &LibraryItem, parent: &ObjectId) -> String {
+    let id = item.id.clone();
+    let (url, content_type, size, features) = if item.is_image() {
+        let request = MediaPhotoRequest {
+            id,
+            width: None,
+            height: None,
+            ken_burns_seconds: None,
+        };
+        let url = client.url("media_photo", &request);
+        (url, request.content_type(), None, DIRECT_FEATURES)
+    } else if item.is_audio() || client.profile.is_direct(&item.path) {
+        // Audio is served as is without the format
+        let path = if item.is_audio() {
+            "media_audio"
+        } else {
+            "media_direct"
+        };
+        let url = client.url(path, &MediaDirectRequest { id });
+        let content_type = crate::media::content_type(&item.path);
+        (url, content_type, Some(item.size), DIRECT_FEATURES)
+    } else {
+        let request = MediaShowRequest {
+            id,
+            encode_opts: Default::default(),
+        };
+        let url = client.url("media_show", &request);
+        (url, "video/mp4", None, TRANSCODE_FEATURES)
+    };
+    let mut object = format!(
+        "<item id=\"{}\" parentID=\"{}\" restricted=\"1\">\
+         <dc:title>{}</dc:title><upnp:class>{}</upnp:class>",
+        xml_escape(&ObjectId::Item(item.id.clone()).to_string()),
+        xml_escape(&parent.to_string()),
+        xml_escape(&item.title()),
+        item_class(item)
+    );
+    if let Some(tags) = &item.audio {
+        for (element, value) in &[
+            ("upnp:artist", &tags.artist),
+            ("dc:creator", &tags.artist),
+            ("upnp:album", &tags.album),
+        ] {
+            if let Some(value) = value {
+                object.push_str(&format!("<{0}>{1}</{0}>", element, xml_escape(value)));
+            }
+        }
+        if let Some(track) = tags.track {
+            object.push_str(&format!(
+                "<upnp:originalTrackNumber>{}</upnp:originalTrackNumber>",
+                track
+            ));
+        }
+    }
+    if let Ok(art) = client.base_url.join(&media::poster_url(item)) {
+        object.push_str(&format!(
+            "<upnp:albumArtURI>{}</upnp:albumArtURI>",
+            xml_escape(art.as_str())
+        ));
+    }
+    let mut attributes = format!("protocolInfo=\"http-get:*:{}:{}\"", content_type, features);
+    if let Some(size) = size {
+        attributes.push_str(&format!(" size=\"{}\"", size));
+    }
+    if let Some(duration) = item.duration.filter(|v| *v > 0.0) {
+        attributes.push_str(&format!(
+            " duration=\"{}.000\"",
+            dlna::format_time(duration)
+        ));
+    }
+    object.push_str(&format!(
+        "<res {}>{}</res></item>",
+        attributes,
+        xml_escape(url.as_str())
+    ));
+    object
+}
+
+fn didl(objects: &[String]) -> String {
+    format!(
+        "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+         xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+         xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">{}</DIDL-Lite>",
+        objects.concat()
+    )
+}
+
+/// UPnP error as SOAP fault
+fn fault(code: u16, description: &str) -> Response<Body> {
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>\
+         <detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+         <errorCode>{}</errorCode><errorDescription>{}</errorDescription>\
+         </UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+        code, description
+    );
+    xml_response(StatusCode::INTERNAL_SERVER_ERROR, xml)
+}
+
+fn xml_response(status: StatusCode, xml: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(xml));
+    *response.status_mut() = status;
+
+    // Headers
+    response.headers_mut().insert(
+        "Content-Type",
+        HeaderValue::from_static("text/xml; charset=\"utf-8\""),
+    );
+    response
+        .headers_mut()
+        .insert("EXT", HeaderValue::from_static(""));
+    response
+}
+
+/// ContentDirectory to content_directory
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            out.push('_');
+        }
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::tests::test_state;
+
+    fn containers(objects: &[Object]) -> Vec<(String, usize)> {
+        objects
+            .iter()
+            .filter_map(|v| match v {
+                Object::Container { id, count, .. } => Some((id.to_string(), *count)),
+                Object::Item { .. } => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_child_count() {
+        let dir = std::env::temp_dir().join(format!("casterson-upnp-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (state, _) = test_state(&dir);
+        let show = dir.join("media").join("Show");
+        std::fs::create_dir_all(show.join("Season 1")).unwrap();
+        std::fs::write(show.join("Season 1").join("S01E01.mkv"), b"first").unwrap();
+        std::fs::write(show.join("Season 1").join("S01E02.mkv"), b"second").unwrap();
+        std::fs::write(show.join("Extras.mkv"), b"extras").unwrap();
+        library::rescan(&state.library, &state.opts.dir, &state.opts.media_exts);
+        let library = state.library.read().unwrap();
+
+        // Counts are of the direct children, not of all the files under
+        let root = children(&state, &library, &ObjectId::Root).unwrap();
+        assert_eq!(containers(&root), vec![("d0".into(), 2)]);
+        let folder = ObjectId::parse("d0").unwrap();
+        let objects = children(&state, &library, &folder).unwrap();
+        assert_eq!(containers(&objects), vec![("d0/Show".into(), 2)]);
+        assert_eq!(objects.len(), 2);
+        let show = ObjectId::parse("d0/Show").unwrap();
+        let objects = metadata(&state, &library, &show).unwrap();
+        assert_eq!(containers(&objects), vec![("d0/Show".into(), 2)]);
+        let objects = children(&state, &library, &show).unwrap();
+        assert_eq!(containers(&objects), vec![("d0/Show/Season 1".into(), 2)]);
+        drop(library);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_object_id() {
+        let id = ObjectId::parse("d1/Movies/Action").unwrap();
+        assert_eq!(id, ObjectId::Folder(1, "Movies/Action".into()));
+        assert_eq!(id.to_string(), "d1/Movies/Action");
+        assert_eq!(id.parent(), "d1/Movies");
+        assert_eq!(ObjectId::parse("d1").unwrap().parent(), "0");
+        assert_eq!(ObjectId::parse("d1").unwrap().to_string(), "d1");
+        assert_eq!(
+            ObjectId::parse("fabc123"),
+            Some(ObjectId::Item("abc123".into()))
+        );
+        assert_eq!(ObjectId::parse("0"), Some(ObjectId::Root));
+        assert_eq!(ObjectId::parse("x"), None);
+    }
+
+    #[test]
+    fn test_parse_search() {
+        let query = parse_search(
+            "(upnp:class derivedfrom \"object.item.audioItem\") and \
+             (dc:title contains \"Blue \\\"Moon\\\"\" or upnp:artist contains \"blue\")",
+        );
+        assert_eq!(
+            query,
+            SearchQuery {
+                words: vec!["blue \"moon\"".into(), "blue".into()],
+                class: Some("object.item.audioItem".into()),
+            }
+        );
+        assert_eq!(parse_search("*"), SearchQuery::default());
+    }
+
+    #[test]
+    fn test_snake_case() {
+        assert_eq!(snake_case("ContentDirectory"), "content_directory");
+        assert_eq!(snake_case("ConnectionManager"), "connection_manager");
+    }
+}
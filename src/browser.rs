@@ -0,0 +1,413 @@
+/// Web pages registered as receivers over WebSocket
+///
+/// The receiver page connects to the socket and plays the loads in a video
+/// element. Commands are sent to the page as JSON messages and the page
+/// reports the status of the video back in the terms of `RendererStatus`.
+/// Each page gets an id when it connects. Receivers are addressed by the IP of
+/// the browser and the id, the latest page of the IP is used without the id.
+use crate::renderer::{Renderer, RendererError, RendererStatus, StatusCallback};
+use crate::websocket::{self, Message};
+use rust_cast::channels::media::{IdleReason, Metadata, PlayerState};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use url::Url;
+
+/// Casting ends if the page doesn't report the status of the load in time
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum BrowserError {
+    ReceiverNotFound,
+    ReceiverClosed,
+}
+
+/// Commands sent to the page
+#[derive(Serialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    Load {
+        url: Url,
+        content_type: Option<String>,
+        title: Option<String>,
+    },
+    Play,
+    Pause,
+    Stop,
+    Seek {
+        seconds: f32,
+    },
+    Volume {
+        level: f32,
+    },
+}
+
+/// Status reported by the page
+#[derive(Deserialize, Debug)]
+struct PageStatus {
+    current_time: Option<f32>,
+    player_state: String,
+    idle_reason: Option<String>,
+}
+
+impl From<PageStatus> for RendererStatus {
+    fn from(status: PageStatus) -> Self {
+        RendererStatus {
+            current_time: status.current_time,
+            player_state: status.player_state.parse().unwrap_or(PlayerState::Idle),
+            idle_reason: status.idle_reason.and_then(|v| v.parse().ok()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BrowserReceiver {
+    pub id: u64,
+    pub ip: IpAddr,
+    pub name: String,
+
+    // Page decodes HEVC on an HDR display, reported by the page
+    pub hdr: bool,
+    messages: UnboundedSender<Message>,
+    status: Mutex<RendererStatus>,
+
+    // Statuses of the cast being played, replaced by the next cast
+    cast: Mutex<Option<mpsc::Sender<RendererStatus>>>,
+}
+
+impl BrowserReceiver {
+    fn send(&self, command: &Command) -> Result<(), BrowserError> {
+        let json = serde_json::to_string(command).unwrap();
+        self.messages
+            .send(Message::Text(json))
+            .map_err(|_| BrowserError::ReceiverClosed)
+    }
+}
+
+/// Connected receiver pages
+#[derive(Default, Debug)]
+pub struct Receivers {
+    receivers: Mutex<Vec<Arc<BrowserReceiver>>>,
+    next_id: AtomicU64,
+}
+
+impl Receivers {
+    pub fn list(&self) -> Vec<Arc<BrowserReceiver>> {
+        self.receivers.lock().unwrap().clone()
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn add(&self, receiver: Arc<BrowserReceiver>) {
+        self.receivers.lock().unwrap().push(receiver);
+    }
+
+    fn remove(&self, receiver: &Arc<BrowserReceiver>) {
+        self.receivers
+            .lock()
+            .unwrap()
+            .retain(|v| !Arc::ptr_eq(v, receiver));
+    }
+}
+
+pub struct BrowserRenderer {
+    receiver: Arc<BrowserReceiver>,
+}
+
+/// Receiver page of the IP with the id, or the latest page of the IP
+pub fn get_renderer(
+    receivers: &Receivers,
+    ip: &IpAddr,
+    id: Option<u64>,
+) -> Result<BrowserRenderer, BrowserError> {
+    let receiver = receivers
+        .list()
+        .into_iter()
+        .rev()
+        .find(|v| &v.ip == ip && id.is_none_or(|id| v.id == id))
+        .ok_or(BrowserError::ReceiverNotFound)?;
+    Ok(BrowserRenderer { receiver })
+}
+
+impl BrowserRenderer {
+    /// Sends the command, the status is the last one reported
+    fn command(&self, command: Command) -> Result<RendererStatus, RendererError> {
+        self.receiver.send(&command)?;
+        Ok(self.receiver.status.lock().unwrap().clone())
+    }
+}
+
+impl Renderer for BrowserRenderer {
+    fn play(&self) -> Result<RendererStatus, RendererError> {
+        self.command(Command::Play)
+    }
+    fn pause(&self) -> Result<RendererStatus, RendererError> {
+        self.command(Command::Pause)
+    }
+    fn stop(&self) -> Result<RendererStatus, RendererError> {
+        self.command(Command::Stop)
+    }
+    fn seek(&self, seconds: f32) -> Result<RendererStatus, RendererError> {
+        self.command(Command::Seek { seconds })
+    }
+    fn set_volume(&self, level: f32) -> Result<(), RendererError> {
+        self.command(Command::Volume {
+            level: level.clamp(0.0, 1.0),
+        })?;
+        Ok(())
+    }
+    fn cast(
+        &self,
+        url: Url,
+        content_type: Option<String>,
+        metadata: Option<Metadata>,
+        mut on_status: StatusCallback,
+    ) -> Result<(), RendererError> {
+        let title = match metadata {
+            Some(Metadata::Movie(v)) => v.title,
+            Some(Metadata::TvShow(v)) => v.episode_title.or(v.series_title),
+            Some(Metadata::MusicTrack(v)) => v.title,
+            Some(Metadata::Photo(v)) => v.title,
+            Some(Metadata::Generic(v)) => v.title,
+            None => None,
+        };
+        let (sender, statuses) = mpsc::channel();
+        *self.receiver.cast.lock().unwrap() = Some(sender);
+        self.receiver.send(&Command::Load {
+            url,
+            content_type,
+            title,
+        })?;
+        // Page which doesn't answer the load is given up, once it has the
+        // statuses come only when the playback changes
+        let mut started = false;
+        loop {
+            let status = if started {
+                statuses
+                    .recv()
+                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+            } else {
+                statuses.recv_timeout(START_TIMEOUT)
+            };
+            match status {
+                Ok(status) => {
+                    let ended = status.idle_reason.is_some();
+                    on_status(&status);
+                    if ended {
+                        break;
+                    }
+                    started = true;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    println!("Receiver {} didn't start the playback", self.receiver.name);
+                    on_status(&idle_status(IdleReason::Error));
+                    break;
+                }
+                // Page closed or the next cast started
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    on_status(&idle_status(IdleReason::Interrupted));
+                    break;
+                }
+            }
+        }
+        println!("Close thread!");
+        Ok(())
+    }
+    fn get_status(&self) -> Result<RendererStatus, RendererError> {
+        Ok(self.receiver.status.lock().unwrap().clone())
+    }
+    fn supports_hdr(&self) -> bool {
+        self.receiver.hdr
+    }
+}
+
+fn idle_status(reason: IdleReason) -> RendererStatus {
+    RendererStatus {
+        current_time: None,
+        player_state: PlayerState::Idle,
+        idle_reason: Some(reason),
+    }
+}
+
+/// Serves the receiver page on the upgraded connection until it's closed
+pub async fn serve<S>(receivers: &Receivers, stream: S, ip: IpAddr, name: String, hdr: bool)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (messages, mut outgoing) = unbounded_channel();
+    let receiver = Arc::new(BrowserReceiver {
+        id: receivers.next_id(),
+        ip,
+        name,
+        hdr,
+        messages,
+        status: Mutex::new(RendererStatus {
+            current_time: None,
+            player_state: PlayerState::Idle,
+            idle_reason: None,
+        }),
+        cast: Mutex::new(None),
+    });
+    println!(
+        "Receiver {} connected from {} with id {}",
+        receiver.name, ip, receiver.id
+    );
+    receivers.add(receiver.clone());
+    tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            let close = message == Message::Close;
+            if websocket::write(&mut writer, &message).await.is_err() || close {
+                break;
+            }
+        }
+    });
+    loop {
+        let message = match websocket::read(&mut reader).await {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(err) => {
+                println!("Receiver {} failed {:?}", receiver.name, err);
+                break;
+            }
+        };
+        match message {
+            Message::Text(text) => {
+                let status: RendererStatus = match serde_json::from_str::<PageStatus>(&text) {
+                    Ok(status) => status.into(),
+                    Err(err) => {
+                        println!("Invalid status of receiver {} {:?}", receiver.name, err);
+                        continue;
+                    }
+                };
+                *receiver.status.lock().unwrap() = status.clone();
+                if let Some(cast) = &*receiver.cast.lock().unwrap() {
+                    let _ = cast.send(status);
+                }
+            }
+            Message::Ping(data) => {
+                let _ = receiver.messages.send(Message::Pong(data));
+            }
+            Message::Close => {
+                let _ = receiver.messages.send(Message::Close);
+                break;
+            }
+            _ => (),
+        }
+    }
+    println!("Receiver {} disconnected", receiver.name);
+    receivers.remove(&receiver);
+    receiver.cast.lock().unwrap().take();
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Connects a page to the receivers, returns the socket of the page
+    async fn connect(receivers: &Arc<Receivers>, listener: &mut TcpListener) -> TcpStream {
+        let page = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let receivers_ = receivers.clone();
+        let count = receivers.list().len();
+        tokio::spawn(async move {
+            serve(&receivers_, stream, addr.ip(), "Test".into(), false).await;
+        });
+        while receivers.list().len() == count {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        page
+    }
+
+    fn status(player_state: &str, idle_reason: Option<&str>) -> Message {
+        Message::Text(
+            serde_json::json!({
+                "current_time": 1.5,
+                "player_state": player_state,
+                "idle_reason": idle_reason,
+            })
+            .to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_cast() {
+        let receivers = Arc::new(Receivers::default());
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut first = connect(&receivers, &mut listener).await;
+        let mut second = connect(&receivers, &mut listener).await;
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let ids: Vec<u64> = receivers.list().iter().map(|v| v.id).collect();
+        assert_ne!(ids[0], ids[1]);
+
+        // Latest page of the IP without the id
+        assert_eq!(
+            get_renderer(&receivers, &ip, None).unwrap().receiver.id,
+            ids[1]
+        );
+        assert!(get_renderer(&receivers, &ip, Some(0)).is_err());
+
+        let renderer = get_renderer(&receivers, &ip, Some(ids[0])).unwrap();
+        let statuses = Arc::new(Mutex::new(vec![]));
+        let statuses_ = statuses.clone();
+        let on_status: StatusCallback = Box::new(move |status: &RendererStatus| {
+            statuses_.lock().unwrap().push((
+                status.player_state.to_string(),
+                status.idle_reason.is_some(),
+            ));
+        });
+        let url: Url = "http://127.0.0.1:3000/media_show?{}".parse().unwrap();
+        let cast = tokio::task::spawn_blocking(move || {
+            renderer.cast(url, Some("video/mp4".into()), None, on_status)
+        });
+
+        let load = match websocket::read(&mut first).await.unwrap() {
+            Some(Message::Text(text)) => serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            v => panic!("Unexpected message {:?}", v),
+        };
+        assert_eq!(load["command"], "load");
+        assert_eq!(load["content_type"], "video/mp4");
+        websocket::write(&mut first, &status("PLAYING", None))
+            .await
+            .unwrap();
+        websocket::write(&mut first, &status("IDLE", Some("FINISHED")))
+            .await
+            .unwrap();
+        cast.await.unwrap().unwrap();
+
+        assert_eq!(
+            *statuses.lock().unwrap(),
+            vec![("PLAYING".into(), false), ("IDLE".into(), true)]
+        );
+
+        // Status is routed to the page cast to only
+        let first_status = get_renderer(&receivers, &ip, Some(ids[0]))
+            .unwrap()
+            .get_status()
+            .unwrap();
+        assert!(first_status.idle_reason.is_some());
+        let second_status = get_renderer(&receivers, &ip, Some(ids[1]))
+            .unwrap()
+            .get_status()
+            .unwrap();
+        assert!(second_status.idle_reason.is_none());
+
+        websocket::write(&mut second, &Message::Close)
+            .await
+            .unwrap();
+        while receivers.list().len() > 1 {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(receivers.list()[0].id, ids[0]);
+    }
+}
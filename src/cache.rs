@@ -0,0 +1,434 @@
+/// Persistent transcode cache
+///
+/// Outputs of `media::encode` are written to the cache directory as they are
+/// streamed to the clients. Each entry is a `<key>.mp4` file with a
+/// `<key>.json` sidecar, key is derived from the file path, modification time
+/// and the encoding options. Least recently used entries are evicted when the
+/// cache grows over the size limit.
+use crate::media;
+use bytes::{Bytes, BytesMut};
+use futures::stream::TryStreamExt;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CacheEntry {
+    pub key: String,
+    pub file: String,
+    pub mtime: u64,
+    pub encode_opts: media::EncodeOpts,
+    pub size: u64,
+    pub complete: bool,
+    pub last_access: u64,
+
+    // Only the first seconds were encoded, 0 if the whole file was encoded
+    #[serde(default)]
+    pub prefix_seconds: u32,
+}
+
+impl CacheEntry {
+    /// Entry has the whole encoded file
+    pub fn is_full(&self) -> bool {
+        self.complete && self.prefix_seconds == 0
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheStatus {
+    max_bytes: u64,
+    total_bytes: u64,
+    entries: Vec<CacheEntry>,
+}
+
+#[derive(Debug)]
+pub struct TranscodeCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    writing: Mutex<HashSet<String>>,
+}
+
+impl TranscodeCache {
+    /// Opens the cache directory, creating it if it doesn't exist
+    pub fn open<P: AsRef<Path>>(dir: P, max_bytes: u64) -> Result<TranscodeCache, std::io::Error> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let mut entries = HashMap::new();
+        for dir_entry in std::fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            if path.extension() != Some(OsStr::new("json")) {
+                continue;
+            }
+            let entry = std::fs::read(&path)
+                .ok()
+                .and_then(|v| serde_json::from_slice::<CacheEntry>(&v).ok());
+            match entry {
+                Some(entry) if dir.join(format!("{}.mp4", entry.key)).exists() => {
+                    entries.insert(entry.key.clone(), entry);
+                }
+                _ => {
+                    println!("Removing broken cache entry {}", path.display());
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        println!(
+            "Transcode cache {} has {} entries",
+            dir.display(),
+            entries.len()
+        );
+        Ok(TranscodeCache {
+            dir,
+            max_bytes,
+            entries: Mutex::new(entries),
+            writing: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Cache key of the file with encoding options
+    ///
+    /// Returns None if the file modification time can't be read.
+    pub fn key<P: AsRef<Path>>(file: P, opts: &media::EncodeOpts) -> Option<(String, u64)> {
+        let mtime = modified_secs(file.as_ref())?;
+        let mut data = file.as_ref().to_string_lossy().into_owned().into_bytes();
+        data.extend(mtime.to_le_bytes().iter());
+        data.extend(serde_json::to_vec(opts).unwrap_or_default());
+        Some((format!("{:016x}", fnv1a(&data)), mtime))
+    }
+
+    /// Cached entry, if there is one not being written at the moment
+    pub fn get<P: AsRef<Path>>(&self, file: P, opts: &media::EncodeOpts) -> Option<CacheEntry> {
+        let (key, _) = TranscodeCache::key(file, opts)?;
+        if self.writing.lock().unwrap().contains(&key) {
+            return None;
+        }
+        self.entries.lock().unwrap().get(&key).cloned()
+    }
+
+    /// Reads bytes `start..=end` of the entry and marks it as recently used
+    pub async fn read(
+        &self,
+        entry: &CacheEntry,
+        start: u64,
+        end: u64,
+    ) -> Result<impl Stream<Item = Result<Bytes, std::io::Error>>, std::io::Error> {
+        self.touch(&entry.key);
+        let mut file = File::open(self.data_path(&entry.key)).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(FramedRead::new(file.take(end + 1 - start), BytesCodec::new()).map_ok(BytesMut::freeze))
+    }
+
+    /// Starts writing a new entry
+    ///
+    /// Returns None if the entry is already being written, or if the whole
+    /// file is already in the cache.
+    pub async fn writer<P: AsRef<Path>>(
+        self: &Arc<Self>,
+        file: P,
+        opts: &media::EncodeOpts,
+    ) -> Option<CacheWriter> {
+        let (key, mtime) = TranscodeCache::key(&file, opts)?;
+        if !self.writing.lock().unwrap().insert(key.clone()) {
+            return None;
+        }
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.get(&key).is_some_and(|v| v.is_full()) {
+                self.writing.lock().unwrap().remove(&key);
+                return None;
+            }
+            entries.remove(&key);
+        }
+        match File::create(self.data_path(&key)).await {
+            Ok(data) => Some(CacheWriter {
+                cache: self.clone(),
+                data,
+                failed: false,
+                finished: false,
+                entry: CacheEntry {
+                    key,
+                    file: file.as_ref().to_string_lossy().into_owned(),
+                    mtime,
+                    encode_opts: opts.clone(),
+                    size: 0,
+                    complete: false,
+                    last_access: now_secs(),
+                    prefix_seconds: 0,
+                },
+            }),
+            Err(err) => {
+                println!("Unable to create cache file {:?}", err);
+                self.writing.lock().unwrap().remove(&key);
+                None
+            }
+        }
+    }
+
+    /// Cache entries and sizes
+    pub fn status(&self) -> CacheStatus {
+        let mut entries: Vec<CacheEntry> = self.entries.lock().unwrap().values().cloned().collect();
+        entries.sort_by_key(|v| Reverse(v.last_access));
+        CacheStatus {
+            max_bytes: self.max_bytes,
+            total_bytes: entries.iter().map(|v| v.size).sum(),
+            entries,
+        }
+    }
+
+    /// Removes the entry, returns false if there is no such entry
+    pub fn purge(&self, key: &str) -> bool {
+        match self.entries.lock().unwrap().remove(key) {
+            Some(_) => {
+                self.remove_files(key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes all entries which are not being written
+    pub fn purge_all(&self) {
+        let keys: Vec<String> = self.entries.lock().unwrap().keys().cloned().collect();
+        for key in keys {
+            self.purge(&key);
+        }
+    }
+
+    fn touch(&self, key: &str) {
+        let entry = self.entries.lock().unwrap().get_mut(key).map(|entry| {
+            entry.last_access = now_secs();
+            entry.clone()
+        });
+        if let Some(entry) = entry {
+            self.write_sidecar(&entry);
+        }
+    }
+
+    fn finish(&self, entry: CacheEntry) {
+        self.write_sidecar(&entry);
+        self.writing.lock().unwrap().remove(&entry.key);
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.key.clone(), entry);
+        self.evict();
+    }
+
+    /// Removes least recently used entries until the cache fits the limit
+    fn evict(&self) {
+        let mut entries: Vec<CacheEntry> = self.entries.lock().unwrap().values().cloned().collect();
+        entries.sort_by_key(|v| v.last_access);
+        let mut total: u64 = entries.iter().map(|v| v.size).sum();
+        for entry in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            println!("Evicting cache entry {} ({})", entry.key, entry.file);
+            if self.purge(&entry.key) {
+                total -= entry.size;
+            }
+        }
+    }
+
+    fn write_sidecar(&self, entry: &CacheEntry) {
+        let json = serde_json::to_vec_pretty(entry).unwrap();
+        if let Err(err) = std::fs::write(self.sidecar_path(&entry.key), json) {
+            println!("Unable to write cache entry {:?}", err);
+        }
+    }
+
+    fn remove_files(&self, key: &str) {
+        let _ = std::fs::remove_file(self.sidecar_path(key));
+        let _ = std::fs::remove_file(self.data_path(key));
+    }
+
+    fn data_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.mp4", key))
+    }
+
+    fn sidecar_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+/// Writes the encoding output to the cache
+///
+/// Entry is stored when `finish` is called, partially finished outputs are
+/// kept as incomplete entries. Output of a writer dropped without `finish` is
+/// discarded.
+pub struct CacheWriter {
+    cache: Arc<TranscodeCache>,
+    data: File,
+    failed: bool,
+    finished: bool,
+    entry: CacheEntry,
+}
+
+impl CacheWriter {
+    pub async fn write(&mut self, bytes: &Bytes) {
+        if self.failed {
+            return;
+        }
+        match self.data.write_all(bytes).await {
+            Ok(_) => self.entry.size += bytes.len() as u64,
+            Err(err) => {
+                println!("Unable to write to the cache {:?}", err);
+                self.failed = true;
+            }
+        }
+    }
+
+    /// Marks the entry to have only the first seconds of the file
+    pub fn prefix(&mut self, seconds: u32) {
+        self.entry.prefix_seconds = seconds;
+    }
+
+    pub async fn finish(mut self, complete: bool) {
+        let _ = self.data.flush().await;
+        if self.failed || self.entry.size == 0 {
+            return;
+        }
+        self.entry.complete = complete;
+        self.entry.last_access = now_secs();
+        self.finished = true;
+        self.cache.finish(self.entry.clone());
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.cache.remove_files(&self.entry.key);
+            self.cache.writing.lock().unwrap().remove(&self.entry.key);
+        }
+    }
+}
+
+/// Parses HTTP `Range` header to inclusive byte range
+///
+/// Only single ranges are supported, returns None if the range is not
+/// satisfiable.
+pub fn parse_range(header: &str, size: u64) -> Option<(u64, u64)> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') || size == 0 {
+        return None;
+    }
+    let mut parts = spec.splitn(2, '-');
+    let start = parts.next()?.trim();
+    let end = parts.next()?.trim();
+    let (start, end) = match (start, end) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), size - 1)
+        }
+        (start, "") => (start.parse().ok()?, size - 1),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(size - 1)),
+    };
+    if start > end || start >= size {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
+/// Range starts from the beginning of the file, live encodes can only serve
+/// those
+pub fn is_range_from_start(header: &str) -> bool {
+    matches!(parse_range(header, u64::MAX), Some((0, _)))
+}
+
+fn modified_secs(file: &Path) -> Option<u64> {
+    std::fs::metadata(file)
+        .and_then(|v| v.modified())
+        .ok()
+        .and_then(|v| v.duration_since(UNIX_EPOCH).ok())
+        .map(|v| v.as_secs())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0)
+}
+
+/// FNV-1a, the keys must stay the same between builds unlike with `DefaultHasher`
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-", 100), Some((0, 99)));
+        assert_eq!(parse_range("bytes=10-19", 100), Some((10, 19)));
+        assert_eq!(parse_range("bytes=90-200", 100), Some((90, 99)));
+        assert_eq!(parse_range("bytes=-10", 100), Some((90, 99)));
+        assert_eq!(parse_range("bytes=100-", 100), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
+    }
+
+    #[test]
+    fn test_is_range_from_start() {
+        assert!(is_range_from_start("bytes=0-"));
+        assert!(is_range_from_start("bytes=0-1023"));
+        assert!(!is_range_from_start("bytes=1024-"));
+        assert!(!is_range_from_start("bytes=-1024"));
+        assert!(!is_range_from_start("bytes=0-1,5-6"));
+    }
+
+    #[tokio::test]
+    async fn test_full_entry_not_rewritten() {
+        let dir = std::env::temp_dir().join(format!("casterson-cache-{}", std::process::id()));
+        let cache = Arc::new(TranscodeCache::open(dir.join("cache"), 1024).unwrap());
+        let file = dir.join("video.mkv");
+        std::fs::write(&file, b"video").unwrap();
+        let opts = media::EncodeOpts::default();
+
+        let mut writer = cache.writer(&file, &opts).await.unwrap();
+        assert!(cache.writer(&file, &opts).await.is_none());
+        writer.write(&Bytes::from_static(b"encoded")).await;
+        writer.finish(true).await;
+
+        assert!(cache.writer(&file, &opts).await.is_none());
+        let entry = cache.get(&file, &opts).unwrap();
+        assert!(entry.is_full());
+        assert_eq!(entry.size, 7);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_writer() {
+        let dir = std::env::temp_dir().join(format!("casterson-dropped-{}", std::process::id()));
+        let cache = Arc::new(TranscodeCache::open(dir.join("cache"), 1024).unwrap());
+        let file = dir.join("video.mkv");
+        std::fs::write(&file, b"video").unwrap();
+        let opts = media::EncodeOpts::default();
+
+        let mut writer = cache.writer(&file, &opts).await.unwrap();
+        writer.write(&Bytes::from_static(b"encoded")).await;
+        drop(writer);
+
+        assert!(cache.get(&file, &opts).is_none());
+        let writer = cache.writer(&file, &opts).await;
+        assert!(writer.is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
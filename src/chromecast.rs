@@ -12,23 +12,30 @@
 /// ```
 extern crate rust_cast;
 
+use crate::dlna;
+use crate::renderer::{Renderer, RendererError, RendererStatus, StatusCallback};
 use derive_more::From;
 use rust_cast::channels::connection::ConnectionResponse;
 use rust_cast::channels::heartbeat::HeartbeatResponse;
 use rust_cast::channels::media::MediaResponse;
-use rust_cast::channels::media::{IdleReason, Media, PlayerState, StatusEntry, StreamType};
+use rust_cast::channels::media::{Media, Metadata, StatusEntry, StreamType};
 use rust_cast::channels::receiver::CastDeviceApp;
 use rust_cast::{CastDevice, ChannelMessage};
-use serde::Serializer;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use url::Url;
 
-use serde::Serialize;
 use std::str::FromStr;
 
 const DEFAULT_DESTINATION_ID: &str = "receiver-0";
 const DEFAULT_PORT: u16 = 8009;
 
+/// Port of the HTTP setup API of the device, it has the device info
+const SETUP_PORT: u16 = 8008;
+
+/// Models supporting HDR, for the devices which don't tell it in the
+/// capabilities of the device info
+const HDR_MODELS: &[&str] = &["Chromecast Ultra", "Google TV Streamer"];
+
 pub fn get_default_media_receiver(
     ip: &IpAddr,
     port: Option<u16>,
@@ -47,18 +54,10 @@ pub enum ChromecastError {
     AppStatusNotFound,
     RustCastError(rust_cast::errors::Error),
 }
-#[derive(Serialize)]
-pub struct ChromecastStatus {
-    current_time: Option<f32>,
-    #[serde(serialize_with = "serialize_player_state")]
-    player_state: PlayerState,
-    #[serde(serialize_with = "serialize_idle_reason")]
-    idle_reason: Option<IdleReason>,
-}
 
-impl From<StatusEntry> for ChromecastStatus {
+impl From<StatusEntry> for RendererStatus {
     fn from(status: StatusEntry) -> Self {
-        ChromecastStatus {
+        RendererStatus {
             current_time: status.current_time,
             player_state: status.player_state,
             idle_reason: status.idle_reason,
@@ -66,14 +65,6 @@ impl From<StatusEntry> for ChromecastStatus {
     }
 }
 
-pub trait BaseMediaReceiver {
-    fn play(&self) -> Result<ChromecastStatus, ChromecastError>;
-    fn pause(&self) -> Result<ChromecastStatus, ChromecastError>;
-    fn stop(&self) -> Result<ChromecastStatus, ChromecastError>;
-    fn cast(&self, url: Url) -> Result<(), ChromecastError>;
-    fn get_status(&self) -> Result<ChromecastStatus, ChromecastError>;
-}
-
 #[derive(Clone)]
 pub struct MediaReceiver {
     ip: IpAddr,
@@ -81,21 +72,67 @@ pub struct MediaReceiver {
     dest_id: String,
 }
 
-impl BaseMediaReceiver for MediaReceiver {
-    fn play(&self) -> Result<ChromecastStatus, ChromecastError> {
-        manage(self, ManageCommmand::Play)
+impl Renderer for MediaReceiver {
+    fn play(&self) -> Result<RendererStatus, RendererError> {
+        Ok(manage(self, ManageCommmand::Play)?)
+    }
+    fn pause(&self) -> Result<RendererStatus, RendererError> {
+        Ok(manage(self, ManageCommmand::Pause)?)
+    }
+    fn stop(&self) -> Result<RendererStatus, RendererError> {
+        Ok(manage(self, ManageCommmand::Stop)?)
+    }
+    fn seek(&self, seconds: f32) -> Result<RendererStatus, RendererError> {
+        Ok(manage(self, ManageCommmand::Seek(seconds))?)
     }
-    fn pause(&self) -> Result<ChromecastStatus, ChromecastError> {
-        manage(self, ManageCommmand::Pause)
+    fn set_volume(&self, level: f32) -> Result<(), RendererError> {
+        Ok(set_volume(self, level)?)
     }
-    fn stop(&self) -> Result<ChromecastStatus, ChromecastError> {
-        manage(self, ManageCommmand::Stop)
+    fn cast(
+        &self,
+        url: Url,
+        content_type: Option<String>,
+        metadata: Option<Metadata>,
+        on_status: StatusCallback,
+    ) -> Result<(), RendererError> {
+        Ok(cast(self, url, content_type, metadata, on_status)?)
     }
-    fn cast(&self, url: Url) -> Result<(), ChromecastError> {
-        cast(self, url)
+    fn get_status(&self) -> Result<RendererStatus, RendererError> {
+        Ok(manage(self, ManageCommmand::Status)?)
     }
-    fn get_status(&self) -> Result<ChromecastStatus, ChromecastError> {
-        manage(self, ManageCommmand::Status)
+    fn supports_hdr(&self) -> bool {
+        device_info(&self.ip).is_some_and(|v| is_hdr_device(&v))
+    }
+}
+
+/// Device info of the setup API, None if the device doesn't answer
+fn device_info(ip: &IpAddr) -> Option<serde_json::Value> {
+    let url = format!(
+        "http://{}/setup/eureka_info?params=device_info",
+        SocketAddr::new(*ip, SETUP_PORT)
+    );
+    match dlna::http_request("GET", &url.parse().ok()?, &[], "") {
+        Ok((200, body)) => serde_json::from_str(&body).ok(),
+        Ok((status, _)) => {
+            println!("Unable to read the device info of {}: {}", ip, status);
+            None
+        }
+        Err(err) => {
+            println!("Unable to read the device info of {}: {:?}", ip, err);
+            None
+        }
+    }
+}
+
+/// Device tells it supports HDR in the capabilities, or it's a model known to
+/// support it
+fn is_hdr_device(info: &serde_json::Value) -> bool {
+    let device_info = &info["device_info"];
+    match device_info["capabilities"]["hdr_supported"].as_bool() {
+        Some(supported) => supported,
+        None => device_info["model_name"]
+            .as_str()
+            .is_some_and(|v| HDR_MODELS.contains(&v)),
     }
 }
 
@@ -103,13 +140,11 @@ enum ManageCommmand {
     Play,
     Pause,
     Stop,
+    Seek(f32),
     Status,
 }
 
-fn manage(
-    med: &MediaReceiver,
-    command: ManageCommmand,
-) -> Result<ChromecastStatus, ChromecastError> {
+fn manage(med: &MediaReceiver, command: ManageCommmand) -> Result<RendererStatus, ChromecastError> {
     let cast_device = CastDevice::connect_without_host_verification(med.ip.to_string(), med.port)?;
 
     cast_device.connection.connect(med.dest_id.as_str())?;
@@ -149,7 +184,18 @@ fn manage(
                     .map(Into::into)
                     .map_err(ChromecastError::RustCastError),
 
-                ManageCommmand::Status => Ok(ChromecastStatus::from(entry.clone())),
+                ManageCommmand::Seek(seconds) => cast_device
+                    .media
+                    .seek(
+                        app.transport_id.as_str(),
+                        entry.media_session_id,
+                        Some(seconds),
+                        None,
+                    )
+                    .map(Into::into)
+                    .map_err(ChromecastError::RustCastError),
+
+                ManageCommmand::Status => Ok(RendererStatus::from(entry.clone())),
             };
             cast_device
                 .connection
@@ -160,7 +206,22 @@ fn manage(
     }
 }
 
-fn cast(med: &MediaReceiver, url: Url) -> Result<(), ChromecastError> {
+/// Volume is of the device, not of the media being cast
+fn set_volume(med: &MediaReceiver, level: f32) -> Result<(), ChromecastError> {
+    let cast_device = CastDevice::connect_without_host_verification(med.ip.to_string(), med.port)?;
+    cast_device.connection.connect(med.dest_id.as_str())?;
+    cast_device.receiver.set_volume(level.clamp(0.0, 1.0))?;
+    cast_device.connection.disconnect(med.dest_id.as_str())?;
+    Ok(())
+}
+
+fn cast(
+    med: &MediaReceiver,
+    url: Url,
+    content_type: Option<String>,
+    metadata: Option<Metadata>,
+    mut on_status: StatusCallback,
+) -> Result<(), ChromecastError> {
     let cast_device = CastDevice::connect_without_host_verification(med.ip.to_string(), med.port)?;
 
     // Connect and ping
@@ -187,10 +248,11 @@ fn cast(med: &MediaReceiver, url: Url) -> Result<(), ChromecastError> {
         &Media {
             // http://commondatastorage.googleapis.com/gtv-videos-bucket/big_buck_bunny_1080p.mp4
             content_id: url.to_string(),
-            content_type: "".into(),
+            // Receiver guesses the type if it's empty, images need it
+            content_type: content_type.unwrap_or_default(),
             stream_type: StreamType::Live, // "buffered"
             duration: None,
-            metadata: None,
+            metadata,
         },
     )?;
 
@@ -204,6 +266,14 @@ fn cast(med: &MediaReceiver, url: Url) -> Result<(), ChromecastError> {
 
                 if let HeartbeatResponse::Ping = response {
                     cast_device.heartbeat.pong()?;
+
+                    // Status is sent only on changes, poll it for the position
+                    let status = cast_device
+                        .media
+                        .get_status(app.transport_id.as_str(), None);
+                    if let Some(entry) = status.ok().and_then(|v| v.entries.into_iter().next()) {
+                        on_status(&RendererStatus::from(entry));
+                    }
                 }
             }
             Ok(ChannelMessage::Connection(ConnectionResponse::Close)) => {
@@ -228,6 +298,7 @@ fn cast(med: &MediaReceiver, url: Url) -> Result<(), ChromecastError> {
             },
             Ok(ChannelMessage::Media(MediaResponse::Status(v))) => {
                 if let Some(entry) = v.entries.first() {
+                    on_status(&RendererStatus::from(entry.clone()));
                     if entry.player_state.to_string() == "IDLE" {
                         cast_device
                             .connection
@@ -252,22 +323,27 @@ fn cast(med: &MediaReceiver, url: Url) -> Result<(), ChromecastError> {
     Ok(())
 }
 
-fn serialize_player_state<S>(x: &PlayerState, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    s.serialize_str(&x.to_string())
-}
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
 
-fn serialize_idle_reason<S>(x: &Option<IdleReason>, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    s.serialize_str(match x {
-        Some(IdleReason::Cancelled) => "CANCELLED",
-        Some(IdleReason::Interrupted) => "INTERRUPTED",
-        Some(IdleReason::Finished) => "FINISHED",
-        Some(IdleReason::Error) => "ERROR",
-        _ => "",
-    })
+    #[test]
+    fn test_is_hdr_device() {
+        let info = |device_info| json!({ "device_info": device_info });
+        assert!(is_hdr_device(&info(json!({
+            "model_name": "Chromecast",
+            "capabilities": { "hdr_supported": true },
+        }))));
+        assert!(!is_hdr_device(&info(json!({
+            "model_name": "Chromecast Ultra",
+            "capabilities": { "hdr_supported": false },
+        }))));
+        assert!(is_hdr_device(&info(
+            json!({ "model_name": "Chromecast Ultra" })
+        )));
+        assert!(!is_hdr_device(&info(json!({ "model_name": "Chromecast" }))));
+        assert!(!is_hdr_device(&json!({})));
+    }
 }
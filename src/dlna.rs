@@ -0,0 +1,792 @@
+/// DLNA media renderers, e.g. smart TVs
+///
+/// Renderers are found with SSDP and controlled with the SOAP actions of the
+/// AVTransport and RenderingControl services listed in the device
+/// description. Like the Chromecast connections the requests are blocking,
+/// the description is fetched again for each command.
+use crate::renderer::{Renderer, RendererError, RendererStatus, StatusCallback};
+use derive_more::From;
+use rust_cast::channels::media::{IdleReason, Metadata, PlayerState};
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use url::Url;
+
+pub const SSDP_ADDR: &str = "239.255.255.250:1900";
+const MEDIA_RENDERER: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+const AV_TRANSPORT: &str = "urn:schemas-upnp-org:service:AVTransport:";
+const RENDERING_CONTROL: &str = "urn:schemas-upnp-org:service:RenderingControl:";
+const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:";
+
+/// Timeout of the HTTP requests to the renderer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval of polling the transport state during casting
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Casting ends if the playback doesn't start in time
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Failed polls in row before casting ends
+const MAX_POLL_ERRORS: u32 = 5;
+
+/// Stopping this close to the end is finishing the media
+pub const FINISHED_SECONDS: f32 = 10.0;
+
+#[derive(Debug, From)]
+pub enum DlnaError {
+    DeviceNotFound,
+    #[from(ignore)]
+    ServiceNotFound(&'static str),
+    #[from(ignore)]
+    HttpError(u16),
+    #[from(ignore)]
+    SoapFault(String),
+    #[from(ignore)]
+    InvalidResponse(String),
+    XmlError(roxmltree::Error),
+    IoError(std::io::Error),
+}
+
+/// Renderer found with SSDP
+#[derive(Serialize, Clone, Debug)]
+pub struct DlnaDevice {
+    pub ip: IpAddr,
+    pub name: String,
+    pub location: Url,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Service {
+    service_type: String,
+    control_url: Url,
+}
+
+/// Parts of the device description used
+#[derive(Clone, PartialEq, Debug)]
+struct Description {
+    name: String,
+    av_transport: Option<Service>,
+    rendering_control: Option<Service>,
+    connection_manager: Option<Service>,
+}
+
+impl Description {
+    fn av_transport(&self) -> Result<&Service, DlnaError> {
+        self.av_transport
+            .as_ref()
+            .ok_or(DlnaError::ServiceNotFound("AVTransport"))
+    }
+
+    fn rendering_control(&self) -> Result<&Service, DlnaError> {
+        self.rendering_control
+            .as_ref()
+            .ok_or(DlnaError::ServiceNotFound("RenderingControl"))
+    }
+
+    fn connection_manager(&self) -> Result<&Service, DlnaError> {
+        self.connection_manager
+            .as_ref()
+            .ok_or(DlnaError::ServiceNotFound("ConnectionManager"))
+    }
+}
+
+/// State of the AVTransport
+struct Transport {
+    state: String,
+    uri: String,
+    position: Option<f32>,
+    duration: Option<f32>,
+}
+
+impl Transport {
+    fn status(&self) -> RendererStatus {
+        RendererStatus {
+            current_time: self.position,
+            player_state: match self.state.as_str() {
+                "PLAYING" => PlayerState::Playing,
+                "PAUSED_PLAYBACK" | "PAUSED_RECORDING" => PlayerState::Paused,
+                "TRANSITIONING" => PlayerState::Buffering,
+                _ => PlayerState::Idle,
+            },
+            idle_reason: None,
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        ["PLAYING", "PAUSED_PLAYBACK", "PAUSED_RECORDING"].contains(&self.state.as_str())
+    }
+
+    fn is_stopped(&self) -> bool {
+        ["STOPPED", "NO_MEDIA_PRESENT"].contains(&self.state.as_str())
+    }
+
+    /// Stopped near the end
+    ///
+    /// Streams of unknown length, e.g. transcodes, are never finished here.
+    /// The caller knowing the length of the media tells them apart.
+    fn is_finished(&self) -> bool {
+        match (self.position, self.duration) {
+            (Some(position), Some(duration)) => position >= duration - FINISHED_SECONDS,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DlnaRenderer {
+    location: Url,
+}
+
+/// Locations of the renderers found by the searches, so each request doesn't
+/// search again
+static LOCATIONS: Mutex<Vec<(IpAddr, Url)>> = Mutex::new(Vec::new());
+
+/// Renderer with the description at the location, the location is searched
+/// with SSDP by the IP if not given or found earlier
+///
+/// Blocks for the search, run it outside the async tasks.
+pub fn get_renderer(ip: &IpAddr, location: Option<Url>) -> Result<DlnaRenderer, DlnaError> {
+    let location = match location.or_else(|| known_location(ip)) {
+        Some(location) => location,
+        None => discover(Duration::from_secs(2))?
+            .into_iter()
+            .find(|v| v.ip == *ip)
+            .map(|v| v.location)
+            .ok_or(DlnaError::DeviceNotFound)?,
+    };
+    Ok(DlnaRenderer { location })
+}
+
+fn known_location(ip: &IpAddr) -> Option<Url> {
+    LOCATIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|v| v.0 == *ip)
+        .map(|v| v.1.clone())
+}
+
+impl Renderer for DlnaRenderer {
+    fn play(&self) -> Result<RendererStatus, RendererError> {
+        let description = self.description()?;
+        let service = description.av_transport()?;
+        soap(service, "Play", &[("InstanceID", "0"), ("Speed", "1")])?;
+        Ok(transport(service)?.status())
+    }
+    fn pause(&self) -> Result<RendererStatus, RendererError> {
+        let description = self.description()?;
+        let service = description.av_transport()?;
+        soap(service, "Pause", &[("InstanceID", "0")])?;
+        Ok(transport(service)?.status())
+    }
+    fn stop(&self) -> Result<RendererStatus, RendererError> {
+        let description = self.description()?;
+        let service = description.av_transport()?;
+        soap(service, "Stop", &[("InstanceID", "0")])?;
+        Ok(transport(service)?.status())
+    }
+    fn seek(&self, seconds: f32) -> Result<RendererStatus, RendererError> {
+        let description = self.description()?;
+        let service = description.av_transport()?;
+        let target = format_time(seconds);
+        soap(
+            service,
+            "Seek",
+            &[
+                ("InstanceID", "0"),
+                ("Unit", "REL_TIME"),
+                ("Target", &target),
+            ],
+        )?;
+        Ok(transport(service)?.status())
+    }
+    fn set_volume(&self, level: f32) -> Result<(), RendererError> {
+        let description = self.description()?;
+        let volume = ((level.clamp(0.0, 1.0) * 100.0).round() as u32).to_string();
+        soap(
+            description.rendering_control()?,
+            "SetVolume",
+            &[
+                ("InstanceID", "0"),
+                ("Channel", "Master"),
+                ("DesiredVolume", &volume),
+            ],
+        )?;
+        Ok(())
+    }
+    fn cast(
+        &self,
+        url: Url,
+        content_type: Option<String>,
+        metadata: Option<Metadata>,
+        on_status: StatusCallback,
+    ) -> Result<(), RendererError> {
+        Ok(cast(self, url, content_type, metadata, on_status)?)
+    }
+    fn get_status(&self) -> Result<RendererStatus, RendererError> {
+        Ok(transport(self.description()?.av_transport()?)?.status())
+    }
+    fn supports_hdr(&self) -> bool {
+        match self.sink_protocol_info() {
+            Ok(sink) => supports_hevc(&sink),
+            Err(err) => {
+                println!("Unable to read the protocol info {:?}", err);
+                false
+            }
+        }
+    }
+}
+
+impl DlnaRenderer {
+    fn description(&self) -> Result<Description, DlnaError> {
+        description(&self.location)
+    }
+
+    /// Formats the renderer can play, comma separated protocol infos
+    fn sink_protocol_info(&self) -> Result<String, DlnaError> {
+        let description = self.description()?;
+        let response = soap(description.connection_manager()?, "GetProtocolInfo", &[])?;
+        Ok(response_value(&response, "Sink")?.unwrap_or_default())
+    }
+}
+
+/// Renderer plays HEVC, HDR video is passed through as 10-bit HEVC
+///
+/// The renderers don't tell the HDR support of their displays, the ones
+/// listing HEVC are taken to have it.
+fn supports_hevc(sink: &str) -> bool {
+    sink.split(',').any(|info| {
+        let info = info.trim().to_ascii_lowercase();
+        let mut fields = info.splitn(4, ':').skip(2);
+        let content_type = fields.next().unwrap_or_default();
+        let extra = fields.next().unwrap_or_default();
+        ["video/hevc", "video/h265", "video/x-h265"].contains(&content_type)
+            || extra.contains("hevc")
+    })
+}
+
+fn cast(
+    renderer: &DlnaRenderer,
+    url: Url,
+    content_type: Option<String>,
+    metadata: Option<Metadata>,
+    mut on_status: StatusCallback,
+) -> Result<(), DlnaError> {
+    let description = renderer.description()?;
+    let service = description.av_transport()?;
+    println!("[DLNA] Casting to {}", description.name);
+
+    // Renderers don't guess the type, the streams of this server are MP4
+    let content_type = content_type.unwrap_or_else(|| {
+        if url.path().ends_with("/media_audio") {
+            "audio/mpeg".into()
+        } else {
+            "video/mp4".into()
+        }
+    });
+    let didl = didl_lite(&url, &content_type, metadata.as_ref());
+
+    // Some renderers refuse a new URI while playing
+    let _ = soap(service, "Stop", &[("InstanceID", "0")]);
+    soap(
+        service,
+        "SetAVTransportURI",
+        &[
+            ("InstanceID", "0"),
+            ("CurrentURI", url.as_str()),
+            ("CurrentURIMetaData", &didl),
+        ],
+    )?;
+    soap(service, "Play", &[("InstanceID", "0"), ("Speed", "1")])?;
+
+    // Renderers don't send events without a subscription, poll until the
+    // playback stops or other media is cast
+    let started_at = Instant::now();
+    let mut started = false;
+    let mut errors = 0;
+    let mut last: Option<Transport> = None;
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        let current = match transport(service) {
+            Ok(current) => current,
+            Err(err) => {
+                println!("[DLNA] Unable to get the transport state {:?}", err);
+                errors += 1;
+                if errors >= MAX_POLL_ERRORS {
+                    return Err(err);
+                }
+                continue;
+            }
+        };
+        errors = 0;
+        if started && !current.uri.is_empty() && current.uri != url.as_str() {
+            println!("[DLNA] Other media was cast");
+            on_status(&idle_status(IdleReason::Interrupted));
+            break;
+        }
+        if started && current.is_stopped() {
+            let reason = match &last {
+                Some(last) if last.is_finished() => IdleReason::Finished,
+                _ => IdleReason::Cancelled,
+            };
+            println!("[DLNA] Stopped {:?}", reason);
+            on_status(&idle_status(reason));
+            break;
+        }
+        if !started && started_at.elapsed() > START_TIMEOUT {
+            println!("[DLNA] Playback didn't start");
+            on_status(&idle_status(IdleReason::Error));
+            break;
+        }
+        started = started || current.is_started();
+        on_status(&current.status());
+        last = Some(current);
+    }
+    println!("Close thread!");
+    Ok(())
+}
+
+fn idle_status(reason: IdleReason) -> RendererStatus {
+    RendererStatus {
+        current_time: None,
+        player_state: PlayerState::Idle,
+        idle_reason: Some(reason),
+    }
+}
+
+/// Transport state and the position of the media
+fn transport(service: &Service) -> Result<Transport, DlnaError> {
+    let info = soap(service, "GetTransportInfo", &[("InstanceID", "0")])?;
+    let position = soap(service, "GetPositionInfo", &[("InstanceID", "0")])?;
+    Ok(Transport {
+        state: response_value(&info, "CurrentTransportState")?.unwrap_or_default(),
+        uri: response_value(&position, "TrackURI")?.unwrap_or_default(),
+        position: response_value(&position, "RelTime")?.and_then(|v| parse_time(&v)),
+        duration: response_value(&position, "TrackDuration")?
+            .and_then(|v| parse_time(&v))
+            .filter(|v| *v > 0.0),
+    })
+}
+
+/// Searches the renderers with SSDP for the given time
+pub fn discover(timeout: Duration) -> Result<Vec<DlnaDevice>, DlnaError> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: {}\r\n\
+         ST: {}\r\n\r\n",
+        SSDP_ADDR,
+        timeout.as_secs().max(1),
+        MEDIA_RENDERER
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDR)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut found: Vec<(IpAddr, Url)> = vec![];
+    let mut buf = [0u8; 4096];
+    while Instant::now() < deadline {
+        match socket.recv_from(&mut buf) {
+            Ok((len, addr)) => {
+                let response = String::from_utf8_lossy(&buf[..len]);
+                if let Some(location) = header(&response, "location").and_then(|v| v.parse().ok()) {
+                    if !found.iter().any(|v| v.1 == location) {
+                        found.push((addr.ip(), location));
+                    }
+                }
+            }
+            Err(err)
+                if err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let mut locations = LOCATIONS.lock().unwrap();
+    for (ip, location) in &found {
+        locations.retain(|v| v.0 != *ip);
+        locations.push((*ip, location.clone()));
+    }
+    drop(locations);
+    Ok(found
+        .into_iter()
+        .filter_map(|(ip, location)| match description(&location) {
+            Ok(description) => Some(DlnaDevice {
+                ip,
+                name: description.name,
+                location,
+            }),
+            Err(err) => {
+                println!("Unable to read the description {} {:?}", location, err);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Value of the header of the SSDP message or the HTTP response
+pub fn header<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message
+        .lines()
+        .take_while(|v| !v.is_empty())
+        .filter_map(|line| {
+            let i = line.find(':')?;
+            Some((&line[..i], line[i + 1..].trim()))
+        })
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|v| v.1)
+}
+
+fn description(location: &Url) -> Result<Description, DlnaError> {
+    let (status, body) = http_request("GET", location, &[], "")?;
+    if status != 200 {
+        return Err(DlnaError::HttpError(status));
+    }
+    parse_description(&body, location)
+}
+
+/// Parses the device description, the control URLs are relative to the
+/// URLBase or the location
+fn parse_description(xml: &str, location: &Url) -> Result<Description, DlnaError> {
+    let doc = roxmltree::Document::parse(xml)?;
+    let find = |node: roxmltree::Node, name: &str| -> Option<String> {
+        node.descendants()
+            .find(|v| v.tag_name().name() == name)
+            .and_then(|v| v.text())
+            .map(|v| v.trim().to_string())
+    };
+    let base = find(doc.root(), "URLBase")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| location.clone());
+    let services: Vec<Service> = doc
+        .descendants()
+        .filter(|v| v.tag_name().name() == "service")
+        .filter_map(|v| {
+            Some(Service {
+                service_type: find(v, "serviceType")?,
+                control_url: base.join(&find(v, "controlURL")?).ok()?,
+            })
+        })
+        .collect();
+    let service = |prefix: &str| {
+        services
+            .iter()
+            .find(|v| v.service_type.starts_with(prefix))
+            .cloned()
+    };
+    Ok(Description {
+        name: find(doc.root(), "friendlyName").unwrap_or_default(),
+        av_transport: service(AV_TRANSPORT),
+        rendering_control: service(RENDERING_CONTROL),
+        connection_manager: service(CONNECTION_MANAGER),
+    })
+}
+
+/// Calls the action of the service, returns the response envelope
+fn soap(service: &Service, action: &str, args: &[(&str, &str)]) -> Result<String, DlnaError> {
+    let body = soap_envelope(&service.service_type, action, args);
+    let soap_action = format!("\"{}#{}\"", service.service_type, action);
+    let (status, response) = http_request(
+        "POST",
+        &service.control_url,
+        &[
+            ("Content-Type", "text/xml; charset=\"utf-8\""),
+            ("SOAPAction", &soap_action),
+        ],
+        &body,
+    )?;
+    match status {
+        200 => Ok(response),
+        _ => match response_value(&response, "errorDescription") {
+            Ok(Some(fault)) => Err(DlnaError::SoapFault(format!("{}: {}", action, fault))),
+            _ => Err(DlnaError::HttpError(status)),
+        },
+    }
+}
+
+/// SOAP envelope of the action or the action response, the values are escaped
+pub fn soap_envelope(service_type: &str, action: &str, args: &[(&str, &str)]) -> String {
+    let args: String = args
+        .iter()
+        .map(|(key, value)| format!("<{0}>{1}</{0}>", key, xml_escape(value)))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+        action, service_type, args
+    )
+}
+
+/// Text of the element in the SOAP response
+fn response_value(xml: &str, name: &str) -> Result<Option<String>, DlnaError> {
+    let doc = roxmltree::Document::parse(xml)?;
+    Ok(doc
+        .descendants()
+        .find(|v| v.tag_name().name() == name)
+        .map(|v| v.text().unwrap_or("").trim().to_string()))
+}
+
+/// Sends the HTTP/1.1 request and reads the whole response
+///
+/// Returns the status and the body.
+pub fn http_request(
+    method: &str,
+    url: &Url,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(u16, String), DlnaError> {
+    let addr = url
+        .socket_addrs(|| None)?
+        .into_iter()
+        .next()
+        .ok_or(DlnaError::DeviceNotFound)?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        &url[url::Position::BeforeHost..url::Position::AfterPort],
+        body.len()
+    );
+    for (key, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+    parse_http_response(&response)
+}
+
+fn parse_http_response(response: &[u8]) -> Result<(u16, String), DlnaError> {
+    let split = response
+        .windows(4)
+        .position(|v| v == b"\r\n\r\n")
+        .ok_or_else(|| DlnaError::InvalidResponse("Headers don't end".into()))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head
+        .lines()
+        .next()
+        .and_then(|v| v.split_whitespace().nth(1))
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| DlnaError::InvalidResponse(head.to_string()))?;
+    let body = &response[split + 4..];
+    let chunked =
+        header(&head, "transfer-encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+    let body = if chunked {
+        decode_chunked(body)
+    } else {
+        body.to_vec()
+    };
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn decode_chunked(mut data: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+    while let Some(i) = data.windows(2).position(|v| v == b"\r\n") {
+        let size = String::from_utf8_lossy(&data[..i]);
+        let size = size.split(';').next().unwrap_or("").trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) if size > 0 => size,
+            _ => break,
+        };
+        let chunk = &data[i + 2..];
+        body.extend_from_slice(&chunk[..size.min(chunk.len())]);
+        data = chunk.get(size + 2..).unwrap_or(&[]);
+    }
+    body
+}
+
+/// DIDL-Lite metadata of the media, renderers show the title and need the
+/// protocol info to play it
+pub fn didl_lite(url: &Url, content_type: &str, metadata: Option<&Metadata>) -> String {
+    let class = match content_type.split('/').next() {
+        Some("audio") => "object.item.audioItem.musicTrack",
+        Some("image") => "object.item.imageItem.photo",
+        _ => "object.item.videoItem",
+    };
+    let (title, creator, album, art) = match metadata {
+        Some(Metadata::Movie(v)) => (v.title.clone(), None, None, v.images.first()),
+        Some(Metadata::TvShow(v)) => (
+            v.episode_title.clone().or_else(|| v.series_title.clone()),
+            None,
+            None,
+            v.images.first(),
+        ),
+        Some(Metadata::MusicTrack(v)) => (
+            v.title.clone(),
+            v.artist.clone(),
+            v.album_name.clone(),
+            v.images.first(),
+        ),
+        Some(Metadata::Photo(v)) => (v.title.clone(), v.artist.clone(), None, None),
+        Some(Metadata::Generic(v)) => (v.title.clone(), None, None, v.images.first()),
+        None => (None, None, None, None),
+    };
+    let mut item = format!(
+        "<dc:title>{}</dc:title><upnp:class>{}</upnp:class>",
+        xml_escape(&title.unwrap_or_else(|| "Casterson".into())),
+        class
+    );
+    if let Some(creator) = creator {
+        item.push_str(&format!(
+            "<dc:creator>{}</dc:creator>",
+            xml_escape(&creator)
+        ));
+    }
+    if let Some(album) = album {
+        item.push_str(&format!("<upnp:album>{}</upnp:album>", xml_escape(&album)));
+    }
+    if let Some(art) = art {
+        item.push_str(&format!(
+            "<upnp:albumArtURI>{}</upnp:albumArtURI>",
+            xml_escape(&art.url)
+        ));
+    }
+    item.push_str(&format!(
+        "<res protocolInfo=\"http-get:*:{}:*\">{}</res>",
+        xml_escape(content_type),
+        xml_escape(url.as_str())
+    ));
+    format!(
+        "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+         xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+         xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">\
+         <item id=\"0\" parentID=\"-1\" restricted=\"1\">{}</item></DIDL-Lite>",
+        item
+    )
+}
+
+pub fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Parses the H:MM:SS or H:MM:SS.F time, None for NOT_IMPLEMENTED and such
+pub fn parse_time(time: &str) -> Option<f32> {
+    let parts: Vec<&str> = time.trim().split(':').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let hours: f32 = parts[0].trim_start_matches('+').parse().ok()?;
+    let minutes: f32 = parts[1].parse().ok()?;
+    let seconds: f32 = parts[2].parse().ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+/// Formats the time as H:MM:SS
+pub fn format_time(seconds: f32) -> String {
+    let seconds = seconds.max(0.0) as u32;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time() {
+        assert_eq!(parse_time("1:02:03"), Some(3723.0));
+        assert_eq!(parse_time("0:00:01.500"), Some(1.5));
+        assert_eq!(parse_time("NOT_IMPLEMENTED"), None);
+        assert_eq!(format_time(3723.9), "1:02:03");
+        assert_eq!(format_time(59.0), "0:00:59");
+    }
+
+    #[test]
+    fn test_header() {
+        let response = "HTTP/1.1 200 OK\r\n\
+                        CACHE-CONTROL: max-age=1800\r\n\
+                        Location: http://192.168.1.20:9197/dmr\r\n\
+                        ST: urn:schemas-upnp-org:device:MediaRenderer:1\r\n\r\n";
+        assert_eq!(
+            header(response, "LOCATION"),
+            Some("http://192.168.1.20:9197/dmr")
+        );
+        assert_eq!(header(response, "usn"), None);
+    }
+
+    #[test]
+    fn test_parse_http_response() {
+        let response = b"HTTP/1.1 200 OK\r\n\
+                         Transfer-Encoding: chunked\r\n\r\n\
+                         5\r\nHello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n";
+        let (status, body) = parse_http_response(response).unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, "Hello, world");
+    }
+
+    #[test]
+    fn test_parse_description() {
+        let xml = r#"<?xml version="1.0"?>
+            <root xmlns="urn:schemas-upnp-org:device-1-0">
+              <device>
+                <deviceType>urn:schemas-upnp-org:device:MediaRenderer:1</deviceType>
+                <friendlyName>Living room TV</friendlyName>
+                <serviceList>
+                  <service>
+                    <serviceType>urn:schemas-upnp-org:service:RenderingControl:1</serviceType>
+                    <controlURL>/upnp/control/RenderingControl1</controlURL>
+                  </service>
+                  <service>
+                    <serviceType>urn:schemas-upnp-org:service:AVTransport:1</serviceType>
+                    <controlURL>upnp/control/AVTransport1</controlURL>
+                  </service>
+                </serviceList>
+              </device>
+            </root>"#;
+        let location: Url = "http://192.168.1.20:9197/dmr/desc.xml".parse().unwrap();
+        let description = parse_description(xml, &location).unwrap();
+        assert_eq!(description.name, "Living room TV");
+        assert_eq!(
+            description.av_transport().unwrap().control_url.as_str(),
+            "http://192.168.1.20:9197/dmr/upnp/control/AVTransport1"
+        );
+        assert_eq!(
+            description
+                .rendering_control()
+                .unwrap()
+                .control_url
+                .as_str(),
+            "http://192.168.1.20:9197/upnp/control/RenderingControl1"
+        );
+        assert!(description.connection_manager().is_err());
+    }
+
+    #[test]
+    fn test_supports_hevc() {
+        let sink = "http-get:*:video/mp4:DLNA.ORG_PN=AVC_MP4_MP_HD_1080i_AAC,\
+                    http-get:*:video/mp4:DLNA.ORG_PN=HEVC_MP4_MP_L153_AAC,\
+                    http-get:*:audio/mpeg:*";
+        assert!(supports_hevc(sink));
+        assert!(supports_hevc("http-get:*:video/hevc:*"));
+        assert!(!supports_hevc(
+            "http-get:*:video/mp4:*,http-get:*:audio/mpeg:*"
+        ));
+        assert!(!supports_hevc(""));
+    }
+}
@@ -0,0 +1,199 @@
+/// Watch history
+///
+/// Last playback position of each media item per device, recorded from the
+/// cast sessions. Items played past the watched threshold are marked watched,
+/// and resuming them starts from the beginning. Watching the item again
+/// clears the mark until the threshold is passed again.
+use crate::library::MediaId;
+use crate::sessions::SessionToken;
+use crate::store;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Playback positions are saved at most this often, the watched marks
+/// immediately
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Playback past this many seconds in a new session is watching the item again
+const REWATCH_SECONDS: f32 = 60.0;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub id: MediaId,
+
+    // Device the item was cast to
+    pub device: String,
+
+    // Seconds from the beginning of the file
+    pub position: f32,
+    pub duration: Option<f32>,
+    pub watched: bool,
+    pub updated: u64,
+
+    // Session which has played the item past the beginning
+    #[serde(skip)]
+    session: Option<SessionToken>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct History {
+    #[serde(skip)]
+    file: Option<PathBuf>,
+    entries: Vec<HistoryEntry>,
+
+    #[serde(skip)]
+    saved: Option<Instant>,
+}
+
+impl History {
+    /// Loads the history from the file, or creates an empty one
+    pub fn load<P: AsRef<Path>>(file: P) -> History {
+        let mut history: History = store::load_json(&file).unwrap_or_default();
+        history.file = Some(file.as_ref().to_path_buf());
+        history
+    }
+
+    /// Records the playback position of the session
+    ///
+    /// The item is marked watched if the position is past the threshold
+    /// percentage of the duration. Watched mark is cleared when a new session
+    /// plays the item past the beginning.
+    pub fn update(
+        &mut self,
+        id: &str,
+        device: &str,
+        session: SessionToken,
+        position: f32,
+        duration: Option<f32>,
+        watched_percent: u32,
+    ) {
+        let watched = duration.is_some_and(|duration| {
+            duration > 0.0 && position >= duration * watched_percent as f32 / 100.0
+        });
+        let entry = self.entry_mut(id, device);
+        if entry.session != Some(session) && position >= REWATCH_SECONDS {
+            entry.session = Some(session);
+            entry.watched = false;
+        }
+        let changed = watched && !entry.watched;
+        entry.position = position;
+        entry.duration = duration.or(entry.duration);
+        entry.watched |= watched;
+        entry.updated = now_secs();
+        if changed || self.saved.is_none_or(|v| v.elapsed() >= SAVE_INTERVAL) {
+            self.save();
+        }
+    }
+
+    /// Marks the item watched on the device, e.g. when the playback finished
+    pub fn set_watched(&mut self, id: &str, device: &str) {
+        let entry = self.entry_mut(id, device);
+        entry.watched = true;
+        entry.updated = now_secs();
+        self.save();
+    }
+
+    /// Position to resume the item from
+    ///
+    /// Position of the device is preferred, otherwise the latest of any device
+    /// is used. Watched items start from the beginning.
+    pub fn resume_position(&self, id: &str, device: &str) -> f32 {
+        let entry = self
+            .entries
+            .iter()
+            .filter(|v| v.id == id)
+            .max_by_key(|v| (v.device == device, v.updated));
+        match entry {
+            Some(entry) if !entry.watched => entry.position,
+            _ => 0.0,
+        }
+    }
+
+    /// Entries ordered by the most recently updated first
+    pub fn entries(&self) -> Vec<&HistoryEntry> {
+        let mut entries: Vec<&HistoryEntry> = self.entries.iter().collect();
+        entries.sort_by_key(|v| Reverse(v.updated));
+        entries
+    }
+
+    fn entry_mut(&mut self, id: &str, device: &str) -> &mut HistoryEntry {
+        match self
+            .entries
+            .iter()
+            .position(|v| v.id == id && v.device == device)
+        {
+            Some(i) => &mut self.entries[i],
+            None => {
+                self.entries.push(HistoryEntry {
+                    id: id.into(),
+                    device: device.into(),
+                    position: 0.0,
+                    duration: None,
+                    watched: false,
+                    updated: 0,
+                    session: None,
+                });
+                self.entries.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Saves the history, also the positions not saved yet
+    pub fn save(&mut self) {
+        self.saved = Some(Instant::now());
+        if let Some(file) = &self.file {
+            if let Err(err) = store::save_json(file, self) {
+                println!("Unable to save history {}: {}", file.display(), err);
+            }
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_position() {
+        let mut history = History::default();
+        history.update("a", "tv", 1, 120.0, Some(1000.0), 90);
+        history.update("a", "kitchen", 2, 300.0, Some(1000.0), 90);
+        assert_eq!(history.resume_position("a", "tv"), 120.0);
+        assert_eq!(history.resume_position("a", "bedroom"), 300.0);
+        assert_eq!(history.resume_position("b", "tv"), 0.0);
+
+        history.update("a", "tv", 1, 950.0, Some(1000.0), 90);
+        assert!(history
+            .entries()
+            .iter()
+            .any(|v| v.device == "tv" && v.watched));
+        assert_eq!(history.resume_position("a", "tv"), 0.0);
+    }
+
+    #[test]
+    fn test_rewatch() {
+        let watched = |history: &History| history.entries()[0].watched;
+        let mut history = History::default();
+        history.update("a", "tv", 1, 100.0, Some(1000.0), 90);
+        history.update("a", "tv", 1, 950.0, Some(1000.0), 90);
+        assert!(watched(&history));
+
+        // Beginning of a new session keeps the mark, playing further clears it
+        history.update("a", "tv", 2, 10.0, Some(1000.0), 90);
+        assert!(watched(&history));
+        history.update("a", "tv", 2, 70.0, Some(1000.0), 90);
+        assert!(!watched(&history));
+        history.update("a", "tv", 2, 960.0, Some(1000.0), 90);
+        assert!(watched(&history));
+    }
+}
@@ -0,0 +1,656 @@
+/// Transcoding job manager
+///
+/// Owns every ffmpeg child started by `media_show`, `media_audio`,
+/// `media_photo` and the prefetcher. Identical concurrent requests share one encode, and the child is killed as
+/// soon as the last client stops reading the stream. Output is also written
+/// to the transcode cache if it's enabled.
+use crate::cache::{CacheWriter, TranscodeCache};
+use crate::media;
+use bytes::Bytes;
+use derive_more::From;
+use futures::channel::{mpsc, oneshot};
+use futures::{Future, FutureExt, SinkExt, StreamExt};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub type JobId = u64;
+
+type Chunk = Result<Bytes, std::io::Error>;
+
+/// Late subscribers get the beginning of the stream replayed from memory, as
+/// long as the encode hasn't produced more than this.
+const HEAD_BUFFER_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Buffered chunks per subscriber
+const SUBSCRIBER_BUFFER: usize = 32;
+
+/// A subscriber of a shared job that doesn't take a chunk within this is
+/// dropped, so a client that stopped reading can't stall the others
+#[cfg(not(test))]
+const SUBSCRIBER_TIMEOUT: Duration = Duration::from_secs(30);
+#[cfg(test)]
+const SUBSCRIBER_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, From)]
+pub enum JobError {
+    TooManyJobs,
+    NotFound,
+    IoError(std::io::Error),
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct JobStatus {
+    id: JobId,
+    kind: &'static str,
+    file: String,
+
+    // None for the jobs which aren't video encodes
+    encode_opts: Option<media::EncodeOpts>,
+    started: u64,
+    subscribers: usize,
+    bytes: u64,
+}
+
+struct Job {
+    id: JobId,
+    key: String,
+    kind: &'static str,
+    input: media::MediaInput,
+    opts: Option<media::EncodeOpts>,
+    started: u64,
+    subscribers: AtomicUsize,
+    bytes: AtomicU64,
+    shareable: AtomicBool,
+    subscribe: mpsc::UnboundedSender<mpsc::Sender<Chunk>>,
+    kill: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl Job {
+    fn status(&self) -> JobStatus {
+        JobStatus {
+            id: self.id,
+            kind: self.kind,
+            file: self.input.to_string(),
+            encode_opts: self.opts.clone(),
+            started: self.started,
+            subscribers: self.subscribers.load(Ordering::SeqCst),
+            bytes: self.bytes.load(Ordering::SeqCst),
+        }
+    }
+}
+
+pub struct JobManager {
+    max_jobs: usize,
+    cache: Option<Arc<TranscodeCache>>,
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<JobId, Arc<Job>>>,
+
+    // Jobs being started, counted against max_jobs before ffmpeg is spawned
+    starting: AtomicUsize,
+}
+
+/// Slot of a job being started, released when dropped
+struct Reservation<'a> {
+    starting: &'a AtomicUsize,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.starting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl std::fmt::Debug for JobManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JobManager")
+            .field("max_jobs", &self.max_jobs)
+            .finish()
+    }
+}
+
+impl JobManager {
+    pub fn new(max_jobs: usize, cache: Option<Arc<TranscodeCache>>) -> JobManager {
+        JobManager {
+            max_jobs,
+            cache,
+            next_id: AtomicU64::new(1),
+            jobs: Mutex::new(HashMap::new()),
+            starting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserves a slot for a new job, concurrent requests can't all pass the
+    /// limit while the encodes are being spawned
+    fn reserve(&self) -> Result<Reservation<'_>, JobError> {
+        let jobs = self.jobs.lock().unwrap();
+        if jobs.len() + self.starting.load(Ordering::SeqCst) >= self.max_jobs {
+            return Err(JobError::TooManyJobs);
+        }
+        self.starting.fetch_add(1, Ordering::SeqCst);
+        Ok(Reservation {
+            starting: &self.starting,
+        })
+    }
+
+    /// List running jobs
+    pub fn list(&self) -> Vec<JobStatus> {
+        let mut list: Vec<JobStatus> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|job| job.status())
+            .collect();
+        list.sort_by_key(|v| v.id);
+        list
+    }
+
+    /// Kill the job, all clients of the job get their stream closed
+    pub fn kill(&self, id: JobId) -> Result<(), JobError> {
+        let job = self.jobs.lock().unwrap().remove(&id);
+        match job {
+            Some(job) => {
+                if let Some(kill) = job.kill.lock().unwrap().take() {
+                    let _ = kill.send(());
+                }
+                Ok(())
+            }
+            None => Err(JobError::NotFound),
+        }
+    }
+
+    /// Stream of the encoded file or network stream
+    ///
+    /// Joins a running job with the same input and options if it's still
+    /// shareable, otherwise starts a new ffmpeg. Only the local files are
+    /// cached. The input is probed unless the `video` information is given.
+    pub async fn stream<I: Into<media::MediaInput>>(
+        self: &Arc<Self>,
+        input: I,
+        opts: &media::EncodeOpts,
+        video: Option<media::VideoInfo>,
+    ) -> Result<mpsc::Receiver<Chunk>, JobError> {
+        let input = input.into();
+        let key = job_key(&input, opts);
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+
+        if let Some(job) = self.find_shareable(&key) {
+            println!("Sharing encoding job {}", job.id);
+            if job.subscribe.unbounded_send(tx.clone()).is_ok() {
+                return Ok(rx);
+            }
+        }
+
+        let reservation = self.reserve()?;
+        let mut child = media::encode(input.clone(), opts, video).await?;
+        let stdout = media::stdout_stream(&mut child)?;
+        let writer = match (&self.cache, input.file()) {
+            (Some(cache), Some(file)) => cache.writer(file, opts).await,
+            _ => None,
+        };
+        let (job, subscribe_rx, kill_rx) = self.insert(key, "video", input, Some(opts), true);
+        drop(reservation);
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            pump(&job, child, stdout, writer, tx, subscribe_rx, kill_rx).await;
+            manager.jobs.lock().unwrap().remove(&job.id);
+            println!("Encoding job {} ended", job.id);
+        });
+        Ok(rx)
+    }
+
+    /// Encodes the first `seconds` of the file to the cache with a niced
+    /// ffmpeg
+    ///
+    /// Runs as a job that isn't shared with the clients, so it counts against
+    /// `max_jobs` and can be killed. Does nothing if the cache is not enabled
+    /// or the file is being written to it already.
+    pub async fn prefetch(
+        &self,
+        file: &Path,
+        opts: &media::EncodeOpts,
+        video: Option<media::VideoInfo>,
+        seconds: u32,
+    ) -> Result<(), JobError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        let reservation = self.reserve()?;
+        let mut child = media::encode_prefix(file, opts, video, seconds).await?;
+        let stdout = media::stdout_stream(&mut child)?;
+        let mut writer = match cache.writer(file, opts).await {
+            Some(writer) => writer,
+            None => {
+                let _ = child.kill();
+                let _ = child.await;
+                return Ok(());
+            }
+        };
+        println!("Prefetching {}", file.display());
+        let input = media::MediaInput::File(file.to_path_buf());
+        let key = format!("prefetch|{}", job_key(&input, opts));
+        let (job, _, kill_rx) = self.insert(key, "prefetch", input, Some(opts), false);
+        drop(reservation);
+
+        let mut stdout = stdout.fuse();
+        let mut kill_rx = kill_rx.fuse();
+        let mut failed = false;
+        loop {
+            let chunk = futures::select! {
+                chunk = stdout.next() => chunk,
+                _ = kill_rx => {
+                    failed = true;
+                    break;
+                }
+            };
+            match chunk {
+                Some(Ok(bytes)) => {
+                    writer.write(&bytes).await;
+                    job.bytes.fetch_add(bytes.len() as u64, Ordering::SeqCst);
+                }
+                Some(Err(_)) => {
+                    failed = true;
+                    break;
+                }
+                None => break,
+            }
+        }
+        let complete = if failed {
+            let _ = child.kill();
+            let _ = child.await;
+            false
+        } else {
+            child.await.map(|v| v.success()).unwrap_or(false)
+        };
+        writer.prefix(seconds);
+        writer.finish(complete).await;
+        self.jobs.lock().unwrap().remove(&job.id);
+        println!("Prefetch job {} ended", job.id);
+        Ok(())
+    }
+
+    /// Stream of the ffmpeg started by `spawn`, for the transcodes that are
+    /// neither shared nor cached, like the audio ones and the Ken Burns videos
+    ///
+    /// Runs as a job so it counts against `max_jobs`, is listed and can be
+    /// killed. The child isn't spawned if there are too many jobs.
+    pub async fn run<F>(
+        self: &Arc<Self>,
+        kind: &'static str,
+        file: &Path,
+        spawn: F,
+    ) -> Result<mpsc::Receiver<Chunk>, JobError>
+    where
+        F: Future<Output = Result<tokio::process::Child, std::io::Error>>,
+    {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let reservation = self.reserve()?;
+        let mut child = spawn.await?;
+        let stdout = media::stdout_stream(&mut child)?;
+        let input = media::MediaInput::File(file.to_path_buf());
+        let key = format!("{}|{}", kind, input);
+        let (job, subscribe_rx, kill_rx) = self.insert(key, kind, input, None, false);
+        drop(reservation);
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            pump(&job, child, stdout, None, tx, subscribe_rx, kill_rx).await;
+            manager.jobs.lock().unwrap().remove(&job.id);
+            println!("{} job {} ended", kind, job.id);
+        });
+        Ok(rx)
+    }
+
+    /// Registers a new running job
+    fn insert(
+        &self,
+        key: String,
+        kind: &'static str,
+        input: media::MediaInput,
+        opts: Option<&media::EncodeOpts>,
+        shareable: bool,
+    ) -> (
+        Arc<Job>,
+        mpsc::UnboundedReceiver<mpsc::Sender<Chunk>>,
+        oneshot::Receiver<()>,
+    ) {
+        let (subscribe, subscribe_rx) = mpsc::unbounded();
+        let (kill, kill_rx) = oneshot::channel();
+        let job = Arc::new(Job {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            key,
+            kind,
+            input,
+            opts: opts.cloned(),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|v| v.as_secs())
+                .unwrap_or(0),
+            subscribers: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            shareable: AtomicBool::new(shareable),
+            subscribe,
+            kill: Mutex::new(Some(kill)),
+        });
+        self.jobs.lock().unwrap().insert(job.id, job.clone());
+        (job, subscribe_rx, kill_rx)
+    }
+
+    fn find_shareable(&self, key: &str) -> Option<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .find(|job| job.key == key && job.shareable.load(Ordering::SeqCst))
+            .cloned()
+    }
+}
+
+/// Identical requests have identical keys
+fn job_key(input: &media::MediaInput, opts: &media::EncodeOpts) -> String {
+    format!(
+        "{}|{}",
+        input,
+        serde_json::to_string(opts).unwrap_or_default()
+    )
+}
+
+enum PumpEvent {
+    Subscribe(Option<mpsc::Sender<Chunk>>),
+    Chunk(Option<Chunk>),
+    Kill,
+}
+
+/// Reads the ffmpeg output and sends it to all subscribers
+///
+/// Returns when ffmpeg finishes, the job is killed or all subscribers have
+/// gone away, the child is killed on return.
+async fn pump(
+    job: &Job,
+    mut child: tokio::process::Child,
+    stdout: impl futures::Stream<Item = Chunk> + Unpin,
+    mut writer: Option<CacheWriter>,
+    first: mpsc::Sender<Chunk>,
+    subscribe_rx: mpsc::UnboundedReceiver<mpsc::Sender<Chunk>>,
+    kill_rx: oneshot::Receiver<()>,
+) {
+    let mut head: Vec<Bytes> = vec![];
+    let mut head_len: usize = 0;
+    let mut subscribers: Vec<mpsc::Sender<Chunk>> = vec![first];
+    let mut stdout = stdout.fuse();
+    let mut subscribe_rx = subscribe_rx.fuse();
+    let mut kill_rx = kill_rx.fuse();
+    let mut finished = false;
+
+    loop {
+        let event = futures::select! {
+            subscriber = subscribe_rx.next() => PumpEvent::Subscribe(subscriber),
+            chunk = stdout.next() => PumpEvent::Chunk(chunk),
+            _ = kill_rx => PumpEvent::Kill,
+        };
+        match event {
+            PumpEvent::Subscribe(Some(mut subscriber)) => {
+                if !job.shareable.load(Ordering::SeqCst) {
+                    let _ = subscriber
+                        .send(Err(std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            "Encoding job is no longer shareable",
+                        )))
+                        .await;
+                    continue;
+                }
+                // The head is replayed by a task of its own, the subscriber
+                // lags until it has caught up
+                let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+                let mut replay = futures::stream::iter(head.clone().into_iter().map(Ok)).chain(rx);
+                tokio::spawn(async move {
+                    while let Some(chunk) = replay.next().await {
+                        if subscriber.send(chunk).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                subscribers.push(tx);
+            }
+            PumpEvent::Subscribe(None) => {}
+            PumpEvent::Chunk(Some(chunk)) => {
+                if let Ok(bytes) = &chunk {
+                    if let Some(writer) = &mut writer {
+                        writer.write(bytes).await;
+                    }
+                    job.bytes.fetch_add(bytes.len() as u64, Ordering::SeqCst);
+                    if head_len + bytes.len() <= HEAD_BUFFER_LIMIT {
+                        head_len += bytes.len();
+                        head.push(bytes.clone());
+                    } else if job.shareable.swap(false, Ordering::SeqCst) {
+                        head.clear();
+                    }
+                }
+                // A lone subscriber paces the encode, shared ones are sent
+                // to concurrently and dropped if they lag
+                let wait = subscribers.len() == 1;
+                let sends: Vec<_> = subscribers
+                    .drain(..)
+                    .map(|mut subscriber| {
+                        let item = match &chunk {
+                            Ok(bytes) => Ok(bytes.clone()),
+                            Err(err) => Err(std::io::Error::new(err.kind(), err.to_string())),
+                        };
+                        async move {
+                            if send_chunk(&mut subscriber, item, wait).await {
+                                Some(subscriber)
+                            } else {
+                                None
+                            }
+                        }
+                    })
+                    .collect();
+                subscribers = futures::future::join_all(sends)
+                    .await
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                if subscribers.is_empty() {
+                    println!("All clients of encoding job {} disconnected", job.id);
+                    break;
+                }
+            }
+            PumpEvent::Chunk(None) => {
+                finished = true;
+                break;
+            }
+            PumpEvent::Kill => break,
+        }
+        job.subscribers.store(subscribers.len(), Ordering::SeqCst);
+    }
+
+    job.shareable.store(false, Ordering::SeqCst);
+    let complete = if finished {
+        child.await.map(|v| v.success()).unwrap_or(false)
+    } else {
+        let _ = child.kill();
+        let _ = child.await;
+        false
+    };
+    if let Some(writer) = writer {
+        writer.finish(complete).await;
+    }
+}
+
+/// Sends the chunk to the subscriber, gives up after `SUBSCRIBER_TIMEOUT`
+/// unless `wait` is set
+async fn send_chunk(subscriber: &mut mpsc::Sender<Chunk>, chunk: Chunk, wait: bool) -> bool {
+    if wait {
+        return subscriber.send(chunk).await.is_ok();
+    }
+    matches!(
+        tokio::time::timeout(SUBSCRIBER_TIMEOUT, subscriber.send(chunk)).await,
+        Ok(Ok(()))
+    )
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_job(
+        id: JobId,
+    ) -> (
+        Arc<Job>,
+        mpsc::UnboundedReceiver<mpsc::Sender<Chunk>>,
+        oneshot::Receiver<()>,
+    ) {
+        let (subscribe, subscribe_rx) = mpsc::unbounded();
+        let (kill, kill_rx) = oneshot::channel();
+        let job = Arc::new(Job {
+            id,
+            key: "test".into(),
+            kind: "video",
+            input: media::MediaInput::File("/media/test.mkv".into()),
+            opts: Some(media::EncodeOpts::default()),
+            started: 0,
+            subscribers: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            shareable: AtomicBool::new(true),
+            subscribe,
+            kill: Mutex::new(Some(kill)),
+        });
+        (job, subscribe_rx, kill_rx)
+    }
+
+    fn sleep_child() -> tokio::process::Child {
+        tokio::process::Command::new("sleep")
+            .arg("30")
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_reserve() {
+        let manager = JobManager::new(2, None);
+        let first = manager.reserve().unwrap();
+        let _second = manager.reserve().unwrap();
+        assert!(matches!(manager.reserve(), Err(JobError::TooManyJobs)));
+        drop(first);
+        let _third = manager.reserve().unwrap();
+
+        // Running jobs count against the limit too
+        let manager = JobManager::new(1, None);
+        let (job, _, _) = test_job(1);
+        manager.jobs.lock().unwrap().insert(1, job);
+        assert!(matches!(manager.reserve(), Err(JobError::TooManyJobs)));
+    }
+
+    #[tokio::test]
+    async fn test_kill() {
+        let manager = Arc::new(JobManager::new(1, None));
+        let (job, subscribe_rx, kill_rx) = test_job(1);
+        manager.jobs.lock().unwrap().insert(1, job.clone());
+        let (tx, _rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let stdout = futures::stream::pending::<Chunk>();
+        let pumping = tokio::spawn(async move {
+            pump(&job, sleep_child(), stdout, None, tx, subscribe_rx, kill_rx).await;
+        });
+
+        manager.kill(1).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), pumping)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(manager.list().is_empty());
+        assert!(matches!(manager.kill(1), Err(JobError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let manager = Arc::new(JobManager::new(1, None));
+        let spawn = async {
+            tokio::process::Command::new("sh")
+                .args(["-c", "echo data; sleep 30"])
+                .stdout(std::process::Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+        };
+        let mut rx = manager
+            .run("audio", Path::new("/media/test.flac"), spawn)
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.next().await.unwrap().unwrap(),
+            Bytes::from_static(b"data\n")
+        );
+
+        // Counted against the limit, the child isn't spawned
+        let list = manager.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].kind, "audio");
+        let spawned = AtomicBool::new(false);
+        let spawn = async {
+            spawned.store(true, Ordering::SeqCst);
+            Ok(sleep_child())
+        };
+        let result = manager.run("audio", Path::new("/media/test.flac"), spawn);
+        assert!(matches!(result.await, Err(JobError::TooManyJobs)));
+        assert!(!spawned.load(Ordering::SeqCst));
+
+        manager.kill(list[0].id).unwrap();
+        let ended = tokio::time::timeout(Duration::from_secs(5), rx.next()).await;
+        assert!(matches!(ended, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn test_clients_disconnected() {
+        let (job, subscribe_rx, kill_rx) = test_job(1);
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        drop(rx);
+        let stdout = futures::stream::iter(vec![Ok(Bytes::from_static(b"data"))])
+            .chain(futures::stream::pending());
+        let pumping = pump(&job, sleep_child(), stdout, None, tx, subscribe_rx, kill_rx);
+        tokio::time::timeout(Duration::from_secs(5), pumping)
+            .await
+            .unwrap();
+        assert!(!job.shareable.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_subscriber() {
+        let (job, subscribe_rx, kill_rx) = test_job(1);
+        let kill = job.kill.lock().unwrap().take().unwrap();
+        let count = SUBSCRIBER_BUFFER * 4;
+        let chunks: Vec<Chunk> = (0..count)
+            .map(|_| Ok(Bytes::from_static(b"data")))
+            .collect();
+        let stdout = futures::stream::iter(chunks).chain(futures::stream::pending());
+
+        // Second subscriber never reads but keeps its end open
+        let (stalled, _stalled_rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        job.subscribe.unbounded_send(stalled).unwrap();
+
+        let (tx, mut rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let pumping = tokio::spawn(async move {
+            pump(&job, sleep_child(), stdout, None, tx, subscribe_rx, kill_rx).await;
+        });
+        let reading = async {
+            for _ in 0..count {
+                rx.next().await.unwrap().unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), reading)
+            .await
+            .unwrap();
+
+        kill.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), pumping)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,770 @@
+/// Library index
+///
+/// Every scanned media file gets a stable ID derived from the file contents,
+/// so the IDs survive moving and renaming files. The API uses only the IDs
+/// and never exposes the paths on the server.
+use crate::media;
+use crate::naming;
+use crate::naming::ParsedName;
+use crate::nfo;
+use crate::playlist;
+use crate::segments::Segments;
+use crate::store;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::UNIX_EPOCH;
+
+pub type MediaId = String;
+
+/// Size of the chunks read from the beginning and the end of the file for the ID
+const HASH_CHUNK_SIZE: u64 = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LibraryItem {
+    pub id: MediaId,
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: u64,
+
+    // Probed in background, None until probed
+    #[serde(default)]
+    pub duration: Option<f32>,
+
+    // Read from the .nfo files and images next to the file
+    #[serde(default)]
+    pub metadata: Option<nfo::LocalMetadata>,
+    #[serde(default)]
+    pub artwork: nfo::Artwork,
+
+    // Intro and credits detected in background, None until analyzed
+    #[serde(default)]
+    pub segments: Option<Segments>,
+
+    // Tags of audio files, probed in background with the duration
+    #[serde(default)]
+    pub audio: Option<media::AudioTags>,
+
+    // Streams of video files, probed in background with the duration
+    #[serde(default)]
+    pub video: Option<media::VideoInfo>,
+}
+
+impl LibraryItem {
+    /// Reads the file information and hashes the file
+    pub fn read(path: &Path) -> Result<LibraryItem, std::io::Error> {
+        let (size, mtime) = file_stat(path).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Unable to read metadata")
+        })?;
+        let (metadata, artwork) = nfo::read_local(path);
+        Ok(LibraryItem {
+            id: media_id(path)?,
+            path: path.to_path_buf(),
+            size,
+            mtime,
+            duration: None,
+            metadata,
+            artwork,
+            segments: None,
+            audio: None,
+            video: None,
+        })
+    }
+
+    /// Takes the probed and analyzed data of the same file from before it was
+    /// moved
+    fn keep_probed(&mut self, old: LibraryItem) {
+        self.duration = old.duration;
+        self.segments = old.segments;
+        self.audio = old.audio;
+        self.video = old.video;
+    }
+
+    /// Re-reads the NFO files and artwork, returns true if they changed
+    pub fn refresh_local_metadata(&mut self) -> bool {
+        let (metadata, artwork) = nfo::read_local(&self.path);
+        if metadata == self.metadata && artwork == self.artwork {
+            return false;
+        }
+        self.metadata = metadata;
+        self.artwork = artwork;
+        true
+    }
+
+    /// File name without the extension
+    pub fn name(&self) -> String {
+        self.path
+            .file_stem()
+            .map(|v| v.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Audio-only file, e.g. music
+    pub fn is_audio(&self) -> bool {
+        media::is_audio_file(&self.path)
+    }
+
+    /// Photo or other image file
+    pub fn is_image(&self) -> bool {
+        media::is_image_file(&self.path)
+    }
+
+    /// Title from the tags of audio files, otherwise parsed from the file name
+    pub fn title(&self) -> String {
+        match self.audio.as_ref().and_then(|v| v.title.clone()) {
+            Some(title) => title,
+            None => self.parsed_name().display_title(),
+        }
+    }
+
+    /// Show, season and episode or movie title and year parsed from the file name
+    ///
+    /// Show name and season are taken from the folders if the file name lacks
+    /// them, e.g. `Show Name/Season 2/S02E05.mkv`. Values in the NFO files
+    /// override the parsed ones.
+    pub fn parsed_name(&self) -> ParsedName {
+        let mut parsed = naming::parse(&self.name());
+        let mut folders = self
+            .path
+            .ancestors()
+            .skip(1)
+            .filter_map(|v| v.file_name())
+            .map(|v| v.to_string_lossy().into_owned());
+        if let Some(mut folder) = folders.next() {
+            if let Some(season) = naming::parse_season_folder(&folder) {
+                parsed.season = parsed.season.or(Some(season));
+                folder = folders.next().unwrap_or_default();
+            }
+            if parsed.title.is_empty() {
+                parsed.title = naming::parse(&folder).title;
+            }
+        }
+        if parsed.title.is_empty() {
+            parsed.title = self.name();
+        }
+        if let Some(metadata) = &self.metadata {
+            parsed.season = metadata.season.or(parsed.season);
+            parsed.episode = metadata.episode.or(parsed.episode);
+            parsed.year = metadata.year.or(parsed.year);
+            let title = if parsed.is_episode() {
+                parsed.episode_title = metadata.title.clone().or(parsed.episode_title);
+                &metadata.show_title
+            } else {
+                &metadata.title
+            };
+            if let Some(title) = title {
+                parsed.title = title.clone();
+            }
+        }
+        parsed
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Library {
+    #[serde(skip)]
+    file: Option<PathBuf>,
+    items: HashMap<MediaId, LibraryItem>,
+
+    // IDs of the items by path, rebuilt on load
+    #[serde(skip)]
+    paths: HashMap<PathBuf, MediaId>,
+
+    // Playlist files in the directories, found by the scans
+    #[serde(skip)]
+    playlists: BTreeSet<PathBuf>,
+}
+
+impl Library {
+    /// Loads the library index from the file, or creates an empty one
+    pub fn load<P: AsRef<Path>>(file: P) -> Library {
+        let mut library: Library = store::load_json(&file).unwrap_or_default();
+        library.file = Some(file.as_ref().to_path_buf());
+        library.index_paths();
+        library
+    }
+
+    /// Scans the directories, adds new files and removes missing ones
+    ///
+    /// Only new or modified files are hashed. Files with the same contents as
+    /// another file get the ID disambiguated with the path, the files already
+    /// in the library keep their IDs. Moved files keep their probed data.
+    pub fn rescan<D: AsRef<Path>, E: AsRef<std::ffi::OsStr>>(&mut self, dirs: &[D], exts: &[E]) {
+        let scan = Scan::new(self.file_stats(), dirs, exts);
+        self.merge(scan);
+    }
+
+    /// Size and modification time of the files in the library
+    pub fn file_stats(&self) -> HashMap<PathBuf, (u64, u64)> {
+        self.items
+            .values()
+            .map(|v| (v.path.clone(), (v.size, v.mtime)))
+            .collect()
+    }
+
+    /// Updates the library to the result of the scan
+    ///
+    /// Items added or modified after the scan started, e.g. by the watcher,
+    /// are kept as they are.
+    pub fn merge(&mut self, scan: Scan) {
+        let Scan {
+            known,
+            unchanged,
+            read,
+            playlists,
+        } = scan;
+        let is_known = |item: &LibraryItem| known.get(&item.path) == Some(&(item.size, item.mtime));
+        let mut by_path: HashMap<PathBuf, LibraryItem> = self
+            .items
+            .drain()
+            .map(|(_, item)| (item.path.clone(), item))
+            .collect();
+        let mut new_items = vec![];
+        self.playlists = playlists.into_iter().collect();
+        for (path, metadata, artwork) in unchanged {
+            if let Some(mut item) = by_path.remove(&path) {
+                if is_known(&item) {
+                    item.metadata = metadata;
+                    item.artwork = artwork;
+                }
+                self.items.insert(item.id.clone(), item);
+            }
+        }
+        for item in read {
+            match by_path.remove(&item.path) {
+                Some(current) if !is_known(&current) => {
+                    self.items.insert(current.id.clone(), current);
+                }
+                _ => new_items.push(item),
+            }
+        }
+        // Files left are gone or moved, by the content hash without the path.
+        // The copy with the plain content hash ID wins.
+        let mut missing: HashMap<MediaId, LibraryItem> = HashMap::new();
+        for item in by_path.into_values() {
+            if !is_known(&item) {
+                self.items.insert(item.id.clone(), item);
+                continue;
+            }
+            let id: MediaId = item.id.split('-').next().unwrap_or_default().into();
+            if item.id == id || !missing.contains_key(&id) {
+                missing.insert(id, item);
+            }
+        }
+        new_items.sort_by(|a, b| a.path.cmp(&b.path));
+        for mut item in new_items {
+            if let Some(old) = missing.remove(&item.id).filter(|v| v.size == item.size) {
+                item.keep_probed(old);
+            }
+            if self.items.contains_key(&item.id) {
+                item.id = path_id(&item.id, &item.path);
+            }
+            self.items.insert(item.id.clone(), item);
+        }
+        self.index_paths();
+        println!("Library has {} media files", self.items.len());
+        self.save();
+    }
+
+    /// File is not in the library or it has been modified
+    pub fn needs_update(&self, path: &Path) -> bool {
+        match file_stat(path) {
+            Some((size, mtime)) => !self
+                .paths
+                .get(path)
+                .and_then(|id| self.items.get(id))
+                .is_some_and(|v| v.size == size && v.mtime == mtime),
+            None => false,
+        }
+    }
+
+    /// Adds or replaces the item of the file
+    pub fn insert(&mut self, mut item: LibraryItem) {
+        if let Some(id) = self.paths.remove(&item.path) {
+            self.items.remove(&id);
+        }
+        if self.items.contains_key(&item.id) {
+            item.id = path_id(&item.id, &item.path);
+        }
+        self.paths.insert(item.path.clone(), item.id.clone());
+        self.items.insert(item.id.clone(), item);
+    }
+
+    /// Playlist files in the directories, in the order of the paths
+    pub fn playlists(&self) -> Vec<PathBuf> {
+        self.playlists.iter().cloned().collect()
+    }
+
+    /// Adds the playlist file, returns true if it's new
+    pub fn add_playlist(&mut self, path: &Path) -> bool {
+        self.playlists.insert(path.to_path_buf())
+    }
+
+    /// Removes the file or all files in the directory, returns true if the
+    /// library changed
+    pub fn remove_path(&mut self, path: &Path) -> bool {
+        let playlists = self.playlists.len();
+        self.playlists.retain(|v| !v.starts_with(path));
+        let count = self.items.len();
+        self.items.retain(|_, v| !v.path.starts_with(path));
+        if count == self.items.len() {
+            return playlists != self.playlists.len();
+        }
+        self.index_paths();
+        true
+    }
+
+    /// Moves the file or files in the directory, the IDs stay the same
+    pub fn rename_path(&mut self, from: &Path, to: &Path) -> bool {
+        let playlists = std::mem::take(&mut self.playlists);
+        let mut changed = false;
+        for path in playlists {
+            match path.strip_prefix(from) {
+                Ok(rest) => {
+                    self.playlists.insert(to.join(rest));
+                    changed = true;
+                }
+                Err(_) => {
+                    self.playlists.insert(path);
+                }
+            }
+        }
+        for item in self.items.values_mut() {
+            if let Ok(rest) = item.path.strip_prefix(from) {
+                item.path = to.join(rest);
+                changed = true;
+            }
+        }
+        if changed {
+            self.index_paths();
+        }
+        changed
+    }
+
+    /// Re-reads the local metadata of the files in the folder and below it,
+    /// returns true if the library changed
+    pub fn refresh_local_metadata(&mut self, dir: &Path) -> bool {
+        let mut changed = false;
+        for item in self.items.values_mut() {
+            if item.path.starts_with(dir) {
+                changed |= item.refresh_local_metadata();
+            }
+        }
+        changed
+    }
+
+    /// Stores the probed duration
+    pub fn set_duration(&mut self, id: &str, duration: f32) {
+        if let Some(item) = self.items.get_mut(id) {
+            item.duration = Some(duration);
+        }
+    }
+
+    /// Stores the probed streams and the duration of a video file
+    pub fn set_video_info(&mut self, id: &str, info: media::VideoInfo) {
+        if let Some(item) = self.items.get_mut(id) {
+            item.duration = Some(info.duration);
+            item.video = Some(info);
+        }
+    }
+
+    /// Stores the probed tags of an audio file
+    pub fn set_audio_tags(&mut self, id: &str, tags: media::AudioTags) {
+        if let Some(item) = self.items.get_mut(id) {
+            item.audio = Some(tags);
+        }
+    }
+
+    /// Stores the detected segments, unless the file was modified meanwhile
+    pub fn set_segments(&mut self, id: &str, mtime: u64, segments: Segments) {
+        if let Some(item) = self.items.get_mut(id) {
+            if item.mtime == mtime {
+                item.segments = Some(segments);
+            }
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&LibraryItem> {
+        self.items.get(id)
+    }
+
+    /// Item after the given one in the same directory, ordered by the file name
+    pub fn next_in_directory(&self, id: &str) -> Option<&LibraryItem> {
+        let item = self.items.get(id)?;
+        let dir = item.path.parent()?;
+        self.items
+            .values()
+            .filter(|v| v.path.parent() == Some(dir) && v.path > item.path)
+            .min_by(|a, b| a.path.cmp(&b.path))
+    }
+
+    /// All items ordered by path
+    pub fn items(&self) -> Vec<&LibraryItem> {
+        let mut items: Vec<&LibraryItem> = self.items.values().collect();
+        items.sort_by(|a, b| a.path.cmp(&b.path));
+        items
+    }
+
+    fn index_paths(&mut self) {
+        self.paths = self
+            .items
+            .values()
+            .map(|v| (v.path.clone(), v.id.clone()))
+            .collect();
+    }
+
+    pub fn save(&self) {
+        if let Some(file) = &self.file {
+            if let Err(err) = store::save_json(file, self) {
+                println!("Unable to save library {}: {}", file.display(), err);
+            }
+        }
+    }
+}
+
+/// Files found by a scan of the media directories
+pub struct Scan {
+    // Size and modification time of the library items when the scan started
+    known: HashMap<PathBuf, (u64, u64)>,
+
+    // Known files, with the re-read NFO files and artwork
+    unchanged: Vec<(PathBuf, Option<nfo::LocalMetadata>, nfo::Artwork)>,
+
+    // New or modified files, read and hashed
+    read: Vec<LibraryItem>,
+    playlists: Vec<PathBuf>,
+}
+
+impl Scan {
+    /// Walks the directories and hashes the files which are not `known`
+    pub fn new<D: AsRef<Path>, E: AsRef<std::ffi::OsStr>>(
+        known: HashMap<PathBuf, (u64, u64)>,
+        dirs: &[D],
+        exts: &[E],
+    ) -> Scan {
+        let (paths, playlists) = scan_files(dirs, exts);
+        let mut unchanged = vec![];
+        let mut read = vec![];
+        for path in paths {
+            let stat = match file_stat(&path) {
+                Some(v) => v,
+                None => continue,
+            };
+            if known.get(&path) == Some(&stat) {
+                let (metadata, artwork) = nfo::read_local(&path);
+                unchanged.push((path, metadata, artwork));
+                continue;
+            }
+            match LibraryItem::read(&path) {
+                Ok(item) => read.push(item),
+                Err(err) => println!("Unable to hash {}: {}", path.display(), err),
+            }
+        }
+        Scan {
+            known,
+            unchanged,
+            read,
+            playlists,
+        }
+    }
+}
+
+/// Rescans the library without holding the lock while walking and hashing,
+/// the write lock is taken only to merge the result
+pub fn rescan<D: AsRef<Path>, E: AsRef<std::ffi::OsStr>>(
+    library: &RwLock<Library>,
+    dirs: &[D],
+    exts: &[E],
+) {
+    let known = library.read().unwrap().file_stats();
+    let scan = Scan::new(known, dirs, exts);
+    library.write().unwrap().merge(scan);
+}
+
+/// Media files and playlist files in the directories, walked once
+///
+/// Artwork images next to the media files aren't media files.
+pub fn scan_files<D: AsRef<Path>, E: AsRef<std::ffi::OsStr>>(
+    dirs: &[D],
+    exts: &[E],
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let exts: Vec<&std::ffi::OsStr> = exts
+        .iter()
+        .map(AsRef::as_ref)
+        .chain(playlist::PLAYLIST_EXTS.iter().map(std::ffi::OsStr::new))
+        .collect();
+    media::scan_media_files(dirs, &exts)
+        .into_iter()
+        .filter(|path| !nfo::is_artwork_file(path))
+        .partition(|path| !playlist::is_playlist_file(path))
+}
+
+/// Probes durations of the items not probed yet, the tags of audio files and
+/// the streams of video files
+///
+/// Videos probed before the audio and subtitle tracks were listed are probed
+/// again.
+///
+/// Interlacing of the videos which don't tell the field order is detected by
+/// sampling the frames.
+///
+/// Runs one ffprobe at a time, images get duration 0. Files which can't be
+/// probed are left unprobed, so they're retried on the next scan.
+pub async fn probe_durations(library: &RwLock<Library>) {
+    let missing: Vec<(MediaId, PathBuf)> = library
+        .read()
+        .unwrap()
+        .items
+        .values()
+        .filter(|v| {
+            let is_video = !v.is_audio() && !v.is_image();
+            let unprobed = v.video.as_ref().is_none_or(|v| v.tracks.is_none());
+            v.duration.is_none() || (is_video && unprobed)
+        })
+        .map(|v| (v.id.clone(), v.path.clone()))
+        .collect();
+    if missing.is_empty() {
+        return;
+    }
+    println!("Probing durations of {} files", missing.len());
+    for (id, path) in missing {
+        if media::is_image_file(&path) {
+            library.write().unwrap().set_duration(&id, 0.0);
+            continue;
+        }
+        if media::is_audio_file(&path) {
+            match media::get_audio_info(&path).await {
+                Ok(info) => {
+                    let mut library = library.write().unwrap();
+                    library.set_duration(&id, info.duration);
+                    library.set_audio_tags(&id, info.tags);
+                }
+                Err(err) => println!("Unable to probe {}: {}", path.display(), err),
+            }
+            continue;
+        }
+        match media::get_info(&path).await {
+            Ok(mut info) => {
+                if info.interlaced.is_none() {
+                    info.interlaced = media::detect_interlace(&path).await.ok().flatten();
+                }
+                library.write().unwrap().set_video_info(&id, info);
+            }
+            Err(err) => println!("Unable to probe {}: {}", path.display(), err),
+        }
+    }
+    library.read().unwrap().save();
+}
+
+/// Fuzzy match score of the search against the text, None if it doesn't match
+///
+/// Substring matches score highest, otherwise all characters of the search
+/// must appear in order and the score decreases with the gaps between them.
+pub fn fuzzy_score(search: &str, text: &str) -> Option<i32> {
+    let search = search.to_lowercase();
+    let text = text.to_lowercase();
+    if search.is_empty() {
+        return Some(0);
+    }
+    if let Some(pos) = text.find(&search) {
+        return Some(10000 - pos as i32);
+    }
+    let mut score = 1000;
+    let mut chars = text.chars();
+    for c in search.chars() {
+        let mut gap = 0;
+        loop {
+            match chars.next() {
+                Some(v) if v == c => break,
+                Some(_) => gap += 1,
+                None => return None,
+            }
+        }
+        score -= gap;
+    }
+    Some(score)
+}
+
+/// Path relative to the root directory containing it
+pub fn relative_path<'a, D: AsRef<Path>>(path: &'a Path, dirs: &[D]) -> Option<(usize, &'a Path)> {
+    dirs.iter()
+        .enumerate()
+        .find_map(|(i, dir)| path.strip_prefix(dir).ok().map(|v| (i, v)))
+}
+
+fn file_stat(path: &Path) -> Option<(u64, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    let mtime = meta
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some((meta.len(), mtime))
+}
+
+/// Content hash of the media file
+///
+/// Same as the OpenSubtitles hash: file size plus the 64-bit words of the
+/// first and the last 64 KiB.
+pub fn media_id<P: AsRef<Path>>(path: P) -> Result<MediaId, std::io::Error> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hash = size;
+    let mut buf = vec![0u8; HASH_CHUNK_SIZE.min(size) as usize];
+    for offset in &[0, size.saturating_sub(HASH_CHUNK_SIZE)] {
+        file.seek(SeekFrom::Start(*offset))?;
+        file.read_exact(&mut buf)?;
+        for word in buf.chunks(8) {
+            let mut bytes = [0u8; 8];
+            bytes[..word.len()].copy_from_slice(word);
+            hash = hash.wrapping_add(u64::from_le_bytes(bytes));
+        }
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+/// ID of a file with the same contents as another file in the library
+///
+/// The content hash is suffixed with the FNV-1a hash of the path, so the
+/// copies are all listed, but the ID of a copy changes when it's moved.
+fn path_id(id: &str, path: &Path) -> MediaId {
+    let hash = path
+        .to_string_lossy()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, v| {
+            (hash ^ u64::from(v)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{}-{:016x}", id, hash)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("casterson-library-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_media_id() {
+        let dir = test_dir("id");
+        let data: Vec<u8> = (0..200_000u32).map(|v| (v % 251) as u8).collect();
+        std::fs::write(dir.join("a.mkv"), &data).unwrap();
+        std::fs::write(dir.join("b.mkv"), &data).unwrap();
+        let mut changed = data.clone();
+        changed[10] ^= 1;
+        std::fs::write(dir.join("c.mkv"), &changed).unwrap();
+        std::fs::write(dir.join("small.mkv"), b"abc").unwrap();
+
+        let id = media_id(dir.join("a.mkv")).unwrap();
+        assert_eq!(id.len(), 16);
+        assert_eq!(id, media_id(dir.join("b.mkv")).unwrap());
+        assert_ne!(id, media_id(dir.join("c.mkv")).unwrap());
+        assert_eq!(
+            media_id(dir.join("small.mkv")).unwrap(),
+            format!("{:016x}", 3 + 2 * u64::from_le_bytes(*b"abc\0\0\0\0\0"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rescan() {
+        let dir = test_dir("rescan");
+        let dirs = [dir.clone()];
+        let exts = ["mkv"];
+        std::fs::write(dir.join("a.mkv"), b"first").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"text").unwrap();
+        std::fs::write(dir.join("list.m3u"), b"a.mkv").unwrap();
+        let mut library = Library::default();
+        library.rescan(&dirs, &exts);
+        assert_eq!(library.items().len(), 1);
+        assert_eq!(library.playlists(), vec![dir.join("list.m3u")]);
+        let id = library.items()[0].id.clone();
+
+        // Copies with the same contents are all listed
+        std::fs::write(dir.join("b.mkv"), b"first").unwrap();
+        library.rescan(&dirs, &exts);
+        let items = library.items();
+        assert_eq!(items.len(), 2);
+        assert_eq!(library.get(&id).unwrap().path, dir.join("a.mkv"));
+        assert_ne!(items[1].id, id);
+        assert!(items[1].id.starts_with(&id));
+
+        // Moved file keeps the ID and the probed data
+        library.set_duration(&id, 12.5);
+        std::fs::remove_file(dir.join("b.mkv")).unwrap();
+        std::fs::rename(dir.join("a.mkv"), dir.join("moved.mkv")).unwrap();
+        library.rescan(&dirs, &exts);
+        assert_eq!(library.items().len(), 1);
+        assert_eq!(library.get(&id).unwrap().path, dir.join("moved.mkv"));
+        assert_eq!(library.get(&id).unwrap().duration, Some(12.5));
+
+        std::fs::remove_file(dir.join("moved.mkv")).unwrap();
+        std::fs::remove_file(dir.join("list.m3u")).unwrap();
+        library.rescan(&dirs, &exts);
+        assert!(library.items().is_empty());
+        assert!(library.playlists().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_concurrent_changes() {
+        let dir = test_dir("merge");
+        let dirs = [dir.clone()];
+        let exts = ["mkv"];
+        std::fs::write(dir.join("a.mkv"), b"first").unwrap();
+        let mut library = Library::default();
+        library.rescan(&dirs, &exts);
+        let scan = Scan::new(library.file_stats(), &dirs, &exts);
+
+        // File added by the watcher after the directories were walked
+        std::fs::write(dir.join("b.mkv"), b"second").unwrap();
+        library.insert(LibraryItem::read(&dir.join("b.mkv")).unwrap());
+        library.merge(scan);
+        let items = library.items();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].path, dir.join("b.mkv"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_next_in_directory() {
+        let dir = test_dir("next");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("e01.mkv"), b"first").unwrap();
+        std::fs::write(dir.join("e02.mkv"), b"second").unwrap();
+        std::fs::write(dir.join("e03.mkv"), b"third").unwrap();
+        std::fs::write(dir.join("sub").join("e04.mkv"), b"fourth").unwrap();
+        let dirs = [dir.clone()];
+        let mut library = Library::default();
+        library.rescan(&dirs, &["mkv"]);
+        let id = |name: &str| library.paths[&dir.join(name)].clone();
+
+        let next = library.next_in_directory(&id("e01.mkv")).unwrap();
+        assert_eq!(next.path, dir.join("e02.mkv"));
+        let next = library.next_in_directory(&id("e02.mkv")).unwrap();
+        assert_eq!(next.path, dir.join("e03.mkv"));
+        assert!(library.next_in_directory(&id("e03.mkv")).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fuzzy_score() {
+        assert!(fuzzy_score("bunny", "Big Buck Bunny").unwrap() > 9000);
+        assert!(fuzzy_score("bbb", "Big Buck Bunny").is_some());
+        assert!(
+            fuzzy_score("bgbk", "Big Buck Bunny").unwrap()
+                > fuzzy_score("bgbk", "Big Bad Buck").unwrap()
+        );
+        assert_eq!(fuzzy_score("xyz", "Big Buck Bunny"), None);
+    }
+}
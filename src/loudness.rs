@@ -0,0 +1,89 @@
+/// Loudness measurements for the two-pass normalization
+///
+/// The first pass of loudnorm decodes the whole audio, which takes too long to
+/// do before the playback starts. A file is measured in background the first
+/// time it's played with the two-pass normalization, and the single-pass
+/// normalization is used until the measurement is ready. Measurements are
+/// stored by the file path, modification time and the audio options.
+use crate::media;
+use crate::media::{FFMpegAudioOpts, LoudnormMeasurement};
+use crate::store;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug)]
+pub struct Loudness {
+    file: PathBuf,
+    measurements: Mutex<HashMap<String, LoudnormMeasurement>>,
+    measuring: Mutex<HashSet<String>>,
+}
+
+impl Loudness {
+    /// Loads the measurements from the file
+    pub fn load<P: AsRef<Path>>(file: P) -> Loudness {
+        Loudness {
+            file: file.as_ref().to_path_buf(),
+            measurements: Mutex::new(store::load_json(&file).unwrap_or_default()),
+            measuring: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Fills in the measurement of the file if the two-pass normalization is
+    /// requested, the file is measured in background if it's not measured yet
+    pub fn apply(self: &Arc<Self>, file: &Path, opts: &mut FFMpegAudioOpts) {
+        opts.measured = None;
+        if !opts.loudnorm_two_pass {
+            return;
+        }
+        let key = match measurement_key(file, opts) {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(measured) = self.measurements.lock().unwrap().get(&key) {
+            opts.measured = Some(measured.clone());
+            return;
+        }
+        if !self.measuring.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        let loudness = self.clone();
+        let file = file.to_path_buf();
+        let opts = opts.clone();
+        tokio::spawn(async move {
+            println!("Measuring loudness of {}", file.display());
+            match media::measure_loudness(&file, &opts).await {
+                Ok(measured) => {
+                    let mut measurements = loudness.measurements.lock().unwrap();
+                    measurements.insert(key.clone(), measured);
+                    if let Err(err) = store::save_json(&loudness.file, &*measurements) {
+                        println!(
+                            "Unable to save loudness measurements {}: {}",
+                            loudness.file.display(),
+                            err
+                        );
+                    }
+                }
+                Err(err) => println!("Unable to measure loudness {}: {}", file.display(), err),
+            }
+            loudness.measuring.lock().unwrap().remove(&key);
+        });
+    }
+}
+
+/// Key of the measurement, None if the file modification time can't be read
+fn measurement_key(file: &Path, opts: &FFMpegAudioOpts) -> Option<String> {
+    let mtime = std::fs::metadata(file)
+        .and_then(|v| v.modified())
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some(format!(
+        "{}|{}|{}",
+        file.to_string_lossy(),
+        mtime,
+        serde_json::to_string(opts).unwrap_or_default()
+    ))
+}
@@ -5,13 +5,37 @@ use crossbeam::unbounded;
 use std::io::Result as IOResult;
 use std::net::IpAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use structopt::StructOpt;
+use tokio::sync::broadcast;
 
 pub mod api;
+pub mod browser;
+pub mod cache;
 pub mod chromecast;
+pub mod dlna;
+pub mod history;
+pub mod jobs;
+pub mod library;
+pub mod loudness;
 pub mod media;
+pub mod mpv;
 pub mod msg;
+pub mod naming;
+pub mod nfo;
+pub mod photos;
+pub mod playlist;
+pub mod prefetch;
+pub mod renderer;
+pub mod segments;
+pub mod sessions;
+pub mod slideshow;
+pub mod store;
+pub mod thumbnails;
+pub mod trickplay;
+pub mod upnp;
+pub mod watcher;
+pub mod websocket;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "basic")]
@@ -30,9 +54,67 @@ pub struct CliOpts {
     port: u16,
 
     /// Media extensions
-    #[structopt(short, long, default_value = "mp4,mkv,avi,mov", value_delimiter = ",")]
+    #[structopt(short, long, default_value = "mp4,mkv,avi,mov,ts,mpg,mp3,flac,ogg,m4a,opus,jpg,jpeg,png,heic,webp", value_delimiter = ",")]
     media_exts: Vec<String>,
 
+    /// Directory for the library index and other persistent data
+    #[structopt(long, default_value = "casterson_data")]
+    data_dir: PathBuf,
+
+    /// Maximum number of concurrent encoding jobs
+    #[structopt(long, default_value = "2")]
+    max_jobs: usize,
+
+    /// Directory of the transcode cache, caching is disabled if not given
+    #[structopt(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Size limit of the transcode cache in megabytes
+    #[structopt(long, default_value = "10240")]
+    cache_size_mb: u64,
+
+    /// Network stream sources allowed to be transcoded, e.g.
+    /// `rtsp://camera.local,http://*:8080` or just `rtsp`, none by default.
+    /// HTTP redirects have to go to allowed sources too
+    #[structopt(long, value_delimiter = ",")]
+    stream_sources: Vec<String>,
+
+    /// Minutes of the next item of the queue, or the next file in the
+    /// directory, to encode to the cache in background, 0 disables
+    #[structopt(long, default_value = "0")]
+    prefetch_minutes: u32,
+
+    /// Widths of the generated thumbnails, requested widths are rounded up
+    #[structopt(long, default_value = "320,640,1280", value_delimiter = ",")]
+    thumbnail_widths: Vec<u32>,
+
+    /// Seconds between the frames of the trickplay sprite sheets generated in
+    /// background, 0 disables
+    #[structopt(long, default_value = "10")]
+    trickplay_interval: u32,
+
+    /// Minutes from the beginning of the episodes searched for the shared
+    /// intros, 0 disables the intro and credits detection
+    #[structopt(long, default_value = "10")]
+    intro_search_minutes: u32,
+
+    /// Items played past this percentage of the duration are marked watched
+    #[structopt(long, default_value = "90")]
+    watched_percent: u32,
+
+    /// Name of the UPnP media server shown on the TVs
+    #[structopt(long, default_value = "Casterson")]
+    upnp_name: String,
+
+    /// Doesn't advertise the UPnP media server
+    #[structopt(long)]
+    no_upnp: bool,
+
+    /// Arguments of mpv when it's started for the mpv device, e.g.
+    /// `--vo=null,--ao=null`
+    #[structopt(long, value_delimiter = ",", allow_hyphen_values = true)]
+    mpv_args: Vec<String>,
+
     /// Directories of media files
     #[structopt(name = "DIR", required = true, parse(try_from_str = parse_path_canonicalized))]
     dir: Vec<PathBuf>,
@@ -42,25 +124,123 @@ pub struct CliOpts {
 pub struct AppState {
     pub opts: CliOpts,
     pub notifier: Sender<msg::NotifyMessage>,
+    pub jobs: Arc<jobs::JobManager>,
+    pub cache: Option<Arc<cache::TranscodeCache>>,
+    pub prefetcher: prefetch::Prefetcher,
+    pub loudness: Arc<loudness::Loudness>,
+    pub library: RwLock<library::Library>,
+    pub thumbnails: thumbnails::Thumbnails,
+    pub photos: photos::Photos,
+    pub trickplay: trickplay::Trickplay,
+    pub segments: segments::SegmentDetector,
+    pub history: RwLock<history::History>,
+    pub sessions: sessions::Sessions,
+    pub slideshows: slideshow::Slideshows,
+    pub upnp: upnp::MediaServer,
+    pub receivers: browser::Receivers,
+    pub events: broadcast::Sender<msg::Event>,
 }
 
 #[tokio::main]
 async fn main() {
     let (notify, rec) = unbounded::<msg::NotifyMessage>();
+    let opts = CliOpts::from_args();
+    let cache = match &opts.cache_dir {
+        Some(dir) => match cache::TranscodeCache::open(dir, opts.cache_size_mb * 1024 * 1024) {
+            Ok(cache) => Some(Arc::new(cache)),
+            Err(err) => {
+                eprintln!("Unable to open cache directory {}: {}", dir.display(), err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let loudness = Arc::new(loudness::Loudness::load(
+        opts.data_dir.join("loudness.json"),
+    ));
+    let mut library = library::Library::load(opts.data_dir.join("library.json"));
+    library.rescan(&opts.dir, &opts.media_exts);
+    let jobs = Arc::new(jobs::JobManager::new(opts.max_jobs, cache.clone()));
     let state = Arc::new(AppState {
-        opts: CliOpts::from_args(),
+        library: RwLock::new(library),
+        thumbnails: thumbnails::Thumbnails::new(
+            opts.data_dir.join("thumbnails"),
+            &opts.thumbnail_widths,
+        ),
+        photos: photos::Photos::new(opts.data_dir.join("photos")),
+        sessions: sessions::Sessions::default(),
+        slideshows: slideshow::Slideshows::default(),
+        upnp: upnp::MediaServer::load(opts.data_dir.join("upnp.json"), &opts.upnp_name),
+        receivers: browser::Receivers::default(),
+        history: RwLock::new(history::History::load(
+            opts.data_dir.join("history.json"),
+        )),
+        trickplay: trickplay::Trickplay::new(
+            opts.data_dir.join("trickplay"),
+            opts.trickplay_interval,
+        ),
+        segments: segments::SegmentDetector::new(opts.intro_search_minutes),
+        jobs: jobs.clone(),
+        prefetcher: prefetch::Prefetcher::new(
+            cache.clone(),
+            jobs.clone(),
+            loudness.clone(),
+            opts.prefetch_minutes,
+        ),
+        loudness,
+        cache,
+        opts,
         notifier: notify.clone(),
+        events: broadcast::channel(16).0,
     });
     for dir in &*state.opts.dir {
         println!("Using media directory: {}", dir.display());
     }
+    if let Err(err) = watcher::start(state.clone()) {
+        println!("Unable to watch media directories {:?}", err);
+    }
+    if !state.opts.no_upnp {
+        if let Err(err) = upnp::start(state.clone()) {
+            println!("Unable to start the UPnP media server {:?}", err);
+        }
+    }
+
+    // Probe durations, generate trickplays and detect intros of new files in
+    // background
+    let state_probe = state.clone();
+    let mut library_events = state.events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            library::probe_durations(&state_probe.library).await;
+            state_probe
+                .trickplay
+                .generate_missing(&state_probe.library)
+                .await;
+            state_probe
+                .segments
+                .detect_missing(&state_probe.library)
+                .await;
+            loop {
+                match library_events.recv().await {
+                    Ok(msg::Event::LibraryChanged) => break,
+                    Err(broadcast::RecvError::Closed) => return,
+                    _ => (),
+                }
+            }
+        }
+    });
 
+    let state_notify = state.clone();
     tokio::spawn(async move {
         loop {
             match rec.recv() {
                 Ok(msg::NotifyMessage::ErrorDuringCasting(err)) => {
                     println!("Error during casting {:?}", err);
                 }
+                Ok(msg::NotifyMessage::LibraryChanged) => {
+                    state_notify.upnp.library_changed();
+                    let _ = state_notify.events.send(msg::Event::LibraryChanged);
+                }
                 _ => (),
             }
         }
@@ -1,30 +1,29 @@
 use bytes::BytesMut;
 use futures::Stream;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::ffi::OsString;
 use std::fmt::Display;
 use std::fs::canonicalize;
+use std::io::Read;
 use std::iter::Iterator;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Stdio;
-use tokio::process::Command;
+use std::time::Duration;
+use tokio::process::{Child, Command};
 use futures::stream::TryStreamExt;
 use tokio_util::codec::{BytesCodec, FramedRead};
+use url::Url;
 use walkdir;
 
 /// Scan media files
 pub fn scan_media_files<E: AsRef<OsStr>, P: AsRef<Path>>(dirs: &[P], exts: &[E]) -> Vec<PathBuf> {
     let mut paths: Vec<PathBuf> = vec![];
-    let exts_os: Vec<OsString> = exts.iter().map(OsString::from).collect();
     for dir in dirs {
         for entry in walkdir::WalkDir::new(dir) {
             let path = entry.unwrap().into_path();
-            let ext = path
-                .extension()
-                .map_or(OsString::from(""), OsStr::to_os_string);
-            if exts_os.contains(&ext) {
+            if has_extension(&path, exts) {
                 paths.push(path);
             }
         }
@@ -32,6 +31,14 @@ pub fn scan_media_files<E: AsRef<OsStr>, P: AsRef<Path>>(dirs: &[P], exts: &[E])
     paths
 }
 
+/// Extension of the file is one of the given, compared case-insensitively
+pub fn has_extension<E: AsRef<OsStr>>(file: &Path, exts: &[E]) -> bool {
+    file.extension().is_some_and(|ext| {
+        exts.iter()
+            .any(|v| v.as_ref().eq_ignore_ascii_case(ext))
+    })
+}
+
 /// Validates file against safe paths and extensions
 ///
 /// File is valid if it's inside one of the safe directories, and it's
@@ -46,9 +53,7 @@ pub fn is_safe_file<P: AsRef<Path>, D: AsRef<Path>, E: AsRef<OsStr>>(
     // TODO: Nightly Rust map_or(false, |...|)
     canonicalize(&file)
         .map(|file_path| {
-            let safe_ext = file_path.extension().map_or(false, |ext| {
-                safe_exts.iter().map(AsRef::as_ref).any(|v| v == ext)
-            });
+            let safe_ext = has_extension(&file_path, safe_exts);
 
             let safe_dir = safe_dirs.iter().any(|d| file_path.starts_with(d));
 
@@ -57,11 +62,32 @@ pub fn is_safe_file<P: AsRef<Path>, D: AsRef<Path>, E: AsRef<OsStr>>(
         .unwrap_or(false)
 }
 
+#[derive(Default, Serialize, Eq, PartialEq, Deserialize, Debug)]
+struct FFProbeStreamTags {
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
 #[derive(Default, Serialize, Eq, PartialEq, Deserialize, Debug)]
 struct FFProbeStreams {
+    #[serde(default)]
+    pub codec_type: String,
+    #[serde(default)]
     pub codec_name: String,
+    #[serde(default)]
     pub width: i32,
+    #[serde(default)]
     pub height: i32,
+    #[serde(default)]
+    pub color_transfer: Option<String>,
+    #[serde(default)]
+    pub color_primaries: Option<String>,
+    #[serde(default)]
+    pub field_order: Option<String>,
+    #[serde(default)]
+    pub avg_frame_rate: Option<String>,
+    #[serde(default)]
+    pub tags: FFProbeStreamTags,
     // Other omitted
 }
 
@@ -70,19 +96,372 @@ struct FFProbeFormat {
     pub duration: String,
 }
 
+#[derive(Default, Serialize, Eq, PartialEq, Deserialize, Debug)]
+struct FFProbeChapterTags {
+    pub title: Option<String>,
+}
+
+#[derive(Default, Serialize, Eq, PartialEq, Deserialize, Debug)]
+struct FFProbeChapter {
+    pub start_time: String,
+    pub end_time: String,
+    #[serde(default)]
+    pub tags: FFProbeChapterTags,
+}
+
 #[derive(Default, Serialize, Eq, PartialEq, Deserialize, Debug)]
 struct FFProbeResult {
-    pub streams: (FFProbeStreams,), // Only first video stream
+    pub streams: Vec<FFProbeStreams>,
     pub format: FFProbeFormat,      // Format (more reliable duration)
-                                    // Other omitted
+    #[serde(default)]
+    pub chapters: Vec<FFProbeChapter>,
+    // Other omitted
 }
 
-#[derive(Default, Serialize, PartialEq, Deserialize, Debug)]
+#[derive(Serialize, PartialEq, Deserialize, Clone, Debug)]
+pub struct Chapter {
+    pub start: f32,
+    pub end: f32,
+    pub title: Option<String>,
+}
+
+/// High dynamic range format of the video
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HdrFormat {
+    // SMPTE ST 2084 (PQ) transfer
+    Hdr10,
+
+    // ARIB STD-B67 (Hybrid Log-Gamma) transfer
+    Hlg,
+}
+
+impl HdrFormat {
+    /// HDR format from the color properties of the stream, None if it's SDR
+    fn from_color(transfer: Option<&str>, primaries: Option<&str>) -> Option<HdrFormat> {
+        if primaries.is_some_and(|v| v != "bt2020") {
+            return None;
+        }
+        match transfer? {
+            "smpte2084" => Some(HdrFormat::Hdr10),
+            "arib-std-b67" => Some(HdrFormat::Hlg),
+            _ => None,
+        }
+    }
+
+    /// Transfer characteristics as named by ffmpeg
+    fn transfer(self) -> &'static str {
+        match self {
+            HdrFormat::Hdr10 => "smpte2084",
+            HdrFormat::Hlg => "arib-std-b67",
+        }
+    }
+}
+
+#[derive(Default, Serialize, PartialEq, Deserialize, Clone, Debug)]
 pub struct VideoInfo {
-    codec_name: String,
-    width: i32,
-    height: i32,
-    duration: f32,
+    pub codec_name: String,
+    pub width: i32,
+    pub height: i32,
+    pub duration: f32,
+    pub chapters: Vec<Chapter>,
+
+    // None if the video is SDR
+    pub hdr: Option<HdrFormat>,
+
+    // Field order as probed, or detected for the library, None if unknown
+    pub interlaced: Option<bool>,
+    pub frame_rate: Option<f32>,
+
+    // None if probed before the tracks were listed
+    #[serde(default)]
+    pub tracks: Option<Tracks>,
+}
+
+/// Audio and subtitle streams of the video, selected by the index in the list
+#[derive(Default, Serialize, PartialEq, Deserialize, Clone, Debug)]
+pub struct Tracks {
+    pub audio: Vec<Track>,
+    pub subtitles: Vec<Track>,
+}
+
+#[derive(Serialize, PartialEq, Deserialize, Clone, Debug)]
+pub struct Track {
+    pub codec_name: String,
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
+impl Tracks {
+    fn new(streams: &[FFProbeStreams]) -> Tracks {
+        let tracks = |codec_type: &str| {
+            streams
+                .iter()
+                .filter(|v| v.codec_type == codec_type)
+                .map(Track::new)
+                .collect()
+        };
+        Tracks {
+            audio: tracks("audio"),
+            subtitles: tracks("subtitle"),
+        }
+    }
+}
+
+impl Track {
+    fn new(stream: &FFProbeStreams) -> Track {
+        Track {
+            codec_name: stream.codec_name.clone(),
+            language: stream.tags.language.clone(),
+            title: stream.tags.title.clone(),
+        }
+    }
+
+    /// Subtitles are images which are overlaid instead of rendered as text
+    fn is_bitmap(&self) -> bool {
+        ["hdmv_pgs_subtitle", "dvd_subtitle", "dvb_subtitle", "xsub"].contains(&self.codec_name.as_str())
+    }
+}
+
+/// Network stream fails if it doesn't send or receive anything within this,
+/// in microseconds as ffmpeg takes it
+const NETWORK_TIMEOUT_MICROS: &str = "15000000";
+
+/// Probe is killed if it doesn't finish within this
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Input of ffmpeg, a local file or a network stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaInput {
+    File(PathBuf),
+    Url(Url),
+}
+
+impl MediaInput {
+    /// Local file, None for network streams
+    pub fn file(&self) -> Option<&Path> {
+        match self {
+            MediaInput::File(file) => Some(file),
+            MediaInput::Url(_) => None,
+        }
+    }
+
+    /// Argument of `-i`
+    fn as_os_str(&self) -> &OsStr {
+        match self {
+            MediaInput::File(file) => file.as_os_str(),
+            MediaInput::Url(url) => OsStr::new(url.as_str()),
+        }
+    }
+
+    /// Options before `-i`
+    ///
+    /// Network streams are limited to the protocols of their scheme, so e.g.
+    /// an HLS playlist can't refer to local files, and time out if they stall.
+    fn input_args(&self) -> Vec<&'static str> {
+        let url = match self {
+            MediaInput::File(_) => return vec![],
+            MediaInput::Url(url) => url,
+        };
+        match url.scheme() {
+            "rtsp" | "rtsps" => vec![
+                "-rtsp_transport", "tcp",
+                "-timeout", NETWORK_TIMEOUT_MICROS,
+                "-protocol_whitelist", "rtsp,rtsps,rtp,srtp,udp,tcp,tls",
+            ],
+            "http" | "https" => vec![
+                "-reconnect", "1",
+                "-reconnect_streamed", "1",
+                "-reconnect_delay_max", "5",
+                "-rw_timeout", NETWORK_TIMEOUT_MICROS,
+                "-protocol_whitelist", "http,https,tls,tcp,crypto,data",
+            ],
+            _ => vec![
+                "-rw_timeout", NETWORK_TIMEOUT_MICROS,
+                "-protocol_whitelist", "udp,rtp,srt,tcp,tls,http,https",
+            ],
+        }
+    }
+}
+
+impl Display for MediaInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MediaInput::File(file) => write!(f, "{}", file.display()),
+            MediaInput::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+impl From<&Path> for MediaInput {
+    fn from(file: &Path) -> Self {
+        MediaInput::File(file.to_path_buf())
+    }
+}
+
+impl From<&PathBuf> for MediaInput {
+    fn from(file: &PathBuf) -> Self {
+        MediaInput::File(file.clone())
+    }
+}
+
+impl From<PathBuf> for MediaInput {
+    fn from(file: PathBuf) -> Self {
+        MediaInput::File(file)
+    }
+}
+
+impl From<Url> for MediaInput {
+    fn from(url: Url) -> Self {
+        MediaInput::Url(url)
+    }
+}
+
+/// Network stream is allowed by the list of sources
+///
+/// Sources are `scheme://host[:port]` or a plain `scheme`, the host can be `*`
+/// or start with `*.` for the subdomains. Local files are never allowed.
+///
+/// Only the URL itself is checked, `resolve_source` checks the locations it
+/// redirects to. The variant playlists and segments of an HLS playlist aren't
+/// filtered, they're only limited to the HTTP protocols.
+pub fn is_allowed_source<S: AsRef<str>>(url: &Url, sources: &[S]) -> bool {
+    if url.scheme() == "file" || url.cannot_be_a_base() {
+        return false;
+    }
+    let host = url.host_str().unwrap_or("").to_lowercase();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    sources.iter().map(|v| v.as_ref().trim()).any(|source| {
+        let (scheme, rest) = match source.find("://") {
+            Some(i) => (&source[..i], &source[i + 3..]),
+            None => (source, "*"),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (source_host, port) = match rest.rfind(':') {
+            Some(i) if !rest[i + 1..].contains(']') => (&rest[..i], rest[i + 1..].parse().ok()),
+            _ => (rest, None),
+        };
+        let source_host = source_host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_lowercase();
+        let host_matches = match source_host.strip_prefix("*.") {
+            _ if source_host == "*" => true,
+            Some(domain) => host.ends_with(&format!(".{}", domain)),
+            None => host == source_host,
+        };
+        scheme.eq_ignore_ascii_case(url.scheme())
+            && host_matches
+            && port.is_none_or(|port: u16| url.port_or_known_default() == Some(port))
+    })
+}
+
+/// Most redirects followed by `resolve_source`
+const MAX_REDIRECTS: usize = 5;
+
+/// Network stream with its HTTP redirects followed, None if it or any
+/// location it redirects to isn't allowed by the sources
+///
+/// ffmpeg follows the redirects on its own, so it's given the resolved URL.
+pub async fn resolve_source<S: AsRef<str>>(
+    url: &Url,
+    sources: &[S],
+) -> Result<Option<Url>, std::io::Error> {
+    let mut url = url.clone();
+    for _ in 0..MAX_REDIRECTS {
+        if !is_allowed_source(&url, sources) {
+            return Ok(None);
+        }
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Ok(Some(url));
+        }
+        let request = url.clone();
+        let location = tokio::task::spawn_blocking(move || http_location(&request))
+            .await
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))??;
+        url = match location {
+            Some(location) => location,
+            None => return Ok(Some(url)),
+        };
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "Too many redirects",
+    ))
+}
+
+/// Location the HTTP URL redirects to, reads only the response head
+fn http_location(url: &Url) -> Result<Option<Url>, std::io::Error> {
+    let strerr = |err: String| std::io::Error::new(std::io::ErrorKind::Other, err);
+    let timeout = Duration::from_secs(15);
+    let host = url.host_str().ok_or_else(|| strerr("No host".into()))?;
+    let addr = url
+        .socket_addrs(|| None)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| strerr(format!("Unable to resolve {}", host)))?;
+    let stream = std::net::TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let path = &url[url::Position::BeforePath..url::Position::AfterQuery];
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        if path.is_empty() { "/" } else { path },
+        &url[url::Position::BeforeHost..url::Position::AfterPort]
+    );
+    let head = if url.scheme() == "https" {
+        let connector = openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls())
+            .map_err(|err| strerr(err.to_string()))?
+            .build();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let stream = connector
+            .connect(host, stream)
+            .map_err(|err| strerr(err.to_string()))?;
+        read_response_head(stream, &request)?
+    } else {
+        read_response_head(stream, &request)?
+    };
+
+    let mut lines = head.lines();
+    let status: u16 = lines
+        .next()
+        .and_then(|v| v.split_whitespace().nth(1))
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| strerr(format!("Invalid response {}", head)))?;
+    if ![301, 302, 303, 307, 308].contains(&status) {
+        return Ok(None);
+    }
+    let location = lines
+        .filter_map(|v| v.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("location"))
+        .map(|(_, value)| value.trim())
+        .ok_or_else(|| strerr("Redirect without a location".into()))?;
+    url.join(location)
+        .map(Some)
+        .map_err(|err| strerr(err.to_string()))
+}
+
+/// Sends the request and reads the response until the end of the headers
+fn read_response_head<S: Read + std::io::Write>(
+    mut stream: S,
+    request: &str,
+) -> Result<String, std::io::Error> {
+    stream.write_all(request.as_bytes())?;
+    let mut head = vec![];
+    let mut buffer = [0; 4096];
+    while !head.windows(4).any(|v| v == b"\r\n\r\n") && head.len() < 64 * 1024 {
+        let count = stream.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        head.extend_from_slice(&buffer[..count]);
+    }
+    let end = head
+        .windows(4)
+        .position(|v| v == b"\r\n\r\n")
+        .unwrap_or(head.len());
+    Ok(String::from_utf8_lossy(&head[..end]).into_owned())
 }
 
 /// Probe video information
@@ -90,6 +469,14 @@ pub async fn get_info<P>(file: P) -> Result<VideoInfo, std::io::Error>
 where
     P: AsRef<Path>,
 {
+    get_input_info(&MediaInput::File(file.as_ref().to_path_buf())).await
+}
+
+/// Probe video information of a file or a network stream
+///
+/// Live streams have no duration, it's 0 for them. The probe is killed if it
+/// takes longer than `PROBE_TIMEOUT`.
+pub async fn get_input_info(input: &MediaInput) -> Result<VideoInfo, std::io::Error> {
     // Fallback to string based error
     let strerr = |err| std::io::Error::new(std::io::ErrorKind::Other, err);
 
@@ -103,13 +490,17 @@ where
     #[rustfmt::skip]
     cmd
         .arg("-v").arg("error")
-        .arg("-select_streams").arg("v:0") // Only first video stream
-        .arg("-show_entries").arg("stream=width,height,codec_name:format=duration")
+        .arg("-show_entries").arg("stream=codec_type,width,height,codec_name,color_transfer,color_primaries,field_order,avg_frame_rate:stream_tags=language,title:format=duration")
+        .arg("-show_chapters")
         .arg("-print_format").arg("json")
-        .arg(file.as_ref())
+        .args(input.input_args())
+        .arg(input.as_os_str())
         .stdout(Stdio::piped()) // redirect the stdout
-        .stderr(Stdio::piped()); // redirect the stderr
-    let out = cmd.output().await?;
+        .stderr(Stdio::piped()) // redirect the stderr
+        .kill_on_drop(true);
+    let out = tokio::time::timeout(PROBE_TIMEOUT, cmd.output())
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Probe timed out"))??;
 
     // Capture stderr and stdout
     let stderr =
@@ -123,18 +514,373 @@ where
     if stderr != "" {
         Err(strerr(&stderr))
     } else {
-        let duration = ff_result
-            .format
-            .duration
-            .parse()
-            .map_err(|_| strerr("Unable to parse duration"))?;
+        let duration = match (ff_result.format.duration.parse(), input) {
+            (Ok(duration), _) => duration,
+            (Err(_), MediaInput::Url(_)) => 0.0,
+            (Err(_), MediaInput::File(_)) => return Err(strerr("Unable to parse duration")),
+        };
+
+        let chapters = ff_result
+            .chapters
+            .into_iter()
+            .filter_map(|v| {
+                Some(Chapter {
+                    start: v.start_time.parse().ok()?,
+                    end: v.end_time.parse().ok()?,
+                    title: v.tags.title,
+                })
+            })
+            .collect();
 
+        let tracks = Tracks::new(&ff_result.streams);
+        // Only first video stream
+        let stream = ff_result
+            .streams
+            .into_iter()
+            .find(|v| v.codec_type == "video")
+            .ok_or_else(|| strerr("No video stream"))?;
         Ok(VideoInfo {
-            codec_name: ff_result.streams.0.codec_name,
+            hdr: HdrFormat::from_color(
+                stream.color_transfer.as_deref(),
+                stream.color_primaries.as_deref(),
+            ),
+            interlaced: stream.field_order.as_deref().and_then(|v| match v {
+                "progressive" => Some(false),
+                "tt" | "bb" | "tb" | "bt" => Some(true),
+                _ => None,
+            }),
+            frame_rate: stream.avg_frame_rate.as_deref().and_then(parse_frame_rate),
+            codec_name: stream.codec_name,
             duration: duration,
-            width: ff_result.streams.0.width,
-            height: ff_result.streams.0.height,
+            width: stream.width,
+            height: stream.height,
+            chapters,
+            tracks: Some(tracks),
+        })
+    }
+}
+
+/// Frame rate from the ffprobe rational, e.g. `30000/1001`
+fn parse_frame_rate(rate: &str) -> Option<f32> {
+    let mut parts = rate.splitn(2, '/');
+    let num: f32 = parts.next()?.parse().ok()?;
+    let den: f32 = parts.next().unwrap_or("1").parse().ok()?;
+    if num > 0.0 && den > 0.0 {
+        Some(num / den)
+    } else {
+        None
+    }
+}
+
+/// Detects interlacing by sampling frames with the idet filter
+///
+/// Used when the stream doesn't tell the field order, returns None if it
+/// can't be determined.
+pub async fn detect_interlace<P: AsRef<Path>>(file: P) -> Result<Option<bool>, std::io::Error> {
+    let mut cmd = ffmpeg_command(false);
+    #[rustfmt::skip]
+    cmd
+        .arg("-loglevel").arg("info")
+        .arg("-nostats")
+        .arg("-i").arg(file.as_ref().as_os_str())
+        .arg("-map").arg("0:v:0")
+        .arg("-frames:v").arg(IDET_FRAMES.to_string())
+        .arg("-vf").arg("idet")
+        .arg("-f").arg("null")
+        .arg("-")
+        .kill_on_drop(true)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+
+    let out = cmd.output().await?;
+    Ok(parse_idet(&String::from_utf8_lossy(&out.stderr)))
+}
+
+/// Number of frames sampled by idet
+const IDET_FRAMES: u32 = 300;
+
+/// Parses the multi frame detection counts of idet, interlaced if there are
+/// more interlaced frames than progressive ones
+fn parse_idet(log: &str) -> Option<bool> {
+    let line = log.lines().find(|v| v.contains("Multi frame detection:"))?;
+    let count = |key: &str| -> Option<u32> {
+        let rest = line[line.find(key)? + key.len()..].trim_start();
+        rest.split_whitespace().next()?.parse().ok()
+    };
+    let interlaced = count("TFF:")? + count("BFF:")?;
+    let progressive = count("Progressive:")?;
+    if interlaced + progressive == 0 {
+        None
+    } else {
+        Some(interlaced > progressive)
+    }
+}
+
+/// Extensions of the audio-only files
+pub const AUDIO_EXTS: &[&str] = &["mp3", "flac", "ogg", "m4a", "opus"];
+
+/// File is an audio-only file by the extension
+pub fn is_audio_file<P: AsRef<Path>>(file: P) -> bool {
+    file.as_ref()
+        .extension()
+        .and_then(|v| v.to_str())
+        .is_some_and(|ext| AUDIO_EXTS.iter().any(|v| v.eq_ignore_ascii_case(ext)))
+}
+
+/// MIME type of the media file by the extension, served as is
+pub fn content_type<P: AsRef<Path>>(file: P) -> &'static str {
+    let ext = file
+        .as_ref()
+        .extension()
+        .and_then(|v| v.to_str())
+        .map(|v| v.to_lowercase());
+    match ext.as_deref() {
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("m4a") => "audio/mp4",
+        Some("ogg") | Some("opus") => "audio/ogg",
+        Some("mp4") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("avi") => "video/x-msvideo",
+        Some("mov") => "video/quicktime",
+        Some("ts") => "video/mp2t",
+        Some("mpg") => "video/mpeg",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        Some("heic") => "image/heic",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Default, Deserialize, Debug)]
+struct FFProbeAudioStream {
+    pub codec_type: String,
+    #[serde(default)]
+    pub codec_name: String,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    #[serde(default)]
+    pub disposition: HashMap<String, i32>,
+}
+
+#[derive(Default, Deserialize, Debug)]
+struct FFProbeAudioFormat {
+    pub duration: String,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Default, Deserialize, Debug)]
+struct FFProbeAudioResult {
+    pub streams: Vec<FFProbeAudioStream>,
+    pub format: FFProbeAudioFormat,
+}
+
+/// Tags of an audio file
+#[derive(Serialize, Deserialize, PartialEq, Default, Clone, Debug)]
+pub struct AudioTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub composer: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+    pub track: Option<u32>,
+    pub disc: Option<u32>,
+}
+
+impl AudioTags {
+    /// Tags from the ffprobe tags, names are matched case-insensitively as
+    /// the containers name them differently
+    fn from_tags(tags: &HashMap<String, String>) -> AudioTags {
+        let tags: HashMap<String, &str> = tags
+            .iter()
+            .map(|(k, v)| (k.to_lowercase(), v.trim()))
+            .filter(|(_, v)| !v.is_empty())
+            .collect();
+        let text = |keys: &[&str]| -> Option<String> {
+            keys.iter().find_map(|k| tags.get(*k)).map(|v| v.to_string())
+        };
+        // Numbers can be "3/12" or dates "1999-05-01"
+        let number = |keys: &[&str]| -> Option<u32> {
+            let value = keys.iter().find_map(|k| tags.get(*k))?;
+            let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+            digits.parse().ok()
+        };
+        AudioTags {
+            title: text(&["title"]),
+            artist: text(&["artist"]),
+            album: text(&["album"]),
+            album_artist: text(&["album_artist", "albumartist", "album artist"]),
+            composer: text(&["composer"]),
+            genre: text(&["genre"]),
+            year: number(&["date", "year", "originaldate"]),
+            track: number(&["track", "tracknumber"]),
+            disc: number(&["disc", "discnumber"]),
+        }
+    }
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct AudioInfo {
+    pub codec_name: String,
+    pub duration: f32,
+    pub tags: AudioTags,
+
+    // File has embedded cover art
+    pub cover: bool,
+}
+
+/// Probe audio file information and tags
+pub async fn get_audio_info<P: AsRef<Path>>(file: P) -> Result<AudioInfo, std::io::Error> {
+    let strerr = |err| std::io::Error::new(std::io::ErrorKind::Other, err);
+
+    let mut cmd = Command::new("ffprobe");
+    #[rustfmt::skip]
+    cmd
+        .arg("-v").arg("error")
+        .arg("-show_entries").arg("stream=codec_type,codec_name:stream_tags:stream_disposition=attached_pic:format=duration:format_tags")
+        .arg("-print_format").arg("json")
+        .arg(file.as_ref())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    let out = cmd.output().await?;
+    let result: FFProbeAudioResult =
+        serde_json::from_slice(&out.stdout).map_err(|_| strerr("Unable to parse json"))?;
+
+    let audio = result
+        .streams
+        .iter()
+        .find(|v| v.codec_type == "audio")
+        .ok_or_else(|| strerr("No audio stream"))?;
+
+    // Ogg and Opus have the tags in the stream
+    let mut tags = audio.tags.clone();
+    tags.extend(result.format.tags);
+    Ok(AudioInfo {
+        codec_name: audio.codec_name.clone(),
+        duration: result.format.duration.parse().unwrap_or(0.0),
+        tags: AudioTags::from_tags(&tags),
+        cover: result
+            .streams
+            .iter()
+            .any(|v| v.disposition.get("attached_pic") == Some(&1)),
+    })
+}
+
+/// Extensions of the image files
+pub const IMAGE_EXTS: &[&str] = &["jpg", "jpeg", "png", "heic", "webp"];
+
+/// Bytes from the beginning of the image searched for the EXIF data
+const EXIF_SEARCH_BYTES: u64 = 256 * 1024;
+
+/// EXIF orientation tag
+const EXIF_ORIENTATION: u16 = 0x0112;
+
+/// File is an image by the extension
+pub fn is_image_file<P: AsRef<Path>>(file: P) -> bool {
+    file.as_ref()
+        .extension()
+        .and_then(|v| v.to_str())
+        .is_some_and(|ext| IMAGE_EXTS.iter().any(|v| v.eq_ignore_ascii_case(ext)))
+}
+
+/// HEIC has the rotation in the container, ffmpeg applies it itself
+fn is_heic_file(file: &Path) -> bool {
+    file.extension()
+        .and_then(|v| v.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("heic"))
+}
+
+/// EXIF orientation of the image, 1 (upright) if it has none
+pub fn image_orientation<P: AsRef<Path>>(file: P) -> u16 {
+    let mut data = vec![];
+    let read = std::fs::File::open(file)
+        .and_then(|f| f.take(EXIF_SEARCH_BYTES).read_to_end(&mut data));
+    match read {
+        Ok(_) => parse_exif_orientation(&data).unwrap_or(1),
+        Err(_) => 1,
+    }
+}
+
+/// Finds the orientation tag of the EXIF data in the image
+///
+/// JPEG and HEIC have the TIFF structure after an `Exif\0\0` header, WebP in
+/// the `EXIF` chunk.
+fn parse_exif_orientation(data: &[u8]) -> Option<u16> {
+    let tiffs = data.windows(6).enumerate().filter_map(|(i, v)| match v {
+        b"Exif\0\0" => Some(i + 6),
+        [b'E', b'X', b'I', b'F', ..] => Some(i + 8),
+        _ => None,
+    });
+    tiffs.filter_map(|i| data.get(i..)).find_map(tiff_orientation)
+}
+
+/// Orientation tag in the first IFD of the TIFF structure
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+    let little_endian = match tiff.get(0..4)? {
+        b"II*\0" => true,
+        b"MM\0*" => false,
+        _ => return None,
+    };
+    let u16_at = |i: usize| -> Option<u16> {
+        let bytes = [*tiff.get(i)?, *tiff.get(i + 1)?];
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let u32_at = |i: usize| -> Option<u32> {
+        let bytes = [*tiff.get(i)?, *tiff.get(i + 1)?, *tiff.get(i + 2)?, *tiff.get(i + 3)?];
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
         })
+    };
+    let ifd = u32_at(4)? as usize;
+    let count = u16_at(ifd)? as usize;
+    (0..count)
+        .map(|n| ifd + 2 + n * 12)
+        .find(|entry| u16_at(*entry) == Some(EXIF_ORIENTATION))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|v| (1..=8).contains(v))
+}
+
+/// Filters turning the image upright by the EXIF orientation
+fn orientation_filters(orientation: u16) -> Vec<String> {
+    let filters: &[&str] = match orientation {
+        2 => &["hflip"],
+        3 => &["hflip", "vflip"],
+        4 => &["vflip"],
+        5 => &["transpose=cclock_flip"],
+        6 => &["transpose=clock"],
+        7 => &["transpose=clock_flip"],
+        8 => &["transpose=cclock"],
+        _ => &[],
+    };
+    filters.iter().map(|v| v.to_string()).collect()
+}
+
+/// Filters turning the image file upright, used with `upright_input_args`
+fn upright_filters(file: &Path) -> Vec<String> {
+    if is_heic_file(file) {
+        vec![]
+    } else {
+        orientation_filters(image_orientation(file))
+    }
+}
+
+/// Input arguments of the image file, ffmpeg versions differ in applying the
+/// EXIF orientation so it's always done with `upright_filters` instead
+fn upright_input_args(file: &Path) -> Vec<&'static str> {
+    if is_heic_file(file) {
+        vec![]
+    } else {
+        vec!["-noautorotate"]
     }
 }
 
@@ -149,7 +895,7 @@ fn ffmpeg_filter_escape(s: &str) -> String {
 ///
 /// http://ffmpeg.org/ffmpeg-filters.html#subtitles-1
 /// https://fileformats.fandom.com/wiki/SubStation_Alpha
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FFMpegSubtitleOpts {
     // charenc:
     pub encoding: String,
@@ -193,7 +939,238 @@ impl Display for FFMpegSubtitleOpts {
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// Measurement of the first pass of the two-pass loudness normalization
+///
+/// Values are kept as printed by ffmpeg, they are only passed back to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoudnormMeasurement {
+    pub input_i: String,
+    pub input_tp: String,
+    pub input_lra: String,
+    pub input_thresh: String,
+    pub target_offset: String,
+}
+
+/// FFMpeg audio options
+///
+/// http://ffmpeg.org/ffmpeg-filters.html#loudnorm
+/// http://ffmpeg.org/ffmpeg-filters.html#acompressor
+/// http://ffmpeg.org/ffmpeg-filters.html#pan-1
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FFMpegAudioOpts {
+    // EBU R128 loudness normalization, two-pass uses the measurement of the
+    // whole file when it's available
+    pub loudnorm: bool,
+    pub loudnorm_two_pass: bool,
+    pub integrated_lufs: f32,
+    pub true_peak_db: f32,
+    pub loudness_range: f32,
+
+    // Compresses the dynamic range, quiet parts are louder and loud ones quieter
+    pub night_mode: bool,
+
+    // Downmix to stereo, the center channel carrying the dialogue is boosted
+    // by the decibels
+    pub downmix_stereo: bool,
+    pub dialogue_boost_db: f32,
+
+    // Filled in by the server from the stored measurements
+    #[serde(skip_deserializing)]
+    pub measured: Option<LoudnormMeasurement>,
+}
+
+impl Default for FFMpegAudioOpts {
+    fn default() -> Self {
+        FFMpegAudioOpts {
+            loudnorm: false,
+            loudnorm_two_pass: false,
+            integrated_lufs: -23.0,
+            true_peak_db: -2.0,
+            loudness_range: 7.0,
+            night_mode: false,
+            downmix_stereo: false,
+            dialogue_boost_db: 0.0,
+            measured: None,
+        }
+    }
+}
+
+impl FFMpegAudioOpts {
+    /// Loudness normalization is enabled
+    pub fn normalizes(&self) -> bool {
+        self.loudnorm || self.loudnorm_two_pass
+    }
+
+    /// Filters before the loudness normalization, the measurement is done
+    /// with these applied
+    fn pre_filters(&self) -> Vec<String> {
+        let mut filters: Vec<String> = vec![];
+        if self.downmix_stereo {
+            // Any layout is converted to 5.1 first, so the pan has the same
+            // channels to work with. Mono ends up in the center channel.
+            let center = 0.707 * 10f32.powf(self.dialogue_boost_db / 20.0);
+            filters.push("aformat=channel_layouts=5.1".into());
+            filters.push(format!(
+                "pan=stereo|FL={:.3}*FC+0.707*FL+0.707*BL|FR={:.3}*FC+0.707*FR+0.707*BR",
+                center, center
+            ));
+        }
+        if self.night_mode {
+            filters.push("acompressor=threshold=0.05:ratio=4:attack=10:release=300:makeup=4".into());
+        }
+        filters
+    }
+
+    fn loudnorm_filter(&self) -> String {
+        format!(
+            "loudnorm=I={}:TP={}:LRA={}",
+            self.integrated_lufs, self.true_peak_db, self.loudness_range
+        )
+    }
+
+    /// Audio filter chain, empty if the audio is encoded as is
+    pub fn filters(&self) -> Vec<String> {
+        let mut filters = self.pre_filters();
+        if self.normalizes() {
+            let mut loudnorm = self.loudnorm_filter();
+            if let (true, Some(measured)) = (self.loudnorm_two_pass, &self.measured) {
+                loudnorm.push_str(&format!(
+                    ":measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+                    measured.input_i,
+                    measured.input_tp,
+                    measured.input_lra,
+                    measured.input_thresh,
+                    measured.target_offset
+                ));
+            }
+            filters.push(loudnorm);
+
+            // Loudnorm upsamples to 192 kHz
+            filters.push("aresample=48000".into());
+        }
+        filters
+    }
+}
+
+/// Tone mapping algorithm of the HDR to SDR conversion
+///
+/// http://ffmpeg.org/ffmpeg-filters.html#tonemap-1
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tonemap {
+    // Only desaturates the overbright pixels
+    None,
+    Linear,
+    Gamma,
+    Clip,
+    Reinhard,
+    #[default]
+    Hable,
+    Mobius,
+}
+
+impl Display for Tonemap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Tonemap::None => "none",
+            Tonemap::Linear => "linear",
+            Tonemap::Gamma => "gamma",
+            Tonemap::Clip => "clip",
+            Tonemap::Reinhard => "reinhard",
+            Tonemap::Hable => "hable",
+            Tonemap::Mobius => "mobius",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Filters converting the HDR video to SDR BT.709 in software
+///
+/// The color properties of the input are given explicitly, as not all files
+/// have them tagged on the frames.
+fn tonemap_filters(hdr: HdrFormat, tonemap: Tonemap) -> Vec<String> {
+    vec![
+        format!(
+            "zscale=tin={}:min=bt2020nc:pin=bt2020:rin=tv:t=linear:npl=100",
+            hdr.transfer()
+        ),
+        "format=gbrpf32le".into(),
+        "zscale=p=bt709".into(),
+        format!("tonemap=tonemap={}:desat=0", tonemap),
+        "zscale=t=bt709:m=bt709:r=tv".into(),
+        "format=yuv420p".into(),
+    ]
+}
+
+/// Video encoder arguments, HDR passed through is encoded as 10-bit HEVC
+/// Main10 with the color properties of the input
+#[rustfmt::skip]
+fn video_codec_args(passthrough: Option<HdrFormat>) -> Vec<String> {
+    let args: Vec<&str> = match passthrough {
+        Some(hdr) => vec![
+            "-c:v", "hevc_nvenc",
+            "-profile:v", "main10",
+            "-pix_fmt", "p010le",
+            "-tag:v", "hvc1",
+            "-color_primaries", "bt2020",
+            "-color_trc", hdr.transfer(),
+            "-colorspace", "bt2020nc",
+        ],
+        None => vec!["-c:v", "h264_nvenc"],
+    };
+    args.into_iter().map(String::from).collect()
+}
+
+/// When to deinterlace the video
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Deinterlace {
+    // Interlaced streams by the field order, or idet of the library probe if
+    // it's not known
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Deinterlacing filter
+///
+/// http://ffmpeg.org/ffmpeg-filters.html#bwdif
+/// http://ffmpeg.org/ffmpeg-filters.html#yadif-1
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Deinterlacer {
+    Yadif,
+    #[default]
+    Bwdif,
+}
+
+/// Deinterlacing filter, with `field_rate` every field becomes a frame
+/// doubling the frame rate
+fn deinterlace_filter(deinterlacer: Deinterlacer, field_rate: bool) -> String {
+    let name = match deinterlacer {
+        Deinterlacer::Yadif => "yadif",
+        Deinterlacer::Bwdif => "bwdif",
+    };
+    let mode = if field_rate { "send_field" } else { "send_frame" };
+    format!("{}=mode={}:parity=auto:deint=all", name, mode)
+}
+
+/// Frame rate conversion filter, None if the rate is kept
+///
+/// The exact `frame_rate` wins over `max_frame_rate`, which only lowers the
+/// rate of the videos above it.
+fn frame_rate_filter(source_rate: Option<f32>, opts: &EncodeOpts) -> Option<String> {
+    let rate = match (opts.frame_rate, opts.max_frame_rate, source_rate) {
+        (Some(rate), _, _) => rate,
+        (None, Some(max), Some(source)) if source > max + 0.01 => max,
+        _ => return None,
+    };
+    Some(format!("fps={}", rate))
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct EncodeOpts {
     pub seek_seconds: i32,
@@ -201,25 +1178,112 @@ pub struct EncodeOpts {
     pub output_resolution: (i32, i32),
     pub crop_max_percent: i32,
     pub subtitle_opts: FFMpegSubtitleOpts,
+    pub audio_opts: FFMpegAudioOpts,
+
+    // HDR video is tone mapped to SDR with the algorithm, unless the device
+    // displays HDR and it's passed through as 10-bit HEVC
+    pub tonemap: Tonemap,
+    pub hdr_passthrough: bool,
+
+    // With `field_rate` each field of interlaced video becomes a frame, e.g.
+    // 50i is deinterlaced to 50p instead of 25p
+    pub deinterlace: Deinterlace,
+    pub deinterlacer: Deinterlacer,
+    pub field_rate: bool,
+
+    // Output frame rate, or the maximum for the videos above it
+    pub frame_rate: Option<f32>,
+    pub max_frame_rate: Option<f32>,
+
+    // Offset added to the output timestamps, used when continuing an earlier
+    // encode of the same file
+    pub output_offset_seconds: i32,
+
+    // Audio and embedded subtitle streams by the index in the probed tracks.
+    // Default audio stream and the subtitle file next to the video if not
+    // given
+    pub audio_track: Option<usize>,
+    pub subtitle_track: Option<usize>,
 }
 
-/// Returns video stream as bytes or io::Error
-pub async fn encode<P: AsRef<Path>>(
-    file: P,
-    opts: EncodeOpts,
-) -> Result<impl Stream<Item = Result<bytes::Bytes, std::io::Error>>, std::io::Error> {
-    // Fallback to string based error
-    let strerr = |err| std::io::Error::new(std::io::ErrorKind::Other, err);
+impl EncodeOpts {
+    /// Options of the next item of the queue, from the beginning and with the
+    /// default tracks, as the streams of the items differ
+    pub fn next_item(&self) -> EncodeOpts {
+        EncodeOpts {
+            seek_seconds: 0,
+            output_offset_seconds: 0,
+            audio_track: None,
+            subtitle_track: None,
+            ..self.clone()
+        }
+    }
+}
 
+/// Spawns ffmpeg encoding the file or the network stream, the video is written
+/// to the stdout of the child
+///
+/// The child is killed if it's dropped, use `stdout_stream` to read the output.
+/// The input is probed unless the `video` information is given, e.g. the one
+/// probed for the library.
+pub async fn encode<I: Into<MediaInput>>(
+    input: I,
+    opts: &EncodeOpts,
+    video: Option<VideoInfo>,
+) -> Result<Child, std::io::Error> {
     println!("Start encoding...");
+    encode_limited(input.into(), opts, video, None, false).await
+}
 
-    let file_ = file.as_ref();
+/// Spawns low priority ffmpeg encoding only the first `seconds` of the file
+pub async fn encode_prefix<I: Into<MediaInput>>(
+    input: I,
+    opts: &EncodeOpts,
+    video: Option<VideoInfo>,
+    seconds: u32,
+) -> Result<Child, std::io::Error> {
+    println!("Start encoding the first {} seconds in background...", seconds);
+    encode_limited(input.into(), opts, video, Some(seconds), true).await
+}
+
+async fn encode_limited(
+    input: MediaInput,
+    opts: &EncodeOpts,
+    video: Option<VideoInfo>,
+    max_seconds: Option<u32>,
+    niced: bool,
+) -> Result<Child, std::io::Error> {
     let mut video_filters: Vec<String> = vec![];
-    let subtitle_file = file_.with_extension("srt");
+    // Subtitles are read only next to the local files
+    let subtitle_file = input.file().map(|v| v.with_extension("srt"));
     let (output_width, output_height) = opts.output_resolution;
+    let video = match video {
+        Some(video) => Some(video),
+        None => get_input_info(&input).await.ok(),
+    };
+    let hdr = video.as_ref().and_then(|v| v.hdr);
+    let mut frame_rate = video.as_ref().and_then(|v| v.frame_rate);
+
+    let deinterlace = match opts.deinterlace {
+        Deinterlace::Always => true,
+        Deinterlace::Never => false,
+        // Field order is sampled with idet in the library probe if the
+        // stream doesn't tell it, it's not done here to not delay the start
+        Deinterlace::Auto => video.as_ref().and_then(|v| v.interlaced).unwrap_or(false),
+    };
+    if deinterlace {
+        video_filters.push(deinterlace_filter(opts.deinterlacer, opts.field_rate));
+        if opts.field_rate {
+            frame_rate = frame_rate.map(|v| v * 2.0);
+        }
+    }
+
+    if let (Some(hdr), false) = (hdr, opts.hdr_passthrough) {
+        video_filters.extend(tonemap_filters(hdr, opts.tonemap));
+    }
 
     if opts.crop_max_percent > 0 && output_width > 0 && output_height > 0 {
-        if let Ok(video) = get_info(file_).await {
+        if let Some(video) = &video {
             // Crop from the left and right towards the output_resolution,
             // amount of cropping can be controlled by crop_max_percent
             let video_width: f64 = f64::from(video.width);
@@ -241,39 +1305,177 @@ pub async fn encode<P: AsRef<Path>>(
         }
     }
 
-    if !opts.disable_subtitles && subtitle_file.exists() {
+    if let Some(filter) = frame_rate_filter(frame_rate, opts) {
+        video_filters.push(filter);
+    }
+
+    // Embedded subtitles of the track instead of the subtitle file, bitmap
+    // subtitles are overlaid as the stream of the input
+    let subtitle_track = opts.subtitle_track.filter(|_| !opts.disable_subtitles);
+    let bitmap_subtitles = subtitle_track.filter(|v| {
+        video
+            .as_ref()
+            .and_then(|video| video.tracks.as_ref())
+            .and_then(|tracks| tracks.subtitles.get(*v))
+            .is_some_and(Track::is_bitmap)
+    });
+    let subtitle_file = match subtitle_track {
+        Some(track) => input.file().map(|v| (v.to_path_buf(), Some(track))),
+        None => subtitle_file.map(|v| (v, None)),
+    };
+    let subtitle_file = subtitle_file.filter(|(v, _)| !opts.disable_subtitles && v.exists());
+    if let (Some((subtitle_file, track)), None) = (subtitle_file, bitmap_subtitles) {
         video_filters.push(format!("setpts=PTS+{}/TB", opts.seek_seconds));
-        video_filters.push(format!("subtitles='{}':{}",
+        video_filters.push(format!("subtitles='{}':{}{}",
             ffmpeg_filter_escape(&subtitle_file.to_string_lossy()),
+            track.map_or(String::new(), |v| format!("si={}:", v)),
             opts.subtitle_opts
         ));
         video_filters.push("setpts=PTS-STARTPTS".into());
     }
 
-    let mut cmd = Command::new("ffmpeg");
+    // Video is overlaid with the subtitles in a filter graph, its output and
+    // the selected audio are mapped
+    let video_args: Vec<String> = match (bitmap_subtitles, video_filters.is_empty()) {
+        (Some(track), _) => {
+            let filters = Some(video_filters.join(",")).filter(|v| !v.is_empty());
+            let graph = match filters {
+                Some(filters) => format!("[0:v:0]{}[base];[base][0:s:{}]overlay=(W-w)/2:H-h[v]", filters, track),
+                None => format!("[0:v:0][0:s:{}]overlay=(W-w)/2:H-h[v]", track),
+            };
+            vec!["-filter_complex".into(), graph]
+        }
+        (None, false) => vec!["-vf".into(), video_filters.join(",")],
+        (None, true) => vec![],
+    };
+    let map_args: Vec<String> = match (bitmap_subtitles, opts.audio_track) {
+        (None, None) => vec![],
+        (overlay, audio_track) => vec![
+            "-map".into(),
+            if overlay.is_some() { "[v]".into() } else { "0:v:0".into() },
+            "-map".into(),
+            // Missing audio isn't an error
+            format!("0:a:{}?", audio_track.unwrap_or(0)),
+        ],
+    };
+
+    let audio_filters = opts.audio_opts.filters();
+
+    let mut cmd = ffmpeg_command(niced);
     #[rustfmt::skip]
     cmd
         .arg("-loglevel").arg("error")
         .arg("-stats")
-        .arg("-ss").arg(opts.seek_seconds.to_string())
+        .args(match (&input, opts.seek_seconds) {
+                // Live streams can't be seeked
+                (MediaInput::Url(_), 0) => vec![],
+                _ => vec!["-ss".into(), opts.seek_seconds.to_string()],
+            })
         .arg("-hwaccel").arg("dxva2")
-        .arg("-i").arg(file_.as_os_str())
-        .args(if video_filters.len() > 0 {
-                vec!["-vf".into(), video_filters.join(",")]
+        .args(input.input_args())
+        .arg("-i").arg(input.as_os_str())
+        .args(video_args)
+        .args(map_args)
+        .args(if !audio_filters.is_empty() {
+                vec!["-af".into(), audio_filters.join(",")]
             } else {
                 vec![]
             })
         .arg("-acodec").arg("aac")
-        .arg("-c:v").arg("h264_nvenc")
+        .args(video_codec_args(hdr.filter(|_| opts.hdr_passthrough)))
         .arg("-preset").arg("slow")
         .arg("-b:v").arg("8M")
+        .args(match max_seconds {
+                Some(seconds) => vec!["-t".into(), seconds.to_string()],
+                None => vec![],
+            })
+        .args(if opts.output_offset_seconds != 0 {
+                vec!["-output_ts_offset".into(), opts.output_offset_seconds.to_string()]
+            } else {
+                vec![]
+            })
         .arg("-movflags").arg("frag_keyframe+empty_moov")
         .arg("-f").arg("mp4")
         .arg("pipe:1")
+        .kill_on_drop(true)
         .stdout(Stdio::piped()); // redirect the stdout
         // .stderr(Stdio::piped()); // redirect the stderr (suppressed)
 
-    let mut child = cmd.spawn()?;
+    cmd.spawn()
+}
+
+/// Audio format of the audio-only transcode
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioFormat {
+    Aac,
+    Mp3,
+}
+
+impl AudioFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            AudioFormat::Aac => "audio/aac",
+            AudioFormat::Mp3 => "audio/mpeg",
+        }
+    }
+}
+
+/// Spawns ffmpeg transcoding the audio of the file, written to the stdout of
+/// the child
+pub async fn encode_audio<P: AsRef<Path>>(
+    file: P,
+    format: AudioFormat,
+    seek_seconds: i32,
+    opts: &FFMpegAudioOpts,
+) -> Result<Child, std::io::Error> {
+    println!("Start encoding audio...");
+    let filters = opts.filters();
+    let (codec, bitrate, container) = match format {
+        AudioFormat::Aac => ("aac", "256k", "adts"),
+        AudioFormat::Mp3 => ("libmp3lame", "320k", "mp3"),
+    };
+    let mut cmd = ffmpeg_command(false);
+    #[rustfmt::skip]
+    cmd
+        .arg("-loglevel").arg("error")
+        .arg("-ss").arg(seek_seconds.to_string())
+        .arg("-i").arg(file.as_ref().as_os_str())
+        .arg("-vn")
+        .arg("-sn")
+        .args(if !filters.is_empty() {
+                vec!["-af".into(), filters.join(",")]
+            } else {
+                vec![]
+            })
+        .arg("-c:a").arg(codec)
+        .arg("-b:a").arg(bitrate)
+        .arg("-f").arg(container)
+        .arg("pipe:1")
+        .kill_on_drop(true)
+        .stdout(Stdio::piped());
+
+    cmd.spawn()
+}
+
+/// FFMpeg command, niced one runs with the lowest CPU priority
+fn ffmpeg_command(niced: bool) -> Command {
+    if niced && cfg!(unix) {
+        let mut cmd = Command::new("nice");
+        cmd.arg("-n").arg("19").arg("ffmpeg");
+        cmd
+    } else {
+        Command::new("ffmpeg")
+    }
+}
+
+/// Takes the stdout of the child as stream of bytes
+pub fn stdout_stream(
+    child: &mut Child,
+) -> Result<impl Stream<Item = Result<bytes::Bytes, std::io::Error>>, std::io::Error> {
+    // Fallback to string based error
+    let strerr = |err| std::io::Error::new(std::io::ErrorKind::Other, err);
+
     let stdout = child
         .stdout()
         .take()
@@ -282,6 +1484,437 @@ pub async fn encode<P: AsRef<Path>>(
     Ok(FramedRead::new(stdout, BytesCodec::new()).map_ok(|v| BytesMut::freeze(v)))
 }
 
+/// Skips the initialization segment (`ftyp` and `moov` boxes) of fragmented MP4
+///
+/// Used for appending a continuation encode to an earlier one, only the movie
+/// fragments of the continuation are needed.
+#[derive(Default)]
+pub struct InitSegmentSkipper {
+    buf: BytesMut,
+    skip: u64,
+    done: bool,
+}
+
+impl InitSegmentSkipper {
+    /// Feeds bytes of the stream, returns the bytes to pass through
+    pub fn feed(&mut self, chunk: bytes::Bytes) -> Option<bytes::Bytes> {
+        if self.done {
+            return Some(chunk);
+        }
+        self.buf.extend_from_slice(&chunk);
+        loop {
+            if self.skip > 0 {
+                let n = self.skip.min(self.buf.len() as u64) as usize;
+                let _ = self.buf.split_to(n);
+                self.skip -= n as u64;
+                if self.skip > 0 {
+                    return None;
+                }
+            }
+            if self.buf.len() < 8 {
+                return None;
+            }
+            let size = u64::from(u32::from_be_bytes([
+                self.buf[0],
+                self.buf[1],
+                self.buf[2],
+                self.buf[3],
+            ]));
+            let size = if size == 1 {
+                // 64-bit box size
+                if self.buf.len() < 16 {
+                    return None;
+                }
+                let mut large = [0u8; 8];
+                large.copy_from_slice(&self.buf[8..16]);
+                u64::from_be_bytes(large)
+            } else {
+                size
+            };
+            match &self.buf[4..8] {
+                b"ftyp" | b"moov" if size >= 8 => self.skip = size,
+                _ => {
+                    self.done = true;
+                    return Some(self.buf.split().freeze());
+                }
+            }
+        }
+    }
+}
+
+/// Measures the loudness of the whole file for the two-pass normalization
+///
+/// Audio is decoded through the same filters as in the encode, so this takes
+/// a while for long files.
+pub async fn measure_loudness<P: AsRef<Path>>(
+    file: P,
+    opts: &FFMpegAudioOpts,
+) -> Result<LoudnormMeasurement, std::io::Error> {
+    let mut filters = opts.pre_filters();
+    filters.push(format!("{}:print_format=json", opts.loudnorm_filter()));
+
+    let mut cmd = ffmpeg_command(true);
+    #[rustfmt::skip]
+    cmd
+        .arg("-loglevel").arg("info")
+        .arg("-nostats")
+        .arg("-i").arg(file.as_ref().as_os_str())
+        .arg("-vn")
+        .arg("-sn")
+        .arg("-af").arg(filters.join(","))
+        .arg("-f").arg("null")
+        .arg("-")
+        .kill_on_drop(true)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+
+    let out = cmd.output().await?;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    match parse_loudnorm_measurement(&stderr) {
+        Some(measured) if out.status.success() => Ok(measured),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "Unable to measure the loudness",
+        )),
+    }
+}
+
+/// Parses the JSON printed by loudnorm at the end of the log
+fn parse_loudnorm_measurement(log: &str) -> Option<LoudnormMeasurement> {
+    let start = log.rfind('{')?;
+    let end = log.rfind('}')?;
+    if end < start {
+        return None;
+    }
+    serde_json::from_str(&log[start..=end]).ok()
+}
+
+/// Extracts a single frame as JPEG, scaled to the width
+///
+/// Frames which are mostly black are skipped if `skip_black` is set, of the
+/// rest the most representative one of the first 50 is picked. Fails if no
+/// frame was written, e.g. seeking past the end or all frames being black.
+pub async fn extract_frame<P: AsRef<Path>, O: AsRef<Path>>(
+    file: P,
+    output: O,
+    seek_seconds: f32,
+    width: u32,
+    skip_black: bool,
+) -> Result<(), std::io::Error> {
+    let mut filters: Vec<String> = vec![];
+    if skip_black {
+        filters.push("blackframe=amount=0".into());
+        filters
+            .push("metadata=mode=select:key=lavfi.blackframe.pblack:value=90:function=less".into());
+    }
+    filters.push("thumbnail=50".into());
+    filters.push(format!("scale={}:-2", width));
+
+    let mut cmd = ffmpeg_command(true);
+    #[rustfmt::skip]
+    cmd
+        .arg("-loglevel").arg("error")
+        .arg("-ss").arg(seek_seconds.to_string())
+        .arg("-i").arg(file.as_ref().as_os_str())
+        .arg("-vf").arg(filters.join(","))
+        .arg("-frames:v").arg("1")
+        .arg("-q:v").arg("3")
+        .arg("-f").arg("image2")
+        .arg("-y")
+        .arg(output.as_ref().as_os_str())
+        .kill_on_drop(true)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    let status = cmd.status().await?;
+    let written = std::fs::metadata(output.as_ref()).map_or(0, |v| v.len());
+    if status.success() && written > 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "Unable to extract a frame",
+        ))
+    }
+}
+
+/// Scales the image upright to a JPEG, the width and height are the maximum
+///
+/// Without the height the image is scaled to the width like the thumbnails,
+/// with it the image is fit inside the box without upscaling.
+pub async fn scale_image<P: AsRef<Path>, O: AsRef<Path>>(
+    file: P,
+    output: O,
+    width: u32,
+    height: Option<u32>,
+) -> Result<(), std::io::Error> {
+    let mut filters = upright_filters(file.as_ref());
+    filters.push(match height {
+        Some(height) => format!(
+            "scale=w='min(iw,{})':h='min(ih,{})':force_original_aspect_ratio=decrease",
+            width, height
+        ),
+        None => format!("scale={}:-2", width),
+    });
+
+    let mut cmd = ffmpeg_command(true);
+    #[rustfmt::skip]
+    cmd
+        .arg("-loglevel").arg("error")
+        .args(upright_input_args(file.as_ref()))
+        .arg("-i").arg(file.as_ref().as_os_str())
+        .arg("-vf").arg(filters.join(","))
+        .arg("-frames:v").arg("1")
+        .arg("-q:v").arg("2")
+        .arg("-f").arg("image2")
+        .arg("-y")
+        .arg(output.as_ref().as_os_str())
+        .kill_on_drop(true)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    let status = cmd.status().await?;
+    let written = std::fs::metadata(output.as_ref()).map_or(0, |v| v.len());
+    if status.success() && written > 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "Unable to scale the image",
+        ))
+    }
+}
+
+/// Frame rate of the Ken Burns videos
+const KEN_BURNS_FPS: u32 = 25;
+
+/// Zoom at the end of the Ken Burns video
+const KEN_BURNS_ZOOM: f32 = 1.2;
+
+/// Spawns ffmpeg rendering the image to a slowly zooming video, written to the
+/// stdout of the child as fragmented MP4
+///
+/// The image is fit inside the frame with black bars, and scaled to double
+/// size before zooming to avoid the jitter of zoompan.
+pub async fn encode_ken_burns<P: AsRef<Path>>(
+    file: P,
+    seconds: u32,
+    width: u32,
+    height: u32,
+) -> Result<Child, std::io::Error> {
+    let frames = (seconds * KEN_BURNS_FPS).max(1);
+    let mut filters = upright_filters(file.as_ref());
+    filters.push(format!(
+        "scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,setsar=1",
+        w = width * 2,
+        h = height * 2
+    ));
+    filters.push(format!(
+        "zoompan=z='1+{}*on/{}':x='iw/2-(iw/zoom/2)':y='ih/2-(ih/zoom/2)':d={}:s={}x{}:fps={}",
+        KEN_BURNS_ZOOM - 1.0,
+        frames,
+        frames,
+        width,
+        height,
+        KEN_BURNS_FPS
+    ));
+    filters.push("format=yuv420p".into());
+
+    let mut cmd = ffmpeg_command(false);
+    #[rustfmt::skip]
+    cmd
+        .arg("-loglevel").arg("error")
+        .args(upright_input_args(file.as_ref()))
+        .arg("-i").arg(file.as_ref().as_os_str())
+        .arg("-vf").arg(filters.join(","))
+        .arg("-frames:v").arg(frames.to_string())
+        .arg("-c:v").arg("libx264")
+        .arg("-preset").arg("veryfast")
+        .arg("-tune").arg("stillimage")
+        .arg("-movflags").arg("frag_keyframe+empty_moov")
+        .arg("-f").arg("mp4")
+        .arg("pipe:1")
+        .kill_on_drop(true)
+        .stdout(Stdio::piped());
+
+    cmd.spawn()
+}
+
+/// Extracts a frame every `interval` seconds tiled to sprite sheets
+///
+/// Sheets are written with the numbered output pattern, e.g.
+/// `sheet-%03d.jpg`. Only the keyframes are decoded, so the frames are from the
+/// nearest preceding keyframe.
+pub async fn extract_tiles<P: AsRef<Path>, O: AsRef<Path>>(
+    file: P,
+    output_pattern: O,
+    interval: u32,
+    width: u32,
+    (columns, rows): (u32, u32),
+) -> Result<(), std::io::Error> {
+    let filters = format!(
+        "fps=1/{},scale={}:-2,tile={}x{}",
+        interval, width, columns, rows
+    );
+    let mut cmd = ffmpeg_command(true);
+    #[rustfmt::skip]
+    cmd
+        .arg("-loglevel").arg("error")
+        .arg("-skip_frame").arg("nokey")
+        .arg("-i").arg(file.as_ref().as_os_str())
+        .arg("-an")
+        .arg("-sn")
+        .arg("-vf").arg(filters)
+        .arg("-vsync").arg("vfr")
+        .arg("-q:v").arg("5")
+        .arg("-f").arg("image2")
+        .arg("-y")
+        .arg(output_pattern.as_ref().as_os_str())
+        .kill_on_drop(true)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    let status = cmd.status().await?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "Unable to extract the frames",
+        ))
+    }
+}
+
+/// Seconds of audio in a point of the Chromaprint fingerprint
+pub const FINGERPRINT_POINT_SECONDS: f32 = 0.1238;
+
+/// Chromaprint fingerprint of the first `seconds` of the audio
+///
+/// Needs ffmpeg built with the chromaprint muxer. Each point of the
+/// fingerprint covers `FINGERPRINT_POINT_SECONDS` of audio.
+pub async fn audio_fingerprint<P: AsRef<Path>>(
+    file: P,
+    seconds: u32,
+) -> Result<Vec<u32>, std::io::Error> {
+    let mut cmd = ffmpeg_command(true);
+    #[rustfmt::skip]
+    cmd
+        .arg("-loglevel").arg("error")
+        .arg("-i").arg(file.as_ref().as_os_str())
+        .arg("-t").arg(seconds.to_string())
+        .arg("-map").arg("0:a:0")
+        .arg("-ac").arg("2")
+        .arg("-f").arg("chromaprint")
+        .arg("-fp_format").arg("raw")
+        .arg("pipe:1")
+        .kill_on_drop(true)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+
+    let out = cmd.output().await?;
+    if !out.status.success() || out.stdout.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "Unable to fingerprint the audio",
+        ));
+    }
+    Ok(out
+        .stdout
+        .chunks_exact(4)
+        .map(|v| u32::from_ne_bytes([v[0], v[1], v[2], v[3]]))
+        .collect())
+}
+
+/// Black and silent intervals of the file, seconds from the beginning
+#[derive(Default, PartialEq, Debug)]
+pub struct BlackAndSilence {
+    pub black: Vec<(f32, f32)>,
+    pub silence: Vec<(f32, f32)>,
+}
+
+/// Detects black frames and silence starting from the position
+///
+/// Intervals shorter than half a second are ignored.
+pub async fn detect_black_and_silence<P: AsRef<Path>>(
+    file: P,
+    from_seconds: f32,
+) -> Result<BlackAndSilence, std::io::Error> {
+    let mut cmd = ffmpeg_command(true);
+    #[rustfmt::skip]
+    cmd
+        .arg("-loglevel").arg("info")
+        .arg("-nostats")
+        .arg("-ss").arg(from_seconds.to_string())
+        .arg("-i").arg(file.as_ref().as_os_str())
+        .arg("-sn")
+        .arg("-vf").arg("blackdetect=d=0.5:pix_th=0.10")
+        .arg("-af").arg("silencedetect=noise=-50dB:d=0.5")
+        .arg("-f").arg("null")
+        .arg("-")
+        .kill_on_drop(true)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+
+    let out = cmd.output().await?;
+    if !out.status.success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "Unable to detect black frames and silence",
+        ));
+    }
+    Ok(parse_black_and_silence(
+        &String::from_utf8_lossy(&out.stderr),
+        from_seconds,
+    ))
+}
+
+/// Parses the blackdetect and silencedetect filter logs, the timestamps of
+/// the log are relative to the offset
+fn parse_black_and_silence(log: &str, offset: f32) -> BlackAndSilence {
+    let value = |line: &str, key: &str| -> Option<f32> {
+        let rest = line[line.find(key)? + key.len()..].trim_start();
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '|')
+            .unwrap_or(rest.len());
+        rest[..end].parse::<f32>().ok().map(|v| v + offset)
+    };
+    let mut result = BlackAndSilence::default();
+    let mut silence_start = None;
+    for line in log.lines() {
+        let black = (value(line, "black_start:"), value(line, "black_end:"));
+        if let (Some(start), Some(end)) = black {
+            result.black.push((start, end));
+        } else if let Some(start) = value(line, "silence_start:") {
+            silence_start = Some(start);
+        } else if let Some(end) = value(line, "silence_end:") {
+            if let Some(start) = silence_start.take() {
+                result.silence.push((start, end));
+            }
+        }
+    }
+    result
+}
+
+/// Seconds into the chapter after which going to the previous chapter
+/// restarts the current one instead
+const CHAPTER_RESTART_SECONDS: f32 = 3.0;
+
+/// Chapter after the position
+pub fn next_chapter(chapters: &[Chapter], position: f32) -> Option<&Chapter> {
+    chapters.iter().find(|v| v.start > position + 0.5)
+}
+
+/// Beginning of the current chapter, or the previous chapter if the position
+/// is near the beginning of the current one
+pub fn previous_chapter(chapters: &[Chapter], position: f32) -> Option<&Chapter> {
+    let current = chapters.iter().rposition(|v| v.start <= position)?;
+    if position - chapters[current].start > CHAPTER_RESTART_SECONDS || current == 0 {
+        Some(&chapters[current])
+    } else {
+        Some(&chapters[current - 1])
+    }
+}
+
 // Unit tests
 #[cfg(test)]
 mod tests {
@@ -289,15 +1922,332 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_info() {
-        let result = get_info(r"./test_data/big_buck_bunny.mp4").await.unwrap();
+        let mut result = get_info(r"./test_data/big_buck_bunny.mp4").await.unwrap();
+        let tracks = result.tracks.take().unwrap();
+        assert!(tracks.subtitles.is_empty());
         assert_eq!(
             VideoInfo {
                 codec_name: "h264".into(),
                 width: 1920,
                 height: 1080,
-                duration: 596.50134
+                duration: 596.50134,
+                chapters: vec![],
+                hdr: None,
+                interlaced: Some(false),
+                frame_rate: Some(24.0),
+                tracks: None,
             },
             result
         );
     }
+
+    #[test]
+    fn test_tracks() {
+        let result: FFProbeResult = serde_json::from_str(r#"{
+            "streams": [
+                { "codec_type": "video", "codec_name": "h264", "width": 1920, "height": 1080 },
+                { "codec_type": "audio", "codec_name": "ac3", "tags": { "language": "fin" } },
+                { "codec_type": "audio", "codec_name": "aac", "tags": { "title": "Commentary" } },
+                { "codec_type": "subtitle", "codec_name": "hdmv_pgs_subtitle" }
+            ],
+            "format": { "duration": "10.0" }
+        }"#).unwrap();
+        let tracks = Tracks::new(&result.streams);
+        assert_eq!(tracks.audio.len(), 2);
+        assert_eq!(tracks.audio[0].language.as_deref(), Some("fin"));
+        assert_eq!(tracks.audio[1].title.as_deref(), Some("Commentary"));
+        assert!(tracks.subtitles[0].is_bitmap());
+        assert!(!tracks.audio[0].is_bitmap());
+    }
+
+    #[test]
+    fn test_chapter_navigation() {
+        let chapter = |start: f32, end: f32| Chapter {
+            start,
+            end,
+            title: None,
+        };
+        let chapters = vec![chapter(0.0, 60.0), chapter(60.0, 120.0), chapter(120.0, 180.0)];
+        assert_eq!(next_chapter(&chapters, 30.0), Some(&chapters[1]));
+        assert_eq!(next_chapter(&chapters, 130.0), None);
+        assert_eq!(previous_chapter(&chapters, 90.0), Some(&chapters[1]));
+        assert_eq!(previous_chapter(&chapters, 61.0), Some(&chapters[0]));
+        assert_eq!(previous_chapter(&chapters, 1.0), Some(&chapters[0]));
+    }
+
+    #[test]
+    fn test_parse_black_and_silence() {
+        let log = "\
+            [blackdetect @ 0x1] black_start:12.5 black_end:14 black_duration:1.5\n\
+            [silencedetect @ 0x2] silence_start: 12.25\n\
+            [silencedetect @ 0x2] silence_end: 14.5 | silence_duration: 2.25\n\
+            [silencedetect @ 0x2] silence_start: 30\n";
+        assert_eq!(
+            parse_black_and_silence(log, 100.0),
+            BlackAndSilence {
+                black: vec![(112.5, 114.0)],
+                silence: vec![(112.25, 114.5)],
+            }
+        );
+    }
+
+    #[test]
+    fn test_hdr_format() {
+        assert_eq!(
+            HdrFormat::from_color(Some("smpte2084"), Some("bt2020")),
+            Some(HdrFormat::Hdr10)
+        );
+        assert_eq!(
+            HdrFormat::from_color(Some("arib-std-b67"), None),
+            Some(HdrFormat::Hlg)
+        );
+        assert_eq!(HdrFormat::from_color(Some("bt709"), Some("bt709")), None);
+        assert_eq!(HdrFormat::from_color(None, None), None);
+        assert_eq!(
+            tonemap_filters(HdrFormat::Hlg, Tonemap::Mobius)[3],
+            "tonemap=tonemap=mobius:desat=0"
+        );
+        assert_eq!(video_codec_args(None), vec!["-c:v", "h264_nvenc"]);
+        let args = video_codec_args(Some(HdrFormat::Hdr10));
+        assert_eq!(&args[..6], &["-c:v", "hevc_nvenc", "-profile:v", "main10", "-pix_fmt", "p010le"]);
+        assert!(args.contains(&"smpte2084".to_string()));
+    }
+
+    #[test]
+    fn test_deinterlace_and_frame_rate() {
+        assert_eq!(parse_frame_rate("30000/1001"), Some(29.97003));
+        assert_eq!(parse_frame_rate("0/0"), None);
+        assert_eq!(
+            parse_idet(
+                "[Parsed_idet_0 @ 0x1] Single frame detection: TFF: 80 BFF: 0 \
+                 Progressive: 150 Undetermined: 70\n\
+                 [Parsed_idet_0 @ 0x1] Multi frame detection: TFF: 290 BFF: 0 \
+                 Progressive: 5 Undetermined: 5\n"
+            ),
+            Some(true)
+        );
+        assert_eq!(parse_idet(""), None);
+        assert_eq!(
+            deinterlace_filter(Deinterlacer::Yadif, true),
+            "yadif=mode=send_field:parity=auto:deint=all"
+        );
+
+        let opts = EncodeOpts {
+            max_frame_rate: Some(30.0),
+            ..EncodeOpts::default()
+        };
+        assert_eq!(frame_rate_filter(Some(50.0), &opts), Some("fps=30".into()));
+        assert_eq!(frame_rate_filter(Some(29.97), &opts), None);
+        assert_eq!(frame_rate_filter(None, &opts), None);
+    }
+
+    #[test]
+    fn test_audio_tags() {
+        let tags: HashMap<String, String> = vec![
+            ("TITLE", "Song"),
+            ("ARTIST", "Band"),
+            ("album_artist", "Band"),
+            ("album", "Album"),
+            ("date", "1999-05-01"),
+            ("track", "3/12"),
+            ("genre", " "),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(
+            AudioTags::from_tags(&tags),
+            AudioTags {
+                title: Some("Song".into()),
+                artist: Some("Band".into()),
+                album: Some("Album".into()),
+                album_artist: Some("Band".into()),
+                year: Some(1999),
+                track: Some(3),
+                ..AudioTags::default()
+            }
+        );
+        assert!(is_audio_file("music/01 Song.FLAC"));
+        assert!(!is_audio_file("movie.mkv"));
+    }
+
+    #[test]
+    fn test_audio_filters() {
+        assert!(FFMpegAudioOpts::default().filters().is_empty());
+
+        let log = "[Parsed_loudnorm_0 @ 0x1] \n{\n\t\"input_i\" : \"-27.61\",\n\
+            \t\"input_tp\" : \"-4.47\",\n\t\"input_lra\" : \"18.06\",\n\
+            \t\"input_thresh\" : \"-39.20\",\n\t\"normalization_type\" : \"dynamic\",\n\
+            \t\"target_offset\" : \"0.58\"\n}\n";
+        let opts = FFMpegAudioOpts {
+            loudnorm_two_pass: true,
+            night_mode: true,
+            downmix_stereo: true,
+            dialogue_boost_db: 6.0,
+            measured: parse_loudnorm_measurement(log),
+            ..FFMpegAudioOpts::default()
+        };
+        assert_eq!(
+            opts.filters(),
+            vec![
+                "aformat=channel_layouts=5.1",
+                "pan=stereo|FL=1.411*FC+0.707*FL+0.707*BL|FR=1.411*FC+0.707*FR+0.707*BR",
+                "acompressor=threshold=0.05:ratio=4:attack=10:release=300:makeup=4",
+                "loudnorm=I=-23:TP=-2:LRA=7:measured_I=-27.61:measured_TP=-4.47\
+                 :measured_LRA=18.06:measured_thresh=-39.20:offset=0.58:linear=true",
+                "aresample=48000",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_init_segment_skipper() {
+        let mut data: Vec<u8> = vec![];
+        data.extend_from_slice(&[0, 0, 0, 12]);
+        data.extend_from_slice(b"ftypisom");
+        data.extend_from_slice(&[0, 0, 0, 10]);
+        data.extend_from_slice(b"moov00");
+        data.extend_from_slice(&[0, 0, 0, 9]);
+        data.extend_from_slice(b"moof1");
+        data.extend_from_slice(&[0, 0, 0, 9]);
+        data.extend_from_slice(b"mdat2");
+
+        let mut skipper = InitSegmentSkipper::default();
+        let mut out: Vec<u8> = vec![];
+        for chunk in data.chunks(5) {
+            if let Some(v) = skipper.feed(bytes::Bytes::copy_from_slice(chunk)) {
+                out.extend_from_slice(&v);
+            }
+        }
+        assert_eq!(&out[4..8], b"moof");
+        assert_eq!(out.len(), 18);
+    }
+
+    #[test]
+    fn test_exif_orientation() {
+        // JPEG with an APP1 segment, big-endian TIFF with two IFD entries
+        let mut data: Vec<u8> = vec![0xff, 0xd8, 0xff, 0xe1, 0x00, 0x2a];
+        data.extend_from_slice(b"Exif\0\0MM\0*\0\0\0\x08\0\x02");
+        data.extend_from_slice(&[0x01, 0x0f, 0, 2, 0, 0, 0, 6, b'C', b'a', b'm', 0]);
+        data.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+        assert_eq!(parse_exif_orientation(&data), Some(6));
+
+        // WebP EXIF chunk, little-endian
+        let mut data: Vec<u8> = b"RIFF\0\0\0\0WEBPEXIF\x1a\0\0\0II*\0\x08\0\0\0\x01\0".to_vec();
+        data.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 3, 0, 0, 0]);
+        assert_eq!(parse_exif_orientation(&data), Some(3));
+
+        assert_eq!(parse_exif_orientation(b"\xff\xd8\xff\xdbExif"), None);
+        assert!(orientation_filters(1).is_empty());
+        assert_eq!(orientation_filters(8), vec!["transpose=cclock"]);
+        assert!(is_image_file("a/IMG_0001.JPG"));
+        assert!(!is_image_file("a/movie.mkv"));
+    }
+
+    #[test]
+    fn test_is_allowed_source() {
+        let sources = ["rtsp://camera.local", "http://*.lan:8080", "udp"];
+        let allowed = |url: &str| is_allowed_source(&Url::parse(url).unwrap(), &sources);
+        assert!(allowed("rtsp://camera.local/stream1"));
+        assert!(allowed("rtsp://CAMERA.local:554/stream1"));
+        assert!(allowed("http://tv.lan:8080/live.ts"));
+        assert!(allowed("udp://239.0.0.1:1234"));
+        assert!(!allowed("rtsp://other.local/stream1"));
+        assert!(!allowed("http://tv.lan/live.ts"));
+        assert!(!allowed("http://lan:8080/live.ts"));
+        assert!(!allowed("https://tv.lan:8080/live.ts"));
+        assert!(!allowed("file:///etc/passwd"));
+        assert!(!is_allowed_source(
+            &Url::parse("file:///etc/passwd").unwrap(),
+            &["file"]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_input_info_url() {
+        // Local HTTP stand-in for a network source
+        let make_svc = hyper::service::make_service_fn(|_| async {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|_| async {
+                let file = tokio::fs::File::open("./test_data/big_buck_bunny.mp4").await?;
+                let body = FramedRead::new(file, BytesCodec::new()).map_ok(BytesMut::freeze);
+                Ok::<_, std::io::Error>(hyper::Response::new(hyper::Body::wrap_stream(body)))
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/big_buck_bunny.mp4", server.local_addr());
+        tokio::spawn(server);
+
+        let input = MediaInput::Url(Url::parse(&url).unwrap());
+        let result = get_input_info(&input).await.unwrap();
+        assert_eq!(
+            (result.codec_name.as_str(), result.width, result.height),
+            ("h264", 1920, 1080)
+        );
+    }
+    #[tokio::test]
+    async fn test_encode_url() {
+        // Local HTTP stand-in for a network source
+        let make_svc = hyper::service::make_service_fn(|_| async {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|_| async {
+                let file = tokio::fs::File::open("./test_data/big_buck_bunny.mp4").await?;
+                let body = FramedRead::new(file, BytesCodec::new()).map_ok(BytesMut::freeze);
+                Ok::<_, std::io::Error>(hyper::Response::new(hyper::Body::wrap_stream(body)))
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/big_buck_bunny.mp4", server.local_addr());
+        tokio::spawn(server);
+
+        let input = MediaInput::Url(Url::parse(&url).unwrap());
+        let opts = EncodeOpts {
+            seek_seconds: 5,
+            ..Default::default()
+        };
+        let mut child = encode(input, &opts, None).await.unwrap();
+        let mut stdout = stdout_stream(&mut child).unwrap();
+        let mut head = vec![];
+        while head.len() < 8 {
+            head.extend_from_slice(&stdout.try_next().await.unwrap().unwrap());
+        }
+        // Fragmented MP4 starts with the file type box
+        assert_eq!(&head[4..8], b"ftyp");
+        let _ = child.kill();
+    }
+
+    #[tokio::test]
+    async fn test_resolve_source() {
+        // Local HTTP stand-in redirecting to itself and elsewhere
+        let make_svc = hyper::service::make_service_fn(|_| async {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                |request: hyper::Request<hyper::Body>| async move {
+                    let location = match request.uri().path() {
+                        "/moved" => "/live.ts",
+                        "/away" => "http://other.invalid/live.ts",
+                        "/loop" => "/loop",
+                        _ => return Ok(hyper::Response::new(hyper::Body::from("data"))),
+                    };
+                    hyper::Response::builder()
+                        .status(302)
+                        .header("Location", location)
+                        .body(hyper::Body::empty())
+                },
+            ))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let base = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let sources = ["http://127.0.0.1"];
+        let resolve = |path: &str| {
+            let url = base.join(path).unwrap();
+            async move { resolve_source(&url, &sources).await }
+        };
+        let live = base.join("/live.ts").unwrap();
+        assert_eq!(resolve("/live.ts").await.unwrap(), Some(live.clone()));
+        assert_eq!(resolve("/moved").await.unwrap(), Some(live));
+        assert_eq!(resolve("/away").await.unwrap(), None);
+        assert!(resolve("/loop").await.is_err());
+        let other = Url::parse("http://other.invalid/live.ts").unwrap();
+        assert_eq!(resolve_source(&other, &sources).await.unwrap(), None);
+    }
 }
@@ -0,0 +1,418 @@
+/// Local mpv player controlled through its JSON IPC socket
+///
+/// Casting works the same as with the cast devices, so the whole flow can be
+/// tried without them. mpv is started in idle mode with the socket if nothing
+/// answers at the socket, e.g. `--mpv-args=--vo=null,--ao=null` plays headless.
+use crate::renderer::{Renderer, RendererError, RendererStatus, StatusCallback};
+use derive_more::From;
+use rust_cast::channels::media::{IdleReason, Metadata, PlayerState};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Timeout of the replies to the commands
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time waited for the started mpv to open the socket
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval of the status callbacks while the position changes
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, From)]
+pub enum MpvError {
+    #[from(ignore)]
+    CommandFailed(String),
+    InvalidReply(serde_json::Error),
+    IoError(std::io::Error),
+}
+
+/// Default socket of the player
+pub fn default_socket() -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(r"\\.\pipe\casterson-mpv")
+    } else {
+        std::env::temp_dir().join("casterson-mpv.sock")
+    }
+}
+
+#[derive(Clone)]
+pub struct MpvRenderer {
+    socket: PathBuf,
+
+    // Arguments of mpv if it's started
+    args: Vec<String>,
+}
+
+pub fn get_renderer(socket: Option<PathBuf>, args: &[String]) -> MpvRenderer {
+    MpvRenderer {
+        socket: socket.unwrap_or_else(default_socket),
+        args: args.to_vec(),
+    }
+}
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+#[cfg(unix)]
+fn open(
+    socket: &Path,
+    timeout: Option<Duration>,
+) -> std::io::Result<(Box<dyn Stream>, Box<dyn Read + Send>)> {
+    let stream = std::os::unix::net::UnixStream::connect(socket)?;
+    stream.set_read_timeout(timeout)?;
+    Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+}
+
+#[cfg(windows)]
+fn open(
+    socket: &Path,
+    _timeout: Option<Duration>,
+) -> std::io::Result<(Box<dyn Stream>, Box<dyn Read + Send>)> {
+    let pipe = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(socket)?;
+    Ok((Box::new(pipe.try_clone()?), Box::new(pipe)))
+}
+
+/// Connection to the player, replies and events are read line by line
+struct Connection {
+    writer: Box<dyn Stream>,
+    reader: BufReader<Box<dyn Read + Send>>,
+    request_id: u64,
+}
+
+impl Connection {
+    fn open(renderer: &MpvRenderer, timeout: Option<Duration>) -> Result<Connection, MpvError> {
+        let (writer, reader) = match open(&renderer.socket, timeout) {
+            Ok(v) => v,
+            Err(_) => {
+                start(renderer)?;
+                open(&renderer.socket, timeout)?
+            }
+        };
+        Ok(Connection {
+            writer,
+            reader: BufReader::new(reader),
+            request_id: 0,
+        })
+    }
+
+    /// Next message, None when the player closes the connection
+    fn read(&mut self) -> Result<Option<Value>, MpvError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&line)?))
+    }
+
+    /// Runs the command and returns the data of the reply, events before the
+    /// reply are skipped
+    fn command(&mut self, command: Value) -> Result<Value, MpvError> {
+        self.request_id += 1;
+        let request = json!({ "command": command, "request_id": self.request_id });
+        self.writer.write_all(format!("{}\n", request).as_bytes())?;
+        loop {
+            let message = self
+                .read()?
+                .ok_or_else(|| MpvError::IoError(std::io::ErrorKind::UnexpectedEof.into()))?;
+            if message["request_id"] != self.request_id {
+                continue;
+            }
+            return match message["error"].as_str() {
+                Some("success") => Ok(message["data"].clone()),
+                Some(error) => Err(MpvError::CommandFailed(error.into())),
+                None => Err(MpvError::CommandFailed("".into())),
+            };
+        }
+    }
+
+    fn status(&mut self) -> Result<RendererStatus, MpvError> {
+        let idle = self.command(json!(["get_property", "idle-active"]))?;
+        if idle == true {
+            return Ok(idle_status(None));
+        }
+        let paused = self.command(json!(["get_property", "pause"]))?;
+        // Position is unavailable until the file is loaded
+        let position = self
+            .command(json!(["get_property", "time-pos"]))
+            .ok()
+            .and_then(|v| v.as_f64());
+        Ok(RendererStatus {
+            current_time: position.map(|v| v as f32),
+            player_state: if paused == true {
+                PlayerState::Paused
+            } else {
+                PlayerState::Playing
+            },
+            idle_reason: None,
+        })
+    }
+}
+
+/// Starts mpv in idle mode and waits for the socket
+fn start(renderer: &MpvRenderer) -> Result<(), MpvError> {
+    println!("Starting mpv at {}", renderer.socket.display());
+    Command::new("mpv")
+        .arg("--idle=yes")
+        .arg("--force-window=no")
+        .arg(format!("--input-ipc-server={}", renderer.socket.display()))
+        .args(&renderer.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let started = Instant::now();
+    while started.elapsed() < START_TIMEOUT {
+        if open(&renderer.socket, None).is_ok() {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Err(MpvError::CommandFailed("mpv didn't open the socket".into()))
+}
+
+fn idle_status(reason: Option<IdleReason>) -> RendererStatus {
+    RendererStatus {
+        current_time: None,
+        player_state: PlayerState::Idle,
+        idle_reason: reason,
+    }
+}
+
+/// Idle reason of the end-file event
+fn end_reason(event: &Value) -> IdleReason {
+    match event["reason"].as_str() {
+        Some("eof") => IdleReason::Finished,
+        Some("error") => IdleReason::Error,
+        Some("redirect") => IdleReason::Interrupted,
+        _ => IdleReason::Cancelled,
+    }
+}
+
+impl Renderer for MpvRenderer {
+    fn play(&self) -> Result<RendererStatus, RendererError> {
+        Ok(manage(self, json!(["set_property", "pause", false]))?)
+    }
+    fn pause(&self) -> Result<RendererStatus, RendererError> {
+        Ok(manage(self, json!(["set_property", "pause", true]))?)
+    }
+    fn stop(&self) -> Result<RendererStatus, RendererError> {
+        Ok(manage(self, json!(["stop"]))?)
+    }
+    fn seek(&self, seconds: f32) -> Result<RendererStatus, RendererError> {
+        Ok(manage(self, json!(["seek", seconds, "absolute"]))?)
+    }
+    fn set_volume(&self, level: f32) -> Result<(), RendererError> {
+        let volume = (level.clamp(0.0, 1.0) * 100.0).round();
+        manage(self, json!(["set_property", "volume", volume]))?;
+        Ok(())
+    }
+    fn cast(
+        &self,
+        url: Url,
+        _content_type: Option<String>,
+        metadata: Option<Metadata>,
+        on_status: StatusCallback,
+    ) -> Result<(), RendererError> {
+        Ok(cast(self, url, metadata, on_status)?)
+    }
+    fn get_status(&self) -> Result<RendererStatus, RendererError> {
+        Ok(Connection::open(self, Some(TIMEOUT))?.status()?)
+    }
+    fn supports_hdr(&self) -> bool {
+        // mpv tone maps HDR video to the display itself
+        true
+    }
+}
+
+fn manage(renderer: &MpvRenderer, command: Value) -> Result<RendererStatus, MpvError> {
+    let mut connection = Connection::open(renderer, Some(TIMEOUT))?;
+    connection.command(command)?;
+    connection.status()
+}
+
+fn cast(
+    renderer: &MpvRenderer,
+    url: Url,
+    metadata: Option<Metadata>,
+    mut on_status: StatusCallback,
+) -> Result<(), MpvError> {
+    let mut connection = Connection::open(renderer, Some(TIMEOUT))?;
+    let title = match metadata {
+        Some(Metadata::Movie(v)) => v.title,
+        Some(Metadata::TvShow(v)) => v.episode_title.or(v.series_title),
+        Some(Metadata::MusicTrack(v)) => v.title,
+        Some(Metadata::Photo(v)) => v.title,
+        Some(Metadata::Generic(v)) => v.title,
+        None => None,
+    };
+    // Named arguments, the options are passed as is without escaping
+    let mut options = json!({ "pause": "no" });
+    if let Some(title) = title {
+        options["force-media-title"] = title.into();
+    }
+    connection.command(json!(["observe_property", 1, "pause"]))?;
+    connection.command(json!(["observe_property", 2, "time-pos"]))?;
+    connection.command(json!({
+        "name": "loadfile",
+        "url": url.as_str(),
+        "flags": "replace",
+        "options": options,
+    }))?;
+
+    // Events of the file replaced come before the start-file of this one
+    let mut started = false;
+    let mut paused = false;
+    let mut position: Option<f32> = None;
+    let mut reported: Option<Instant> = None;
+    loop {
+        let event = match connection.read() {
+            Ok(Some(event)) => event,
+            Ok(None) => {
+                println!("[mpv] Connection closed");
+                on_status(&idle_status(Some(IdleReason::Cancelled)));
+                break;
+            }
+            // Read timeout while paused
+            Err(MpvError::IoError(err))
+                if err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(err) => return Err(err),
+        };
+        match event["event"].as_str() {
+            Some("start-file") => started = true,
+            Some("end-file") if started => {
+                let reason = end_reason(&event);
+                println!("[mpv] End of file {:?}", reason);
+                on_status(&idle_status(Some(reason)));
+                break;
+            }
+            Some("property-change") if started => {
+                let changed = match event["name"].as_str() {
+                    Some("pause") => {
+                        paused = event["data"] == true;
+                        true
+                    }
+                    Some("time-pos") => {
+                        position = event["data"].as_f64().map(|v| v as f32);
+                        reported.is_none_or(|v| v.elapsed() >= STATUS_INTERVAL)
+                    }
+                    _ => false,
+                };
+                if changed && position.is_some() {
+                    reported = Some(Instant::now());
+                    on_status(&RendererStatus {
+                        current_time: position,
+                        player_state: if paused {
+                            PlayerState::Paused
+                        } else {
+                            PlayerState::Playing
+                        },
+                        idle_reason: None,
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+    println!("Close thread!");
+    Ok(())
+}
+
+// Unit tests
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use rust_cast::channels::media::GenericMediaMetadata;
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Mutex};
+
+    /// Answers the commands like mpv and plays the loaded file to the end
+    fn fake_mpv(socket: &Path) -> std::thread::JoinHandle<Vec<Value>> {
+        let listener = UnixListener::bind(socket).unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut commands = vec![];
+            let mut send = |v: Value| writer.write_all(format!("{}\n", v).as_bytes()).unwrap();
+            for line in BufReader::new(stream).lines() {
+                let request: Value = serde_json::from_str(&line.unwrap()).unwrap();
+                let command = request["command"].clone();
+                send(
+                    json!({ "error": "success", "data": null, "request_id": request["request_id"] }),
+                );
+                if command["name"] == "loadfile" {
+                    // End of the replaced file is ignored
+                    send(json!({ "event": "end-file", "reason": "stop" }));
+                    send(json!({ "event": "start-file" }));
+                    send(json!({ "event": "property-change", "name": "pause", "data": false }));
+                    send(json!({ "event": "property-change", "name": "time-pos", "data": 1.5 }));
+                    send(json!({ "event": "end-file", "reason": "eof" }));
+                }
+                commands.push(command);
+            }
+            commands
+        })
+    }
+
+    #[test]
+    fn test_cast() {
+        let socket =
+            std::env::temp_dir().join(format!("casterson-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let server = fake_mpv(&socket);
+        let renderer = get_renderer(Some(socket.clone()), &[]);
+        let statuses = Arc::new(Mutex::new(vec![]));
+        let statuses_ = statuses.clone();
+        let on_status: StatusCallback = Box::new(move |status: &RendererStatus| {
+            statuses_.lock().unwrap().push((
+                status.current_time,
+                status.player_state.to_string(),
+                status.idle_reason.is_some(),
+            ));
+        });
+        let url: Url = "http://127.0.0.1:3000/media_show?{\"id\":\"abc\"}"
+            .parse()
+            .unwrap();
+        let metadata = Metadata::Generic(GenericMediaMetadata {
+            title: Some("Hello, World".into()),
+            subtitle: None,
+            images: vec![],
+            release_date: None,
+        });
+        renderer
+            .cast(url.clone(), None, Some(metadata), on_status)
+            .unwrap();
+        let commands = server.join().unwrap();
+        let _ = std::fs::remove_file(&socket);
+
+        let loadfile = commands.last().unwrap();
+        assert_eq!(loadfile["name"], "loadfile");
+        assert_eq!(loadfile["url"], url.as_str());
+        assert_eq!(loadfile["options"]["force-media-title"], "Hello, World");
+        let statuses = statuses.lock().unwrap();
+        assert_eq!(statuses[0], (Some(1.5), "PLAYING".into(), false));
+        assert_eq!(statuses[1], (None, "IDLE".into(), true));
+        assert_eq!(statuses.len(), 2);
+    }
+
+    #[test]
+    fn test_end_reason() {
+        assert!(matches!(
+            end_reason(&json!({ "reason": "eof" })),
+            IdleReason::Finished
+        ));
+        assert!(matches!(
+            end_reason(&json!({ "reason": "quit" })),
+            IdleReason::Cancelled
+        ));
+    }
+}
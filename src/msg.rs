@@ -1,7 +1,16 @@
 use crate::api;
+use serde::Serialize;
 
 pub enum NotifyMessage {
     EncodingStarted,
     RequestClosed,
     ErrorDuringCasting(api::ApiError),
+    LibraryChanged,
+}
+
+/// Events sent to the clients of the event stream
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Event {
+    LibraryChanged,
 }
@@ -0,0 +1,312 @@
+/// TV-show and movie recognition from file names
+///
+/// Understands the common scene style names such as
+/// `Show.Name.S02E05.Episode.Title.1080p.WEB-DL.mkv`, `Show Name 2x05.mkv` and
+/// `Movie Name (2019) [1080p].mp4`.
+use serde::{Deserialize, Serialize};
+
+/// Release tags recognized in the file names, compared case-insensitively
+const RELEASE_TAGS: &[&str] = &[
+    "bluray",
+    "blu-ray",
+    "bdrip",
+    "brrip",
+    "remux",
+    "web-dl",
+    "webdl",
+    "webrip",
+    "hdtv",
+    "dvdrip",
+    "dvd",
+    "hdrip",
+    "x264",
+    "x265",
+    "h264",
+    "h265",
+    "hevc",
+    "avc",
+    "xvid",
+    "10bit",
+    "hdr",
+    "hdr10",
+    "aac",
+    "ac3",
+    "dts",
+    "atmos",
+    "truehd",
+    "ddp5.1",
+    "dd5.1",
+    "repack",
+    "unrated",
+    "remastered",
+    "internal",
+    "subbed",
+];
+
+/// Release tags which are also common words, only recognized after the year,
+/// the resolution or other tags
+const WEAK_TAGS: &[&str] = &["web", "dv", "proper", "extended", "limited", "multi"];
+
+const RESOLUTIONS: &[&str] = &[
+    "480p", "576p", "720p", "1080p", "1080i", "2160p", "4k", "uhd",
+];
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct ParsedName {
+    // Show name for the episodes, title for the movies
+    pub title: String,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+    pub episode_title: Option<String>,
+    pub year: Option<u32>,
+    pub resolution: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl ParsedName {
+    pub fn is_episode(&self) -> bool {
+        self.episode.is_some()
+    }
+
+    /// Human readable title, e.g. "Show Name S02E05 - Episode" or "Movie (2019)"
+    pub fn display_title(&self) -> String {
+        let mut title = self.title.clone();
+        if let (Some(season), Some(episode)) = (self.season, self.episode) {
+            title = format!("{} S{:02}E{:02}", title, season, episode);
+        }
+        if let Some(episode_title) = &self.episode_title {
+            title = format!("{} - {}", title, episode_title);
+        }
+        if let (false, Some(year)) = (self.is_episode(), self.year) {
+            title = format!("{} ({})", title, year);
+        }
+        title.trim().to_string()
+    }
+}
+
+/// Parses the file name without the extension
+pub fn parse(name: &str) -> ParsedName {
+    let tokens = tokenize(name);
+    let year_index = find_year(name, &tokens);
+    let mut parsed = ParsedName::default();
+    let mut title: Vec<&str> = vec![];
+    let mut episode_title: Vec<&str> = vec![];
+    let mut marker_found = false;
+
+    for (i, token) in tokens.iter().enumerate() {
+        let lower = token.to_lowercase();
+        if let Some((season, episode)) = parse_episode(&lower) {
+            if parsed.episode.is_none() {
+                parsed.season = Some(season);
+                parsed.episode = Some(episode);
+                marker_found = true;
+                continue;
+            }
+        }
+        if Some(i) == year_index {
+            parsed.year = parse_year(&lower);
+            marker_found = true;
+            continue;
+        }
+        if RESOLUTIONS.contains(&lower.as_str()) {
+            parsed.resolution = Some(lower);
+            marker_found = true;
+            continue;
+        }
+        let weak_tags =
+            parsed.year.is_some() || parsed.resolution.is_some() || !parsed.tags.is_empty();
+        let is_tag = |v: &str| {
+            let v = v.to_lowercase();
+            RELEASE_TAGS.contains(&v.as_str()) || (weak_tags && WEAK_TAGS.contains(&v.as_str()))
+        };
+        let tags: Vec<&str> = token.split('-').filter(|v| is_tag(v)).collect();
+        if is_tag(token) {
+            parsed.tags.push(token.to_string());
+            marker_found = true;
+            continue;
+        } else if !tags.is_empty() {
+            // Tag with the release group, e.g. "x264-GROUP"
+            parsed.tags.extend(tags.iter().map(|v| v.to_string()));
+            marker_found = true;
+            continue;
+        }
+
+        if !marker_found {
+            title.push(token);
+        } else if parsed.is_episode()
+            && parsed.resolution.is_none()
+            && parsed.tags.is_empty()
+            && parsed.year.is_none()
+        {
+            episode_title.push(token);
+        }
+    }
+
+    parsed.title = join_words(&title);
+    let episode_title = join_words(&episode_title);
+    if !episode_title.is_empty() {
+        parsed.episode_title = Some(episode_title);
+    }
+    parsed
+}
+
+/// Season number from folder names like "Season 2", "S02" or "Series 2"
+pub fn parse_season_folder(name: &str) -> Option<u32> {
+    let lower = name.to_lowercase();
+    let rest = lower
+        .strip_prefix("season")
+        .or_else(|| lower.strip_prefix("series"))
+        .or_else(|| lower.strip_prefix('s'))?;
+    rest.trim_matches(|c: char| c == ' ' || c == '.' || c == '_')
+        .parse()
+        .ok()
+}
+
+/// Splits to words, dashes inside words such as "WEB-DL" are kept
+fn tokenize(name: &str) -> Vec<&str> {
+    name.split(|c: char| c == '.' || c == '_' || c.is_whitespace() || "()[]{}".contains(c))
+        .map(|v| v.trim_matches('-'))
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Index of the release year among the tokens
+///
+/// Year as the first token is part of the title, e.g. "1917". Of the years
+/// before the resolution and the tags, the one in parentheses or brackets is
+/// preferred, otherwise the last one, e.g. "Blade Runner 2049 (2017)".
+fn find_year(name: &str, tokens: &[&str]) -> Option<usize> {
+    let mut year = None;
+    for (i, token) in tokens.iter().enumerate().skip(1) {
+        let lower = token.to_lowercase();
+        if RESOLUTIONS.contains(&lower.as_str()) || RELEASE_TAGS.contains(&lower.as_str()) {
+            break;
+        }
+        if parse_year(&lower).is_none() {
+            continue;
+        }
+        if is_bracketed(name, token) {
+            return Some(i);
+        }
+        year = Some(i);
+    }
+    year
+}
+
+/// Token of the name is enclosed in parentheses or brackets
+fn is_bracketed(name: &str, token: &str) -> bool {
+    let start = token.as_ptr() as usize - name.as_ptr() as usize;
+    let end = start + token.len();
+    name[..start].ends_with(['(', '[']) && name[end..].starts_with([')', ']'])
+}
+
+fn join_words(words: &[&str]) -> String {
+    words.join(" ").trim().to_string()
+}
+
+/// Parses "s02e05", "s02e05e06" and "2x05"
+fn parse_episode(token: &str) -> Option<(u32, u32)> {
+    if let Some(rest) = token.strip_prefix('s') {
+        let e = rest.find('e')?;
+        let season = rest[..e].parse().ok()?;
+        let episode: String = rest[e + 1..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        return Some((season, episode.parse().ok()?));
+    }
+    let x = token.find('x')?;
+    let season = &token[..x];
+    let episode = &token[x + 1..];
+    if season.len() > 2 || episode.len() > 3 {
+        return None;
+    }
+    Some((season.parse().ok()?, episode.parse().ok()?))
+}
+
+fn parse_year(token: &str) -> Option<u32> {
+    if token.len() != 4 {
+        return None;
+    }
+    let year: u32 = token.parse().ok()?;
+    if (1900..2100).contains(&year) {
+        Some(year)
+    } else {
+        None
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_episode() {
+        let parsed = parse("Show.Name.S02E05.The.Episode.1080p.WEB-DL.x264-GROUP");
+        assert_eq!(parsed.title, "Show Name");
+        assert_eq!(parsed.season, Some(2));
+        assert_eq!(parsed.episode, Some(5));
+        assert_eq!(parsed.episode_title, Some("The Episode".into()));
+        assert_eq!(parsed.resolution, Some("1080p".into()));
+        assert_eq!(parsed.tags, vec!["WEB-DL", "x264"]);
+        assert_eq!(parsed.display_title(), "Show Name S02E05 - The Episode");
+
+        let parsed = parse("Show Name - 1x03 - Pilot");
+        assert_eq!(parsed.title, "Show Name");
+        assert_eq!((parsed.season, parsed.episode), (Some(1), Some(3)));
+        assert_eq!(parsed.episode_title, Some("Pilot".into()));
+    }
+
+    #[test]
+    fn test_parse_movie() {
+        let parsed = parse("Movie (2019)");
+        assert_eq!(parsed.title, "Movie");
+        assert_eq!(parsed.year, Some(2019));
+        assert!(!parsed.is_episode());
+        assert_eq!(parsed.display_title(), "Movie (2019)");
+
+        let parsed = parse("1917.2019.2160p.BluRay.REMUX.HEVC");
+        assert_eq!(parsed.title, "1917");
+        assert_eq!(parsed.year, Some(2019));
+        assert_eq!(parsed.resolution, Some("2160p".into()));
+        assert_eq!(parsed.tags, vec!["BluRay", "REMUX", "HEVC"]);
+    }
+
+    #[test]
+    fn test_parse_year() {
+        let parsed = parse("Blade Runner 2049 (2017)");
+        assert_eq!(parsed.title, "Blade Runner 2049");
+        assert_eq!(parsed.year, Some(2017));
+
+        let parsed = parse("2001.A.Space.Odyssey.1968.1080p");
+        assert_eq!(parsed.title, "2001 A Space Odyssey");
+        assert_eq!(parsed.year, Some(1968));
+
+        let parsed = parse("Movie.2019.1080p.BluRay.2020");
+        assert_eq!(parsed.title, "Movie");
+        assert_eq!(parsed.year, Some(2019));
+    }
+
+    #[test]
+    fn test_parse_weak_tags() {
+        let parsed = parse("The Web (2019)");
+        assert_eq!(parsed.title, "The Web");
+        assert!(parsed.tags.is_empty());
+
+        let parsed = parse("Show.S01E02.Limited.Edition.1080p.WEB.PROPER-GROUP");
+        assert_eq!(parsed.episode_title, Some("Limited Edition".into()));
+        assert_eq!(parsed.tags, vec!["WEB", "PROPER"]);
+
+        let parsed = parse("Movie.2019.EXTENDED.DV.x265");
+        assert_eq!(parsed.title, "Movie");
+        assert_eq!(parsed.tags, vec!["EXTENDED", "DV", "x265"]);
+    }
+
+    #[test]
+    fn test_parse_season_folder() {
+        assert_eq!(parse_season_folder("Season 2"), Some(2));
+        assert_eq!(parse_season_folder("S03"), Some(3));
+        assert_eq!(parse_season_folder("Specials"), None);
+    }
+}
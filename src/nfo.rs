@@ -0,0 +1,357 @@
+/// Kodi/Jellyfin style local metadata
+///
+/// Reads the `.nfo` XML files and artwork images next to the media files, e.g.
+/// `Movie (2019).nfo` or `movie.nfo` with `poster.jpg` and `fanart.jpg`. For
+/// episodes the `tvshow.nfo` and artwork of the show folder are used too, also
+/// when the episodes are in season folders.
+use crate::naming;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const IMAGE_EXTS: &[&str] = &["jpg", "jpeg", "png"];
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct Actor {
+    pub name: String,
+    pub role: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct LocalMetadata {
+    // Movie or episode title
+    pub title: Option<String>,
+
+    // From the tvshow.nfo
+    pub show_title: Option<String>,
+
+    pub plot: Option<String>,
+    pub tagline: Option<String>,
+    pub genres: Vec<String>,
+    pub rating: Option<f32>,
+    pub year: Option<u32>,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+    pub cast: Vec<Actor>,
+}
+
+/// Local artwork files, the paths are never exposed in the API
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct Artwork {
+    pub poster: Option<PathBuf>,
+    pub fanart: Option<PathBuf>,
+}
+
+/// Files of a folder, lowercase names with the paths
+type Listing = Vec<(String, PathBuf)>;
+
+/// Reads the metadata and finds the artwork of the media file
+///
+/// Metadata is None if there are no NFO files. Each folder is listed once.
+pub fn read_local(media: &Path) -> (Option<LocalMetadata>, Artwork) {
+    let (stem, dir) = match (media.file_stem(), media.parent()) {
+        (Some(stem), Some(dir)) => (stem.to_string_lossy().into_owned(), dir),
+        _ => return (None, Artwork::default()),
+    };
+    let listings: Vec<Listing> = folders(dir).into_iter().map(list_dir).collect();
+    (
+        read_metadata(&stem, &listings),
+        find_artwork(&stem, &listings),
+    )
+}
+
+fn read_metadata(stem: &str, listings: &[Listing]) -> Option<LocalMetadata> {
+    let own = find_file(&listings[0], &[format!("{}.nfo", stem), "movie.nfo".into()])
+        .and_then(|v| read_nfo(&v));
+    let show = listings
+        .iter()
+        .find_map(|v| find_file(v, &["tvshow.nfo".into()]))
+        .and_then(|v| read_nfo(&v));
+    match (own, show) {
+        (Some(mut own), Some(show)) => {
+            own.show_title = own.show_title.or(show.title);
+            own.plot = own.plot.or(show.plot);
+            if own.genres.is_empty() {
+                own.genres = show.genres;
+            }
+            if own.cast.is_empty() {
+                own.cast = show.cast;
+            }
+            Some(own)
+        }
+        (Some(own), None) => Some(own),
+        // Only the show level metadata applies to the episode
+        (None, Some(show)) => Some(LocalMetadata {
+            show_title: show.title,
+            plot: show.plot,
+            genres: show.genres,
+            cast: show.cast,
+            ..LocalMetadata::default()
+        }),
+        (None, None) => None,
+    }
+}
+
+fn find_artwork(stem: &str, listings: &[Listing]) -> Artwork {
+    let own_posters = image_names(&[
+        &format!("{}-poster", stem),
+        stem,
+        &format!("{}-thumb", stem),
+    ]);
+    let own_fanarts = image_names(&[&format!("{}-fanart", stem)]);
+    let posters = image_names(&["poster", "folder", "cover"]);
+    let fanarts = image_names(&["fanart", "backdrop"]);
+    Artwork {
+        poster: find_file(&listings[0], &own_posters)
+            .or_else(|| listings.iter().find_map(|v| find_file(v, &posters))),
+        fanart: find_file(&listings[0], &own_fanarts)
+            .or_else(|| listings.iter().find_map(|v| find_file(v, &fanarts))),
+    }
+}
+
+/// NFO or artwork file which may change the metadata of the media files in
+/// the same folder or below it
+pub fn is_sidecar_file(path: &Path) -> bool {
+    let is_nfo = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("nfo"));
+    is_nfo || is_artwork_file(path)
+}
+
+/// Image named as artwork, e.g. `poster.jpg` or `Movie-fanart.jpg`
+///
+/// The poster named exactly like the media file isn't recognized, it can't
+/// be told apart from a photo without looking at the folder.
+pub fn is_artwork_file(path: &Path) -> bool {
+    let is_image = path
+        .extension()
+        .is_some_and(|ext| IMAGE_EXTS.iter().any(|v| ext.eq_ignore_ascii_case(v)));
+    let stem = match path.file_stem() {
+        Some(stem) if is_image => stem.to_string_lossy().to_lowercase(),
+        _ => return false,
+    };
+    ["poster", "folder", "cover", "fanart", "backdrop"].contains(&stem.as_str())
+        || ["-poster", "-thumb", "-fanart"]
+            .iter()
+            .any(|v| stem.ends_with(v))
+}
+
+/// Parses the `<movie>`, `<tvshow>` or `<episodedetails>` document
+///
+/// Kodi allows a scraper URL after the XML, it's ignored.
+pub fn parse(xml: &str) -> Option<LocalMetadata> {
+    let xml = &xml[..xml.rfind('>')? + 1];
+    let doc = roxmltree::Document::parse(xml).ok()?;
+    let root = doc.root_element();
+    if !["movie", "tvshow", "episodedetails"].contains(&root.tag_name().name()) {
+        return None;
+    }
+    let year = text(root, "year")
+        .or_else(|| text(root, "premiered"))
+        .or_else(|| text(root, "aired"))
+        .and_then(|v| v.get(..4).and_then(|v| v.parse().ok()));
+    Some(LocalMetadata {
+        title: text(root, "title"),
+        show_title: text(root, "showtitle"),
+        plot: text(root, "plot").or_else(|| text(root, "outline")),
+        tagline: text(root, "tagline"),
+        genres: children(root, "genre")
+            .filter_map(node_text)
+            .flat_map(|v| {
+                v.split('/')
+                    .map(|v| v.trim().to_string())
+                    .collect::<Vec<String>>()
+            })
+            .filter(|v| !v.is_empty())
+            .collect(),
+        rating: rating(root),
+        year,
+        season: text(root, "season").and_then(|v| v.parse().ok()),
+        episode: text(root, "episode").and_then(|v| v.parse().ok()),
+        cast: children(root, "actor")
+            .filter_map(|actor| {
+                Some(Actor {
+                    name: text(actor, "name")?,
+                    role: text(actor, "role"),
+                })
+            })
+            .collect(),
+    })
+}
+
+fn read_nfo(path: &Path) -> Option<LocalMetadata> {
+    let data = std::fs::read(path).ok()?;
+    let metadata = parse(&String::from_utf8_lossy(&data));
+    if metadata.is_none() {
+        println!("Unable to parse NFO file {}", path.display());
+    }
+    metadata
+}
+
+/// Folder of the file, and the show folder above it if it's a season folder
+fn folders(dir: &Path) -> Vec<&Path> {
+    let mut folders = vec![dir];
+    let is_season = dir
+        .file_name()
+        .and_then(|v| naming::parse_season_folder(&v.to_string_lossy()))
+        .is_some();
+    if let (true, Some(parent)) = (is_season, dir.parent()) {
+        folders.push(parent);
+    }
+    folders
+}
+
+fn image_names(stems: &[&str]) -> Vec<String> {
+    stems
+        .iter()
+        .flat_map(|stem| {
+            IMAGE_EXTS
+                .iter()
+                .map(move |ext| format!("{}.{}", stem, ext))
+        })
+        .collect()
+}
+
+/// Files of the folder, empty if it can't be read
+fn list_dir(dir: &Path) -> Listing {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .filter_map(|v| v.ok())
+        .filter(|v| v.file_type().is_ok_and(|v| !v.is_dir()))
+        .map(|v| (v.file_name().to_string_lossy().to_lowercase(), v.path()))
+        .collect()
+}
+
+/// First existing file of the names in the order given, case-insensitively
+fn find_file(files: &[(String, PathBuf)], names: &[String]) -> Option<PathBuf> {
+    names.iter().find_map(|name| {
+        let name = name.to_lowercase();
+        files.iter().find(|v| v.0 == name).map(|v| v.1.clone())
+    })
+}
+
+fn children<'a, 'input: 'a>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> + 'a {
+    node.children().filter(move |v| v.has_tag_name(name))
+}
+
+fn node_text(node: roxmltree::Node) -> Option<String> {
+    let text = node.text()?.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+fn text(node: roxmltree::Node, name: &str) -> Option<String> {
+    children(node, name).next().and_then(node_text)
+}
+
+/// Plain `<rating>` or the default one of `<ratings>`
+fn rating(root: roxmltree::Node) -> Option<f32> {
+    if let Some(rating) = text(root, "rating").and_then(|v| v.parse().ok()) {
+        return Some(rating);
+    }
+    let ratings: Vec<roxmltree::Node> = children(root, "ratings")
+        .flat_map(|v| children(v, "rating"))
+        .collect();
+    ratings
+        .iter()
+        .find(|v| v.attribute("default") == Some("true"))
+        .or_else(|| ratings.first())
+        .and_then(|v| text(*v, "value"))
+        .and_then(|v| v.parse().ok())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_movie() {
+        let metadata = parse(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes" ?>
+            <movie>
+                <title>Big Buck Bunny</title>
+                <plot>A giant rabbit.</plot>
+                <genre>Animation / Comedy</genre>
+                <ratings>
+                    <rating name="imdb"><value>6.4</value></rating>
+                    <rating name="tmdb" default="true"><value>6.5</value></rating>
+                </ratings>
+                <premiered>2008-04-10</premiered>
+                <actor><name>Bunny</name><role>Himself</role></actor>
+            </movie>
+            https://www.themoviedb.org/movie/10378"#,
+        )
+        .unwrap();
+        assert_eq!(metadata.title, Some("Big Buck Bunny".into()));
+        assert_eq!(metadata.genres, vec!["Animation", "Comedy"]);
+        assert_eq!(metadata.rating, Some(6.5));
+        assert_eq!(metadata.year, Some(2008));
+        assert_eq!(
+            metadata.cast,
+            vec![Actor {
+                name: "Bunny".into(),
+                role: Some("Himself".into())
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_episode() {
+        let metadata = parse(
+            "<episodedetails><title>Pilot</title><season>1</season>\
+             <episode>3</episode><rating>8</rating></episodedetails>",
+        )
+        .unwrap();
+        assert_eq!(metadata.title, Some("Pilot".into()));
+        assert_eq!((metadata.season, metadata.episode), (Some(1), Some(3)));
+        assert_eq!(metadata.rating, Some(8.0));
+        assert_eq!(parse("<html></html>"), None);
+    }
+
+    #[test]
+    fn test_is_artwork_file() {
+        assert!(is_artwork_file(Path::new("Movie/poster.jpg")));
+        assert!(is_artwork_file(Path::new("Show/Fanart.PNG")));
+        assert!(is_artwork_file(Path::new("Show/S01E02-thumb.jpg")));
+        assert!(!is_artwork_file(Path::new("Photos/IMG_0001.JPG")));
+        assert!(!is_artwork_file(Path::new("Movie/poster.mkv")));
+        assert!(is_sidecar_file(Path::new("Movie/movie.NFO")));
+        assert!(!is_sidecar_file(Path::new("Photos/beach.jpg")));
+    }
+
+    #[test]
+    fn test_read_local() {
+        let dir = std::env::temp_dir().join(format!("casterson-nfo-{}", std::process::id()));
+        let season = dir.join("Show").join("Season 1");
+        std::fs::create_dir_all(&season).unwrap();
+        std::fs::write(
+            dir.join("Show").join("tvshow.nfo"),
+            "<tvshow><title>Show</title><plot>About the show.</plot><year>2010</year>\
+             <season>4</season><episode>20</episode><rating>9</rating></tvshow>",
+        )
+        .unwrap();
+        std::fs::write(dir.join("Show").join("poster.jpg"), b"").unwrap();
+        std::fs::write(season.join("S01E02-fanart.png"), b"").unwrap();
+
+        // Episode with only the tvshow.nfo
+        let (metadata, artwork) = read_local(&season.join("S01E02.mkv"));
+        let metadata = metadata.unwrap();
+        assert_eq!(metadata.show_title, Some("Show".into()));
+        assert_eq!(metadata.plot, Some("About the show.".into()));
+        assert_eq!(metadata.title, None);
+        assert_eq!((metadata.season, metadata.episode), (None, None));
+        assert_eq!((metadata.year, metadata.rating), (None, None));
+        assert_eq!(artwork.poster, Some(dir.join("Show").join("poster.jpg")));
+        assert_eq!(artwork.fanart, Some(season.join("S01E02-fanart.png")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
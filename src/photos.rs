@@ -0,0 +1,98 @@
+/// Photos scaled for the cast devices
+///
+/// Photos are turned upright by the EXIF orientation and fit inside the
+/// requested size, as the cast devices can't handle the full-size camera
+/// images. Scaled photos are stored as `<id>-<mtime>-<width>x<height>.jpg`
+/// files in the photo directory, like the thumbnails.
+use crate::library::LibraryItem;
+use crate::media;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Semaphore;
+
+/// Size used when the request doesn't specify one
+pub const DEFAULT_SIZE: (u32, u32) = (1920, 1080);
+
+/// Largest size photos are scaled to, limits the number of cached sizes
+pub const MAX_SIZE: (u32, u32) = (3840, 2160);
+
+/// Maximum number of concurrent ffmpeg processes scaling photos
+const MAX_SCALES: usize = 2;
+
+/// Counter making the temporary file names unique
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub struct Photos {
+    dir: PathBuf,
+    scales: Semaphore,
+}
+
+impl Photos {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Photos {
+        Photos {
+            dir: dir.as_ref().to_path_buf(),
+            scales: Semaphore::new(MAX_SCALES),
+        }
+    }
+
+    /// Scaled photo of the item, generated if it doesn't exist yet
+    pub async fn get(
+        &self,
+        item: &LibraryItem,
+        (width, height): (u32, u32),
+    ) -> Result<PathBuf, std::io::Error> {
+        let width = width.clamp(1, MAX_SIZE.0);
+        let height = height.clamp(1, MAX_SIZE.1);
+        let file = self.dir.join(format!(
+            "{}-{}-{}x{}.jpg",
+            item.id, item.mtime, width, height
+        ));
+        if file.exists() {
+            return Ok(file);
+        }
+        let _permit = self.scales.acquire().await;
+        // Could have been generated while waiting
+        if file.exists() {
+            return Ok(file);
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        self.remove_outdated(item);
+
+        println!("Scaling photo {}", file.display());
+        // Same size can be requested concurrently, each scales to its own file
+        let n = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let tmp = file.with_extension(format!("{}.{}.tmp.jpg", std::process::id(), n));
+        match media::scale_image(&item.path, &tmp, width, Some(height)).await {
+            Ok(_) => {
+                std::fs::rename(&tmp, &file)?;
+                Ok(file)
+            }
+            Err(err) => {
+                let _ = std::fs::remove_file(&tmp);
+                Err(err)
+            }
+        }
+    }
+
+    /// Removes photos scaled before the file was modified
+    ///
+    /// Photos of the copies with the ID disambiguated by a suffix are kept.
+    fn remove_outdated(&self, item: &LibraryItem) {
+        let prefix = format!("{}-", item.id);
+        let current = format!("{}-{}-", item.id, item.mtime);
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|v| v.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_own = name
+                .strip_prefix(&prefix)
+                .is_some_and(|v| v.split('-').count() == 2);
+            if is_own && !name.starts_with(&current) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
@@ -0,0 +1,267 @@
+/// M3U, M3U8 and PLS playlists
+///
+/// Playlists are read from the media directories when they are listed.
+/// Relative entries are resolved from the folder of the playlist, URLs are
+/// skipped, and entries outside the media directories are dropped with
+/// `is_safe_file` like any other file request.
+use crate::media;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use url::Url;
+
+/// Extensions of the playlist files
+pub const PLAYLIST_EXTS: &[&str] = &["m3u", "m3u8", "pls"];
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct PlaylistEntry {
+    pub path: PathBuf,
+
+    // From #EXTINF or the PLS TitleN and LengthN
+    pub title: Option<String>,
+    pub duration: Option<f32>,
+}
+
+/// File is a playlist by the extension
+pub fn is_playlist_file<P: AsRef<Path>>(file: P) -> bool {
+    file.as_ref()
+        .extension()
+        .and_then(|v| v.to_str())
+        .is_some_and(|ext| PLAYLIST_EXTS.iter().any(|v| v.eq_ignore_ascii_case(ext)))
+}
+
+/// Reads the playlist, keeping only the entries safe to serve
+///
+/// Paths of the entries are canonicalized.
+pub fn read<P: AsRef<Path>, D: AsRef<Path>, E: AsRef<OsStr>>(
+    file: P,
+    safe_dirs: &[D],
+    safe_exts: &[E],
+) -> Result<Vec<PlaylistEntry>, std::io::Error> {
+    let file = file.as_ref();
+    let data = std::fs::read(file)?;
+    // M3U is often Latin-1, lossy decoding keeps the ASCII paths working
+    let text = String::from_utf8_lossy(&data);
+    let text = text.trim_start_matches('\u{feff}');
+    let base = file.parent().unwrap_or_else(|| Path::new(""));
+    let is_pls = file
+        .extension()
+        .is_some_and(|v| v.eq_ignore_ascii_case("pls"));
+    let entries = if is_pls {
+        parse_pls(text, base)
+    } else {
+        parse_m3u(text, base)
+    };
+    Ok(entries
+        .into_iter()
+        .filter(|v| media::is_safe_file(&v.path, safe_dirs, safe_exts))
+        .filter_map(|mut v| {
+            // Same form as the paths in the library
+            v.path = v.path.canonicalize().ok()?;
+            Some(v)
+        })
+        .collect())
+}
+
+/// Parses the M3U or M3U8 playlist, with or without the #EXTINF lines
+pub fn parse_m3u(text: &str, base: &Path) -> Vec<PlaylistEntry> {
+    let mut entries = vec![];
+    let mut info: Option<(Option<f32>, Option<String>)> = None;
+    for line in text.lines().map(str::trim).filter(|v| !v.is_empty()) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            // Duration and optional attributes before the comma, title after it
+            let (head, title) = match extinf.find(',') {
+                Some(i) => (&extinf[..i], Some(extinf[i + 1..].trim())),
+                None => (extinf, None),
+            };
+            let duration = head
+                .split_whitespace()
+                .next()
+                .and_then(|v| v.parse::<f32>().ok())
+                .filter(|v| *v >= 0.0);
+            let title = title.filter(|v| !v.is_empty()).map(String::from);
+            info = Some((duration, title));
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let (duration, title) = info.take().unwrap_or((None, None));
+        if let Some(path) = resolve(line, base) {
+            entries.push(PlaylistEntry {
+                path,
+                title,
+                duration,
+            });
+        }
+    }
+    entries
+}
+
+/// Values of the numbered keys of a PLS playlist
+#[derive(Default)]
+struct PlsFile<'a> {
+    file: Option<&'a str>,
+    title: Option<&'a str>,
+    length: Option<&'a str>,
+}
+
+/// Parses the PLS playlist, entries are ordered by their numbers
+pub fn parse_pls(text: &str, base: &Path) -> Vec<PlaylistEntry> {
+    let mut files: BTreeMap<u32, PlsFile> = BTreeMap::new();
+    for line in text.lines().map(str::trim) {
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim().to_lowercase(), line[i + 1..].trim()),
+            None => continue,
+        };
+        let split = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let number = match key[split..].parse::<u32>() {
+            Ok(number) => number,
+            Err(_) => continue,
+        };
+        let entry = files.entry(number).or_default();
+        match &key[..split] {
+            "file" => entry.file = Some(value),
+            "title" => entry.title = Some(value),
+            "length" => entry.length = Some(value),
+            _ => (),
+        }
+    }
+    files
+        .into_values()
+        .filter_map(|v| {
+            Some(PlaylistEntry {
+                path: resolve(v.file?, base)?,
+                title: v.title.filter(|v| !v.is_empty()).map(String::from),
+                duration: v
+                    .length
+                    .and_then(|v| v.parse::<f32>().ok())
+                    .filter(|v| *v >= 0.0),
+            })
+        })
+        .collect()
+}
+
+/// Path of the playlist entry, None for the URLs other than file URLs
+fn resolve(location: &str, base: &Path) -> Option<PathBuf> {
+    if location.contains("://") {
+        return Url::parse(location).ok()?.to_file_path().ok();
+    }
+    // Playlists made on Windows
+    let location = location.replace('\\', "/");
+    Some(base.join(location))
+}
+
+/// Writes the extended M3U playlist of the entries
+pub fn write_m3u(entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for entry in entries {
+        if entry.title.is_some() || entry.duration.is_some() {
+            out.push_str(&format!(
+                "#EXTINF:{},{}\n",
+                entry.duration.map_or(-1, |v| v.round() as i64),
+                entry.title.as_deref().unwrap_or("")
+            ));
+        }
+        out.push_str(&entry.path.to_string_lossy().replace('\\', "/"));
+        out.push('\n');
+    }
+    out
+}
+
+/// Path of the file relative to the folder, with `..` where needed
+pub fn relative_to(folder: &Path, file: &Path) -> PathBuf {
+    let folder: Vec<Component> = folder.components().collect();
+    let file: Vec<Component> = file.components().collect();
+    let common = folder
+        .iter()
+        .zip(file.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut path = PathBuf::new();
+    for _ in common..folder.len() {
+        path.push("..");
+    }
+    for component in &file[common..] {
+        path.push(component.as_os_str());
+    }
+    path
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_m3u() {
+        let text = "#EXTM3U\n\
+                    #EXTINF:123 tvg-id=\"x\",Artist - Song\n\
+                    Music/song.mp3\n\
+                    \n\
+                    # Comment\n\
+                    ../Movies\\movie.mkv\n\
+                    http://example.com/stream.mp3\n\
+                    #EXTINF:-1,\n\
+                    file:///media/other.mp4\n";
+        let entries = parse_m3u(text, Path::new("/media/lists"));
+        assert_eq!(
+            entries,
+            vec![
+                PlaylistEntry {
+                    path: "/media/lists/Music/song.mp3".into(),
+                    title: Some("Artist - Song".into()),
+                    duration: Some(123.0),
+                },
+                PlaylistEntry {
+                    path: "/media/lists/../Movies/movie.mkv".into(),
+                    title: None,
+                    duration: None,
+                },
+                PlaylistEntry {
+                    path: "/media/other.mp4".into(),
+                    title: None,
+                    duration: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_pls() {
+        let text = "[playlist]\n\
+                    File2=b.mp3\n\
+                    Title2=Second\n\
+                    File1=a.mp3\n\
+                    Length1=-1\n\
+                    Title1=First\n\
+                    NumberOfEntries=2\n\
+                    Version=2\n";
+        let entries = parse_pls(text, Path::new("/music"));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, Path::new("/music/a.mp3"));
+        assert_eq!(entries[0].title.as_deref(), Some("First"));
+        assert_eq!(entries[0].duration, None);
+        assert_eq!(entries[1].title.as_deref(), Some("Second"));
+    }
+
+    #[test]
+    fn test_write_m3u() {
+        let entries = vec![
+            PlaylistEntry {
+                path: relative_to(Path::new("/media/lists"), Path::new("/media/Music/a.mp3")),
+                title: Some("A".into()),
+                duration: Some(61.6),
+            },
+            PlaylistEntry {
+                path: relative_to(Path::new("/media"), Path::new("/media/b.mkv")),
+                title: None,
+                duration: None,
+            },
+        ];
+        assert_eq!(
+            write_m3u(&entries),
+            "#EXTM3U\n#EXTINF:62,A\n../Music/a.mp3\nb.mkv\n"
+        );
+    }
+}
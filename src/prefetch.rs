@@ -0,0 +1,76 @@
+/// Background pre-transcoding
+///
+/// When a file is being watched, the beginning of the next item of its queue,
+/// or the next file in the same directory if it's not cast as a queue, is
+/// encoded to the transcode cache with a niced ffmpeg, so that `media_show`
+/// can start it instantly from the cache. The encode runs as a
+/// job of the `JobManager`, so it counts against `max_jobs`.
+use crate::cache::TranscodeCache;
+use crate::jobs::JobManager;
+use crate::loudness::Loudness;
+use crate::media;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::time::{delay_for, Duration};
+
+/// Give the actual encode a head start before starting to prefetch
+const PREFETCH_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Prefetcher {
+    cache: Option<Arc<TranscodeCache>>,
+    jobs: Arc<JobManager>,
+    loudness: Arc<Loudness>,
+    minutes: u32,
+    busy: Arc<AtomicBool>,
+}
+
+impl Prefetcher {
+    pub fn new(
+        cache: Option<Arc<TranscodeCache>>,
+        jobs: Arc<JobManager>,
+        loudness: Arc<Loudness>,
+        minutes: u32,
+    ) -> Prefetcher {
+        Prefetcher {
+            cache,
+            jobs,
+            loudness,
+            minutes,
+            busy: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Schedules prefetching of the next file, encoded with the options of
+    /// the file being watched
+    ///
+    /// Does nothing if prefetching is disabled, the cache is not enabled or
+    /// other prefetch is running.
+    pub fn schedule(
+        &self,
+        next: PathBuf,
+        video: Option<media::VideoInfo>,
+        opts: media::EncodeOpts,
+    ) {
+        let cache = match (&self.cache, self.minutes) {
+            (Some(cache), minutes) if minutes > 0 => cache.clone(),
+            _ => return,
+        };
+        let mut opts = opts.next_item();
+        self.loudness.apply(&next, &mut opts.audio_opts);
+        let seconds = self.minutes * 60;
+        let jobs = self.jobs.clone();
+        let busy = self.busy.clone();
+        tokio::spawn(async move {
+            delay_for(PREFETCH_DELAY).await;
+            if cache.get(&next, &opts).is_some() || busy.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            if let Err(err) = jobs.prefetch(&next, &opts, video, seconds).await {
+                println!("Prefetching {} failed {:?}", next.display(), err);
+            }
+            busy.store(false, Ordering::SeqCst);
+        });
+    }
+}
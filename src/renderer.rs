@@ -0,0 +1,88 @@
+/// Devices the media is cast to
+///
+/// Chromecasts, DLNA media renderers, local mpv and the receiver pages
+/// implement the same `Renderer` trait, the status is reported in the terms of
+/// the Chromecast media channel.
+use crate::browser::BrowserError;
+use crate::chromecast::ChromecastError;
+use crate::dlna::DlnaError;
+use crate::mpv::MpvError;
+use derive_more::From;
+use rust_cast::channels::media::{IdleReason, Metadata, PlayerState};
+use serde::{Deserialize, Serialize, Serializer};
+use url::Url;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    #[default]
+    Chromecast,
+    Dlna,
+    Mpv,
+    Browser,
+}
+
+#[derive(Debug, From)]
+pub enum RendererError {
+    ChromecastError(ChromecastError),
+    DlnaError(DlnaError),
+    MpvError(MpvError),
+    BrowserError(BrowserError),
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RendererStatus {
+    pub current_time: Option<f32>,
+    #[serde(serialize_with = "serialize_player_state")]
+    pub player_state: PlayerState,
+    #[serde(serialize_with = "serialize_idle_reason")]
+    pub idle_reason: Option<IdleReason>,
+}
+
+/// Called with the media status during casting
+pub type StatusCallback = Box<dyn FnMut(&RendererStatus) + Send>;
+
+pub trait Renderer: Send + Sync {
+    fn play(&self) -> Result<RendererStatus, RendererError>;
+    fn pause(&self) -> Result<RendererStatus, RendererError>;
+    fn stop(&self) -> Result<RendererStatus, RendererError>;
+    fn seek(&self, seconds: f32) -> Result<RendererStatus, RendererError>;
+
+    /// Sets the volume, level is from 0.0 to 1.0
+    fn set_volume(&self, level: f32) -> Result<(), RendererError>;
+
+    /// Casts the media and blocks until the playback ends
+    fn cast(
+        &self,
+        url: Url,
+        content_type: Option<String>,
+        metadata: Option<Metadata>,
+        on_status: StatusCallback,
+    ) -> Result<(), RendererError>;
+    fn get_status(&self) -> Result<RendererStatus, RendererError>;
+
+    /// Device displays HDR and decodes 10-bit HEVC, so HDR video can be
+    /// passed through. Blocks for querying the device, false if it can't be
+    /// queried.
+    fn supports_hdr(&self) -> bool;
+}
+
+fn serialize_player_state<S>(x: &PlayerState, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.serialize_str(&x.to_string())
+}
+
+fn serialize_idle_reason<S>(x: &Option<IdleReason>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.serialize_str(match x {
+        Some(IdleReason::Cancelled) => "CANCELLED",
+        Some(IdleReason::Interrupted) => "INTERRUPTED",
+        Some(IdleReason::Finished) => "FINISHED",
+        Some(IdleReason::Error) => "ERROR",
+        _ => "",
+    })
+}
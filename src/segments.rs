@@ -0,0 +1,305 @@
+/// Intro and credits detection
+///
+/// Episodes of a season usually share the intro. The beginning of the audio of
+/// an episode is fingerprinted and compared with the other episodes in the
+/// same folder, and the longest part they share is taken as the intro. Credits
+/// start at the first black frames accompanied by silence near the end of the
+/// file. Episodes are analyzed in background, and the found ranges are stored
+/// in the library.
+use crate::library::{Library, LibraryItem, MediaId};
+use crate::media;
+use crate::media::{BlackAndSilence, FINGERPRINT_POINT_SECONDS};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+
+/// Shared parts shorter than this are not intros
+const MIN_INTRO_SECONDS: f32 = 15.0;
+
+/// Gap allowed in the shared part, e.g. dialogue over the intro music
+const MAX_GAP_SECONDS: f32 = 3.5;
+
+/// Fingerprint points differing at most by this many bits match
+const MAX_BIT_DIFF: u32 = 6;
+
+/// Number of the most common offsets between two fingerprints compared
+const CANDIDATE_SHIFTS: usize = 10;
+
+/// Credits are searched from this far from the end of the file
+const CREDITS_SEARCH_SECONDS: f32 = 240.0;
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub struct Segment {
+    // Seconds from the beginning of the file
+    pub start: f32,
+    pub end: f32,
+}
+
+impl Segment {
+    pub fn contains(&self, position: f32) -> bool {
+        self.start <= position && position < self.end
+    }
+}
+
+/// Segments found in the item, None if the item has no such segment
+#[derive(Serialize, Deserialize, PartialEq, Default, Clone, Debug)]
+pub struct Segments {
+    pub intro: Option<Segment>,
+    pub credits: Option<Segment>,
+}
+
+#[derive(Debug)]
+pub struct SegmentDetector {
+    search_seconds: u32,
+
+    // Items which couldn't be fingerprinted, not retried until restart
+    failed: Mutex<HashSet<String>>,
+}
+
+impl SegmentDetector {
+    /// Intros are searched from the first minutes of the episodes, 0 disables
+    /// the detection
+    pub fn new(search_minutes: u32) -> SegmentDetector {
+        SegmentDetector {
+            search_seconds: search_minutes * 60,
+            failed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Detects the segments of the episodes not analyzed yet
+    pub async fn detect_missing(&self, library: &RwLock<Library>) {
+        if self.search_seconds == 0 {
+            return;
+        }
+        let mut folders: BTreeMap<PathBuf, Vec<LibraryItem>> = BTreeMap::new();
+        for item in library.read().unwrap().items() {
+            let folder = match item.path.parent() {
+                Some(folder) => folder,
+                None => continue,
+            };
+            if item.duration.is_some_and(|v| v > 0.0) && item.parsed_name().is_episode() {
+                folders
+                    .entry(folder.to_path_buf())
+                    .or_default()
+                    .push(item.clone());
+            }
+        }
+        for (folder, episodes) in folders {
+            if episodes.iter().all(|v| !self.is_missing(v)) {
+                continue;
+            }
+            println!("Detecting intros and credits in {}", folder.display());
+            self.detect_folder(library, &episodes).await;
+            library.read().unwrap().save();
+        }
+    }
+
+    async fn detect_folder(&self, library: &RwLock<Library>, episodes: &[LibraryItem]) {
+        let mut fingerprints: HashMap<MediaId, Vec<u32>> = HashMap::new();
+        for (i, item) in episodes.iter().enumerate() {
+            if !self.is_missing(item) {
+                continue;
+            }
+            let fingerprint = self.fingerprint(&mut fingerprints, item).await;
+            if fingerprint.is_empty() {
+                self.failed.lock().unwrap().insert(key(item));
+                continue;
+            }
+
+            // Nearest episodes are compared first
+            let mut others: Vec<&LibraryItem> =
+                episodes.iter().filter(|v| v.id != item.id).collect();
+            others.sort_by_key(|v| {
+                let j = episodes.iter().position(|e| e.id == v.id).unwrap_or(0);
+                (j as isize - i as isize).abs()
+            });
+            let mut intro = None;
+            for other in others {
+                let other_fingerprint = self.fingerprint(&mut fingerprints, other).await;
+                if let Some((segment, _)) = shared_segment(&fingerprint, &other_fingerprint) {
+                    intro = Some(segment);
+                    break;
+                }
+            }
+
+            let credits = match detect_credits(item).await {
+                Ok(credits) => credits,
+                Err(err) => {
+                    println!("Unable to detect credits {}: {}", item.path.display(), err);
+                    None
+                }
+            };
+            library.write().unwrap().set_segments(
+                &item.id,
+                item.mtime,
+                Segments { intro, credits },
+            );
+        }
+    }
+
+    /// Fingerprint of the item, empty if it can't be fingerprinted
+    async fn fingerprint(
+        &self,
+        fingerprints: &mut HashMap<MediaId, Vec<u32>>,
+        item: &LibraryItem,
+    ) -> Vec<u32> {
+        if let Some(fingerprint) = fingerprints.get(&item.id) {
+            return fingerprint.clone();
+        }
+        let fingerprint = match media::audio_fingerprint(&item.path, self.search_seconds).await {
+            Ok(fingerprint) => fingerprint,
+            Err(err) => {
+                println!("Unable to fingerprint {}: {}", item.path.display(), err);
+                vec![]
+            }
+        };
+        fingerprints.insert(item.id.clone(), fingerprint.clone());
+        fingerprint
+    }
+
+    fn is_missing(&self, item: &LibraryItem) -> bool {
+        item.segments.is_none() && !self.failed.lock().unwrap().contains(&key(item))
+    }
+}
+
+fn key(item: &LibraryItem) -> String {
+    format!("{}-{}", item.id, item.mtime)
+}
+
+/// Credits at the end of the item, None if not found
+async fn detect_credits(item: &LibraryItem) -> Result<Option<Segment>, std::io::Error> {
+    let duration = match item.duration {
+        Some(duration) if duration > 0.0 => duration,
+        _ => return Ok(None),
+    };
+    let from = (duration - CREDITS_SEARCH_SECONDS).max(0.0);
+    let detected = media::detect_black_and_silence(&item.path, from).await?;
+    Ok(credits_start(&detected).map(|start| Segment {
+        start,
+        end: duration,
+    }))
+}
+
+/// Start of the first black frames which are also silent
+pub fn credits_start(detected: &BlackAndSilence) -> Option<f32> {
+    detected
+        .black
+        .iter()
+        .find(|(black_start, black_end)| {
+            detected
+                .silence
+                .iter()
+                .any(|(start, end)| start < black_end && black_start < end)
+        })
+        .map(|v| v.0)
+}
+
+/// Longest part shared by the fingerprints, the segment in `a` and in `b`
+///
+/// Offsets with the most identical points are the candidates for the
+/// alignment, of which the longest run of matching points is taken.
+pub fn shared_segment(a: &[u32], b: &[u32]) -> Option<(Segment, Segment)> {
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (j, v) in b.iter().enumerate() {
+        index.entry(*v).or_default().push(j);
+    }
+    let mut votes: HashMap<isize, usize> = HashMap::new();
+    for (i, v) in a.iter().enumerate() {
+        for j in index.get(v).into_iter().flatten() {
+            *votes.entry(*j as isize - i as isize).or_default() += 1;
+        }
+    }
+    let mut shifts: Vec<(isize, usize)> = votes.into_iter().collect();
+    shifts.sort_by_key(|(shift, votes)| (Reverse(*votes), *shift));
+
+    let max_gap = (MAX_GAP_SECONDS / FINGERPRINT_POINT_SECONDS) as usize;
+    let mut best: Option<(usize, usize, isize)> = None;
+    for (shift, _) in shifts.into_iter().take(CANDIDATE_SHIFTS) {
+        if let Some((start, end)) = longest_match(a, b, shift, max_gap) {
+            if best.is_none_or(|v| end - start > v.1 - v.0) {
+                best = Some((start, end, shift));
+            }
+        }
+    }
+
+    let min_points = (MIN_INTRO_SECONDS / FINGERPRINT_POINT_SECONDS) as usize;
+    let (start, end, shift) = best.filter(|v| v.1 - v.0 >= min_points)?;
+    let segment = |start: isize, end: isize| Segment {
+        start: start as f32 * FINGERPRINT_POINT_SECONDS,
+        end: end as f32 * FINGERPRINT_POINT_SECONDS,
+    };
+    let (start, end) = (start as isize, end as isize);
+    Some((segment(start, end), segment(start + shift, end + shift)))
+}
+
+/// Longest run of matching points with `b` shifted by `shift` points, as the
+/// range of the points in `a`
+fn longest_match(a: &[u32], b: &[u32], shift: isize, max_gap: usize) -> Option<(usize, usize)> {
+    let first = (-shift).max(0) as usize;
+    let last = (b.len() as isize - shift).min(a.len() as isize).max(0) as usize;
+    let mut best: Option<(usize, usize)> = None;
+    let mut run: Option<(usize, usize)> = None;
+    for (i, point) in a.iter().enumerate().take(last).skip(first) {
+        let j = (i as isize + shift) as usize;
+        if (point ^ b[j]).count_ones() > MAX_BIT_DIFF {
+            continue;
+        }
+        let start = match run {
+            Some((start, previous)) if i - previous <= max_gap + 1 => start,
+            _ => i,
+        };
+        run = Some((start, i));
+        if best.is_none_or(|(s, e)| i + 1 - start > e - s) {
+            best = Some((start, i + 1));
+        }
+    }
+    best
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_points(seed: u32, count: usize) -> Vec<u32> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shared_segment() {
+        let intro = random_points(1, 300);
+        let mut a = random_points(2, 1000);
+        let mut b = random_points(3, 1000);
+        a.splice(100..400, intro.iter().cloned());
+        // Some points differ by a few bits, e.g. from a different encode
+        let noisy = intro
+            .iter()
+            .enumerate()
+            .map(|(i, v)| if i % 5 == 0 { v ^ 0b101 } else { *v });
+        b.splice(500..800, noisy);
+        let (in_a, in_b) = shared_segment(&a, &b).unwrap();
+        assert!((in_a.start - 100.0 * FINGERPRINT_POINT_SECONDS).abs() < 0.01);
+        assert!((in_a.end - 400.0 * FINGERPRINT_POINT_SECONDS).abs() < 0.01);
+        assert!((in_b.start - 500.0 * FINGERPRINT_POINT_SECONDS).abs() < 0.01);
+
+        let unrelated = random_points(4, 1000);
+        assert_eq!(shared_segment(&a, &unrelated), None);
+    }
+
+    #[test]
+    fn test_credits_start() {
+        let detected = BlackAndSilence {
+            black: vec![(100.0, 101.0), (200.0, 202.0), (250.0, 251.0)],
+            silence: vec![(150.0, 160.0), (201.5, 203.0), (250.0, 252.0)],
+        };
+        assert_eq!(credits_start(&detected), Some(200.0));
+    }
+}
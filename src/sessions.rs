@@ -0,0 +1,119 @@
+/// Active cast sessions
+///
+/// What is being cast to each device and where the playback is, updated from
+/// the status of the cast loop. Casting again to the same device replaces the
+/// session, the replaced cast loop can't end the new session.
+use crate::library::MediaId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use url::Url;
+
+pub type SessionToken = u64;
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub token: SessionToken,
+    pub id: Option<MediaId>,
+    pub url: Url,
+
+    // Seeks past the intro when the playback enters it
+    pub skip_intro: bool,
+
+    // Items cast one after another, the item of the session is one of them
+    pub queue: Vec<MediaId>,
+
+    // Seconds from the beginning of the file
+    pub position: f32,
+}
+
+#[derive(Debug, Default)]
+pub struct Sessions {
+    next_token: AtomicU64,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Sessions {
+    /// Starts a new session on the device, replacing the earlier one
+    pub fn start(
+        &self,
+        device: &str,
+        id: Option<MediaId>,
+        url: Url,
+        position: f32,
+        skip_intro: bool,
+        queue: Vec<MediaId>,
+    ) -> SessionToken {
+        let token = self.next_token.fetch_add(1, Ordering::SeqCst);
+        self.sessions.lock().unwrap().insert(
+            device.into(),
+            Session {
+                token,
+                id,
+                url,
+                skip_intro,
+                queue,
+                position,
+            },
+        );
+        token
+    }
+
+    pub fn set_position(&self, device: &str, token: SessionToken, position: f32) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(device) {
+            if session.token == token {
+                session.position = position;
+            }
+        }
+    }
+
+    /// Ends the session, unless it has been replaced already
+    pub fn end(&self, device: &str, token: SessionToken) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(device).is_some_and(|v| v.token == token) {
+            sessions.remove(device);
+        }
+    }
+
+    pub fn get(&self, device: &str) -> Option<Session> {
+        self.sessions.lock().unwrap().get(device).cloned()
+    }
+
+    /// Item after the given one in the queue of the session casting it
+    pub fn next_in_queue(&self, id: &str) -> Option<MediaId> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|v| v.id.as_deref() == Some(id))
+            .find_map(|v| {
+                let current = v.queue.iter().position(|v| v == id)?;
+                v.queue.get(current + 1).cloned()
+            })
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_in_queue() {
+        let sessions = Sessions::default();
+        let url = Url::parse("http://localhost/media_show").unwrap();
+        let queue = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        sessions.start("tv", Some("b".into()), url, 0.0, false, queue);
+        assert_eq!(sessions.next_in_queue("b"), Some("c".into()));
+        assert_eq!(sessions.next_in_queue("a"), None);
+        sessions.start(
+            "tv",
+            Some("c".into()),
+            Url::parse("http://x/").unwrap(),
+            0.0,
+            false,
+            vec![],
+        );
+        assert_eq!(sessions.next_in_queue("c"), None);
+    }
+}
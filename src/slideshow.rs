@@ -0,0 +1,90 @@
+/// Running slideshows
+///
+/// A slideshow casts the photos one at a time from a background task.
+/// Starting another slideshow, casting something else or stopping the device
+/// replaces the slideshow of the device, and the task stops before casting the
+/// next photo.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub type SlideshowToken = u64;
+
+#[derive(Debug, Default)]
+pub struct Slideshows {
+    next_token: AtomicU64,
+    running: Mutex<HashMap<String, SlideshowToken>>,
+}
+
+impl Slideshows {
+    /// Starts a new slideshow on the device, replacing the earlier one
+    pub fn start(&self, device: &str) -> SlideshowToken {
+        let token = self.next_token.fetch_add(1, Ordering::SeqCst);
+        self.running.lock().unwrap().insert(device.into(), token);
+        token
+    }
+
+    /// Stops the slideshow of the device, if any
+    pub fn stop(&self, device: &str) {
+        self.running.lock().unwrap().remove(device);
+    }
+
+    /// Ends the slideshow, unless it has been replaced already
+    pub fn end(&self, device: &str, token: SlideshowToken) {
+        let mut running = self.running.lock().unwrap();
+        if running.get(device) == Some(&token) {
+            running.remove(device);
+        }
+    }
+
+    /// Slideshow is still the one running on the device
+    pub fn is_current(&self, device: &str, token: SlideshowToken) -> bool {
+        self.running.lock().unwrap().get(device) == Some(&token)
+    }
+}
+
+/// Shuffles the items with a xorshift generator seeded with the seed
+///
+/// Not for anything needing good randomness, but enough for the order of the
+/// photos.
+pub fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed | 1;
+    for i in (1..items.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        items.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shuffle() {
+        let mut items: Vec<u32> = (0..20).collect();
+        shuffle(&mut items, 12345);
+        assert_ne!(items, (0..20).collect::<Vec<u32>>());
+        let mut sorted = items.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..20).collect::<Vec<u32>>());
+
+        let mut again: Vec<u32> = (0..20).collect();
+        shuffle(&mut again, 12345);
+        assert_eq!(items, again);
+    }
+
+    #[test]
+    fn test_slideshow_replaced() {
+        let slideshows = Slideshows::default();
+        let first = slideshows.start("tv");
+        let second = slideshows.start("tv");
+        assert!(!slideshows.is_current("tv", first));
+        slideshows.end("tv", first);
+        assert!(slideshows.is_current("tv", second));
+        slideshows.stop("tv");
+        assert!(!slideshows.is_current("tv", second));
+    }
+}
@@ -0,0 +1,33 @@
+/// Persistent JSON files in the data directory
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Saves are done one at a time, so the concurrent saves of the same file
+/// don't write the same temporary file
+static SAVING: Mutex<()> = Mutex::new(());
+
+/// Loads JSON file, returns None if it doesn't exist or can't be parsed
+pub fn load_json<T: DeserializeOwned, P: AsRef<Path>>(file: P) -> Option<T> {
+    let data = std::fs::read(file.as_ref()).ok()?;
+    match serde_json::from_slice(&data) {
+        Ok(v) => Some(v),
+        Err(err) => {
+            println!("Unable to parse {}: {}", file.as_ref().display(), err);
+            None
+        }
+    }
+}
+
+/// Saves JSON file, the file is replaced atomically
+pub fn save_json<T: Serialize, P: AsRef<Path>>(file: P, value: &T) -> Result<(), std::io::Error> {
+    let file = file.as_ref();
+    let _saving = SAVING.lock().unwrap_or_else(|v| v.into_inner());
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = file.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec(value)?)?;
+    std::fs::rename(&tmp, file)
+}
@@ -0,0 +1,198 @@
+/// Thumbnail and poster frame generation
+///
+/// Thumbnails are extracted with ffmpeg when they are requested the first
+/// time, and stored as `<id>-<mtime>-<width>.jpg` files in the thumbnail
+/// directory. Modifying the media file changes the mtime, and the old
+/// thumbnails of it are removed when new ones are generated.
+use crate::library::LibraryItem;
+use crate::media;
+use std::path::{Path, PathBuf};
+use tokio::sync::Semaphore;
+
+/// Width used when the request doesn't specify one
+const DEFAULT_WIDTH: u32 = 640;
+
+/// Maximum number of concurrent ffmpeg processes extracting frames
+const MAX_EXTRACTS: usize = 2;
+
+/// Frame is taken from this far into the file, so the intros and logos at the
+/// beginning are skipped
+const POSITION_PERCENT: f32 = 10.0;
+
+/// Position used when the duration is not known yet
+const FALLBACK_SECONDS: f32 = 60.0;
+
+#[derive(Debug)]
+pub struct Thumbnails {
+    dir: PathBuf,
+    widths: Vec<u32>,
+    extracts: Semaphore,
+}
+
+impl Thumbnails {
+    /// Thumbnails are generated only in the given widths, requests are
+    /// rounded up to the nearest one
+    pub fn new<P: AsRef<Path>>(dir: P, widths: &[u32]) -> Thumbnails {
+        let mut widths = widths.to_vec();
+        widths.sort_unstable();
+        widths.dedup();
+        if widths.is_empty() {
+            widths.push(DEFAULT_WIDTH);
+        }
+        Thumbnails {
+            dir: dir.as_ref().to_path_buf(),
+            widths,
+            extracts: Semaphore::new(MAX_EXTRACTS),
+        }
+    }
+
+    /// Nearest allowed width equal or larger than requested
+    pub fn width(&self, requested: Option<u32>) -> u32 {
+        let requested = requested.unwrap_or(DEFAULT_WIDTH);
+        *self
+            .widths
+            .iter()
+            .find(|v| **v >= requested)
+            .or_else(|| self.widths.last())
+            .unwrap()
+    }
+
+    /// Thumbnail file of the item, generated if it doesn't exist yet
+    pub async fn get(&self, item: &LibraryItem, width: u32) -> Result<PathBuf, std::io::Error> {
+        let file = self.path(item, width);
+        if file.exists() {
+            return Ok(file);
+        }
+        let _permit = self.extracts.acquire().await;
+        // Could have been generated while waiting
+        if file.exists() {
+            return Ok(file);
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        self.remove_outdated(item);
+
+        println!("Generating thumbnail {}", file.display());
+        let tmp = file.with_extension("tmp.jpg");
+        let position = position(item);
+        let mut result = if item.is_image() {
+            media::scale_image(&item.path, &tmp, width, None).await
+        } else if item.is_audio() {
+            // Embedded cover art of audio files
+            media::extract_frame(&item.path, &tmp, 0.0, width, false).await
+        } else {
+            media::extract_frame(&item.path, &tmp, position, width, true).await
+        };
+        if result.is_err() && !item.is_audio() && !item.is_image() {
+            // Short or dark file, take whatever the beginning has
+            result = media::extract_frame(&item.path, &tmp, 0.0, width, false).await;
+        }
+        match result {
+            Ok(_) => {
+                std::fs::rename(&tmp, &file)?;
+                Ok(file)
+            }
+            Err(err) => {
+                let _ = std::fs::remove_file(&tmp);
+                Err(err)
+            }
+        }
+    }
+
+    /// Thumbnail file of the item in the width, it may not exist yet
+    fn path(&self, item: &LibraryItem, width: u32) -> PathBuf {
+        self.dir
+            .join(format!("{}-{}-{}.jpg", item.id, item.mtime, width))
+    }
+
+    /// Removes thumbnails generated before the file was modified
+    ///
+    /// Thumbnails of the copies with the ID disambiguated by a suffix are
+    /// kept.
+    fn remove_outdated(&self, item: &LibraryItem) {
+        let prefix = format!("{}-", item.id);
+        let current = format!("{}-{}-", item.id, item.mtime);
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|v| v.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_own = name
+                .strip_prefix(&prefix)
+                .is_some_and(|v| v.split('-').count() == 2);
+            if is_own && !name.starts_with(&current) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+/// Position of the frame taken from the video
+fn position(item: &LibraryItem) -> f32 {
+    match item.duration {
+        Some(duration) if duration > 0.0 => duration * POSITION_PERCENT / 100.0,
+        _ => FALLBACK_SECONDS,
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_item(id: &str, mtime: u64, duration: Option<f32>) -> LibraryItem {
+        LibraryItem {
+            id: id.into(),
+            path: "/media/movie.mkv".into(),
+            size: 100,
+            mtime,
+            duration,
+            metadata: None,
+            artwork: Default::default(),
+            segments: None,
+            audio: None,
+            video: None,
+        }
+    }
+
+    #[test]
+    fn test_width() {
+        let thumbnails = Thumbnails::new("/tmp/thumbnails", &[320, 160, 320]);
+        assert_eq!(thumbnails.width(Some(100)), 160);
+        assert_eq!(thumbnails.width(Some(200)), 320);
+        assert_eq!(thumbnails.width(Some(1000)), 320);
+        assert_eq!(thumbnails.width(None), 320);
+        assert_eq!(Thumbnails::new("/tmp", &[]).width(Some(100)), DEFAULT_WIDTH);
+    }
+
+    #[test]
+    fn test_position() {
+        assert_eq!(position(&test_item("a", 1, Some(600.0))), 60.0);
+        assert_eq!(position(&test_item("a", 1, Some(0.0))), FALLBACK_SECONDS);
+        assert_eq!(position(&test_item("a", 1, None)), FALLBACK_SECONDS);
+    }
+
+    #[test]
+    fn test_remove_outdated() {
+        let dir = std::env::temp_dir().join(format!("casterson-thumbnails-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let thumbnails = Thumbnails::new(&dir, &[320]);
+        let item = test_item("0123", 20, None);
+        let path = thumbnails.path(&item, 320);
+        assert_eq!(path, dir.join("0123-20-320.jpg"));
+
+        let names = [
+            "0123-10-320.jpg",
+            "0123-20-160.jpg",
+            "0123-abcd-10-320.jpg",
+            "01234-10-320.jpg",
+        ];
+        for name in &names {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        thumbnails.remove_outdated(&item);
+        let exists: Vec<bool> = names.iter().map(|v| dir.join(v).exists()).collect();
+        assert_eq!(exists, vec![false, true, true, true]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+/// Trickplay sprite sheets for scrubbing previews
+///
+/// A frame of every `interval` seconds is tiled to sprite sheets, with a WebVTT
+/// thumbnails track pointing to the frames with `#xywh=` fragments. They are
+/// generated in background one file at a time, and stored in the
+/// `<id>-<mtime>` folders of the trickplay directory. The index is written
+/// last, so a folder with the index is complete.
+use crate::library::{Library, LibraryItem};
+use crate::media;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+/// Width of a single frame in the sprite sheets
+const FRAME_WIDTH: u32 = 160;
+
+/// Frames per sprite sheet, columns and rows
+const TILES: (u32, u32) = (10, 10);
+
+pub const INDEX_FILE: &str = "index.vtt";
+
+#[derive(Debug)]
+pub struct Trickplay {
+    dir: PathBuf,
+    interval: u32,
+
+    // Folders with the index, listed on start so browsing doesn't stat them
+    ready: Mutex<HashSet<String>>,
+
+    // Folders which couldn't be generated, not retried until restart
+    failed: Mutex<HashSet<String>>,
+}
+
+impl Trickplay {
+    /// Interval of 0 disables the generation
+    pub fn new<P: AsRef<Path>>(dir: P, interval: u32) -> Trickplay {
+        let ready = std::fs::read_dir(&dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|v| v.ok())
+                    .filter(|v| v.path().join(INDEX_FILE).exists())
+                    .map(|v| v.file_name().to_string_lossy().into_owned())
+                    .filter(|v| !v.ends_with(".tmp"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Trickplay {
+            dir: dir.as_ref().to_path_buf(),
+            interval,
+            ready: Mutex::new(ready),
+            failed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Index has been generated for the item
+    pub fn is_ready(&self, item: &LibraryItem) -> bool {
+        self.ready.lock().unwrap().contains(&self.key(item))
+    }
+
+    /// File of the generated trickplay, the index or one of the sheets
+    pub fn file(&self, item: &LibraryItem, name: &str) -> Option<PathBuf> {
+        let valid = name == INDEX_FILE
+            || (name.starts_with("sheet-")
+                && name.ends_with(".jpg")
+                && !name.contains('/')
+                && !name.contains(".."));
+        if valid && self.is_ready(item) {
+            Some(self.folder(item).join(name))
+        } else {
+            None
+        }
+    }
+
+    /// Generates the missing trickplays of the library items
+    pub async fn generate_missing(&self, library: &RwLock<Library>) {
+        if self.interval == 0 {
+            return;
+        }
+        let missing: Vec<LibraryItem> = library
+            .read()
+            .unwrap()
+            .items()
+            .into_iter()
+            .filter(|v| {
+                v.duration.is_some_and(|v| v > 0.0) && v.video.is_some() && !self.is_ready(v)
+            })
+            .filter(|v| !self.failed.lock().unwrap().contains(&self.key(v)))
+            .cloned()
+            .collect();
+        for item in missing {
+            // File may have been removed or modified meanwhile
+            if library.read().unwrap().get(&item.id).map(|v| v.mtime) != Some(item.mtime) {
+                continue;
+            }
+            if let Err(err) = self.generate(&item).await {
+                println!(
+                    "Unable to generate trickplay {}: {}",
+                    item.path.display(),
+                    err
+                );
+                self.failed.lock().unwrap().insert(self.key(&item));
+            }
+        }
+    }
+
+    /// Generates the trickplay of a probed video
+    async fn generate(&self, item: &LibraryItem) -> Result<(), std::io::Error> {
+        let (info, duration) = match (&item.video, item.duration) {
+            (Some(info), Some(duration)) => (info, duration),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Video is not probed",
+                ))
+            }
+        };
+        println!("Generating trickplay for {}", item.path.display());
+        let folder = self.folder(item);
+        let tmp = folder.with_extension("tmp");
+        let _ = std::fs::remove_dir_all(&tmp);
+        std::fs::create_dir_all(&tmp)?;
+        let result = media::extract_tiles(
+            &item.path,
+            tmp.join("sheet-%03d.jpg"),
+            self.interval,
+            FRAME_WIDTH,
+            TILES,
+        )
+        .await;
+        if let Err(err) = result {
+            let _ = std::fs::remove_dir_all(&tmp);
+            return Err(err);
+        }
+        let height = frame_height(info.width, info.height, FRAME_WIDTH);
+        let index = webvtt_index(duration, self.interval, (FRAME_WIDTH, height), TILES);
+        std::fs::write(tmp.join(INDEX_FILE), index)?;
+        self.remove_outdated(item);
+        std::fs::rename(&tmp, &folder)?;
+        self.ready.lock().unwrap().insert(self.key(item));
+        Ok(())
+    }
+
+    /// Removes the trickplays generated before the file was modified
+    ///
+    /// Folders of the copies with the IDs disambiguated by the path are not
+    /// the item's own.
+    fn remove_outdated(&self, item: &LibraryItem) {
+        let prefix = format!("{}-", item.id);
+        let current = self.key(item);
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        let mut ready = self.ready.lock().unwrap();
+        for entry in entries.filter_map(|v| v.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_own = name
+                .strip_prefix(&prefix)
+                .is_some_and(|v| !v.contains('-') && !v.ends_with(".tmp"));
+            if is_own && name != current {
+                let _ = std::fs::remove_dir_all(entry.path());
+                ready.remove(&name);
+            }
+        }
+    }
+
+    fn key(&self, item: &LibraryItem) -> String {
+        format!("{}-{}", item.id, item.mtime)
+    }
+
+    fn folder(&self, item: &LibraryItem) -> PathBuf {
+        self.dir.join(self.key(item))
+    }
+}
+
+/// Height of the frame scaled to the width, as ffmpeg's `scale=W:-2` does
+fn frame_height(video_width: i32, video_height: i32, width: u32) -> u32 {
+    if video_width <= 0 || video_height <= 0 {
+        return width * 9 / 16;
+    }
+    let half = f64::from(width) * f64::from(video_height) / f64::from(video_width * 2);
+    half.round() as u32 * 2
+}
+
+/// WebVTT thumbnails track of the sprite sheets
+///
+/// Sheets are named `sheet-001.jpg`, `sheet-002.jpg` and so on, like ffmpeg
+/// numbers them.
+pub fn webvtt_index(
+    duration: f32,
+    interval: u32,
+    (width, height): (u32, u32),
+    (columns, rows): (u32, u32),
+) -> String {
+    let frames = (duration / interval as f32).ceil() as u32;
+    let per_sheet = columns * rows;
+    let mut vtt = String::from("WEBVTT\n");
+    for i in 0..frames {
+        let start = (i * interval) as f32;
+        let end = (start + interval as f32).min(duration);
+        let tile = i % per_sheet;
+        vtt.push_str(&format!(
+            "\n{} --> {}\nsheet-{:03}.jpg#xywh={},{},{},{}\n",
+            vtt_timestamp(start),
+            vtt_timestamp(end),
+            i / per_sheet + 1,
+            (tile % columns) * width,
+            (tile / columns) * height,
+            width,
+            height
+        ));
+    }
+    vtt
+}
+
+fn vtt_timestamp(seconds: f32) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webvtt_index() {
+        let vtt = webvtt_index(25.5, 10, (160, 90), (2, 1));
+        assert_eq!(
+            vtt,
+            "WEBVTT\n\
+             \n00:00:00.000 --> 00:00:10.000\nsheet-001.jpg#xywh=0,0,160,90\n\
+             \n00:00:10.000 --> 00:00:20.000\nsheet-001.jpg#xywh=160,0,160,90\n\
+             \n00:00:20.000 --> 00:00:25.500\nsheet-002.jpg#xywh=0,0,160,90\n"
+        );
+        assert_eq!(frame_height(1920, 1080, 160), 90);
+        assert_eq!(frame_height(1920, 800, 160), 66);
+    }
+
+    #[test]
+    fn test_remove_outdated() {
+        let dir = std::env::temp_dir().join(format!("casterson-trickplay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for name in &["abc-100", "abc-200", "abc-0123456789abcdef-100"] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+            std::fs::write(dir.join(name).join(INDEX_FILE), "WEBVTT\n").unwrap();
+        }
+        let trickplay = Trickplay::new(&dir, 10);
+        let item: LibraryItem = serde_json::from_value(serde_json::json!({
+            "id": "abc",
+            "path": "/media/a.mkv",
+            "size": 5,
+            "mtime": 200,
+        }))
+        .unwrap();
+        assert!(trickplay.is_ready(&item));
+
+        // Copy with the disambiguated ID keeps its folder
+        trickplay.remove_outdated(&item);
+        assert!(!dir.join("abc-100").exists());
+        assert!(dir.join("abc-200").exists());
+        assert!(dir.join("abc-0123456789abcdef-100").exists());
+        assert!(trickplay.is_ready(&item));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
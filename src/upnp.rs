@@ -0,0 +1,331 @@
+/// UPnP MediaServer advertised with SSDP
+///
+/// TVs find the server from the SSDP announcements and the answers to their
+/// searches, and browse the library with the ContentDirectory service served
+/// by the API. Resources of the video items are either the files as is or the
+/// `media_show` transcodes, depending on the profile of the client.
+use crate::dlna;
+use crate::store;
+use crate::AppState;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const MEDIA_SERVER: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+pub const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+pub const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+/// URL path of the device description
+pub const DESCRIPTION_PATH: &str = "/upnp/description.xml";
+
+/// Seconds the announcements are valid, they are repeated at half of it
+const MAX_AGE: u64 = 1800;
+
+const SERVER: &str = "casterson/0.1 UPnP/1.0 DLNADOC/1.50";
+
+#[derive(Serialize, Deserialize)]
+struct ServerFile {
+    uuid: String,
+}
+
+/// Identity of the media server, the UUID is kept over the restarts so the
+/// TVs don't list the server twice
+#[derive(Debug)]
+pub struct MediaServer {
+    pub uuid: String,
+    pub name: String,
+
+    // Changes when the library changes, clients refresh their listings
+    update_id: AtomicU32,
+}
+
+impl MediaServer {
+    pub fn load<P: AsRef<Path>>(file: P, name: &str) -> MediaServer {
+        let uuid = match store::load_json::<ServerFile, _>(&file) {
+            Some(v) => v.uuid,
+            None => {
+                let uuid = new_uuid();
+                let value = ServerFile { uuid: uuid.clone() };
+                if let Err(err) = store::save_json(&file, &value) {
+                    println!("Unable to save UPnP server UUID {}", err);
+                }
+                uuid
+            }
+        };
+        MediaServer {
+            uuid,
+            name: name.into(),
+            update_id: AtomicU32::new(1),
+        }
+    }
+
+    pub fn update_id(&self) -> u32 {
+        self.update_id.load(Ordering::Relaxed)
+    }
+
+    pub fn library_changed(&self) {
+        self.update_id.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Version 4 style UUID from the time and the process, it's only generated
+/// once
+fn new_uuid() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |v| v.as_nanos() as u64);
+    let mut x = (nanos ^ (u64::from(std::process::id()) << 32)) | 1;
+    let mut next = || {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        x
+    };
+    let (a, b) = (next(), next());
+    format!(
+        "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        a >> 32,
+        (a >> 16) & 0xffff,
+        a & 0xfff,
+        (b >> 48) & 0x3fff | 0x8000,
+        b & 0xffff_ffff_ffff
+    )
+}
+
+/// Client of the ContentDirectory, recognized by the User-Agent
+#[derive(Debug)]
+pub struct ClientProfile {
+    pub name: &'static str,
+
+    // Substrings of the User-Agent or X-AV-Client-Info headers
+    user_agents: &'static [&'static str],
+
+    // Extensions of the video files played as is, others are transcoded
+    direct_exts: &'static [&'static str],
+}
+
+impl ClientProfile {
+    /// Video file is played as is instead of the transcode
+    pub fn is_direct<P: AsRef<Path>>(&self, file: P) -> bool {
+        file.as_ref()
+            .extension()
+            .and_then(|v| v.to_str())
+            .is_some_and(|ext| self.direct_exts.iter().any(|v| v.eq_ignore_ascii_case(ext)))
+    }
+}
+
+/// Known clients, the last one is used for the others
+pub const PROFILES: &[ClientProfile] = &[
+    ClientProfile {
+        name: "Samsung",
+        user_agents: &["samsung", "sec_hhp"],
+        direct_exts: &["mp4", "mkv", "avi", "mov", "ts", "mpg"],
+    },
+    ClientProfile {
+        name: "LG",
+        user_agents: &["lge", "webos"],
+        direct_exts: &["mp4", "mkv", "avi", "mov", "ts"],
+    },
+    ClientProfile {
+        name: "Sony",
+        user_agents: &["bravia", "sony"],
+        direct_exts: &["mp4", "mkv", "ts", "mpg"],
+    },
+    ClientProfile {
+        name: "Panasonic",
+        user_agents: &["panasonic", "viera"],
+        direct_exts: &["mp4", "mkv", "ts", "mpg"],
+    },
+    ClientProfile {
+        name: "Xbox",
+        user_agents: &["xbox"],
+        direct_exts: &["mp4", "mkv", "avi", "mov"],
+    },
+    ClientProfile {
+        name: "Generic",
+        user_agents: &[],
+        direct_exts: &["mp4"],
+    },
+];
+
+/// Profile of the client by the User-Agent
+pub fn profile(user_agent: &str) -> &'static ClientProfile {
+    let user_agent = user_agent.to_lowercase();
+    PROFILES
+        .iter()
+        .find(|v| v.user_agents.iter().any(|v| user_agent.contains(v)))
+        .unwrap_or(&PROFILES[PROFILES.len() - 1])
+}
+
+/// Notification types announced, with the USNs
+fn notification_types(uuid: &str) -> Vec<(String, String)> {
+    let device = format!("uuid:{}", uuid);
+    vec![
+        (
+            "upnp:rootdevice".to_string(),
+            format!("{}::upnp:rootdevice", device),
+        ),
+        (device.clone(), device.clone()),
+        (
+            MEDIA_SERVER.to_string(),
+            format!("{}::{}", device, MEDIA_SERVER),
+        ),
+        (
+            CONTENT_DIRECTORY.to_string(),
+            format!("{}::{}", device, CONTENT_DIRECTORY),
+        ),
+        (
+            CONNECTION_MANAGER.to_string(),
+            format!("{}::{}", device, CONNECTION_MANAGER),
+        ),
+    ]
+}
+
+/// Notification types answering the M-SEARCH, None if it's not a search
+fn search_matches(message: &str, uuid: &str) -> Option<Vec<(String, String)>> {
+    if !message.starts_with("M-SEARCH") {
+        return None;
+    }
+    let st = dlna::header(message, "st")?;
+    Some(
+        notification_types(uuid)
+            .into_iter()
+            .filter(|(nt, _)| st == "ssdp:all" || st == nt)
+            .collect(),
+    )
+}
+
+/// Address of this host as seen from the address
+fn local_ip(to: SocketAddr) -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(to).ok()?;
+    socket.local_addr().ok().map(|v| v.ip())
+}
+
+/// Starts answering the searches and announcing the server on a background
+/// thread
+pub fn start(state: Arc<AppState>) -> Result<(), std::io::Error> {
+    let multicast: SocketAddr = dlna::SSDP_ADDR.parse().unwrap();
+    let socket = net2::UdpBuilder::new_v4()?
+        .reuse_address(true)?
+        .bind(("0.0.0.0", multicast.port()))?;
+    socket.join_multicast_v4(&Ipv4Addr::new(239, 255, 255, 250), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let uuid = state.upnp.uuid.clone();
+    let port = state.opts.port;
+    let location = move |to: SocketAddr| -> Option<String> {
+        let ip = match state.opts.ip {
+            ip if ip.is_unspecified() => local_ip(to)?,
+            ip => ip,
+        };
+        Some(format!(
+            "http://{}{}",
+            SocketAddr::new(ip, port),
+            DESCRIPTION_PATH
+        ))
+    };
+    std::thread::spawn(move || {
+        let mut announced: Option<Instant> = None;
+        let mut buf = [0u8; 4096];
+        loop {
+            if announced.is_none_or(|v| v.elapsed() > Duration::from_secs(MAX_AGE / 2)) {
+                if let Some(location) = location(multicast) {
+                    for (nt, usn) in notification_types(&uuid) {
+                        let message = format!(
+                            "NOTIFY * HTTP/1.1\r\n\
+                             HOST: {}\r\n\
+                             CACHE-CONTROL: max-age={}\r\n\
+                             LOCATION: {}\r\n\
+                             NT: {}\r\n\
+                             NTS: ssdp:alive\r\n\
+                             SERVER: {}\r\n\
+                             USN: {}\r\n\r\n",
+                            multicast, MAX_AGE, location, nt, SERVER, usn
+                        );
+                        let _ = socket.send_to(message.as_bytes(), multicast);
+                    }
+                }
+                announced = Some(Instant::now());
+            }
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            let message = String::from_utf8_lossy(&buf[..len]);
+            let matches = match search_matches(&message, &uuid) {
+                Some(matches) => matches,
+                None => continue,
+            };
+            let location = match location(from) {
+                Some(location) => location,
+                None => continue,
+            };
+            for (st, usn) in matches {
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     CACHE-CONTROL: max-age={}\r\n\
+                     EXT:\r\n\
+                     LOCATION: {}\r\n\
+                     SERVER: {}\r\n\
+                     ST: {}\r\n\
+                     USN: {}\r\n\r\n",
+                    MAX_AGE, location, SERVER, st, usn
+                );
+                let _ = socket.send_to(response.as_bytes(), from);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_matches() {
+        let uuid = "4d696e69-444c-164e-9d41-b827eb000001";
+        let search = "M-SEARCH * HTTP/1.1\r\n\
+                      HOST: 239.255.255.250:1900\r\n\
+                      MAN: \"ssdp:discover\"\r\n\
+                      MX: 2\r\n\
+                      ST: urn:schemas-upnp-org:service:ContentDirectory:1\r\n\r\n";
+        let matches = search_matches(search, uuid).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(
+            matches[0].1,
+            format!("uuid:{}::{}", uuid, CONTENT_DIRECTORY)
+        );
+
+        let all = search.replace(CONTENT_DIRECTORY, "ssdp:all");
+        assert_eq!(search_matches(&all, uuid).unwrap().len(), 5);
+        let renderer = search.replace(
+            CONTENT_DIRECTORY,
+            "urn:schemas-upnp-org:device:MediaRenderer:1",
+        );
+        assert!(search_matches(&renderer, uuid).unwrap().is_empty());
+        assert!(search_matches("NOTIFY * HTTP/1.1\r\n\r\n", uuid).is_none());
+    }
+
+    #[test]
+    fn test_profile() {
+        let samsung = profile("SEC_HHP_[TV] Samsung Q70 Series (55)/1.0 UPnP/1.0");
+        assert_eq!(samsung.name, "Samsung");
+        assert!(samsung.is_direct("/media/movie.MKV"));
+        let generic = profile("VLC/3.0.9 LibVLC/3.0.9");
+        assert_eq!(generic.name, "Generic");
+        assert!(!generic.is_direct("/media/movie.mkv"));
+        assert!(generic.is_direct("/media/movie.mp4"));
+    }
+
+    #[test]
+    fn test_new_uuid() {
+        let uuid = new_uuid();
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+    }
+}
@@ -0,0 +1,256 @@
+/// Watches the media directories and updates the library incrementally
+///
+/// Bursts of filesystem events are collected and applied at once, after which
+/// the library is saved and `LibraryChanged` is notified.
+use crate::library;
+use crate::library::{Library, LibraryItem};
+use crate::msg;
+use crate::nfo;
+use crate::playlist;
+use crate::AppState;
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Delay of the notify's debouncer, events for a single path are merged
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Events are collected until there has been a pause this long
+const BURST_PAUSE: Duration = Duration::from_secs(1);
+
+/// Events are applied at least this often during a long burst
+const BURST_MAX: Duration = Duration::from_secs(10);
+
+/// Starts watching the media directories on a background thread
+pub fn start(state: Arc<AppState>) -> Result<(), notify::Error> {
+    let (tx, rx) = channel();
+    let mut watcher = watcher(tx, DEBOUNCE)?;
+    for dir in &state.opts.dir {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+    }
+    std::thread::spawn(move || {
+        // Watcher stops when it's dropped
+        let _watcher = watcher;
+        while let Some(events) = next_burst(&rx) {
+            let changed = apply(
+                &state.library,
+                &state.opts.dir,
+                &state.opts.media_exts,
+                events,
+            );
+            if changed {
+                state.library.read().unwrap().save();
+                let _ = state.notifier.send(msg::NotifyMessage::LibraryChanged);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Blocks until there are events, returns None if the watcher is gone
+fn next_burst(rx: &Receiver<DebouncedEvent>) -> Option<Vec<DebouncedEvent>> {
+    let mut events = vec![rx.recv().ok()?];
+    let started = Instant::now();
+    while started.elapsed() < BURST_MAX {
+        match rx.recv_timeout(BURST_PAUSE) {
+            Ok(event) => events.push(event),
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Some(events)
+}
+
+/// Applies the events to the library, returns true if the library changed
+fn apply(
+    library: &RwLock<Library>,
+    dirs: &[PathBuf],
+    exts: &[String],
+    events: Vec<DebouncedEvent>,
+) -> bool {
+    let mut changed = false;
+    for event in events {
+        changed |= match event {
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Remove(path)
+                if nfo::is_sidecar_file(&path) =>
+            {
+                refresh_sidecar(library, &path)
+            }
+            DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => add(library, exts, &path),
+            DebouncedEvent::Remove(path) => library.write().unwrap().remove_path(&path),
+            DebouncedEvent::Rename(from, to)
+                if nfo::is_sidecar_file(&from) || nfo::is_sidecar_file(&to) =>
+            {
+                refresh_sidecar(library, &from) | refresh_sidecar(library, &to)
+            }
+            DebouncedEvent::Rename(from, to)
+                if to.is_dir() || is_media_file(exts, &to) || playlist::is_playlist_file(&to) =>
+            {
+                // Write lock is released before adding, it reads the library
+                let renamed = library.write().unwrap().rename_path(&from, &to);
+                renamed | add(library, exts, &to)
+            }
+            DebouncedEvent::Rename(from, _) => library.write().unwrap().remove_path(&from),
+            DebouncedEvent::Rescan => {
+                library::rescan(library, dirs, exts);
+                true
+            }
+            DebouncedEvent::Error(err, path) => {
+                println!("Watching error {:?} {:?}", err, path);
+                false
+            }
+            _ => false,
+        };
+    }
+    changed
+}
+
+/// Adds the media file, or the media files in the directory
+fn add(library: &RwLock<Library>, exts: &[String], path: &Path) -> bool {
+    let (files, playlists) = if path.is_dir() {
+        library::scan_files(&[path], exts)
+    } else if is_media_file(exts, path) {
+        (vec![path.to_path_buf()], vec![])
+    } else if playlist::is_playlist_file(path) {
+        (vec![], vec![path.to_path_buf()])
+    } else {
+        (vec![], vec![])
+    };
+    let mut changed = false;
+    for file in playlists {
+        changed |= library.write().unwrap().add_playlist(&file);
+    }
+    for file in files {
+        if !library.read().unwrap().needs_update(&file) {
+            continue;
+        }
+        // Hashing is done without holding the lock
+        match LibraryItem::read(&file) {
+            Ok(item) => {
+                library.write().unwrap().insert(item);
+                changed = true;
+            }
+            Err(err) => println!("Unable to hash {}: {}", file.display(), err),
+        }
+    }
+    changed
+}
+
+/// NFO file or artwork changed, refreshes the items using it
+fn refresh_sidecar(library: &RwLock<Library>, path: &Path) -> bool {
+    match path.parent() {
+        Some(dir) => library.write().unwrap().refresh_local_metadata(dir),
+        None => false,
+    }
+}
+
+/// Media file to index, the artwork images aren't photos
+fn is_media_file(exts: &[String], path: &Path) -> bool {
+    crate::media::has_extension(path, exts) && !nfo::is_artwork_file(path)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_next_burst() {
+        let (tx, rx) = channel();
+        tx.send(DebouncedEvent::Rescan).unwrap();
+        tx.send(DebouncedEvent::Remove("/media/a.mkv".into()))
+            .unwrap();
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(BURST_PAUSE * 2);
+            tx.send(DebouncedEvent::Remove("/media/b.mkv".into()))
+                .unwrap();
+        });
+        assert_eq!(next_burst(&rx).unwrap().len(), 2);
+        assert_eq!(next_burst(&rx).unwrap().len(), 1);
+        sender.join().unwrap();
+        assert!(next_burst(&rx).is_none());
+    }
+
+    #[test]
+    fn test_apply() {
+        let dir = std::env::temp_dir().join(format!("casterson-watcher-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("show")).unwrap();
+        let dirs = vec![dir.clone()];
+        let exts = vec!["mkv".to_string(), "jpg".to_string()];
+        let library = RwLock::new(Library::default());
+        let file = dir.join("show/a.mkv");
+        std::fs::write(&file, b"video").unwrap();
+
+        assert!(apply(
+            &library,
+            &dirs,
+            &exts,
+            vec![DebouncedEvent::Create(file.clone())]
+        ));
+        let id = library.read().unwrap().items()[0].id.clone();
+        // Unchanged file isn't hashed again
+        assert!(!apply(
+            &library,
+            &dirs,
+            &exts,
+            vec![DebouncedEvent::Write(file.clone())]
+        ));
+        assert!(!apply(
+            &library,
+            &dirs,
+            &exts,
+            vec![DebouncedEvent::Create(dir.join("notes.txt"))]
+        ));
+
+        // Photos are indexed, the artwork isn't
+        let photo = dir.join("IMG_0001.JPG");
+        std::fs::write(&photo, b"photo").unwrap();
+        std::fs::write(dir.join("show/poster.jpg"), b"poster").unwrap();
+        let events = vec![
+            DebouncedEvent::Create(photo.clone()),
+            DebouncedEvent::Create(dir.join("show/poster.jpg")),
+        ];
+        assert!(apply(&library, &dirs, &exts, events));
+        assert_eq!(library.read().unwrap().items().len(), 2);
+        std::fs::remove_file(&photo).unwrap();
+        assert!(apply(
+            &library,
+            &dirs,
+            &exts,
+            vec![DebouncedEvent::Remove(photo)]
+        ));
+        assert_eq!(library.read().unwrap().items().len(), 1);
+
+        // Renamed directory moves the files, the IDs stay the same
+        let moved = dir.join("moved");
+        std::fs::rename(dir.join("show"), &moved).unwrap();
+        let event = DebouncedEvent::Rename(dir.join("show"), moved.clone());
+        assert!(apply(&library, &dirs, &exts, vec![event]));
+        assert_eq!(
+            library.read().unwrap().get(&id).unwrap().path,
+            moved.join("a.mkv")
+        );
+
+        std::fs::remove_dir_all(&moved).unwrap();
+        assert!(apply(
+            &library,
+            &dirs,
+            &exts,
+            vec![DebouncedEvent::Remove(moved)]
+        ));
+        assert!(library.read().unwrap().items().is_empty());
+
+        std::fs::create_dir_all(dir.join("show")).unwrap();
+        std::fs::write(&file, b"video").unwrap();
+        std::fs::write(file.with_file_name("b.mkv"), b"other").unwrap();
+        assert!(apply(&library, &dirs, &exts, vec![DebouncedEvent::Rescan]));
+        assert_eq!(library.read().unwrap().items().len(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
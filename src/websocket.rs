@@ -0,0 +1,167 @@
+/// Minimal server side of the WebSocket protocol (RFC 6455)
+///
+/// Enough for the browser receivers: the handshake key, and reading and
+/// writing of the frames on the upgraded connection. Extensions aren't
+/// negotiated.
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message accepted from the clients
+const MAX_MESSAGE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/// Value of the Sec-WebSocket-Accept header for the Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let digest = Sha1::digest(format!("{}{}", key.trim(), GUID).as_bytes());
+    base64::encode(digest)
+}
+
+/// Reads the next message, None when the connection is closed
+pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<Message>> {
+    let mut opcode = 0;
+    let mut data = vec![];
+    loop {
+        let mut header = [0u8; 2];
+        if let Err(err) = reader.read_exact(&mut header).await {
+            return match err.kind() {
+                std::io::ErrorKind::UnexpectedEof => Ok(None),
+                _ => Err(err),
+            };
+        }
+        let fin = header[0] & 0x80 != 0;
+        let frame_opcode = header[0] & 0x0f;
+        let masked = header[1] & 0x80 != 0;
+        let len = match header[1] & 0x7f {
+            126 => u64::from(reader.read_u16().await?),
+            127 => reader.read_u64().await?,
+            len => u64::from(len),
+        };
+        if len + data.len() as u64 > MAX_MESSAGE_SIZE {
+            return Err(invalid_data("Message is too large"));
+        }
+        let mut mask = [0u8; 4];
+        if masked {
+            reader.read_exact(&mut mask).await?;
+        }
+        let mut payload = vec![0u8; len as usize];
+        reader.read_exact(&mut payload).await?;
+        if masked {
+            for (i, v) in payload.iter_mut().enumerate() {
+                *v ^= mask[i % 4];
+            }
+        }
+
+        // Control frames may come between the fragments, pings and pongs of
+        // those are skipped
+        let fragmented = !data.is_empty();
+        match frame_opcode {
+            0x8 => return Ok(Some(Message::Close)),
+            0x9 if fragmented => continue,
+            0xa if fragmented => continue,
+            0x9 => return Ok(Some(Message::Ping(payload))),
+            0xa => return Ok(Some(Message::Pong(payload))),
+            0x0 => {}
+            v => opcode = v,
+        }
+        data.extend(payload);
+        if !fin {
+            continue;
+        }
+        return match opcode {
+            0x1 => String::from_utf8(data)
+                .map(|v| Some(Message::Text(v)))
+                .map_err(|_| invalid_data("Text isn't UTF-8")),
+            0x2 => Ok(Some(Message::Binary(data))),
+            _ => Err(invalid_data("Unknown opcode")),
+        };
+    }
+}
+
+/// Writes the message as a single unmasked frame
+pub async fn write<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> std::io::Result<()> {
+    let (opcode, payload): (u8, &[u8]) = match message {
+        Message::Text(v) => (0x1, v.as_bytes()),
+        Message::Binary(v) => (0x2, v),
+        Message::Close => (0x8, &[]),
+        Message::Ping(v) => (0x9, v),
+        Message::Pong(v) => (0xa, v),
+    };
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xffff => {
+            frame.push(126);
+            frame.extend(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // Example of the RFC
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_write() {
+        // Ping and the masked "Hello" of the RFC split to two fragments
+        let frames: &[u8] = &[
+            0x89, 0x00, // Ping
+            0x01, 0x83, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, // "Hel"
+            0x80, 0x82, 0x37, 0xfa, 0x21, 0x3d, 0x5b, 0x95, // "lo"
+        ];
+        let mut reader = frames;
+        assert_eq!(
+            read(&mut reader).await.unwrap(),
+            Some(Message::Ping(vec![]))
+        );
+        assert_eq!(
+            read(&mut reader).await.unwrap(),
+            Some(Message::Text("Hello".into()))
+        );
+        assert_eq!(read(&mut reader).await.unwrap(), None);
+
+        let mut written = vec![];
+        let text = "x".repeat(200);
+        write(&mut written, &Message::Text(text.clone()))
+            .await
+            .unwrap();
+        assert_eq!(&written[..4], &[0x81, 126, 0, 200]);
+        assert_eq!(
+            read(&mut &written[..]).await.unwrap(),
+            Some(Message::Text(text))
+        );
+    }
+}